@@ -3,4 +3,7 @@ pub mod convert;
 pub mod operation;
 pub mod remap;
 pub mod dataset;
-pub mod metric;
\ No newline at end of file
+pub mod metric;
+pub mod palette;
+pub mod vectorize;
+pub mod walk;
\ No newline at end of file
@@ -22,39 +22,36 @@ use tracing::{info_span, Instrument, Span};
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 use tracing_unwrap::{OptionExt, ResultExt};
 
+use super::dataset::pairing::{join_key, relparent_and_stem};
+use super::palette::Palette;
+use super::walk::{
+    output_dir, output_path, output_root, relative_parent, WalkOptions, IMAGE_EXTENSIONS,
+};
 use crate::THREAD_POOL;
 
-pub async fn split_images(dataset_path: &String, target_height: &u32, target_width: &u32) {
-    let mut entries: Vec<PathBuf> = Vec::new();
+pub async fn split_images(
+    dataset_path: &String,
+    target_height: &u32,
+    target_width: &u32,
+    walk: &WalkOptions,
+) {
     let dataset_path = PathBuf::from(dataset_path);
-    if dataset_path.is_file() {
-        entries.push(dataset_path.clone());
-        fs::create_dir_all(format!(
-            "{}/output/",
-            dataset_path.parent().unwrap().to_str().unwrap()
-        ))
-        .expect_or_log("Failed to create directory");
-    } else {
-        entries = fs::read_dir(dataset_path.clone())
-            .unwrap()
-            .map(|x| x.unwrap().path())
-            .collect();
-
-        fs::create_dir_all(format!("{}/output/", dataset_path.to_str().unwrap()))
-            .expect_or_log("Failed to create directory");
-    }
+    let output_root =
+        output_root(&dataset_path, "output").expect_or_log("Failed to get output dir");
+    let entries = walk
+        .collect_files(&dataset_path, IMAGE_EXTENSIONS, &[&output_root])
+        .expect_or_log("Failed to read directory");
     let mut threads = JoinSet::new();
 
     for entry in entries {
-        if entry.is_dir() {
-            continue;
-        }
         let target_height = *target_height;
         let target_width = *target_width;
         let entry_path = entry
             .to_str()
             .expect_or_log("Failed to convert path to string")
             .to_owned();
+        let output_dir = output_dir(&dataset_path, &entry, &output_root)
+            .expect_or_log("Failed to create directory");
 
         threads.spawn(async move {
             let file_name = entry
@@ -124,8 +121,8 @@ pub async fn split_images(dataset_path: &String, target_height: &u32, target_wid
                     )
                     .expect_or_log("Failed to crop image");
                     let path = format!(
-                        "{}/output/{}_LTR_x{}_y{}.{}",
-                        entry.parent().unwrap().to_str().unwrap(),
+                        "{}/{}_LTR_x{}_y{}.{}",
+                        output_dir.display(),
                         file_stem,
                         x_index,
                         y_index,
@@ -177,8 +174,8 @@ pub async fn split_images(dataset_path: &String, target_height: &u32, target_wid
                     )
                     .expect_or_log("Failed to crop image");
                     let path = format!(
-                        "{}/output/{}_RTL_x{}_y{}.{}",
-                        entry.parent().unwrap().to_str().unwrap(),
+                        "{}/{}_RTL_x{}_y{}.{}",
+                        output_dir.display(),
                         file_stem,
                         x_index,
                         y_index,
@@ -221,27 +218,27 @@ pub async fn split_images_with_bias(
     bias_step: &u32,
     target_height: &u32,
     target_width: &u32,
+    walk: &WalkOptions,
 ) {
-    let entries = fs::read_dir(dataset_path).unwrap();
+    let dataset_path = PathBuf::from(dataset_path);
+    let output_root = dataset_path.join("..").join("output");
+    let entries = walk
+        .collect_files(&dataset_path, IMAGE_EXTENSIONS, &[])
+        .expect_or_log("Failed to read directory");
     let mut threads = JoinSet::new();
 
-    fs::create_dir_all(format!("{}/../output/", dataset_path)).unwrap();
-
     for entry in entries {
-        let entry = entry.unwrap();
-        if entry.path().is_dir() {
-            continue;
-        }
-        let dataset_path = dataset_path.clone();
         let bias_step = *bias_step;
         let target_height = *target_height;
         let target_width = *target_width;
-        let entry_path = entry.path().to_str().unwrap().to_string();
+        let entry_path = entry.to_str().unwrap().to_string();
+        let output_dir = output_dir(&dataset_path, &entry, &output_root)
+            .expect_or_log("Failed to create directory");
 
         threads.spawn(async move {
             tracing::info!(
                 "Image {} processing...",
-                entry.file_name().to_str().unwrap()
+                entry.file_name().unwrap().to_str().unwrap()
             );
 
             // 读取图片
@@ -278,13 +275,13 @@ pub async fn split_images_with_bias(
                         .unwrap();
                         imgcodecs::imwrite(
                             format!(
-                                "{}/../output/{}_LTR_bias{}_x{}_y{}.{}",
-                                dataset_path,
-                                entry.path().file_stem().unwrap().to_str().unwrap(),
+                                "{}/{}_LTR_bias{}_x{}_y{}.{}",
+                                output_dir.display(),
+                                entry.file_stem().unwrap().to_str().unwrap(),
                                 bias,
                                 x_index,
                                 y_index,
-                                entry.path().extension().unwrap().to_str().unwrap()
+                                entry.extension().unwrap().to_str().unwrap()
                             )
                             .as_str(),
                             &cropped_img,
@@ -295,7 +292,7 @@ pub async fn split_images_with_bias(
                 }
                 tracing::info!(
                     "Image LTR {} bias {} done",
-                    entry.file_name().to_str().unwrap(),
+                    entry.file_name().unwrap().to_str().unwrap(),
                     bias
                 );
             }
@@ -334,13 +331,13 @@ pub async fn split_images_with_bias(
                         .unwrap();
                         imgcodecs::imwrite(
                             format!(
-                                "{}/../output/{}_RTL_bias{}_x{}_y{}.{}",
-                                dataset_path,
-                                entry.path().file_stem().unwrap().to_str().unwrap(),
+                                "{}/{}_RTL_bias{}_x{}_y{}.{}",
+                                output_dir.display(),
+                                entry.file_stem().unwrap().to_str().unwrap(),
                                 bias,
                                 x_index,
                                 y_index,
-                                entry.path().extension().unwrap().to_str().unwrap()
+                                entry.extension().unwrap().to_str().unwrap()
                             )
                             .as_str(),
                             &cropped_img,
@@ -351,12 +348,15 @@ pub async fn split_images_with_bias(
                 }
                 tracing::info!(
                     "Image RTL {} bias {} done",
-                    entry.file_name().to_str().unwrap(),
+                    entry.file_name().unwrap().to_str().unwrap(),
                     bias
                 );
             }
 
-            tracing::info!("Image {} done", entry.file_name().to_str().unwrap());
+            tracing::info!(
+                "Image {} done",
+                entry.file_name().unwrap().to_str().unwrap()
+            );
         });
     }
     while threads.join_next().await.is_some() {}
@@ -403,20 +403,28 @@ pub fn check_valid_pixel_count(
     (ratio > 0.01, ratio)
 }
 
+/// Colors checked by [`check_valid_pixel_count`], the images are converted to RGB first.
+/// In valid mode the classes without the ignore flag count as valid pixels,
+/// otherwise the ignored classes are the invalid ones. A plain RGB list has no ignore
+/// flag, so all of its colors are used in both modes.
+fn filter_colors(palette: &Palette, valid_rgb_mode: bool) -> Vec<core::Vec3b> {
+    let any_ignored = palette.classes.iter().any(|class| class.ignore);
+    palette
+        .classes
+        .iter()
+        .filter(|class| !any_ignored || class.ignore != valid_rgb_mode)
+        .map(|class| core::Vec3b::from(class.rgb))
+        .collect()
+}
+
 pub async fn process_dataset_with_rgblist(
     dataset_path: &String,
-    rgb_list_str: &str,
+    palette: &Palette,
     valid_rgb_mode: bool,
+    walk: &WalkOptions,
 ) {
-    let rgb_list: Arc<RwLock<Vec<core::Vec3b>>> = Arc::new(RwLock::new(Vec::new()));
-    for rgb in rgb_list_str.split(";") {
-        let rgb_list = Arc::clone(&rgb_list);
-        let mut rgb_list = rgb_list.write().unwrap();
-
-        let rgb_vec: Vec<u8> = rgb.split(',').map(|s| s.parse::<u8>().unwrap()).collect();
-
-        rgb_list.push(core::Vec3b::from([rgb_vec[0], rgb_vec[1], rgb_vec[2]]));
-    }
+    let rgb_list: Arc<RwLock<Vec<core::Vec3b>>> =
+        Arc::new(RwLock::new(filter_colors(palette, valid_rgb_mode)));
 
     tracing::info!(
         "RGB list length: {} Mode: {}",
@@ -427,12 +435,6 @@ pub async fn process_dataset_with_rgblist(
         }
     );
 
-    let image_entries: Vec<PathBuf>;
-    let label_entries: Vec<PathBuf>;
-
-    let image_output_path: String;
-    let label_output_path: String;
-
     let image_path = PathBuf::from(dataset_path).join("images");
     let label_path = PathBuf::from(dataset_path).join("labels");
     tracing::debug!(
@@ -450,35 +452,14 @@ pub async fn process_dataset_with_rgblist(
         return;
     }
 
-    if image_path.is_dir() {
-        image_entries = fs::read_dir(&image_path)
-            .unwrap()
-            .map(|e| e.unwrap().path())
-            .collect();
-
-        image_output_path = format!("{}/filtered_images/", image_path.to_str().unwrap());
-    } else {
-        image_entries = vec![image_path.clone()];
-        image_output_path = format!(
-            "{}/filtered/images/",
-            image_path.parent().unwrap().to_str().unwrap()
-        );
-    }
-
-    if label_path.is_dir() {
-        label_entries = fs::read_dir(&label_path)
-            .unwrap()
-            .map(|e| e.unwrap().path())
-            .collect();
-
-        label_output_path = format!("{}/filtered_labels/", label_path.to_str().unwrap());
-    } else {
-        label_entries = vec![label_path.clone()];
-        label_output_path = format!(
-            "{}/filtered/labels/",
-            label_path.parent().unwrap().to_str().unwrap()
-        );
-    }
+    let image_output_path = image_path.join("filtered_images");
+    let label_output_path = label_path.join("filtered_labels");
+    let image_entries = walk
+        .collect_files(&image_path, IMAGE_EXTENSIONS, &[&image_output_path])
+        .expect_or_log("Failed to read image directory");
+    let label_entries = walk
+        .collect_files(&label_path, IMAGE_EXTENSIONS, &[&label_output_path])
+        .expect_or_log("Failed to read label directory");
 
     let sem = Arc::new(Semaphore::new(
         (*THREAD_POOL.read().expect_or_log("Get pool error")).into(),
@@ -536,7 +517,10 @@ pub async fn process_dataset_with_rgblist(
         let sem = Arc::clone(&sem);
         let valid_id = Arc::clone(&valid_id);
         let rgb_list = Arc::clone(&rgb_list);
-        let label_output_path = label_output_path.clone();
+        // Labels are matched with images by relative folder + stem
+        let key = relparent_and_stem(&label_path, &path).expect_or_log("Failed to get label id");
+        let output_path = output_path(&label_path, &path, &label_output_path)
+            .expect_or_log("Failed to create directory");
 
         threads.spawn(
             async move {
@@ -561,21 +545,9 @@ pub async fn process_dataset_with_rgblist(
                 let rgb_list = rgb_list.read().unwrap();
                 let (valid, _) = check_valid_pixel_count(&img, &rgb_list, valid_rgb_mode);
                 if valid {
-                    valid_id
-                        .write()
-                        .unwrap()
-                        .push(path.file_stem().unwrap().to_str().unwrap().to_string());
-                    imgcodecs::imwrite(
-                        &format!(
-                            "{}//{}.{}",
-                            label_output_path,
-                            path.file_stem().unwrap().to_str().unwrap(),
-                            path.extension().unwrap().to_str().unwrap(),
-                        ),
-                        &img,
-                        &core::Vector::new(),
-                    )
-                    .unwrap();
+                    valid_id.write().unwrap().push(key);
+                    imgcodecs::imwrite(output_path.to_str().unwrap(), &img, &core::Vector::new())
+                        .unwrap();
                 }
                 Span::current().pb_set_message(path.file_name().unwrap().to_str().unwrap());
                 Span::current().pb_inc(1);
@@ -604,6 +576,8 @@ pub async fn process_dataset_with_rgblist(
     for path in image_entries {
         let sem = Arc::clone(&sem);
         let valid_id = Arc::clone(&valid_id);
+        let key = relparent_and_stem(&image_path, &path).expect_or_log("Failed to get image id");
+        let image_path = image_path.clone();
         let image_output_path = image_output_path.clone();
 
         threads.spawn(
@@ -613,22 +587,11 @@ pub async fn process_dataset_with_rgblist(
                     imgcodecs::imread(path.to_str().unwrap(), imgcodecs::IMREAD_UNCHANGED).unwrap();
 
                 let img = BoxedRef::from(img);
-                if valid_id
-                    .read()
-                    .unwrap()
-                    .contains(&path.file_stem().unwrap().to_str().unwrap().to_string())
-                {
-                    imgcodecs::imwrite(
-                        &format!(
-                            "{}//{}.{}",
-                            image_output_path,
-                            path.file_stem().unwrap().to_str().unwrap(),
-                            path.extension().unwrap().to_str().unwrap(),
-                        ),
-                        &img,
-                        &core::Vector::new(),
-                    )
-                    .unwrap();
+                if valid_id.read().unwrap().contains(&key) {
+                    let output_path = output_path(&image_path, &path, &image_output_path)
+                        .expect_or_log("Failed to create directory");
+                    imgcodecs::imwrite(output_path.to_str().unwrap(), &img, &core::Vector::new())
+                        .unwrap();
                 }
                 Span::current().pb_set_message(path.file_name().unwrap().to_str().unwrap());
                 Span::current().pb_inc(1);
@@ -646,18 +609,12 @@ pub async fn split_images_with_rgb_filter(
     images_path: &str,
     target_height: &u32,
     target_width: &u32,
-    rgb_list_str: &str,
+    palette: &Palette,
     valid_rgb_mode: bool,
+    walk: &WalkOptions,
 ) {
-    let rgb_list: Arc<RwLock<Vec<core::Vec3b>>> = Arc::new(RwLock::new(Vec::new()));
-    for rgb in rgb_list_str.split(";") {
-        let rgb_list = Arc::clone(&rgb_list);
-        let mut rgb_list = rgb_list.write().unwrap();
-
-        let rgb_vec: Vec<u8> = rgb.split(',').map(|s| s.parse::<u8>().unwrap()).collect();
-
-        rgb_list.push(core::Vec3b::from([rgb_vec[0], rgb_vec[1], rgb_vec[2]]));
-    }
+    let rgb_list: Arc<RwLock<Vec<core::Vec3b>>> =
+        Arc::new(RwLock::new(filter_colors(palette, valid_rgb_mode)));
 
     tracing::info!(
         "RGB list length: {} Mode: {}",
@@ -668,26 +625,16 @@ pub async fn split_images_with_rgb_filter(
         }
     );
 
-    let label_entries: Vec<PathBuf>;
-
-    let label_output_path: String;
-
     let label_path = PathBuf::from(images_path);
 
-    if label_path.is_dir() {
-        label_entries = fs::read_dir(&label_path)
-            .unwrap()
-            .map(|e| e.unwrap().path())
-            .collect();
-
-        label_output_path = format!("{}/output/", label_path.to_str().unwrap());
+    let label_output_path = if label_path.is_dir() {
+        label_path.join("output")
     } else {
-        label_entries = vec![label_path.clone()];
-        label_output_path = format!(
-            "{}/output/labels/",
-            label_path.parent().unwrap().to_str().unwrap()
-        );
-    }
+        label_path.parent().unwrap().join("output").join("labels")
+    };
+    let label_entries = walk
+        .collect_files(&label_path, IMAGE_EXTENSIONS, &[&label_output_path])
+        .expect_or_log("Failed to read directory");
 
     let sem = Arc::new(Semaphore::new(
         (*THREAD_POOL.read().expect_or_log("Get pool error")).into(),
@@ -712,10 +659,6 @@ pub async fn split_images_with_rgb_filter(
     // Label Processing
     let mut label_extension = None;
     for entry in label_entries {
-        if !entry.is_file() {
-            continue;
-        }
-
         if label_extension.is_none() {
             let extension = entry
                 .extension()
@@ -732,7 +675,8 @@ pub async fn split_images_with_rgb_filter(
         let rgb_list = Arc::clone(&rgb_list);
         let label_extension = label_extension.clone();
 
-        let label_output_path = label_output_path.clone();
+        let label_output_path = output_dir(&label_path, &entry, &label_output_path)
+            .expect_or_log("Failed to create directory");
         threads.spawn(async move {
             let _ = permit.acquire().await.unwrap();
             tracing::info!(
@@ -792,7 +736,7 @@ pub async fn split_images_with_rgb_filter(
                         imgcodecs::imwrite(
                             &format!(
                                 "{}/{}.{}",
-                                label_output_path,
+                                label_output_path.display(),
                                 label_id,
                                 label_extension.as_ref().unwrap()
                             ),
@@ -842,7 +786,7 @@ pub async fn split_images_with_rgb_filter(
                         imgcodecs::imwrite(
                             &format!(
                                 "{}/{}.{}",
-                                label_output_path,
+                                label_output_path.display(),
                                 label_id,
                                 label_extension.as_ref().unwrap()
                             ),
@@ -875,39 +819,32 @@ pub async fn split_images_with_label_filter(
     labels_path: &String,
     target_height: &u32,
     target_width: &u32,
+    walk: &WalkOptions,
 ) -> Result<()> {
+    // Labels are keyed by relative folder + stem, the globs only apply to the images
+    let labels_path = PathBuf::from(labels_path);
+    let label_walk = WalkOptions {
+        recursive: walk.recursive,
+        ..Default::default()
+    };
     let mut valid_name_set = HashSet::<String>::new();
-
-    for entry in fs::read_dir(labels_path)? {
-        let path = entry?.path();
-        if let Some(name) = path.file_stem().map(|s| s.to_string_lossy()) {
-            valid_name_set.insert(name.into_owned());
-        } else {
-            bail!("Failed to read file stem, illigal path");
-        }
+    for path in label_walk.collect_files(&labels_path, IMAGE_EXTENSIONS, &[])? {
+        valid_name_set.insert(relparent_and_stem(&labels_path, &path)?);
     }
     let valid_name_set = Arc::new(valid_name_set);
 
-    let image_entries: Vec<PathBuf>;
-    let images_output_path: String;
     let images_path = PathBuf::from(images_path);
-
-    if images_path.is_dir() {
-        image_entries = fs::read_dir(&images_path)
-            .context(format!("Failed to read directory: {:?}", images_path))?
-            .map(|e| e.map(|e| e.path()))
-            .collect::<Result<Vec<_>, _>>()?;
-        images_output_path = images_path.join("output").to_string_lossy().into_owned();
+    let images_output_path = if images_path.is_dir() {
+        images_path.join("output")
     } else {
-        image_entries = vec![images_path.clone()];
-        images_output_path = images_path
+        images_path
             .parent()
             .ok_or(anyhow!("Failed to get parent dir"))?
             .join("output")
             .join("labels")
-            .to_string_lossy()
-            .into_owned();
-    }
+    };
+    let image_entries =
+        walk.collect_files(&images_path, IMAGE_EXTENSIONS, &[&images_output_path])?;
 
     let sem = Arc::new(Semaphore::new(
         (*THREAD_POOL.read().expect_or_log("Get pool error")).into(),
@@ -941,10 +878,6 @@ pub async fn split_images_with_label_filter(
     // Image Processing
     let mut image_extension = None;
     for entry in image_entries {
-        if !entry.is_file() {
-            continue;
-        }
-
         if image_extension.is_none() {
             let extension: String = entry
                 .extension()
@@ -959,7 +892,8 @@ pub async fn split_images_with_label_filter(
         let target_height = *target_height;
         let valid_name_set = Arc::clone(&valid_name_set);
         let image_extension = image_extension.clone();
-        let images_output_path = images_output_path.clone();
+        let relative_parent = relative_parent(&images_path, &entry)?;
+        let images_output_path = output_dir(&images_path, &entry, &images_output_path)?;
 
         let header_span = header_span.clone();
 
@@ -1012,7 +946,7 @@ pub async fn split_images_with_label_filter(
                 for col_index in 0..x_count {
                     let image_id = format!("{}_LTR_x{}_y{}", image_id, col_index, row_index);
                     task_span.pb_inc(1);
-                    if !valid_name_set.contains(&image_id) {
+                    if !valid_name_set.contains(&join_key(&relative_parent, &image_id)) {
                         continue;
                     }
                     let cropped = core::Mat::roi(
@@ -1029,7 +963,7 @@ pub async fn split_images_with_label_filter(
                     imgcodecs::imwrite(
                         &format!(
                             "{}/{}.{}",
-                            images_output_path,
+                            images_output_path.display(),
                             image_id,
                             image_extension.as_ref().unwrap()
                         ),
@@ -1056,7 +990,7 @@ pub async fn split_images_with_label_filter(
                 for col_index in 0..x_count {
                     let image_id = format!("{}_RTL_x{}_y{}", image_id, col_index, row_index);
                     task_span.pb_inc(1);
-                    if !valid_name_set.contains(&image_id) {
+                    if !valid_name_set.contains(&join_key(&relative_parent, &image_id)) {
                         continue;
                     }
                     let cropped = core::Mat::roi(
@@ -1073,7 +1007,7 @@ pub async fn split_images_with_label_filter(
                     imgcodecs::imwrite(
                         &format!(
                             "{}/{}.{}",
-                            images_output_path,
+                            images_output_path.display(),
                             image_id,
                             image_extension.as_ref().unwrap()
                         ),
@@ -1106,8 +1040,18 @@ pub async fn split_images_with_label_filter(
     Ok(())
 }
 
-pub async fn stich_images(splited_images: &String, target_height: &i32, target_width: &i32) {
-    let entries = fs::read_dir(splited_images).unwrap();
+pub async fn stich_images(
+    splited_images: &String,
+    target_height: &i32,
+    target_width: &i32,
+    walk: &WalkOptions,
+) {
+    let splited_images_path = PathBuf::from(splited_images);
+    let mut entries = walk
+        .collect_files(&splited_images_path, IMAGE_EXTENSIONS, &[])
+        .expect_or_log("Failed to read directory");
+    // Output of a previous run
+    entries.retain(|x| x.file_name().is_some_and(|name| name != "stiched.png"));
     let mut size: Option<(i32, i32)> = None;
 
     let mut result_mat = Mat::new_rows_cols_with_default(
@@ -1121,9 +1065,7 @@ pub async fn stich_images(splited_images: &String, target_height: &i32, target_w
         .expect_or_log("Failed to compile regex");
 
     for entry in entries {
-        let entry = entry.unwrap();
-        let file_name = entry.file_name();
-        let file_name = file_name.to_str().unwrap();
+        let file_name = entry.file_name().unwrap().to_str().unwrap();
 
         // Image name, direction, x, y
         let mut info = Vec::new();
@@ -1141,18 +1083,14 @@ pub async fn stich_images(splited_images: &String, target_height: &i32, target_w
             return;
         }
 
-        let img =
-            imgcodecs::imread(entry.path().to_str().unwrap(), imgcodecs::IMREAD_UNCHANGED).unwrap();
+        let img = imgcodecs::imread(entry.to_str().unwrap(), imgcodecs::IMREAD_UNCHANGED).unwrap();
 
         let current_img_size = img.size().unwrap();
         if size.is_none() {
             size = Some((current_img_size.width, current_img_size.height));
         } else if let Some((width, height)) = size {
             if current_img_size.width != width || current_img_size.height != height {
-                tracing::error!(
-                    "Image {} size is not consistent",
-                    entry.file_name().to_str().unwrap()
-                );
+                tracing::error!("Image {} size is not consistent", file_name);
                 return;
             }
         }
@@ -1184,7 +1122,7 @@ pub async fn stich_images(splited_images: &String, target_height: &i32, target_w
             return;
         }
 
-        tracing::info!("Image {} processed", entry.file_name().to_str().unwrap());
+        tracing::info!("Image {} processed", file_name);
     }
 
     imgcodecs::imwrite(
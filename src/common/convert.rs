@@ -1,162 +1,291 @@
-use std::{
-    collections::HashMap,
-    fs,
-    sync::{Arc, Mutex},
-};
+use std::{fs, path::Path, sync::Arc};
 
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Args;
 use cocotools::{
     coco::object_detection::{Annotation, Bbox, Category, Dataset, Image, Rle, Segmentation},
     mask::utils::Area,
 };
-use image::{Rgb, RgbImage};
+use indicatif::ProgressStyle;
+use opencv::{
+    core::{self, Mat, MatTraitConst, MatTraitConstManual, Rect, Scalar, Size},
+    imgcodecs, imgproc,
+};
 use tokio::{sync::Semaphore, task::JoinSet};
-use tracing_unwrap::ResultExt;
+use tracing::info_span;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
 
+use super::palette::{Palette, PaletteClass};
+use super::vectorize::{color_mask, vectorize_mask, VectorizeOptions};
+use super::walk::WalkOptions;
 use crate::THREAD_POOL;
 
-fn mask_image_array(image: &RgbImage, rgb: Rgb<u8>) -> ndarray::Array2<u8> {
-    let mut mask = ndarray::Array2::<u8>::zeros((image.height() as usize, image.width() as usize));
-    for (x, y, pixel) in image.enumerate_pixels() {
-        let Rgb([r, g, b]) = pixel;
-        let [tr, tg, tb] = &rgb.0;
-        if r == tr && g == tg && b == tb {
-            mask[[y as usize, x as usize]] = 1;
-        }
-    }
-    mask
+#[derive(Args, Debug, Clone)]
+pub struct CocoExportOptions {
+    #[arg(
+        long,
+        help = "One annotation per connected component (iscrowd 0) instead of one RLE per class (iscrowd 1)"
+    )]
+    pub instances: bool,
+
+    #[arg(
+        long,
+        requires = "instances",
+        help = "Write instance segmentations as polygons instead of RLE"
+    )]
+    pub polygons: bool,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Annotations with fewer pixels are dropped"
+    )]
+    pub min_area: u32,
+
+    #[arg(
+        long,
+        default_value = "jpg",
+        help = "Extension of the image files the labels belong to, used for the file names in the dataset"
+    )]
+    pub image_extension: String,
+
+    #[command(flatten)]
+    pub vectorize: VectorizeOptions,
 }
 
-pub async fn rgb2rle(dataset_path: &String, rgb_list: &str) {
-    let mut color_class_map = HashMap::<Rgb<u8>, u32>::new();
-    let dataset = Arc::new(Mutex::new(Dataset {
+/// Convert the RGB labels in `dataset_path` to a COCO dataset, saved to
+/// `<dataset_path>/resized_labels.json`.
+///
+/// By default every class present in a label is one RLE annotation with `iscrowd: 1`,
+/// with `options.instances` every connected component is one annotation with `iscrowd: 0`.
+pub async fn rgb2rle(
+    dataset_path: &String,
+    palette: &Palette,
+    options: &CocoExportOptions,
+    walk: &WalkOptions,
+) -> Result<()> {
+    options.vectorize.validate()?;
+    // Ignored classes are not exported
+    let classes = Arc::new(palette.active().cloned().collect::<Vec<_>>());
+    let categories = classes
+        .iter()
+        .map(|class| Category {
+            // TODO: add ability for super category
+            id: class.id as u32,
+            name: class.name.clone(),
+            supercategory: class.name.clone(),
+        })
+        .collect::<Vec<_>>();
+
+    let sem = Arc::new(Semaphore::new(
+        (*THREAD_POOL
+            .read()
+            .map_err(|_| anyhow!("THREAD_POOL lock poisoned"))?)
+        .into(),
+    ));
+    let mut threads = JoinSet::new();
+
+    let root = Path::new(dataset_path);
+    let entries = walk.collect_files(root, &["png"], &[])?;
+
+    let header_span = info_span!("rgb2rle_threads");
+    header_span.pb_set_style(&ProgressStyle::with_template(
+        "{spinner} Processing {msg}\n{wide_bar} {pos}/{len}",
+    )?);
+    header_span.pb_set_length(entries.len() as u64);
+
+    let header_span_enter = header_span.enter();
+
+    for entry in entries {
+        // Relative to the dataset root, so recursive datasets keep their subfolders
+        let relative_name = if root.is_file() {
+            Path::new(entry.file_name().unwrap_or_default()).to_path_buf()
+        } else {
+            entry.strip_prefix(root)?.to_path_buf()
+        };
+        let file_name = relative_name
+            .with_extension(&options.image_extension)
+            .to_string_lossy()
+            .into_owned();
+
+        let permit = sem.clone().acquire_owned().await?;
+        let header_span = header_span.clone();
+        let classes = classes.clone();
+        let options = options.clone();
+        threads.spawn_blocking(move || -> Result<(Image, Vec<Annotation>)> {
+            let _permit = permit;
+            let label = imgcodecs::imread(
+                entry.to_str().ok_or(anyhow!("Invalid path"))?,
+                imgcodecs::IMREAD_COLOR,
+            )?;
+            if label.empty() {
+                bail!("Label {} is not readable", entry.display());
+            }
+            let annotations = label_annotations(&label, &classes, &options)
+                .with_context(|| format!("Converting {}", entry.display()))?;
+            let image = Image {
+                id: 0,
+                width: label.cols() as u32,
+                height: label.rows() as u32,
+                file_name,
+                license: Default::default(),
+                flickr_url: Default::default(),
+                coco_url: Default::default(),
+                date_captured: Default::default(),
+            };
+            header_span.pb_set_message(&entry.to_string_lossy());
+            header_span.pb_inc(1);
+            Ok((image, annotations))
+        });
+    }
+
+    let mut dataset = Dataset {
         info: Default::default(),
         images: Vec::<Image>::new(),
         annotations: Vec::<Annotation>::new(),
-        categories: Vec::<Category>::new(),
+        categories,
         licenses: vec![],
-    }));
-    // Add categories
-    {
-        let mut dataset_guard = dataset.lock().unwrap();
-
-        for (class_id, rgb) in rgb_list.split(";").enumerate() {
-            let mut rgb_vec: Vec<u8> = vec![];
-            for splited in rgb.split(',') {
-                let splited = splited.parse::<u8>().unwrap();
-                rgb_vec.push(splited);
-            }
-
-            // TODO: add ability for super category
-            let rgb = Rgb([rgb_vec[0], rgb_vec[1], rgb_vec[2]]);
-            color_class_map.insert(rgb, class_id as u32);
-            dataset_guard.categories.push(Category {
-                id: class_id as u32,
-                name: rgb_vec[3].to_string(),
-                supercategory: rgb_vec[3].to_string(),
-            });
+    };
+    while let Some(result) = threads.join_next().await {
+        let (mut image, annotations) = result??;
+        image.id = dataset.images.len() as u64;
+        for mut annotation in annotations {
+            annotation.id = dataset.annotations.len() as u64;
+            annotation.image_id = image.id;
+            dataset.annotations.push(annotation);
         }
+        dataset.images.push(image);
     }
+    drop(header_span_enter);
+    drop(header_span);
 
-    let mut threads = JoinSet::new();
-    let sem = Arc::new(Semaphore::new(
-        (*THREAD_POOL.read().expect_or_log("Get pool error")).into(),
-    ));
+    let save_path = Path::new(dataset_path).join("resized_labels.json");
+    fs::write(&save_path, serde_json::to_string(&dataset)?)
+        .with_context(|| format!("Writing {}", save_path.display()))?;
+    tracing::info!(
+        "{} images, {} annotations saved to {}",
+        dataset.images.len(),
+        dataset.annotations.len(),
+        save_path.display()
+    );
+    Ok(())
+}
 
-    let image_count = Arc::new(Mutex::new(0));
-    let annotation_count = Arc::new(Mutex::new(0));
-    // Walk through all images in BASE_PATH
-    let entries = fs::read_dir(dataset_path).unwrap();
-    for entry in entries {
-        let entry = entry.unwrap();
-        if entry
-            .path()
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .ends_with(".png")
-        {
-            let permit = Arc::clone(&sem);
-            let dataset = Arc::clone(&dataset);
-            let color_class_map = color_class_map.clone();
-            let image_count = Arc::clone(&image_count);
-            let annotation_count = Arc::clone(&annotation_count);
-            threads.spawn(async move {
-                // Limit tasks to 10
-                let _permit = permit.acquire().await.unwrap();
-
-                let img = image::open(entry.path()).unwrap().into_rgb8();
-                // First process the image without acquiring the lock, boost performance
-                let mut annotations = Vec::<Annotation>::new();
-
-                for (color, class_id) in color_class_map.clone().iter() {
-                    let mask: cocotools::mask::Mask = mask_image_array(&img, *color);
-
-                    let rle = Rle::from(&mask);
-                    let area = rle.area();
-                    let bbox = Bbox::from(&rle);
-                    let new_annotation = Annotation {
-                        id: 0,
-                        image_id: 0,
-                        category_id: *class_id,
-                        segmentation: Segmentation::Rle(rle),
-                        area: area as f64,
-                        bbox,
-                        iscrowd: 1,
-                    };
-                    annotations.push(new_annotation);
-                }
+/// Annotations of one BGR label, ids are assigned by the caller
+fn label_annotations(
+    label: &Mat,
+    classes: &[PaletteClass],
+    options: &CocoExportOptions,
+) -> Result<Vec<Annotation>> {
+    let mut annotations = Vec::new();
+    for class in classes {
+        let mask = color_mask(label, class.rgb)?;
+        let pixels = core::count_non_zero(&mask)?;
+        // Absent classes would give zero area annotations
+        if pixels == 0 || pixels < options.min_area as i32 {
+            continue;
+        }
 
-                // Acquire image lock
-                let mut image = Image {
-                    id: 0,
-                    width: img.width(),
-                    height: img.height(),
-                    file_name: entry
-                        .file_name()
-                        .into_string()
-                        .unwrap()
-                        .replace(".png", ".jpg"),
-                    license: Default::default(),
-                    flickr_url: Default::default(),
-                    coco_url: Default::default(),
-                    date_captured: Default::default(),
-                };
-                {
-                    let mut image_guard = image_count.lock().unwrap();
-                    image.id = *image_guard;
-                    *image_guard += 1;
-                }
+        if !options.instances {
+            let full = Rect::new(0, 0, mask.cols(), mask.rows());
+            let rle = Rle::from(&mask_array(&mask, full, mask.size()?)?);
+            annotations.push(Annotation {
+                id: 0,
+                image_id: 0,
+                category_id: class.id as u32,
+                area: rle.area() as f64,
+                bbox: Bbox::from(&rle),
+                segmentation: Segmentation::Rle(rle),
+                iscrowd: 1,
+            });
+            continue;
+        }
 
-                // Acquire annotation lock
-                {
-                    let mut annotation_guard = annotation_count.lock().unwrap();
-                    for annotion in annotations.iter_mut() {
-                        annotion.id = *annotation_guard;
-                        *annotation_guard += 1;
-                        annotion.image_id = image.id;
-                    }
-                }
+        let mut components = Mat::default();
+        let mut component_stats = Mat::default();
+        let mut centroids = Mat::default();
+        let count = imgproc::connected_components_with_stats(
+            &mask,
+            &mut components,
+            &mut component_stats,
+            &mut centroids,
+            8,
+            core::CV_32S,
+        )?;
+        // Component 0 is the background of the mask
+        for index in 1..count {
+            let area = *component_stats.at_2d::<i32>(index, imgproc::CC_STAT_AREA)?;
+            if area < options.min_area as i32 {
+                continue;
+            }
+            let rect = Rect::new(
+                *component_stats.at_2d::<i32>(index, imgproc::CC_STAT_LEFT)?,
+                *component_stats.at_2d::<i32>(index, imgproc::CC_STAT_TOP)?,
+                *component_stats.at_2d::<i32>(index, imgproc::CC_STAT_WIDTH)?,
+                *component_stats.at_2d::<i32>(index, imgproc::CC_STAT_HEIGHT)?,
+            );
+            let mut component = Mat::default();
+            core::compare(
+                &Mat::roi(&components, rect)?,
+                &Scalar::all(index as f64),
+                &mut component,
+                core::CMP_EQ,
+            )?;
 
-                // Lock dataset and start to add
-                let mut dataset_guard = dataset.lock().unwrap();
-                dataset_guard.images.push(image);
-                dataset_guard.annotations.extend(annotations);
-                println!(
-                    "{} finished process",
-                    entry.file_name().into_string().unwrap()
-                );
+            let segmentation = if options.polygons {
+                let polygons = vectorize_mask(&component, &options.vectorize)?
+                    .into_iter()
+                    .flat_map(|region| region.polygons)
+                    .map(|polygon| {
+                        polygon
+                            .iter()
+                            .flat_map(|(x, y)| [(x + rect.x) as f64, (y + rect.y) as f64])
+                            .collect::<Vec<_>>()
+                    })
+                    .collect::<Vec<_>>();
+                if polygons.is_empty() {
+                    continue;
+                }
+                Segmentation::Polygons(polygons)
+            } else {
+                Segmentation::Rle(Rle::from(&mask_array(&component, rect, mask.size()?)?))
+            };
+            annotations.push(Annotation {
+                id: 0,
+                image_id: 0,
+                category_id: class.id as u32,
+                segmentation,
+                area: area as f64,
+                bbox: Bbox {
+                    left: rect.x as f64,
+                    top: rect.y as f64,
+                    width: rect.width as f64,
+                    height: rect.height as f64,
+                },
+                iscrowd: 0,
             });
         }
     }
+    Ok(annotations)
+}
 
-    while threads.join_next().await.is_some() {}
-
-    let dataset_guard = dataset.lock().unwrap();
-    let dataset_string = serde_json::to_string(&*dataset_guard).unwrap();
-    fs::write(
-        format!("{}/resized_labels.json", dataset_path),
-        dataset_string,
-    )
-    .unwrap();
+/// 0 / 1 mask of the full label size from a binary `mask` covering `rect`
+fn mask_array(mask: &Mat, rect: Rect, size: Size) -> Result<cocotools::mask::Mask> {
+    // ROI results are not always continuous, a copy is
+    let mask = mask.try_clone()?;
+    let data = mask.data_typed::<u8>()?;
+    let mut array = ndarray::Array2::<u8>::zeros((size.height as usize, size.width as usize));
+    for (row, values) in data.chunks_exact(rect.width as usize).enumerate() {
+        for (col, value) in values.iter().enumerate() {
+            if *value > 0 {
+                array[[rect.y as usize + row, rect.x as usize + col]] = 1;
+            }
+        }
+    }
+    Ok(array)
 }
+
+pub mod coco;
+pub mod cvat;
+pub mod labelme;
+pub mod voc;
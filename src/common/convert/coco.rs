@@ -0,0 +1,557 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Args, ValueEnum};
+use cocotools::coco::object_detection::{
+    Annotation, Bbox, Category, Dataset, PolygonsRS, Rle, Segmentation,
+};
+use cocotools::mask::Mask;
+use image::{GrayImage, Luma, Rgb, RgbImage};
+use indicatif::ProgressStyle;
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::info_span;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+use crate::common::dataset::merge::LabelKind;
+use crate::common::dataset::paths::contained_path;
+use crate::common::palette::Palette;
+use crate::common::walk::output_root;
+use crate::yolo::raster::OverlapOrder;
+use crate::THREAD_POOL;
+
+/// How COCO categories find their palette class
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CategoryMatch {
+    /// The category id is the class id
+    Id,
+    /// The category name is the class name
+    Name,
+}
+
+/// What happens to `iscrowd: 1` annotations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CrowdMode {
+    /// Drawn like the other annotations of their category
+    Draw,
+    /// Left out
+    Skip,
+    /// Drawn on top of everything as the ignored class
+    Ignore,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct CocoRasterOptions {
+    #[arg(
+        long,
+        value_enum,
+        default_value = "id",
+        help = "How COCO categories are matched to the classes of the palette"
+    )]
+    pub match_by: CategoryMatch,
+
+    #[arg(
+        long,
+        value_name = "FROM=TO",
+        help = "Map the COCO category id FROM to the class id TO, overrides --match-by, can be given multiple times"
+    )]
+    pub category_map: Vec<String>,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Class id of the pixels not covered by any annotation"
+    )]
+    pub background: u8,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "file",
+        help = "Which annotation is kept where annotations overlap"
+    )]
+    pub overlap: OverlapOrder,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "draw",
+        help = "How crowd annotations are drawn"
+    )]
+    pub crowd: CrowdMode,
+
+    #[arg(
+        long,
+        default_value = "255",
+        help = "Class id of ignored crowd regions in class masks, RGB masks use the ignored class of the palette"
+    )]
+    pub ignore_index: u8,
+}
+
+/// Rasterizing annotation tool exports, whose shapes carry label names instead of ids
+#[derive(Args, Debug, Clone)]
+pub struct LabelRasterOptions {
+    #[arg(
+        long,
+        value_enum,
+        default_value = "rgb",
+        help = "Write RGB masks with the palette colors or 8 bit class id masks"
+    )]
+    pub label_kind: LabelKind,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Class id of the pixels not covered by any shape"
+    )]
+    pub background: u8,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "file",
+        help = "Which shape is kept where shapes overlap"
+    )]
+    pub overlap: OverlapOrder,
+
+    #[arg(
+        long,
+        help = "Fail on labels missing from the palette instead of skipping their shapes"
+    )]
+    pub strict: bool,
+}
+
+pub fn load_dataset(path: &Path) -> Result<Dataset> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Reading {}", path.display()))?;
+    serde_json::from_str(&content).with_context(|| format!("Parsing {}", path.display()))
+}
+
+/// Rasterize the annotations of the COCO JSON at `dataset_path` into RGB masks (palette
+/// colors) or 8 bit class masks, one PNG per image named after its `file_name`, saved to
+/// the `output` folder next to the JSON.
+///
+/// Categories without a class are skipped with a warning.
+pub async fn coco2mask(
+    dataset_path: &str,
+    label_kind: LabelKind,
+    palette: Option<&Palette>,
+    options: &CocoRasterOptions,
+) -> Result<()> {
+    let dataset_path = PathBuf::from(dataset_path);
+    let dataset = load_dataset(&dataset_path)?;
+    let output_root = output_root(&dataset_path, "output")?;
+    dataset2mask(dataset, &output_root, label_kind, palette, options).await
+}
+
+/// Rasterize an in memory COCO dataset to `output_root`, see [`coco2mask`]
+pub async fn dataset2mask(
+    dataset: Dataset,
+    output_root: &Path,
+    label_kind: LabelKind,
+    palette: Option<&Palette>,
+    options: &CocoRasterOptions,
+) -> Result<()> {
+    let category_classes = Arc::new(category_classes(&dataset, palette, options)?);
+
+    let ignore_id = match (options.crowd, label_kind, palette) {
+        (CrowdMode::Ignore, LabelKind::Rgb, Some(palette)) => palette
+            .classes
+            .iter()
+            .find(|class| class.ignore)
+            .map(|class| class.id)
+            .ok_or(anyhow!(
+                "Drawing crowds as ignored needs a palette class with the ignore flag"
+            ))?,
+        _ => options.ignore_index,
+    };
+    // Class id -> RGB, the background stays black when it has no color
+    let colors = match (label_kind, palette) {
+        (LabelKind::Rgb, Some(palette)) => Some(Arc::new(
+            palette
+                .classes
+                .iter()
+                .map(|class| (class.id, class.rgb))
+                .collect::<HashMap<_, _>>(),
+        )),
+        (LabelKind::Rgb, None) => bail!("RGB masks need a palette"),
+        (LabelKind::Class, _) => None,
+    };
+
+    let mut image_annotations = HashMap::<u64, Vec<Annotation>>::new();
+    for annotation in dataset.annotations {
+        image_annotations
+            .entry(annotation.image_id)
+            .or_default()
+            .push(annotation);
+    }
+
+    let sem = Arc::new(Semaphore::new(
+        (*THREAD_POOL
+            .read()
+            .map_err(|_| anyhow!("THREAD_POOL lock poisoned"))?)
+        .into(),
+    ));
+    let mut threads = JoinSet::new();
+
+    let header_span = info_span!("coco2mask_threads");
+    header_span.pb_set_style(&ProgressStyle::with_template(
+        "{spinner} Processing {msg}\n{wide_bar} {pos}/{len}",
+    )?);
+    header_span.pb_set_length(dataset.images.len() as u64);
+
+    let header_span_enter = header_span.enter();
+
+    for image in dataset.images {
+        let save_path = output_root
+            .join(contained_path(&image.file_name))
+            .with_extension("png");
+        if let Some(parent) = save_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let annotations = image_annotations.remove(&image.id).unwrap_or_default();
+
+        let permit = sem.clone().acquire_owned().await?;
+        let header_span = header_span.clone();
+        let category_classes = category_classes.clone();
+        let colors = colors.clone();
+        let options = options.clone();
+        threads.spawn_blocking(move || -> Result<()> {
+            let _permit = permit;
+            let ids = rasterize(
+                &annotations,
+                (image.width, image.height),
+                &category_classes,
+                ignore_id,
+                &options,
+            )
+            .with_context(|| format!("Rasterizing {}", image.file_name))?;
+            match colors {
+                Some(colors) => RgbImage::from_fn(image.width, image.height, |x, y| {
+                    let id = ids.get_pixel(x, y).0[0];
+                    Rgb(*colors.get(&id).unwrap_or(&[0, 0, 0]))
+                })
+                .save(&save_path),
+                None => ids.save(&save_path),
+            }
+            .with_context(|| format!("Writing {}", save_path.display()))?;
+            header_span.pb_set_message(&image.file_name);
+            header_span.pb_inc(1);
+            Ok(())
+        });
+    }
+
+    while let Some(result) = threads.join_next().await {
+        result??;
+    }
+    drop(header_span_enter);
+    drop(header_span);
+    tracing::info!("Saved to {}", output_root.display());
+    Ok(())
+}
+
+/// COCO category id -> class id
+fn category_classes(
+    dataset: &Dataset,
+    palette: Option<&Palette>,
+    options: &CocoRasterOptions,
+) -> Result<HashMap<u32, u8>> {
+    let mut explicit = HashMap::new();
+    for entry in &options.category_map {
+        let (from, to) = entry
+            .split_once('=')
+            .with_context(|| format!("Malformed category map {}, please use FROM=TO", entry))?;
+        let from = from
+            .trim()
+            .parse::<u32>()
+            .with_context(|| format!("Malformed category id in {}", entry))?;
+        let to = to
+            .trim()
+            .parse::<u8>()
+            .with_context(|| format!("Malformed class id in {}", entry))?;
+        explicit.insert(from, to);
+    }
+
+    let mut classes = HashMap::new();
+    for category in &dataset.categories {
+        let class_id = match (explicit.get(&category.id), options.match_by, palette) {
+            (Some(class_id), _, _) => Some(*class_id),
+            (None, CategoryMatch::Id, Some(palette)) => palette
+                .classes
+                .iter()
+                .find(|class| class.id as u32 == category.id)
+                .map(|class| class.id),
+            (None, CategoryMatch::Id, None) => u8::try_from(category.id).ok(),
+            (None, CategoryMatch::Name, Some(palette)) => palette
+                .classes
+                .iter()
+                .find(|class| class.name == category.name)
+                .map(|class| class.id),
+            (None, CategoryMatch::Name, None) => {
+                bail!("Matching categories by name needs a palette")
+            }
+        };
+        match class_id {
+            Some(class_id) => {
+                if let Some(palette) = palette {
+                    if !palette.classes.iter().any(|class| class.id == class_id) {
+                        bail!(
+                            "Category {} ({}) maps to class {}, which is not in the palette",
+                            category.id,
+                            category.name,
+                            class_id
+                        );
+                    }
+                }
+                classes.insert(category.id, class_id);
+            }
+            None => tracing::warn!(
+                "Category {} ({}) has no class, its annotations are skipped",
+                category.id,
+                category.name
+            ),
+        }
+    }
+    Ok(classes)
+}
+
+/// Class id mask of one image, annotations drawn in the order given by `options.overlap`
+fn rasterize(
+    annotations: &[Annotation],
+    (width, height): (u32, u32),
+    category_classes: &HashMap<u32, u8>,
+    ignore_id: u8,
+    options: &CocoRasterOptions,
+) -> Result<GrayImage> {
+    let mut shapes = Vec::new();
+    let mut crowds = Vec::new();
+    for annotation in annotations {
+        let Some(class_id) = category_classes.get(&annotation.category_id) else {
+            continue;
+        };
+        match (annotation.iscrowd, options.crowd) {
+            (1, CrowdMode::Skip) => {}
+            (1, CrowdMode::Ignore) => crowds.push((annotation, ignore_id)),
+            _ => shapes.push((annotation, *class_id)),
+        }
+    }
+    match options.overlap {
+        OverlapOrder::File => {}
+        OverlapOrder::ClassAsc => shapes.sort_by_key(|(_, class_id)| *class_id),
+        OverlapOrder::ClassDesc => shapes.sort_by_key(|(_, class_id)| std::cmp::Reverse(*class_id)),
+        OverlapOrder::AreaDesc => shapes.sort_by(|a, b| b.0.area.total_cmp(&a.0.area)),
+    }
+
+    let mut ids = GrayImage::from_pixel(width, height, Luma([options.background]));
+    // Ignored crowds go last, on top of everything
+    for (annotation, class_id) in shapes.into_iter().chain(crowds) {
+        let mask = annotation_mask(&annotation.segmentation, width, height)
+            .with_context(|| format!("Decoding annotation {}", annotation.id))?;
+        for ((y, x), value) in mask.indexed_iter() {
+            if *value > 0 {
+                ids.put_pixel(x as u32, y as u32, Luma([class_id]));
+            }
+        }
+    }
+    Ok(ids)
+}
+
+pub fn annotation_mask(segmentation: &Segmentation, width: u32, height: u32) -> Result<Mask> {
+    let mask = match segmentation {
+        // Plain polygons carry no image size, cocotools needs it
+        Segmentation::Polygons(polygons) => Mask::try_from(&PolygonsRS {
+            size: vec![height, width],
+            counts: polygons
+                .iter()
+                .filter(|polygon| polygon.len() >= 6)
+                .map(|polygon| polygon[..polygon.len() - polygon.len() % 2].to_vec())
+                .collect(),
+        })?,
+        other => Mask::try_from(other)?,
+    };
+    if mask.dim() != (height as usize, width as usize) {
+        bail!(
+            "Mask size {}x{} differs from the image size {}x{}",
+            mask.ncols(),
+            mask.nrows(),
+            width,
+            height
+        );
+    }
+    Ok(mask)
+}
+
+/// Rasterize a dataset whose category names are label names, each matched to the palette
+/// class of the same name. Labels missing from the palette are reported with their shape
+/// count, then skipped, or an error with `options.strict`.
+pub async fn named_dataset2mask(
+    mut dataset: Dataset,
+    output_root: &Path,
+    palette: &Palette,
+    options: &LabelRasterOptions,
+) -> Result<()> {
+    let unknown = dataset
+        .categories
+        .iter()
+        .filter(|category| {
+            !palette
+                .classes
+                .iter()
+                .any(|class| class.name == category.name)
+        })
+        .map(|category| {
+            let count = dataset
+                .annotations
+                .iter()
+                .filter(|annotation| annotation.category_id == category.id)
+                .count();
+            (category.id, format!("{} ({} shapes)", category.name, count))
+        })
+        .collect::<HashMap<_, _>>();
+    if !unknown.is_empty() {
+        let mut labels = unknown.values().cloned().collect::<Vec<_>>();
+        labels.sort();
+        if options.strict {
+            bail!("Labels missing from the palette: {}", labels.join(", "));
+        }
+        tracing::warn!(
+            "Labels missing from the palette, their shapes are skipped: {}",
+            labels.join(", ")
+        );
+        dataset
+            .categories
+            .retain(|category| !unknown.contains_key(&category.id));
+        dataset
+            .annotations
+            .retain(|annotation| !unknown.contains_key(&annotation.category_id));
+    }
+
+    let raster = CocoRasterOptions {
+        match_by: CategoryMatch::Name,
+        category_map: Vec::new(),
+        background: options.background,
+        overlap: options.overlap,
+        crowd: CrowdMode::Draw,
+        ignore_index: u8::MAX,
+    };
+    dataset2mask(
+        dataset,
+        output_root,
+        options.label_kind,
+        Some(palette),
+        &raster,
+    )
+    .await
+}
+
+/// Id of the category called `name`, added with the next free id when missing
+pub fn named_category(dataset: &mut Dataset, name: &str) -> u32 {
+    if let Some(category) = dataset.categories.iter().find(|c| c.name == name) {
+        return category.id;
+    }
+    let id = dataset.categories.iter().map(|c| c.id).max().unwrap_or(0) + 1;
+    dataset.categories.push(Category {
+        id,
+        name: name.to_string(),
+        supercategory: name.to_string(),
+    });
+    id
+}
+
+/// Annotation of a closed polygon in pixel coordinates, `None` below 3 points,
+/// ids are assigned by the caller
+pub fn polygon_annotation(category_id: u32, points: &[(f64, f64)]) -> Option<Annotation> {
+    if points.len() < 3 {
+        return None;
+    }
+    let (mut left, mut top) = (f64::MAX, f64::MAX);
+    let (mut right, mut bottom) = (f64::MIN, f64::MIN);
+    let mut area = 0.0;
+    for (index, (x, y)) in points.iter().enumerate() {
+        left = left.min(*x);
+        top = top.min(*y);
+        right = right.max(*x);
+        bottom = bottom.max(*y);
+        // Shoelace formula
+        let (next_x, next_y) = points[(index + 1) % points.len()];
+        area += x * next_y - next_x * y;
+    }
+    Some(Annotation {
+        id: 0,
+        image_id: 0,
+        category_id,
+        segmentation: Segmentation::Polygons(vec![points
+            .iter()
+            .flat_map(|(x, y)| [*x, *y])
+            .collect()]),
+        area: area.abs() / 2.0,
+        bbox: Bbox {
+            left,
+            top,
+            width: right - left,
+            height: bottom - top,
+        },
+        iscrowd: 0,
+    })
+}
+
+/// Annotation of the non zero pixels of a full image `mask`, `None` when it is empty
+pub fn mask_annotation(category_id: u32, mask: &Mask) -> Option<Annotation> {
+    let (mut left, mut top) = (usize::MAX, usize::MAX);
+    let (mut right, mut bottom) = (0, 0);
+    let mut area = 0;
+    for ((y, x), value) in mask.indexed_iter() {
+        if *value > 0 {
+            left = left.min(x);
+            top = top.min(y);
+            right = right.max(x);
+            bottom = bottom.max(y);
+            area += 1;
+        }
+    }
+    if area == 0 {
+        return None;
+    }
+    Some(Annotation {
+        id: 0,
+        image_id: 0,
+        category_id,
+        segmentation: Segmentation::Rle(Rle::from(mask)),
+        area: area as f64,
+        bbox: Bbox {
+            left: left as f64,
+            top: top as f64,
+            width: (right - left + 1) as f64,
+            height: (bottom - top + 1) as f64,
+        },
+        iscrowd: 0,
+    })
+}
+
+/// `points` rotated clockwise (y pointing down) by `degrees` around `center`
+pub fn rotate_points(points: &mut [(f64, f64)], center: (f64, f64), degrees: f64) {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    for (x, y) in points.iter_mut() {
+        let (dx, dy) = (*x - center.0, *y - center.1);
+        *x = center.0 + dx * cos - dy * sin;
+        *y = center.1 + dx * sin + dy * cos;
+    }
+}
+
+/// Polygon approximating an ellipse, rotated clockwise by `degrees`
+pub fn ellipse_points(center: (f64, f64), radii: (f64, f64), degrees: f64) -> Vec<(f64, f64)> {
+    const VERTICES: usize = 64;
+    let mut points = (0..VERTICES)
+        .map(|index| {
+            let angle = index as f64 * std::f64::consts::TAU / VERTICES as f64;
+            (
+                center.0 + radii.0 * angle.cos(),
+                center.1 + radii.1 * angle.sin(),
+            )
+        })
+        .collect::<Vec<_>>();
+    rotate_points(&mut points, center, degrees);
+    points
+}
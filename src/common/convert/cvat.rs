@@ -0,0 +1,218 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use cocotools::coco::object_detection::{Annotation, Dataset, Image};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+use super::coco::{
+    ellipse_points, mask_annotation, named_category, named_dataset2mask, polygon_annotation,
+    rotate_points, LabelRasterOptions,
+};
+use crate::common::palette::Palette;
+use crate::common::walk::output_root;
+
+/// Read a "CVAT for images 1.1" XML export into a COCO dataset, categories named after
+/// the labels in order of appearance.
+///
+/// Boxes, polygons, ellipses and masks become annotations, drawn by `z_order` then in file
+/// order. Polylines, points, cuboids, skeletons and tags are counted and skipped.
+pub fn read_cvat_dataset(path: &Path) -> Result<Dataset> {
+    let mut reader =
+        Reader::from_file(path).with_context(|| format!("Opening {}", path.display()))?;
+    reader.config_mut().trim_text(true);
+
+    let mut dataset = Dataset::default();
+    let mut skipped = BTreeMap::<String, usize>::new();
+    // The image being read with its (z_order, label, annotation)
+    let mut current: Option<(Image, Vec<(i32, String, Annotation)>)> = None;
+    let mut buf = Vec::new();
+    loop {
+        let event = reader
+            .read_event_into(&mut buf)
+            .with_context(|| format!("Parsing {}", path.display()))?;
+        match event {
+            Event::Start(ref element) | Event::Empty(ref element) => {
+                let name = String::from_utf8_lossy(element.name().as_ref()).into_owned();
+                let is_empty = matches!(event, Event::Empty(_));
+                if name == "image" {
+                    let attributes = attributes(element)?;
+                    let image = Image {
+                        id: dataset.images.len() as u64 + 1,
+                        width: number(&attributes, "width")?,
+                        height: number(&attributes, "height")?,
+                        file_name: attributes
+                            .get("name")
+                            .ok_or(anyhow!("An image has no name"))?
+                            .clone(),
+                        ..Default::default()
+                    };
+                    if is_empty {
+                        dataset.images.push(image);
+                    } else {
+                        current = Some((image, Vec::new()));
+                    }
+                } else if let Some((image, shapes)) = current.as_mut() {
+                    let attributes = attributes(element)?;
+                    // Attributes of a shape are child elements without a label
+                    if let Some(label) = attributes.get("label") {
+                        let annotation =
+                            shape_annotation(&name, &attributes, image).with_context(|| {
+                                format!("Converting the {} {} of {}", name, label, image.file_name)
+                            })?;
+                        match annotation {
+                            Some(annotation) => shapes.push((
+                                number(&attributes, "z_order").unwrap_or(0),
+                                label.clone(),
+                                annotation,
+                            )),
+                            None => *skipped.entry(name).or_default() += 1,
+                        }
+                    }
+                }
+            }
+            Event::End(ref element) if element.name().as_ref() == b"image" => {
+                if let Some((image, mut shapes)) = current.take() {
+                    // Stable, shapes of the same z_order keep the file order
+                    shapes.sort_by_key(|(z_order, _, _)| *z_order);
+                    for (_, label, mut annotation) in shapes {
+                        annotation.category_id = named_category(&mut dataset, &label);
+                        annotation.id = dataset.annotations.len() as u64 + 1;
+                        annotation.image_id = image.id;
+                        dataset.annotations.push(annotation);
+                    }
+                    dataset.images.push(image);
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    if dataset.images.is_empty() {
+        bail!("No image found in {}", path.display());
+    }
+    if !skipped.is_empty() {
+        tracing::warn!(
+            "Shapes without area skipped: {}",
+            skipped
+                .iter()
+                .map(|(shape_type, count)| format!("{} {}", count, shape_type))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    Ok(dataset)
+}
+
+fn attributes(element: &BytesStart) -> Result<HashMap<String, String>> {
+    let mut attributes = HashMap::new();
+    for attribute in element.attributes() {
+        let attribute = attribute?;
+        attributes.insert(
+            String::from_utf8_lossy(attribute.key.as_ref()).into_owned(),
+            attribute.unescape_value()?.into_owned(),
+        );
+    }
+    Ok(attributes)
+}
+
+fn number<T: std::str::FromStr>(attributes: &HashMap<String, String>, key: &str) -> Result<T> {
+    attributes
+        .get(key)
+        .ok_or(anyhow!("Missing attribute {}", key))?
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("Malformed attribute {}", key))
+}
+
+/// `None` for shapes without area, ids and category are set by the caller
+fn shape_annotation(
+    shape_type: &str,
+    attributes: &HashMap<String, String>,
+    image: &Image,
+) -> Result<Option<Annotation>> {
+    let rotation = number::<f64>(attributes, "rotation").unwrap_or(0.0);
+    let annotation = match shape_type {
+        "box" => {
+            let (left, top): (f64, f64) = (number(attributes, "xtl")?, number(attributes, "ytl")?);
+            let (right, bottom): (f64, f64) =
+                (number(attributes, "xbr")?, number(attributes, "ybr")?);
+            let mut points = vec![(left, top), (right, top), (right, bottom), (left, bottom)];
+            rotate_points(
+                &mut points,
+                ((left + right) / 2.0, (top + bottom) / 2.0),
+                rotation,
+            );
+            polygon_annotation(0, &points)
+        }
+        "polygon" => {
+            let points = attributes
+                .get("points")
+                .ok_or(anyhow!("Missing attribute points"))?
+                .split(';')
+                .map(|point| -> Result<(f64, f64)> {
+                    let (x, y) = point
+                        .split_once(',')
+                        .ok_or(anyhow!("Malformed point {}", point))?;
+                    Ok((x.trim().parse()?, y.trim().parse()?))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            polygon_annotation(0, &points)
+        }
+        "ellipse" => polygon_annotation(
+            0,
+            &ellipse_points(
+                (number(attributes, "cx")?, number(attributes, "cy")?),
+                (number(attributes, "rx")?, number(attributes, "ry")?),
+                rotation,
+            ),
+        ),
+        "mask" => {
+            let (left, top): (usize, usize) =
+                (number(attributes, "left")?, number(attributes, "top")?);
+            let width: usize = number(attributes, "width")?;
+            if width == 0 {
+                bail!("The mask has no width");
+            }
+            // Run lengths over the box, row major, starting with a background run
+            let counts = attributes
+                .get("rle")
+                .ok_or(anyhow!("Missing attribute rle"))?
+                .split(',')
+                .map(|count| count.trim().parse::<usize>())
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut mask =
+                ndarray::Array2::<u8>::zeros((image.height as usize, image.width as usize));
+            let mut offset = 0;
+            for (index, count) in counts.into_iter().enumerate() {
+                if index % 2 == 1 {
+                    for pixel in offset..offset + count {
+                        let (y, x) = (top + pixel / width, left + pixel % width);
+                        if let Some(value) = mask.get_mut((y, x)) {
+                            *value = 1;
+                        }
+                    }
+                }
+                offset += count;
+            }
+            mask_annotation(0, &mask)
+        }
+        _ => None,
+    };
+    Ok(annotation)
+}
+
+/// Rasterize a CVAT for images 1.1 XML into RGB / class masks, saved to the `output`
+/// folder next to it, label names matched to the palette class names
+pub async fn cvat2mask(
+    dataset_path: &str,
+    palette: &Palette,
+    options: &LabelRasterOptions,
+) -> Result<()> {
+    let path = PathBuf::from(dataset_path);
+    let dataset = read_cvat_dataset(&path)?;
+    named_dataset2mask(dataset, &output_root(&path, "output")?, palette, options).await
+}
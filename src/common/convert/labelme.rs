@@ -0,0 +1,177 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use cocotools::coco::object_detection::{Annotation, Dataset, Image};
+use serde::Deserialize;
+
+use super::coco::{
+    ellipse_points, mask_annotation, named_category, named_dataset2mask, polygon_annotation,
+    LabelRasterOptions,
+};
+use crate::common::palette::Palette;
+use crate::common::walk::{output_root, WalkOptions};
+
+/// One LabelMe JSON, the embedded `imageData` is not needed
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct LabelmeFile {
+    shapes: Vec<LabelmeShape>,
+    image_path: String,
+    image_height: u32,
+    image_width: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct LabelmeShape {
+    label: String,
+    points: Vec<[f64; 2]>,
+    /// Missing or null in old files, which only had polygons
+    #[serde(default)]
+    shape_type: Option<String>,
+    /// Base64 PNG of the bounding box given by the two points, for `mask` shapes
+    #[serde(default)]
+    mask: Option<String>,
+}
+
+/// Read the LabelMe JSON files under `root` into a COCO dataset, categories named after
+/// the labels in order of appearance.
+///
+/// Polygons, rectangles, circles and masks become annotations in file order. Lines and
+/// points have no area, they are counted and skipped. Image file names are the JSON paths
+/// relative to `root`, with the extension of the `imagePath`.
+pub fn read_labelme_dataset(root: &Path, walk: &WalkOptions) -> Result<Dataset> {
+    let output = output_root(root, "output")?;
+    let entries = walk.collect_files(root, &["json"], &[output.as_path()])?;
+    if entries.is_empty() {
+        bail!("No LabelMe JSON found in {}", root.display());
+    }
+
+    let mut dataset = Dataset::default();
+    let mut skipped = BTreeMap::<String, usize>::new();
+    for entry in entries {
+        let content =
+            fs::read_to_string(&entry).with_context(|| format!("Reading {}", entry.display()))?;
+        let file: LabelmeFile = serde_json::from_str(&content)
+            .with_context(|| format!("Parsing {}", entry.display()))?;
+
+        let relative_name = if root.is_file() {
+            PathBuf::from(entry.file_name().unwrap_or_default())
+        } else {
+            entry.strip_prefix(root)?.to_path_buf()
+        };
+        // Windows separators are common in imagePath
+        let image_path = file.image_path.replace('\\', "/");
+        let extension = Path::new(&image_path)
+            .extension()
+            .and_then(|s| s.to_str())
+            .unwrap_or("jpg");
+        let image = Image {
+            id: dataset.images.len() as u64 + 1,
+            width: file.image_width,
+            height: file.image_height,
+            file_name: relative_name
+                .with_extension(extension)
+                .to_string_lossy()
+                .into_owned(),
+            ..Default::default()
+        };
+
+        for shape in &file.shapes {
+            let shape_type = shape.shape_type.as_deref().unwrap_or("polygon");
+            let annotation = shape_annotation(shape, shape_type, &image).with_context(|| {
+                format!(
+                    "Converting the {} {} in {}",
+                    shape_type,
+                    shape.label,
+                    entry.display()
+                )
+            })?;
+            match annotation {
+                Some(mut annotation) => {
+                    annotation.category_id = named_category(&mut dataset, &shape.label);
+                    annotation.id = dataset.annotations.len() as u64 + 1;
+                    annotation.image_id = image.id;
+                    dataset.annotations.push(annotation);
+                }
+                None => *skipped.entry(shape_type.to_string()).or_default() += 1,
+            }
+        }
+        dataset.images.push(image);
+    }
+
+    if !skipped.is_empty() {
+        tracing::warn!(
+            "Shapes without area skipped: {}",
+            skipped
+                .iter()
+                .map(|(shape_type, count)| format!("{} {}", count, shape_type))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    Ok(dataset)
+}
+
+/// `None` for shapes without area, ids and category are set by the caller
+fn shape_annotation(
+    shape: &LabelmeShape,
+    shape_type: &str,
+    image: &Image,
+) -> Result<Option<Annotation>> {
+    let points = shape
+        .points
+        .iter()
+        .map(|[x, y]| (*x, *y))
+        .collect::<Vec<_>>();
+    let annotation = match (shape_type, points.as_slice()) {
+        ("polygon", _) => polygon_annotation(0, &points),
+        ("rectangle", [(x1, y1), (x2, y2)]) => {
+            polygon_annotation(0, &[(*x1, *y1), (*x2, *y1), (*x2, *y2), (*x1, *y2)])
+        }
+        ("circle", [(cx, cy), (x, y)]) => {
+            let radius = (x - cx).hypot(y - cy);
+            polygon_annotation(0, &ellipse_points((*cx, *cy), (radius, radius), 0.0))
+        }
+        ("mask", [(x1, y1), ..]) => {
+            let Some(data) = &shape.mask else {
+                bail!("The mask shape has no mask data");
+            };
+            let bytes = base64::engine::general_purpose::STANDARD.decode(data)?;
+            let patch = image::load_from_memory(&bytes)?.to_luma8();
+            let (left, top) = (x1.floor().max(0.0) as u32, y1.floor().max(0.0) as u32);
+            let mask = ndarray::Array2::from_shape_fn(
+                (image.height as usize, image.width as usize),
+                |(y, x)| {
+                    let (x, y) = (x as u32, y as u32);
+                    (x >= left
+                        && y >= top
+                        && x - left < patch.width()
+                        && y - top < patch.height()
+                        && patch.get_pixel(x - left, y - top).0[0] > 0) as u8
+                },
+            );
+            mask_annotation(0, &mask)
+        }
+        ("rectangle" | "circle" | "mask", _) => {
+            bail!("Unexpected number of points {}", points.len())
+        }
+        _ => None,
+    };
+    Ok(annotation)
+}
+
+/// Rasterize the LabelMe JSON files under `dataset_path` into RGB / class masks, saved to
+/// the `output` folder, label names matched to the palette class names
+pub async fn labelme2mask(
+    dataset_path: &str,
+    palette: &Palette,
+    options: &LabelRasterOptions,
+    walk: &WalkOptions,
+) -> Result<()> {
+    let root = PathBuf::from(dataset_path);
+    let dataset = read_labelme_dataset(&root, walk)?;
+    named_dataset2mask(dataset, &output_root(&root, "output")?, palette, options).await
+}
@@ -0,0 +1,511 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use cocotools::coco::object_detection::{
+    Annotation, Bbox, Category, Dataset, Image, Rle, Segmentation,
+};
+use image::{GrayImage, Luma};
+use serde::{Deserialize, Serialize};
+
+use super::coco::{annotation_mask, load_dataset};
+use crate::common::dataset::paths::contained_path;
+use crate::common::vectorize::VectorizeOptions;
+use crate::common::walk::WalkOptions;
+use crate::yolo::coco::{load_class_names, read_yolo_dataset, write_yolo_dataset, CLASSES_FILE};
+use crate::yolo::convert::YoloFormat;
+
+/// Object index of the void / difficult pixels in VOC masks
+const VOID_INDEX: u8 = 255;
+
+/// One Pascal VOC annotation XML, unknown elements like `source` or `part` are ignored
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename = "annotation")]
+pub struct VocAnnotation {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub folder: String,
+    pub filename: String,
+    pub size: VocSize,
+    #[serde(default)]
+    pub segmented: u8,
+    #[serde(rename = "object", default)]
+    pub objects: Vec<VocObject>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct VocSize {
+    pub width: u32,
+    pub height: u32,
+    #[serde(default = "default_depth")]
+    pub depth: u32,
+}
+
+fn default_depth() -> u32 {
+    3
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct VocObject {
+    pub name: String,
+    #[serde(default = "default_pose")]
+    pub pose: String,
+    #[serde(default)]
+    pub truncated: u8,
+    #[serde(default)]
+    pub difficult: u8,
+    pub bndbox: VocBox,
+}
+
+fn default_pose() -> String {
+    "Unspecified".to_string()
+}
+
+/// 1-based, inclusive pixel coordinates
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct VocBox {
+    pub xmin: f64,
+    pub ymin: f64,
+    pub xmax: f64,
+    pub ymax: f64,
+}
+
+/// The VOC color map, index -> RGB, used as the palette of the mask PNGs
+pub fn voc_colormap() -> Vec<[u8; 3]> {
+    (0..256u32)
+        .map(|index| {
+            let mut rgb = [0u8; 3];
+            let mut c = index;
+            for shift in (0..8).rev() {
+                for (channel, value) in rgb.iter_mut().enumerate() {
+                    *value |= (((c >> channel) & 1) << shift) as u8;
+                }
+                c >>= 3;
+            }
+            rgb
+        })
+        .collect()
+}
+
+/// Raw indices of an 8 bit indexed PNG (or the values of a grayscale one), without
+/// expanding the palette to colors
+pub fn read_index_png(path: &Path) -> Result<GrayImage> {
+    let mut decoder = png::Decoder::new(BufReader::new(
+        File::open(path).with_context(|| format!("Opening {}", path.display()))?,
+    ));
+    decoder.set_transformations(png::Transformations::IDENTITY);
+    let mut reader = decoder.read_info()?;
+    let info = reader.info();
+    let (width, height) = (info.width, info.height);
+    if info.bit_depth != png::BitDepth::Eight
+        || !matches!(
+            info.color_type,
+            png::ColorType::Indexed | png::ColorType::Grayscale
+        )
+    {
+        bail!("{} is not an 8 bit indexed / grayscale PNG", path.display());
+    }
+    let mut data = vec![
+        0;
+        reader
+            .output_buffer_size()
+            .ok_or(anyhow!("{} is too large", path.display()))?
+    ];
+    let frame = reader.next_frame(&mut data)?;
+    data.truncate(frame.buffer_size());
+    GrayImage::from_raw(width, height, data).ok_or(anyhow!("Malformed {}", path.display()))
+}
+
+/// Save indices as an 8 bit PNG with the VOC color map as palette
+pub fn write_index_png(path: &Path, indices: &GrayImage) -> Result<()> {
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(File::create(path).with_context(|| format!("Creating {}", path.display()))?),
+        indices.width(),
+        indices.height(),
+    );
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(voc_colormap().concat());
+    encoder.write_header()?.write_image_data(indices.as_raw())?;
+    Ok(())
+}
+
+/// Read the VOC XML files under `annotations_root` into a COCO dataset.
+///
+/// Boxes are shifted from the 1-based inclusive VOC coordinates. With `masks_path`, the
+/// `SegmentationObject` folder, the object masks of the same relative folder + stem become
+/// RLE segmentations, object `n` of the XML being the pixels of index `n`. Category ids follow
+/// `names_path` (TXT, one name per line, or a palette file) plus one, names missing from it get
+/// the next ids in order of appearance. `difficult` objects become crowd annotations, the
+/// reverse of [`write_voc_dataset`].
+pub fn read_voc_dataset(
+    annotations_root: &Path,
+    masks_path: Option<&Path>,
+    names_path: Option<&str>,
+    walk: &WalkOptions,
+) -> Result<Dataset> {
+    let entries = walk.collect_files(annotations_root, &["xml"], &[])?;
+    if entries.is_empty() {
+        bail!("No XML annotation found in {}", annotations_root.display());
+    }
+    let base = if annotations_root.is_file() {
+        annotations_root.parent().unwrap_or(Path::new(""))
+    } else {
+        annotations_root
+    };
+
+    let names = match names_path {
+        Some(path) => load_class_names(Path::new(path))?,
+        None => BTreeMap::new(),
+    };
+    let mut dataset = Dataset::default();
+    let mut category_ids = HashMap::new();
+    for (class_id, name) in &names {
+        category_ids.insert(name.clone(), class_id + 1);
+        dataset.categories.push(Category {
+            id: class_id + 1,
+            name: name.clone(),
+            supercategory: name.clone(),
+        });
+    }
+
+    for entry in entries {
+        let content =
+            fs::read_to_string(&entry).with_context(|| format!("Reading {}", entry.display()))?;
+        let voc: VocAnnotation = quick_xml::de::from_str(&content)
+            .with_context(|| format!("Parsing {}", entry.display()))?;
+        let (width, height) = (voc.size.width, voc.size.height);
+        if width == 0 || height == 0 {
+            bail!("{} has no image size", entry.display());
+        }
+        let relparent = entry
+            .parent()
+            .and_then(|parent| parent.strip_prefix(base).ok())
+            .unwrap_or(Path::new(""))
+            .to_path_buf();
+        let image = Image {
+            id: dataset.images.len() as u64 + 1,
+            width,
+            height,
+            file_name: relparent.join(&voc.filename).to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+
+        let object_mask = match masks_path {
+            Some(masks_path) => {
+                let stem = entry.file_stem().unwrap_or_default();
+                let mask_path = masks_path.join(&relparent).join(stem).with_extension("png");
+                if mask_path.is_file() {
+                    let mask = read_index_png(&mask_path)?;
+                    if mask.dimensions() != (width, height) {
+                        bail!(
+                            "{} is {}x{}, the annotation says {}x{}",
+                            mask_path.display(),
+                            mask.width(),
+                            mask.height(),
+                            width,
+                            height
+                        );
+                    }
+                    Some(mask)
+                } else {
+                    tracing::warn!("{} has no object mask", entry.display());
+                    None
+                }
+            }
+            None => None,
+        };
+
+        for (index, object) in voc.objects.iter().enumerate() {
+            let category_id = match category_ids.get(&object.name) {
+                Some(id) => *id,
+                None => {
+                    let id = dataset.categories.iter().map(|c| c.id).max().unwrap_or(0) + 1;
+                    if !names.is_empty() {
+                        tracing::warn!(
+                            "Class {} is not in the names, added as {}",
+                            object.name,
+                            id
+                        );
+                    }
+                    category_ids.insert(object.name.clone(), id);
+                    dataset.categories.push(Category {
+                        id,
+                        name: object.name.clone(),
+                        supercategory: object.name.clone(),
+                    });
+                    id
+                }
+            };
+            let bbox = Bbox {
+                left: object.bndbox.xmin - 1.0,
+                top: object.bndbox.ymin - 1.0,
+                width: object.bndbox.xmax - object.bndbox.xmin + 1.0,
+                height: object.bndbox.ymax - object.bndbox.ymin + 1.0,
+            };
+
+            let mut segmentation = Segmentation::Polygons(vec![]);
+            let mut area = bbox.width * bbox.height;
+            if let Some(object_mask) = &object_mask {
+                let object_index = index + 1;
+                let mask =
+                    ndarray::Array2::from_shape_fn((height as usize, width as usize), |(y, x)| {
+                        (object_mask.get_pixel(x as u32, y as u32).0[0] as usize == object_index)
+                            as u8
+                    });
+                let pixels = mask.iter().filter(|v| **v > 0).count();
+                if pixels > 0 {
+                    segmentation = Segmentation::Rle(Rle::from(&mask));
+                    area = pixels as f64;
+                }
+            }
+            dataset.annotations.push(Annotation {
+                id: dataset.annotations.len() as u64 + 1,
+                image_id: image.id,
+                category_id,
+                segmentation,
+                area,
+                bbox,
+                iscrowd: (object.difficult == 1) as u32,
+            });
+        }
+        dataset.images.push(image);
+    }
+    Ok(dataset)
+}
+
+/// Write a COCO dataset as VOC XML files to `<output_root>/Annotations`, one per image.
+///
+/// Crowd annotations become `difficult` objects. With `masks`, the indexed
+/// `SegmentationObject` (object `n` of the XML as index `n`) and `SegmentationClass`
+/// (categories sorted by id as 1, 2, ...) PNGs are written as well, crowds as void (255).
+pub fn write_voc_dataset(dataset: &Dataset, output_root: &Path, masks: bool) -> Result<()> {
+    let mut categories = dataset.categories.iter().collect::<Vec<_>>();
+    categories.sort_by_key(|category| category.id);
+    // Category id -> (name, class index)
+    let classes = categories
+        .iter()
+        .enumerate()
+        .map(|(index, category)| (category.id, (category.name.as_str(), index + 1)))
+        .collect::<HashMap<_, _>>();
+    if masks && classes.len() >= VOID_INDEX as usize {
+        bail!("Class masks hold at most 254 categories");
+    }
+
+    let mut image_annotations = HashMap::<u64, Vec<&Annotation>>::new();
+    for annotation in &dataset.annotations {
+        image_annotations
+            .entry(annotation.image_id)
+            .or_default()
+            .push(annotation);
+    }
+
+    for image in &dataset.images {
+        let annotations = image_annotations.remove(&image.id).unwrap_or_default();
+        let relative_name = contained_path(&image.file_name);
+        let mut voc = VocAnnotation {
+            folder: String::new(),
+            filename: relative_name
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            size: VocSize {
+                width: image.width,
+                height: image.height,
+                depth: 3,
+            },
+            segmented: masks as u8,
+            objects: Vec::new(),
+        };
+        let mut object_mask = GrayImage::new(image.width, image.height);
+        let mut class_mask = GrayImage::new(image.width, image.height);
+
+        for annotation in annotations {
+            let (name, class_index) = classes.get(&annotation.category_id).ok_or(anyhow!(
+                "Annotation {} has the unknown category {}",
+                annotation.id,
+                annotation.category_id
+            ))?;
+            let bbox = &annotation.bbox;
+            voc.objects.push(VocObject {
+                name: name.to_string(),
+                pose: default_pose(),
+                truncated: 0,
+                difficult: (annotation.iscrowd == 1) as u8,
+                bndbox: VocBox {
+                    xmin: (bbox.left + 1.0).round(),
+                    ymin: (bbox.top + 1.0).round(),
+                    xmax: (bbox.left + bbox.width).round(),
+                    ymax: (bbox.top + bbox.height).round(),
+                },
+            });
+
+            if masks {
+                let object_index = voc.objects.len();
+                let (object_value, class_value) = if annotation.iscrowd == 1 {
+                    (VOID_INDEX, VOID_INDEX)
+                } else if object_index >= VOID_INDEX as usize {
+                    tracing::warn!(
+                        "{} has more than 254 objects, object {} is left out of the mask",
+                        image.file_name,
+                        object_index
+                    );
+                    continue;
+                } else {
+                    (object_index as u8, *class_index as u8)
+                };
+                let mask = annotation_mask(&annotation.segmentation, image.width, image.height)
+                    .with_context(|| format!("Decoding annotation {}", annotation.id))?;
+                for ((y, x), value) in mask.indexed_iter() {
+                    if *value > 0 {
+                        object_mask.put_pixel(x as u32, y as u32, Luma([object_value]));
+                        class_mask.put_pixel(x as u32, y as u32, Luma([class_value]));
+                    }
+                }
+            }
+        }
+
+        let xml_path = output_root
+            .join("Annotations")
+            .join(&relative_name)
+            .with_extension("xml");
+        if let Some(parent) = xml_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut xml = String::new();
+        let mut serializer = quick_xml::se::Serializer::new(&mut xml);
+        serializer.indent(' ', 4);
+        voc.serialize(serializer)?;
+        fs::write(&xml_path, xml).with_context(|| format!("Writing {}", xml_path.display()))?;
+
+        if masks {
+            for (folder, mask) in [
+                ("SegmentationObject", &object_mask),
+                ("SegmentationClass", &class_mask),
+            ] {
+                let mask_path = output_root
+                    .join(folder)
+                    .join(&relative_name)
+                    .with_extension("png");
+                if let Some(parent) = mask_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                write_index_png(&mask_path, mask)?;
+            }
+        }
+    }
+
+    let names = categories
+        .iter()
+        .map(|category| format!("{}\n", category.name))
+        .collect::<String>();
+    fs::write(output_root.join(CLASSES_FILE), names)?;
+    tracing::info!(
+        "{} annotations of {} images saved to {}",
+        dataset.annotations.len(),
+        dataset.images.len(),
+        output_root.display()
+    );
+    Ok(())
+}
+
+/// The folder containing `path`, `path` itself when it is a folder
+fn parent_folder(path: &Path) -> PathBuf {
+    path.parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+        .to_path_buf()
+}
+
+/// Convert VOC XML files to a COCO JSON, see [`read_voc_dataset`], saved to `save_path`,
+/// defaulting to `annotations.json` next to the annotations folder.
+pub fn voc2coco(
+    dataset_path: &str,
+    masks_path: Option<&str>,
+    names_path: Option<&str>,
+    save_path: Option<&str>,
+    walk: &WalkOptions,
+) -> Result<()> {
+    let annotations_root = PathBuf::from(dataset_path);
+    let dataset = read_voc_dataset(
+        &annotations_root,
+        masks_path.map(Path::new),
+        names_path,
+        walk,
+    )?;
+    let save_path = match save_path {
+        Some(path) => PathBuf::from(path),
+        None => parent_folder(&annotations_root).join("annotations.json"),
+    };
+    fs::write(&save_path, serde_json::to_string(&dataset)?)
+        .with_context(|| format!("Writing {}", save_path.display()))?;
+    tracing::info!(
+        "{} images, {} annotations, {} categories saved to {}",
+        dataset.images.len(),
+        dataset.annotations.len(),
+        dataset.categories.len(),
+        save_path.display()
+    );
+    Ok(())
+}
+
+/// Convert a COCO JSON to VOC, see [`write_voc_dataset`], saved to `output_path`,
+/// defaulting to the folder of the JSON.
+pub fn coco2voc(dataset_path: &str, output_path: Option<&str>, masks: bool) -> Result<()> {
+    let dataset_path = PathBuf::from(dataset_path);
+    let dataset = load_dataset(&dataset_path)?;
+    let output_root = match output_path {
+        Some(path) => PathBuf::from(path),
+        None => parent_folder(&dataset_path),
+    };
+    write_voc_dataset(&dataset, &output_root, masks)
+}
+
+/// Convert VOC XML files (and object masks) to YOLO TXT labels, saved to `output_path`,
+/// defaulting to the `labels` folder next to the annotations folder. `difficult` objects are
+/// skipped like crowd annotations.
+pub fn voc2yolo(
+    dataset_path: &str,
+    masks_path: Option<&str>,
+    names_path: Option<&str>,
+    output_path: Option<&str>,
+    format: YoloFormat,
+    vectorize: &VectorizeOptions,
+    walk: &WalkOptions,
+) -> Result<()> {
+    vectorize.validate()?;
+    let annotations_root = PathBuf::from(dataset_path);
+    let dataset = read_voc_dataset(
+        &annotations_root,
+        masks_path.map(Path::new),
+        names_path,
+        walk,
+    )?;
+    let output_root = match output_path {
+        Some(path) => PathBuf::from(path),
+        None => parent_folder(&annotations_root).join("labels"),
+    };
+    write_yolo_dataset(&dataset, &output_root, format, false, vectorize)
+}
+
+/// Convert a YOLO dataset to VOC XML files (and masks), saved to `output_path`,
+/// defaulting to the folder containing the labels folder.
+pub fn yolo2voc(
+    dataset_path: &str,
+    images_path: Option<&str>,
+    image_extensions: &[String],
+    names_path: Option<&str>,
+    output_path: Option<&str>,
+    masks: bool,
+    walk: &WalkOptions,
+) -> Result<()> {
+    let label_root = PathBuf::from(dataset_path);
+    let dataset = read_yolo_dataset(&label_root, images_path, image_extensions, names_path, walk)?;
+    let output_root = match output_path {
+        Some(path) => PathBuf::from(path),
+        None => parent_folder(&label_root),
+    };
+    write_voc_dataset(&dataset, &output_root, masks)
+}
@@ -1,18 +1,16 @@
+use anyhow::{anyhow, bail, Context, Result};
 use indicatif::ProgressStyle;
 use itertools::Itertools;
-use num::ToPrimitive;
-use num_rational::Ratio;
 use opencv::{
-    core::{self, MatTraitConst, ModifyInplace, Vec3b},
-    imgcodecs::{self, imread, IMREAD_COLOR, IMREAD_GRAYSCALE},
+    core::{MatTraitConst, ModifyInplace, Vec3b},
+    imgcodecs::{imread, IMREAD_COLOR, IMREAD_GRAYSCALE},
     imgproc::COLOR_BGR2RGB,
 };
 use parking_lot::RwLock;
 use rayon::prelude::*;
 use rayon_progress::ProgressAdaptor;
-use serde::Serialize;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fs,
     path::{Path, PathBuf},
     sync::{Arc, Mutex},
@@ -20,9 +18,16 @@ use std::{
 use tokio::{sync::Semaphore, task::JoinSet};
 use tracing::{info_span, Instrument, Span};
 use tracing_indicatif::span_ext::IndicatifSpanExt;
-use tracing_unwrap::{OptionExt, ResultExt};
+use tracing_unwrap::ResultExt;
 
+use super::palette::Palette;
+use super::walk::{WalkOptions, IMAGE_EXTENSIONS};
 use crate::THREAD_POOL;
+use journal::Journal;
+use pairing::{pair_images_labels, relparent_and_stem, ImageLabelPair};
+use paths::{is_derived_list, normalize, PathOptions};
+use splits::{assign_splits, Split};
+use weights::{compute_class_weights, save_class_weights, ClassCount, WeightOptions};
 
 fn check_semantic_segmentation_dataset(dataset_path: &Path) -> bool {
     if !(dataset_path.join("images").is_dir() && dataset_path.join("labels").is_dir()) {
@@ -36,65 +41,59 @@ fn check_semantic_segmentation_dataset(dataset_path: &Path) -> bool {
     }
 }
 
-// This will generate CSV format dataset list for huggingface dataset lib
-pub fn generate_dataset_csv(dataset_path: &String, train_ratio: &f32) {
-    let dataset_path = PathBuf::from(dataset_path);
-    if !check_semantic_segmentation_dataset(&dataset_path) {
-        return;
+/// Pair `<dataset>/images` with `<dataset>/labels` by relative folder + stem
+fn pair_dataset(
+    dataset_path: &Path,
+    image_extensions: &[String],
+    label_extensions: &[String],
+) -> Result<Vec<ImageLabelPair>> {
+    if !check_semantic_segmentation_dataset(dataset_path) {
+        bail!("Invalid dataset path: {}", dataset_path.display());
     }
+    pair_images_labels(
+        &dataset_path.join("images"),
+        &dataset_path.join("labels"),
+        image_extensions,
+        label_extensions,
+        false,
+    )
+}
 
-    let mut images = fs::read_dir(dataset_path.join("images").clone())
-        .unwrap()
-        .map(|x| x.unwrap().path())
-        .filter(|x| x.is_file())
-        .collect::<Vec<PathBuf>>();
-
-    images.sort_unstable_by(|a, b| a.file_name().cmp(&b.file_name()));
-
-    let mut labels = fs::read_dir(dataset_path.join("labels").clone())
-        .unwrap()
-        .map(|x| x.unwrap().path())
-        .filter(|x| x.is_file())
-        .collect::<Vec<PathBuf>>();
-
-    labels.sort_unstable_by(|a, b| a.file_name().cmp(&b.file_name()));
+// This will generate CSV format dataset list for huggingface dataset lib
+pub fn generate_dataset_csv(
+    dataset_path: &String,
+    splits: &[Split],
+    image_extensions: &[String],
+    label_extensions: &[String],
+    path_options: &PathOptions,
+) -> Result<()> {
+    let dataset_path = PathBuf::from(dataset_path);
+    let pairs = pair_dataset(&dataset_path, image_extensions, label_extensions)?;
+    let rewriter = path_options.rewriter()?;
 
     let mut data = Vec::<String>::new();
-
-    for (image, label) in images.iter().zip(labels.iter()) {
-        if image.file_stem() != label.file_stem() {
-            return tracing::error!(
-                "Image and label should have same name, but encountered {}, {}",
-                image.display(),
-                label.display()
-            );
-        }
-
+    for pair in pairs {
         data.push(format!(
             "{},{}",
-            fs::canonicalize(image).unwrap().display(),
-            fs::canonicalize(label).unwrap().display()
+            rewriter.rewrite(&fs::canonicalize(&pair.image)?, &dataset_path)?,
+            rewriter.rewrite(&fs::canonicalize(&pair.label)?, &dataset_path)?
         ));
     }
 
     use rand::seq::SliceRandom;
     data.shuffle(&mut rand::thread_rng());
 
-    let train_count = (data.len() as f32 * train_ratio) as i32;
-    let mut train_data = data[0..train_count as usize].to_vec();
-    let mut val_data = data[train_count as usize..].to_vec();
-
-    train_data.insert(0, "image,label".to_string());
-    val_data.insert(0, "image,label".to_string());
-
-    fs::write(dataset_path.join("train.csv"), train_data.join("\n"))
-        .expect_or_log("Failed to write");
-    fs::write(dataset_path.join("val.csv"), val_data.join("\n")).expect_or_log("Failed to write");
-    tracing::info!(
-        "Saved to train: {} val: {}",
-        dataset_path.join("train.csv").display(),
-        dataset_path.join("val.csv").display()
-    );
+    for (split, mut split_data) in assign_splits(data, splits) {
+        split_data.insert(0, "image,label".to_string());
+        let save_path = dataset_path.join(format!("{}.csv", split));
+        fs::write(&save_path, split_data.join("\n"))?;
+        tracing::info!(
+            "Saved {} items to {}",
+            split_data.len() - 1,
+            save_path.display()
+        );
+    }
+    Ok(())
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
@@ -103,68 +102,64 @@ struct DatasetItem {
     label: String,
 }
 
-pub fn generate_dataset_json(dataset_path: &String, train_ratio: &f32) {
-    let dataset_path = PathBuf::from(dataset_path);
-    if !check_semantic_segmentation_dataset(&dataset_path) {
-        return;
+/// Every JSON dataset list directly in `dataset_path` keyed by split (the file stem),
+/// derived lists and other JSON files are skipped
+fn load_list_files(dataset_path: &Path) -> Result<BTreeMap<String, Vec<DatasetItem>>> {
+    let mut lists = BTreeMap::new();
+    for entry in fs::read_dir(dataset_path)? {
+        let path = entry?.path();
+        if !path.is_file()
+            || path.extension().and_then(|s| s.to_str()) != Some("json")
+            || is_derived_list(&path)
+        {
+            continue;
+        }
+        let Ok(items) = serde_json::from_str::<Vec<DatasetItem>>(&fs::read_to_string(&path)?)
+        else {
+            continue;
+        };
+        let split = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or(anyhow!("Non-UTF8 list file name {}", path.display()))?
+            .to_string();
+        lists.insert(split, items);
     }
+    Ok(lists)
+}
 
-    let mut images = fs::read_dir(dataset_path.join("images").clone())
-        .unwrap()
-        .map(|x| x.unwrap().path())
-        .filter(|x| x.is_file())
-        .collect::<Vec<PathBuf>>();
-
-    images.sort_unstable_by(|a, b| a.file_name().cmp(&b.file_name()));
-
-    let mut labels = fs::read_dir(dataset_path.join("labels").clone())
-        .unwrap()
-        .map(|x| x.unwrap().path())
-        .filter(|x| x.is_file())
-        .collect::<Vec<PathBuf>>();
-
-    labels.sort_unstable_by(|a, b| a.file_name().cmp(&b.file_name()));
+pub fn generate_dataset_json(
+    dataset_path: &String,
+    splits: &[Split],
+    image_extensions: &[String],
+    label_extensions: &[String],
+    path_options: &PathOptions,
+) -> Result<()> {
+    let dataset_path = PathBuf::from(dataset_path);
+    let pairs = pair_dataset(&dataset_path, image_extensions, label_extensions)?;
+    let rewriter = path_options.rewriter()?;
 
     let mut data = Vec::<DatasetItem>::new();
-
-    for (image, label) in images.iter().zip(labels.iter()) {
-        if image.file_stem() != label.file_stem() {
-            return tracing::error!(
-                "Image and label should have same name, but encountered {}, {}",
-                image.display(),
-                label.display()
-            );
-        }
-
+    for pair in pairs {
         data.push(DatasetItem {
-            image: fs::canonicalize(image).unwrap().display().to_string(),
-            label: fs::canonicalize(label).unwrap().display().to_string(),
+            image: rewriter.rewrite(&fs::canonicalize(&pair.image)?, &dataset_path)?,
+            label: rewriter.rewrite(&fs::canonicalize(&pair.label)?, &dataset_path)?,
         });
     }
 
     use rand::seq::SliceRandom;
     data.shuffle(&mut rand::thread_rng());
 
-    let train_count = (data.len() as f32 * train_ratio) as i32;
-    let train_data = data[0..train_count as usize].to_vec();
-    let val_data = data[train_count as usize..].to_vec();
-
-    fs::write(
-        dataset_path.join("train.json"),
-        serde_json::to_string(&train_data).expect_or_log("Failed to serialize"),
-    )
-    .expect_or_log("Failed to write");
-
-    fs::write(
-        dataset_path.join("val.json"),
-        serde_json::to_string(&val_data).expect_or_log("Failed to serialize"),
-    )
-    .expect_or_log("Failed to write");
-    tracing::info!(
-        "Saved to train: {} val: {}",
-        dataset_path.join("train.json").display(),
-        dataset_path.join("val.json").display()
-    );
+    for (split, split_data) in assign_splits(data, splits) {
+        let save_path = dataset_path.join(format!("{}.json", split));
+        fs::write(&save_path, serde_json::to_string(&split_data)?)?;
+        tracing::info!(
+            "Saved {} items to {}",
+            split_data.len(),
+            save_path.display()
+        );
+    }
+    Ok(())
 }
 
 pub fn combine_dataset_json(dataset_path: &Vec<String>, save_path: &String) {
@@ -172,331 +167,196 @@ pub fn combine_dataset_json(dataset_path: &Vec<String>, save_path: &String) {
     if !save_path.is_dir() {
         fs::create_dir_all(save_path.clone()).expect_or_log("Failed to create directory");
     }
-    let mut combined_train_datas = Vec::<DatasetItem>::new();
-    let mut combined_val_datas = Vec::<DatasetItem>::new();
+    // Every split list present is combined, e.g. train.json, val.json and test.json
+    let mut combined_datas = BTreeMap::<String, Vec<DatasetItem>>::new();
     for dataset_path in dataset_path {
         let dataset_path = PathBuf::from(dataset_path);
-        if !(dataset_path.join("train.json").is_file() && dataset_path.join("val.json").is_file()) {
+        let lists = load_list_files(&dataset_path).expect_or_log("Failed to read");
+        if lists.is_empty() {
             tracing::error!(
-                "Invalid dataset path: {}, should contain train.json and val.json",
+                "Invalid dataset path: {}, should contain split JSON lists such as train.json and val.json",
                 dataset_path.display()
             );
             return;
         }
 
-        let train_data: Vec<DatasetItem> = serde_json::from_str(
-            &fs::read_to_string(dataset_path.join("train.json")).expect_or_log("Failed to read"),
-        )
-        .expect("Failed to deserialize");
+        // Relative entries are relative to their own dataset, not to the combined lists
+        let dataset_root = fs::canonicalize(&dataset_path).expect_or_log("Failed to resolve");
+        let resolve = |item: DatasetItem| DatasetItem {
+            image: normalize(&dataset_root.join(&item.image))
+                .display()
+                .to_string(),
+            label: normalize(&dataset_root.join(&item.label))
+                .display()
+                .to_string(),
+        };
+        for (split, items) in lists {
+            combined_datas
+                .entry(split)
+                .or_default()
+                .extend(items.into_iter().map(resolve));
+        }
+    }
 
-        let val_data: Vec<DatasetItem> = serde_json::from_str(
-            &fs::read_to_string(dataset_path.join("val.json")).expect_or_log("Failed to read"),
+    for (split, items) in combined_datas {
+        let save_path = save_path.join(format!("{}.json", split));
+        fs::write(
+            &save_path,
+            serde_json::to_string(&items).expect_or_log("Failed to serialize"),
         )
-        .expect("Failed to deserialize");
-
-        combined_train_datas.extend(train_data);
-        combined_val_datas.extend(val_data);
+        .expect_or_log("Failed to write");
+        tracing::info!("Saved {} items to {}", items.len(), save_path.display());
     }
-
-    let combined_train_datas =
-        serde_json::to_string(&combined_train_datas).expect_or_log("Failed to serialize");
-
-    let combined_val_datas =
-        serde_json::to_string(&combined_val_datas).expect_or_log("Failed to serialize");
-
-    fs::write(save_path.join("train.json"), combined_train_datas).expect_or_log("Failed to write");
-    fs::write(save_path.join("val.json"), combined_val_datas).expect_or_log("Failed to write");
 }
 
-pub async fn generate_dataset_txt(dataset_path: &String, train_ratio: &f32) {
-    // Check dataset_path contain images and labels folder
-
+pub async fn generate_dataset_txt(
+    dataset_path: &String,
+    splits: &[Split],
+    image_extensions: &[String],
+    label_extensions: &[String],
+) -> Result<()> {
+    // Only images having a label are listed
     let dataset_path = PathBuf::from(dataset_path);
-    if !(dataset_path.join("images").is_dir() && dataset_path.join("labels").is_dir()) {
-        tracing::error!(
-            "Invalid dataset path: {}, should contain images and labels folders",
-            dataset_path.display()
-        );
-        return;
-    }
-
-    let dataset_path = dataset_path.join("images");
-    let entries = fs::read_dir(dataset_path.clone())
-        .unwrap()
-        .map(|x| x.unwrap().path())
-        .collect::<Vec<PathBuf>>();
-
-    let mut threads = JoinSet::new();
-    let sem = Arc::new(Semaphore::new(
-        (*THREAD_POOL.read().expect_or_log("Get pool error")).into(),
-    ));
-    let result = Arc::new(Mutex::new(Vec::<String>::new()));
-    for entry in entries {
-        let permit = Arc::clone(&sem);
-        let result = Arc::clone(&result);
-        threads.spawn(async move {
-            let _permit = permit.acquire().await.unwrap();
-            result.lock().unwrap().push(format!(
-                "{}\n",
-                entry.file_name().unwrap().to_str().unwrap()
-            ));
-        });
-    }
-
-    while threads.join_next().await.is_some() {}
+    let pairs = pair_dataset(&dataset_path, image_extensions, label_extensions)?;
+
+    let mut data = pairs
+        .iter()
+        .map(|pair| {
+            pair.image
+                .file_name()
+                .map(|name| format!("{}\n", name.to_string_lossy()))
+                .ok_or(anyhow!("Failed to get file name"))
+        })
+        .collect::<Result<Vec<String>>>()?;
 
-    let mut data = result.lock().unwrap();
     use rand::seq::SliceRandom;
     data.shuffle(&mut rand::thread_rng());
-    let train_count = (data.len() as f32 * train_ratio) as i32;
-    let train_data = data[0..train_count as usize].to_vec();
-    let valid_data = data[train_count as usize..].to_vec();
-
-    let dataset_path = dataset_path.to_str().unwrap();
-    fs::write(format!("{}/../val.txt", dataset_path), valid_data.concat()).unwrap();
-    fs::write(
-        format!("{}/../train.txt", dataset_path),
-        train_data.concat(),
-    )
-    .unwrap();
-    tracing::info!("Train dataset length: {}", train_data.len());
-    tracing::info!("Saved to {}/../train.txt", dataset_path);
-    tracing::info!("Valid dataset length: {}", valid_data.len());
-    tracing::info!("Saved to {}/../val.txt", dataset_path);
+
+    for (split, split_data) in assign_splits(data, splits) {
+        let save_path = dataset_path.join(format!("{}.txt", split));
+        fs::write(&save_path, split_data.concat())?;
+        tracing::info!("{} dataset length: {}", split, split_data.len());
+        tracing::info!("Saved to {}", save_path.display());
+    }
     tracing::info!("Dataset split done");
+    Ok(())
 }
 
-pub fn txt2json(txt_path: &String) {
-    let save_path = PathBuf::from(txt_path);
-    let (save_path, file_name) = (
-        save_path.parent().unwrap().to_str().unwrap().to_string(),
-        save_path.file_stem().unwrap().to_str().unwrap().to_string(),
-    );
-    let save_path = PathBuf::from(format!("{}/{}.json", save_path, file_name));
-
-    #[derive(Serialize)]
-    struct DatasetItem {
-        image: String,
-        label: String,
-    }
+/// Convert a txt image list into the JSON list format.
+///
+/// Each line is an image path, absolute or relative to `<dataset>/images` (or `<dataset>`),
+/// the label is looked up in `<dataset>/labels` by relative folder + stem.
+/// `dataset_path` defaults to the folder containing the txt file.
+pub fn txt2json(
+    txt_path: &String,
+    dataset_path: Option<&String>,
+    image_extensions: &[String],
+    label_extensions: &[String],
+    path_options: &PathOptions,
+) -> Result<()> {
+    let txt_path = PathBuf::from(txt_path);
+    let dataset_path = match dataset_path {
+        Some(path) => PathBuf::from(path),
+        None => txt_path
+            .parent()
+            .ok_or(anyhow!("Failed to get parent of {}", txt_path.display()))?
+            .to_path_buf(),
+    };
+    let save_path = txt_path.with_extension("json");
+    let save_dir = save_path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let rewriter = path_options.rewriter()?;
+
+    let pairs = pair_dataset(&dataset_path, image_extensions, label_extensions)?;
+    let image_root = fs::canonicalize(dataset_path.join("images"))?;
+    let labels = pairs
+        .into_iter()
+        .map(|pair| (pair.key, pair.label))
+        .collect::<HashMap<String, PathBuf>>();
 
     let mut json = Vec::new();
-    for line in fs::read_to_string(txt_path).unwrap().lines() {
-        let image = line.to_string();
-        let label = line.replace("/images/", "/labels/");
-        let label = label.replace(".tif", ".png");
-        json.push(DatasetItem { image, label });
+    let content = fs::read_to_string(&txt_path)
+        .with_context(|| format!("Failed to read {}", txt_path.display()))?;
+    for line in content.lines().map(str::trim).filter(|x| !x.is_empty()) {
+        let image = [
+            PathBuf::from(line),
+            image_root.join(line),
+            dataset_path.join(line),
+        ]
+        .into_iter()
+        .find(|x| x.is_file())
+        .ok_or(anyhow!("Image {} not found", line))?;
+        let image = fs::canonicalize(&image)?;
+
+        let key = relparent_and_stem(&image_root, &image)?;
+        let label = labels
+            .get(&key)
+            .ok_or(anyhow!("No label found for image {}", image.display()))?;
+
+        json.push(DatasetItem {
+            image: rewriter.rewrite(&image, save_dir)?,
+            label: rewriter.rewrite(&fs::canonicalize(label)?, save_dir)?,
+        });
     }
 
-    let json = serde_json::to_string(&json).unwrap();
-
-    fs::write(&save_path, json).unwrap();
+    fs::write(&save_path, serde_json::to_string(&json)?)?;
 
     tracing::info!("Saved to {}", save_path.display());
+    Ok(())
 }
 
-// TODO: rewrite this to make it suitable for all datasets
-pub async fn split_dataset(dataset_path: &String, train_ratio: &f32) {
-    // Check dataset_path contain images and labels folder
-
+pub async fn split_dataset(
+    dataset_path: &String,
+    splits: &[Split],
+    image_extensions: &[String],
+    label_extensions: &[String],
+    dry_run: bool,
+) -> Result<()> {
     let dataset_path = PathBuf::from(dataset_path);
-    if !(dataset_path.join("images").is_dir() && dataset_path.join("labels").is_dir()) {
-        tracing::error!(
-            "Invalid dataset path: {}, should contain images and labels folders",
-            dataset_path.display()
-        );
-        return;
-    }
-
-    // Create dir
-    fs::create_dir_all(dataset_path.join("images").join("train"))
-        .expect_or_log("Failed to create directory");
-    fs::create_dir_all(dataset_path.join("images").join("val"))
-        .expect_or_log("Failed to create directory");
-
-    fs::create_dir_all(dataset_path.join("labels").join("train"))
-        .expect_or_log("Failed to create directory");
-    fs::create_dir_all(dataset_path.join("labels").join("val"))
-        .expect_or_log("Failed to create directory");
+    let mut pairs = pair_dataset(&dataset_path, image_extensions, label_extensions)?;
 
-    let dataset_path = dataset_path.join("images");
-    let entries = fs::read_dir(dataset_path.clone())
-        .unwrap()
-        .map(|x| x.unwrap().path())
-        .collect::<Vec<PathBuf>>();
-
-    let mut threads = JoinSet::new();
-    let sem = Arc::new(Semaphore::new(
-        (*THREAD_POOL.read().expect_or_log("Get pool error")).into(),
-    ));
-    let result = Arc::new(Mutex::new(Vec::<PathBuf>::new()));
-    for entry in entries {
-        let permit = Arc::clone(&sem);
-        let result = Arc::clone(&result);
-        threads.spawn(async move {
-            let _permit = permit.acquire().await.unwrap();
-            result.lock().unwrap().push(entry);
-        });
-    }
-
-    while threads.join_next().await.is_some() {}
-
-    let mut data = result.lock().unwrap();
     use rand::seq::SliceRandom;
-    data.shuffle(&mut rand::thread_rng());
-    let train_count = (data.len() as f32 * train_ratio) as i32;
-    let train_data = data[0..train_count as usize].to_vec();
-    let valid_data = data[train_count as usize..].to_vec();
+    pairs.shuffle(&mut rand::thread_rng());
 
-    for entry in train_data {
-        if !entry.is_file() {
-            continue;
+    // Moves are planned first and carried out through the journal,
+    // so an interrupted split can be resumed or rolled back
+    let mut journal = Journal::new(
+        dataset_path.join("split_dataset.journal.jsonl"),
+        "split_dataset",
+    );
+    for (split, split_pairs) in assign_splits(pairs, splits) {
+        tracing::info!("{}: {} pairs", split, split_pairs.len());
+        for pair in &split_pairs {
+            for (path, folder) in [(&pair.image, "images"), (&pair.label, "labels")] {
+                // Keep the subfolder, pairs are only unique by relative folder + stem
+                let relative = path.strip_prefix(dataset_path.join(folder))?;
+                journal.push_move(path, &dataset_path.join(folder).join(&split).join(relative));
+            }
         }
-        tracing::info!(
-            "Renaming {} to {}",
-            &entry.display(),
-            &dataset_path
-                .join("train")
-                .join(entry.file_name().unwrap())
-                .display()
-        );
-        fs::rename(
-            &entry,
-            dataset_path.join("train").join(entry.file_name().unwrap()),
-        )
-        .unwrap();
-
-        tracing::info!(
-            "Renaming {} to {}",
-            &entry
-                .to_str()
-                .unwrap()
-                .to_string()
-                .replace("images", "labels")
-                .replace(".tif", ".png"),
-            &dataset_path
-                .parent()
-                .unwrap()
-                .join("labels")
-                .join("train")
-                .join(
-                    entry
-                        .file_name()
-                        .unwrap()
-                        .to_str()
-                        .unwrap()
-                        .to_string()
-                        .replace(".tif", ".png"),
-                )
-                .display(),
-        );
-        fs::rename(
-            entry
-                .to_str()
-                .unwrap()
-                .to_string()
-                .replace("images", "labels")
-                .replace(".tif", ".png"),
-            dataset_path
-                .parent()
-                .unwrap()
-                .join("labels")
-                .join("train")
-                .join(
-                    entry
-                        .file_name()
-                        .unwrap()
-                        .to_str()
-                        .unwrap()
-                        .to_string()
-                        .replace(".tif", ".png"),
-                ),
-        )
-        .unwrap();
     }
 
-    for entry in valid_data {
-        if !entry.is_file() {
-            continue;
-        }
-        tracing::info!(
-            "Renaming {} to {}",
-            &entry.display(),
-            &dataset_path
-                .join("val")
-                .join(entry.file_name().unwrap())
-                .display()
-        );
-        fs::rename(
-            &entry,
-            dataset_path.join("val").join(entry.file_name().unwrap()),
-        )
-        .unwrap();
-
-        tracing::info!(
-            "Renaming {} to {}",
-            &entry
-                .to_str()
-                .unwrap()
-                .to_string()
-                .replace("images", "labels")
-                .replace(".tif", ".png"),
-            &dataset_path
-                .parent()
-                .unwrap()
-                .join("labels")
-                .join("val")
-                .join(
-                    entry
-                        .file_name()
-                        .unwrap()
-                        .to_str()
-                        .unwrap()
-                        .to_string()
-                        .replace(".tif", ".png"),
-                )
-                .display()
-        );
-
-        fs::rename(
-            entry
-                .to_str()
-                .unwrap()
-                .to_string()
-                .replace("images", "labels")
-                .replace(".tif", ".png"),
-            dataset_path
-                .parent()
-                .unwrap()
-                .join("labels")
-                .join("val")
-                .join(
-                    entry
-                        .file_name()
-                        .unwrap()
-                        .to_str()
-                        .unwrap()
-                        .to_string()
-                        .replace(".tif", ".png"),
-                ),
-        )
-        .unwrap();
-    }
+    journal.execute(dry_run)
 }
 
-pub async fn count_classes(dataset_path: &String) {
-    let mut entries: Vec<PathBuf> = Vec::new();
-    let dataset_path = PathBuf::from(dataset_path);
-    if dataset_path.is_file() {
-        entries.push(dataset_path.clone());
-    } else {
-        entries = fs::read_dir(dataset_path.clone())
-            .unwrap()
-            .map(|x| x.unwrap().path())
-            .collect();
-    }
-
-    let type_map = Arc::new(Mutex::new(HashMap::<u8, u64>::new()));
+/// Count the pixels of every class id in the 8 bit class labels under `dataset_path` and save
+/// the class weights.
+///
+/// The weight vector is indexed by class id, its length is the largest non-ignored id + 1.
+/// Ids in `ignore_ids` (e.g. 255) are left out of the weights, an ignored id below that length
+/// keeps its slot with a weight of 0.
+pub async fn count_classes(
+    dataset_path: &String,
+    ignore_ids: &[u8],
+    walk: &WalkOptions,
+    weights: &WeightOptions,
+) {
+    let entries = walk
+        .collect_files(Path::new(dataset_path), IMAGE_EXTENSIONS, &[])
+        .expect_or_log("Failed to read directory");
+
+    let type_map = Arc::new(Mutex::new(HashMap::<u8, ClassCount>::new()));
     let sem = Arc::new(Semaphore::new(
         (*THREAD_POOL.read().expect_or_log("Get pool error")).into(),
     ));
@@ -513,9 +373,6 @@ pub async fn count_classes(dataset_path: &String) {
     let header_span_enter = header_span.enter();
 
     for entry in entries {
-        if entry.is_dir() {
-            continue;
-        }
         let type_map = Arc::clone(&type_map);
         let sem = Arc::clone(&sem);
         let header_span = header_span.clone();
@@ -527,6 +384,7 @@ pub async fn count_classes(dataset_path: &String) {
                 let row = img.rows();
                 let cols = img.cols();
                 let img = Arc::new(RwLock::new(img));
+                let image_map = Mutex::new(HashMap::<u8, u64>::new());
 
                 let row_iter = ProgressAdaptor::new(0..row);
                 row_iter.for_each(|row_index| {
@@ -540,12 +398,24 @@ pub async fn count_classes(dataset_path: &String) {
                             .or_insert(1);
                     }
 
-                    let mut entry = type_map.lock().unwrap();
+                    let mut entry = image_map.lock().unwrap();
                     for (class_id, count) in row_type_map.iter() {
                         let total_count = entry.entry(*class_id).or_insert(0);
                         *total_count += count;
                     }
                 });
+
+                // Image pixels are kept per class for median frequency balancing
+                let image_pixels = (row as u64) * (cols as u64);
+                {
+                    let mut total_map = type_map.lock().unwrap();
+                    for (class_id, count) in image_map.into_inner().unwrap() {
+                        let class_count = total_map.entry(class_id).or_default();
+                        class_count.pixels += count;
+                        class_count.image_pixels += image_pixels;
+                        class_count.images += 1;
+                    }
+                }
                 tracing::info!("Image {} done", entry.to_str().unwrap());
                 tracing::trace!("Image {} done", entry.to_str().unwrap());
                 Span::current().pb_set_message(entry.file_name().unwrap().to_str().unwrap());
@@ -571,54 +441,57 @@ pub async fn count_classes(dataset_path: &String) {
     tracing::info!("Dataset counted");
 
     let type_map = type_map.lock().unwrap();
-    tracing::info!("Classes counts: {:?}", type_map);
-
-    let total_pixel = type_map.values().sum::<u64>();
-
-    let mut weight_map = HashMap::<u8, f64>::new();
-    let class_count = type_map.len();
-    for (class_id, count) in type_map.iter() {
-        let weight = Ratio::new(
-            total_pixel,
-            *count * u64::try_from(class_count).unwrap_or_log(),
-        );
-        let weight = weight.to_f64().unwrap();
-        weight_map.insert(*class_id, weight);
+    for class_id in type_map.keys().sorted() {
+        tracing::info!("{}: {}", class_id, type_map[class_id].pixels);
     }
 
-    tracing::info!("Inverse class weights:");
-    for class_id in weight_map.keys().sorted() {
-        tracing::info!("{}: {}", class_id, weight_map[class_id]);
+    let ignored = type_map
+        .iter()
+        .filter(|(class_id, _)| ignore_ids.contains(class_id))
+        .map(|(_, count)| count.pixels)
+        .sum::<u64>();
+    if ignored > 0 {
+        tracing::info!("{} ignored pixels are left out of the weights", ignored);
     }
+
+    // Class ids index the loss weights, so every id up to the largest counted one is listed
+    let max_class = type_map
+        .keys()
+        .filter(|class_id| !ignore_ids.contains(class_id))
+        .max()
+        .copied()
+        .unwrap_or(0);
+    let counts = (0..=max_class)
+        .map(|class_id| {
+            let count = if ignore_ids.contains(&class_id) {
+                ClassCount::default()
+            } else {
+                type_map.get(&class_id).cloned().unwrap_or_default()
+            };
+            (class_id.to_string(), count)
+        })
+        .collect::<Vec<_>>();
+    let class_weights = compute_class_weights(&counts, weights);
+    save_class_weights(&class_weights, Path::new(dataset_path), weights)
+        .expect_or_log("Failed to save class weights");
 }
 
-pub async fn count_rgb(dataset_path: &String, rgb_list: &str) {
-    let mut entries: Vec<PathBuf> = Vec::new();
-    let dataset_path = PathBuf::from(dataset_path);
-    if dataset_path.is_file() {
-        entries.push(dataset_path.clone());
-    } else {
-        entries = fs::read_dir(dataset_path.clone())
+pub async fn count_rgb(
+    dataset_path: &String,
+    palette: &Palette,
+    walk: &WalkOptions,
+    weights: &WeightOptions,
+) {
+    let entries = walk
+        .collect_files(Path::new(dataset_path), IMAGE_EXTENSIONS, &[])
+        .expect_or_log("Failed to read directory");
+
+    let count_map = Arc::new(Mutex::new(HashMap::<[u8; 3], ClassCount>::new()));
+    for class in &palette.classes {
+        count_map
+            .lock()
             .unwrap()
-            .map(|x| x.unwrap().path())
-            .collect();
-    }
-
-    let count_map = Arc::new(Mutex::new(HashMap::<[u8; 3], u64>::new()));
-    {
-        // Split RGB list
-        for rgb in rgb_list.split(";") {
-            let mut rgb_vec: Vec<u8> = vec![];
-            for splited in rgb.split(',') {
-                let splited = splited.parse::<u8>().unwrap();
-                rgb_vec.push(splited);
-            }
-
-            count_map
-                .lock()
-                .unwrap()
-                .insert([rgb_vec[0], rgb_vec[1], rgb_vec[2]], 0);
-        }
+            .insert(class.rgb, ClassCount::default());
     }
 
     let sem = Arc::new(Semaphore::new(
@@ -637,9 +510,6 @@ pub async fn count_rgb(dataset_path: &String, rgb_list: &str) {
     let header_span_enter = header_span.enter();
 
     for entry in entries {
-        if !entry.is_file() {
-            continue;
-        }
         let count_map = Arc::clone(&count_map);
         let sem = Arc::clone(&sem);
         let header_span = header_span.clone();
@@ -656,6 +526,7 @@ pub async fn count_rgb(dataset_path: &String, rgb_list: &str) {
                 let row = img.rows();
                 let cols = img.cols();
                 let img = Arc::new(RwLock::new(img));
+                let image_map = Mutex::new(HashMap::<[u8; 3], u64>::new());
 
                 (0..row).into_par_iter().for_each(|row_index| {
                     let mut row_type_map = HashMap::<[u8; 3], u64>::new();
@@ -668,12 +539,24 @@ pub async fn count_rgb(dataset_path: &String, rgb_list: &str) {
                             .or_insert(1);
                     }
 
-                    let mut entry = count_map.lock().unwrap();
+                    let mut entry = image_map.lock().unwrap();
                     for (rgb_color, count) in row_type_map.iter() {
                         let total_count = entry.entry(*rgb_color).or_insert(0);
                         *total_count = total_count.saturating_add(*count);
                     }
                 });
+
+                let image_pixels = (row as u64) * (cols as u64);
+                {
+                    let mut total_map = count_map.lock().unwrap();
+                    for (rgb_color, count) in image_map.into_inner().unwrap() {
+                        let class_count = total_map.entry(rgb_color).or_default();
+                        class_count.pixels = class_count.pixels.saturating_add(count);
+                        class_count.image_pixels =
+                            class_count.image_pixels.saturating_add(image_pixels);
+                        class_count.images += 1;
+                    }
+                }
                 tracing::trace!("Image {} done", entry.to_str().unwrap());
                 Span::current().pb_set_message(entry.file_name().unwrap().to_str().unwrap());
                 Span::current().pb_inc(1);
@@ -698,176 +581,135 @@ pub async fn count_rgb(dataset_path: &String, rgb_list: &str) {
 
     let type_map = count_map.lock().unwrap();
     for (rgb, count) in (*type_map).iter() {
-        tracing::info!("{},{},{}: {}", rgb[0], rgb[1], rgb[2], count);
+        tracing::info!("{},{},{}: {}", rgb[0], rgb[1], rgb[2], count.pixels);
     }
-    tracing::info!("Classes counts: {:?}", type_map);
 
-    // let total_pixel = type_map.values().sum::<i64>();
-
-    // let mut weight_map = HashMap::<[u8; 3], f64>::new();
-    // let class_count = type_map.len() as f64;
-    // for (class_id, count) in type_map.iter() {
-    //     let class_weight: f64 = f64::from(total_pixel) / (f64::from(*count) * class_count);
-    //     weight_map.insert(*class_id, class_weight);
-    // }
+    let unlisted = type_map
+        .iter()
+        .filter(|(rgb, _)| palette.by_rgb(**rgb).is_none())
+        .map(|(_, count)| count.pixels)
+        .sum::<u64>();
+    if unlisted > 0 {
+        tracing::warn!(
+            "{} pixels have a color outside the palette and are left out of the weights",
+            unlisted
+        );
+    }
 
-    // tracing::info!("Inverse class weights: {:?}", weight_map);
+    let counts = palette_class_counts(palette, &type_map);
+    let class_weights = compute_class_weights(&counts, weights);
+    save_class_weights(&class_weights, Path::new(dataset_path), weights)
+        .expect_or_log("Failed to save class weights");
 }
 
-pub async fn calc_mean_std(dataset_path: &String) {
-    let entries = fs::read_dir(dataset_path).expect_or_log("Failed to read directory");
-    let entries = entries
-        .into_iter()
-        .map(|x| x.expect_or_log("Failed to iterate entries").path())
-        .collect::<Vec<PathBuf>>();
-    let mut threads = JoinSet::new();
-
-    let mean_map = Arc::new(Mutex::new(HashMap::<usize, Vec<f64>>::new()));
-    let std_map = Arc::new(Mutex::new(HashMap::<usize, Vec<f64>>::new()));
-
-    let min_value = Arc::new(Mutex::new(f64::MAX));
-    let max_value = Arc::new(Mutex::new(f64::MIN));
-
-    let header_span = info_span!("calc_mean_std_thread");
-    header_span.pb_set_style(
-        &ProgressStyle::with_template("{spinner} Processing {msg}\n{wide_bar} {pos}/{len}")
-            .unwrap(),
-    );
-    header_span.pb_set_length(entries.len() as u64);
-
-    let header_span_enter = header_span.enter();
-
-    // let entries_iter = ProgressAdaptor::new(0..entries.len());
-    // let entries_iter_progress = entries_iter.items_processed();
-    // let log_interval = {
-    //     let mut tmp = entries.len();
-    //     tracing::debug!("Entries len: {}", entries.len());
-    //     let mut result = 0;
-    //     while tmp != 0 {
-    //         result += 1;
-    //         tmp /= 10;
-    //     }
-    //     10_usize.pow(result - 2)
-    // };
+/// Counts in class id order for the loss weights, one slot per id up to the largest
+/// non-ignored one. Ids missing from the palette and ignored classes get a count of 0
+fn palette_class_counts(
+    palette: &Palette,
+    type_map: &HashMap<[u8; 3], ClassCount>,
+) -> Vec<(String, ClassCount)> {
+    let max_class = palette.active().map(|class| class.id).max().unwrap_or(0);
+    (0..=max_class)
+        .map(
+            |class_id| match palette.classes.iter().find(|class| class.id == class_id) {
+                Some(class) if !class.ignore => (
+                    class.name.clone(),
+                    type_map.get(&class.rgb).cloned().unwrap_or_default(),
+                ),
+                Some(class) => (class.name.clone(), ClassCount::default()),
+                None => (class_id.to_string(), ClassCount::default()),
+            },
+        )
+        .collect()
+}
 
-    for entry in entries {
-        if !entry.is_file() {
-            return;
+pub mod dedup;
+pub mod histogram;
+pub mod journal;
+pub mod manifest;
+pub mod mask;
+pub mod merge;
+pub mod pairing;
+pub mod paths;
+pub mod report;
+pub mod sampling;
+pub mod splits;
+pub mod stats;
+pub mod weights;
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::palette_class_counts;
+    use super::weights::ClassCount;
+    use crate::common::palette::{Palette, PaletteClass};
+
+    fn class(id: u8, name: &str, rgb: [u8; 3], ignore: bool) -> PaletteClass {
+        PaletteClass {
+            id,
+            name: name.to_string(),
+            rgb,
+            ignore,
         }
-        let mean_map = Arc::clone(&mean_map);
-        let std_map = Arc::clone(&std_map);
-
-        let global_min_value = Arc::clone(&min_value);
-        let global_max_value = Arc::clone(&max_value);
-
-        threads.spawn(
-            async move {
-                let image = imgcodecs::imread(
-                    entry
-                        .to_str()
-                        .expect_or_log("Failed to convert path to string"),
-                    imgcodecs::IMREAD_UNCHANGED,
-                )
-                .expect_or_log("Failed to read image");
-
-                let mut mean = core::Vector::<f64>::new();
-                let mut stddev = core::Vector::<f64>::new();
-                if image.empty() {
-                    tracing::error!("Image {} is empty", entry.display());
-                    return;
-                }
-                core::mean_std_dev(&image, &mut mean, &mut stddev, &core::no_array())
-                    .expect_or_log("Failed to calculate mean & std dev of mat");
-                let mut min_value = 0.0;
-                let mut max_value = 0.0;
-                core::min_max_loc(
-                    &image,
-                    Some(&mut min_value),
-                    Some(&mut max_value),
-                    None,
-                    None,
-                    &core::no_array(),
-                )
-                .expect_or_log("Failed to find min & max value of mat");
-
-                let mut mean_map = mean_map.lock().expect_or_log("Failed to lock mean map");
-                let mut std_map = std_map.lock().expect_or_log("Failed to lock std map");
-                for i in 1..=mean.len() {
-                    if let std::collections::hash_map::Entry::Vacant(e) = mean_map.entry(i) {
-                        e.insert(Vec::<f64>::new());
-                        std_map.insert(i, Vec::<f64>::new());
-                    }
-                    mean_map
-                        .get_mut(&i)
-                        .unwrap()
-                        .push(mean.get(i - 1).expect_or_log("Failed to get mean value"));
-                    std_map.get_mut(&i).unwrap().push(
-                        stddev
-                            .get(i - 1)
-                            .expect_or_log("Failed to get stddev value"),
-                    );
-                }
-
-                let mut global_min_value = global_min_value
-                    .lock()
-                    .expect_or_log("Failed to lock min value");
-                let mut global_max_value = global_max_value
-                    .lock()
-                    .expect_or_log("Failed to lock max value");
-
-                *global_min_value = f64::min(*global_min_value, min_value);
-                *global_max_value = f64::max(*global_max_value, max_value);
-
-                Span::current().pb_set_message(entry.file_name().unwrap().to_str().unwrap());
-                Span::current().pb_inc(1);
-            }
-            .in_current_span(),
-        );
     }
 
-    while let Some(result) = threads.join_next().await {
-        match result {
-            Ok(()) => {}
-            Err(e) => {
-                tracing::error!("Error {}", e);
-                threads.abort_all();
-                break;
-            }
+    fn count(pixels: u64) -> ClassCount {
+        ClassCount {
+            pixels,
+            image_pixels: pixels * 2,
+            images: 1,
         }
     }
 
-    drop(header_span_enter);
-    drop(header_span);
-
-    let mean_map = mean_map.lock().expect_or_log("Failed to lock mean map");
-    let std_map = std_map.lock().expect_or_log("Failed to lock std map");
-
-    assert_eq!(mean_map.len(), std_map.len());
-
-    let mut mean_vec: Vec<f64> = vec![0.0; mean_map.len()];
-    for (i, mean) in mean_map.iter() {
-        let mean = mean.iter().sum::<f64>() / mean.len() as f64;
-        *mean_vec
-            .get_mut(*i - 1)
-            .expect_or_log("Failed to get mean value") = mean;
+    #[test]
+    fn palette_with_gaps_keeps_ids_as_indices() {
+        let palette = Palette {
+            classes: vec![
+                class(5, "road", [0, 0, 255], false),
+                class(0, "background", [0, 0, 0], true),
+                class(1, "water", [0, 255, 0], false),
+                class(7, "unlabeled", [255, 255, 255], true),
+            ],
+        };
+        let type_map = HashMap::from([
+            ([0, 0, 0], count(100)),
+            ([0, 255, 0], count(10)),
+            ([0, 0, 255], count(5)),
+            ([255, 255, 255], count(50)),
+        ]);
+        let counts = palette_class_counts(&palette, &type_map);
+        let counts = counts
+            .iter()
+            .map(|(name, count)| (name.as_str(), count.pixels))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            counts,
+            [
+                ("background", 0),
+                ("water", 10),
+                ("2", 0),
+                ("3", 0),
+                ("4", 0),
+                ("road", 5),
+            ]
+        );
     }
 
-    let mut std_vec: Vec<f64> = vec![0.0; std_map.len()];
-    for (i, std) in std_map.iter() {
-        let std = std.iter().sum::<f64>() / std.len() as f64;
-        *std_vec
-            .get_mut(*i - 1)
-            .expect_or_log("Failed to get std value") = std;
+    #[test]
+    fn class_without_pixels_gets_zero() {
+        let palette = Palette {
+            classes: vec![
+                class(0, "background", [0, 0, 0], false),
+                class(2, "road", [0, 0, 255], false),
+            ],
+        };
+        let type_map = HashMap::from([([0, 0, 0], count(100))]);
+        let counts = palette_class_counts(&palette, &type_map);
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts[0].1.pixels, 100);
+        assert_eq!(counts[2].0, "road");
+        assert_eq!(counts[2].1.pixels, 0);
     }
-
-    let min_value = min_value.lock().expect_or_log("Failed to lock min value");
-    let max_value = max_value.lock().expect_or_log("Failed to lock max value");
-
-    tracing::info!("Mean: {:?}", mean_vec);
-    tracing::info!("Std: {:?}", std_vec);
-    tracing::info!("Min: {:?}", *min_value);
-    tracing::info!("Max: {:?}", *max_value);
-    tracing::info!("Dataset calculated");
 }
-
-pub mod mask;
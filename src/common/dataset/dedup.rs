@@ -0,0 +1,524 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use indicatif::ProgressStyle;
+use opencv::{
+    core::{Mat, MatTraitConst, MatTraitConstManual, Size},
+    imgcodecs, imgproc,
+};
+use serde::Serialize;
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::info_span;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+use walkdir::WalkDir;
+
+use super::paths::DEDUPED_SUFFIX;
+use super::{load_list_files, DatasetItem};
+use crate::THREAD_POOL;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HashKind {
+    /// Average hash, 8x8 thumbnail compared with its mean
+    #[value(name = "ahash")]
+    AHash,
+    /// Difference hash, 9x8 thumbnail compared with the neighbour pixel
+    #[value(name = "dhash")]
+    DHash,
+    /// Perceptual hash, low frequencies of the 32x32 DCT compared with their median
+    #[value(name = "phash")]
+    PHash,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DuplicateAction {
+    /// Only write the duplicate report
+    Report,
+    /// Keep one image of every cluster, the first one listed in a split, and remove the others
+    /// from the list files
+    Drop,
+    /// Move every cluster that crosses splits into a single split of the list files
+    Merge,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateMember {
+    pub image: String,
+    pub hash: String,
+    pub split: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateCluster {
+    pub members: Vec<DuplicateMember>,
+    pub splits: Vec<String>,
+    pub cross_split: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateReport {
+    pub hash: String,
+    pub max_distance: u32,
+    pub image_count: usize,
+    pub cluster_count: usize,
+    pub cross_split_count: usize,
+    pub clusters: Vec<DuplicateCluster>,
+}
+
+/// Find near-duplicate images of a dataset with perceptual hashing:
+/// - Hashes every file under `<dataset_path>/images` recursively
+/// - Clusters images whose hashes are within `max_distance` bits (Hamming distance)
+/// - Looks up the split of every image in the list files (`<dataset_path>/*.json`)
+///   generated by `generate_dataset_json` and flags clusters crossing splits
+/// - Writes the result to `<dataset_path>/duplicates.json`
+///
+/// With [`DuplicateAction::Drop`] or [`DuplicateAction::Merge`] the updated lists are written
+/// next to the originals as `<split>_deduped.json`, list and image files are never touched.
+pub async fn find_duplicates(
+    dataset_path: &str,
+    hash_kind: HashKind,
+    max_distance: u32,
+    action: DuplicateAction,
+    merge_split: Option<&str>,
+) -> Result<()> {
+    let dataset_path = PathBuf::from(dataset_path);
+    let image_root = dataset_path.join("images");
+    if !image_root.is_dir() {
+        bail!(
+            "Invalid dataset path: {}, should contain images folder",
+            dataset_path.display()
+        );
+    }
+
+    let mut entries = Vec::<PathBuf>::new();
+    for entry in WalkDir::new(&image_root).follow_links(false) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            entries.push(entry.into_path());
+        }
+    }
+    entries.sort();
+    tracing::info!("Hashing {} images", entries.len());
+
+    let hashes = hash_images(entries, hash_kind).await?;
+
+    // Split lookup, keyed by canonical image path
+    let lists = load_list_files(&dataset_path)?;
+    let mut split_of = HashMap::<PathBuf, String>::new();
+    for (split, items) in &lists {
+        for item in items {
+            let image = fs::canonicalize(dataset_path.join(&item.image))
+                .unwrap_or_else(|_| PathBuf::from(&item.image));
+            split_of.insert(image, split.clone());
+        }
+    }
+    tracing::info!(
+        "Loaded {} list files: {:?}",
+        lists.len(),
+        lists.keys().collect::<Vec<_>>()
+    );
+
+    let groups = cluster_hashes(
+        &hashes.iter().map(|(_, h)| *h).collect::<Vec<_>>(),
+        max_distance,
+    );
+
+    let mut clusters = Vec::<DuplicateCluster>::new();
+    for group in groups {
+        let members = group
+            .iter()
+            .map(|index| {
+                let (path, hash) = &hashes[*index];
+                let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+                DuplicateMember {
+                    image: canonical.to_string_lossy().to_string(),
+                    hash: format!("{:016x}", hash),
+                    split: split_of.get(&canonical).cloned(),
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut splits = members
+            .iter()
+            .filter_map(|m| m.split.clone())
+            .collect::<Vec<_>>();
+        splits.sort();
+        splits.dedup();
+        clusters.push(DuplicateCluster {
+            cross_split: splits.len() > 1,
+            members,
+            splits,
+        });
+    }
+
+    let cross_split_count = clusters.iter().filter(|c| c.cross_split).count();
+    tracing::info!(
+        "Found {} duplicate clusters, {} of them cross split boundaries",
+        clusters.len(),
+        cross_split_count
+    );
+    for cluster in clusters.iter().filter(|c| c.cross_split) {
+        tracing::warn!(
+            "Leakage between {:?}: {:?}",
+            cluster.splits,
+            cluster
+                .members
+                .iter()
+                .map(|m| m.image.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    let report = DuplicateReport {
+        hash: format!("{:?}", hash_kind),
+        max_distance,
+        image_count: hashes.len(),
+        cluster_count: clusters.len(),
+        cross_split_count,
+        clusters,
+    };
+    let report_path = dataset_path.join("duplicates.json");
+    fs::write(&report_path, serde_json::to_string_pretty(&report)?)
+        .with_context(|| format!("Writing {}", report_path.display()))?;
+    tracing::info!("Report saved to {}", report_path.display());
+
+    match action {
+        DuplicateAction::Report => {}
+        DuplicateAction::Drop => {
+            let dropped_images = report
+                .clusters
+                .iter()
+                .flat_map(|c| {
+                    let kept = kept_member(c);
+                    c.members
+                        .iter()
+                        .enumerate()
+                        .filter(move |(index, _)| *index != kept)
+                        .map(|(_, m)| m.image.clone())
+                })
+                .collect::<HashSet<_>>();
+            let mut lists = lists;
+            let mut dropped = 0;
+            for items in lists.values_mut() {
+                let before = items.len();
+                items.retain(|item| {
+                    !dropped_images.contains(&canonical_string(&dataset_path, &item.image))
+                });
+                dropped += before - items.len();
+            }
+            write_list_files(&dataset_path, &lists)?;
+            tracing::info!("Dropped {} duplicated items from list files", dropped);
+        }
+        DuplicateAction::Merge => {
+            let mut target_split = HashMap::<String, String>::new();
+            for cluster in report.clusters.iter().filter(|c| c.cross_split) {
+                let target = match merge_split {
+                    Some(split) => split.to_string(),
+                    None => cluster
+                        .members
+                        .iter()
+                        .find_map(|m| m.split.clone())
+                        .ok_or(anyhow!("Cross split cluster without split"))?,
+                };
+                for member in &cluster.members {
+                    target_split.insert(member.image.clone(), target.clone());
+                }
+            }
+
+            let mut merged = BTreeMap::<String, Vec<DatasetItem>>::new();
+            let mut moved = 0;
+            for (split, items) in lists {
+                merged.entry(split.clone()).or_default();
+                for item in items {
+                    let target = target_split
+                        .get(&canonical_string(&dataset_path, &item.image))
+                        .unwrap_or(&split);
+                    if *target != split {
+                        moved += 1;
+                    }
+                    merged.entry(target.clone()).or_default().push(item);
+                }
+            }
+            write_list_files(&dataset_path, &merged)?;
+            tracing::info!("Moved {} leaked items between list files", moved);
+        }
+    }
+
+    Ok(())
+}
+
+async fn hash_images(entries: Vec<PathBuf>, hash_kind: HashKind) -> Result<Vec<(PathBuf, u64)>> {
+    let mut threads = JoinSet::new();
+    let sem = Arc::new(Semaphore::new(
+        (*THREAD_POOL
+            .read()
+            .map_err(|_| anyhow!("THREAD_POOL lock poisoned"))?)
+        .into(),
+    ));
+
+    let header_span = info_span!("find_duplicates_threads");
+    header_span.pb_set_style(&ProgressStyle::with_template(
+        "{spinner} Hashing {msg}\n{wide_bar} {pos}/{len}",
+    )?);
+    header_span.pb_set_length(entries.len() as u64);
+
+    let header_span_enter = header_span.enter();
+
+    for entry in entries {
+        let permit = sem.clone().acquire_owned().await?;
+        let header_span = header_span.clone();
+        threads.spawn_blocking(move || -> Result<Option<(PathBuf, u64)>> {
+            let _permit = permit;
+            let img = imgcodecs::imread(
+                entry.to_str().ok_or(anyhow!("Failed to get entry path"))?,
+                imgcodecs::IMREAD_GRAYSCALE,
+            )?;
+            header_span.pb_inc(1);
+            if img.empty() {
+                tracing::warn!("Skipping {}, not a readable image", entry.display());
+                return Ok(None);
+            }
+            let hash = image_hash(&img, hash_kind)?;
+            Ok(Some((entry, hash)))
+        });
+    }
+
+    let mut hashes = Vec::new();
+    while let Some(result) = threads.join_next().await {
+        if let Some(hash) = result?? {
+            hashes.push(hash);
+        }
+    }
+    drop(header_span_enter);
+
+    hashes.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(hashes)
+}
+
+fn image_hash(img: &Mat, hash_kind: HashKind) -> Result<u64> {
+    let (width, height) = match hash_kind {
+        HashKind::AHash => (8, 8),
+        HashKind::DHash => (9, 8),
+        HashKind::PHash => (32, 32),
+    };
+    let mut thumbnail = Mat::default();
+    imgproc::resize(
+        img,
+        &mut thumbnail,
+        Size::new(width, height),
+        0.,
+        0.,
+        imgproc::INTER_AREA,
+    )?;
+    let pixels: &[u8] = thumbnail.data_typed()?;
+
+    Ok(match hash_kind {
+        HashKind::AHash => average_hash(pixels),
+        HashKind::DHash => difference_hash(pixels),
+        HashKind::PHash => perceptual_hash(pixels),
+    })
+}
+
+/// 8x8 pixels, one bit per pixel brighter than the mean
+fn average_hash(pixels: &[u8]) -> u64 {
+    let mean = pixels.iter().map(|p| u32::from(*p)).sum::<u32>() as f64 / pixels.len() as f64;
+    pixels.iter().enumerate().fold(0u64, |hash, (i, p)| {
+        if f64::from(*p) > mean {
+            hash | (1 << i)
+        } else {
+            hash
+        }
+    })
+}
+
+/// 9x8 pixels, one bit per pixel brighter than its right neighbour
+fn difference_hash(pixels: &[u8]) -> u64 {
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            if pixels[y * 9 + x] > pixels[y * 9 + x + 1] {
+                hash |= 1 << (y * 8 + x);
+            }
+        }
+    }
+    hash
+}
+
+/// 32x32 pixels, DCT-II, one bit per low frequency coefficient above the median
+fn perceptual_hash(pixels: &[u8]) -> u64 {
+    const N: usize = 32;
+    let cos_table = (0..8)
+        .map(|u| {
+            (0..N)
+                .map(|x| {
+                    (std::f64::consts::PI * (2 * x + 1) as f64 * u as f64 / (2 * N) as f64).cos()
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    // Only the top-left 8x8 block is needed, rows first then columns
+    let mut rows = vec![[0f64; 8]; N];
+    for (y, row) in rows.iter_mut().enumerate() {
+        for (u, value) in row.iter_mut().enumerate() {
+            *value = (0..N)
+                .map(|x| f64::from(pixels[y * N + x]) * cos_table[u][x])
+                .sum();
+        }
+    }
+    let mut coefficients = [0f64; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            coefficients[v * 8 + u] = (0..N).map(|y| rows[y][u] * cos_table[v][y]).sum();
+        }
+    }
+
+    // Median without the DC term, which only carries the mean brightness
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let median = sorted[sorted.len() / 2];
+
+    coefficients.iter().enumerate().fold(
+        0u64,
+        |hash, (i, c)| if *c > median { hash | (1 << i) } else { hash },
+    )
+}
+
+/// Group hashes within `max_distance` of each other (transitively), only groups
+/// with more than one member are returned
+fn cluster_hashes(hashes: &[u64], max_distance: u32) -> Vec<Vec<usize>> {
+    let mut parent = (0..hashes.len()).collect::<Vec<_>>();
+    fn find(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+
+    let mut tree = BkTree::default();
+    for (index, hash) in hashes.iter().enumerate() {
+        for other in tree.query(*hash, max_distance) {
+            let (a, b) = (find(&mut parent, index), find(&mut parent, other));
+            if a != b {
+                parent[a.max(b)] = a.min(b);
+            }
+        }
+        tree.insert(*hash, index);
+    }
+
+    let mut groups = BTreeMap::<usize, Vec<usize>>::new();
+    for index in 0..hashes.len() {
+        let root = find(&mut parent, index);
+        groups.entry(root).or_default().push(index);
+    }
+    groups.into_values().filter(|g| g.len() > 1).collect()
+}
+
+/// BK-tree over Hamming distance, avoids comparing every pair of hashes
+#[derive(Default)]
+struct BkTree {
+    nodes: Vec<(u64, usize, HashMap<u32, usize>)>,
+}
+
+impl BkTree {
+    fn insert(&mut self, hash: u64, index: usize) {
+        if self.nodes.is_empty() {
+            self.nodes.push((hash, index, HashMap::new()));
+            return;
+        }
+        let mut current = 0;
+        loop {
+            let distance = (self.nodes[current].0 ^ hash).count_ones();
+            match self.nodes[current].2.get(&distance) {
+                Some(child) => current = *child,
+                None => {
+                    let new_node = self.nodes.len();
+                    self.nodes[current].2.insert(distance, new_node);
+                    self.nodes.push((hash, index, HashMap::new()));
+                    return;
+                }
+            }
+        }
+    }
+
+    fn query(&self, hash: u64, max_distance: u32) -> Vec<usize> {
+        let mut result = Vec::new();
+        if self.nodes.is_empty() {
+            return result;
+        }
+        let mut stack = vec![0];
+        while let Some(current) = stack.pop() {
+            let (node_hash, index, children) = &self.nodes[current];
+            let distance = (node_hash ^ hash).count_ones();
+            if distance <= max_distance {
+                result.push(*index);
+            }
+            for (child_distance, child) in children {
+                if child_distance.abs_diff(distance) <= max_distance {
+                    stack.push(*child);
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Index of the member kept by [`DuplicateAction::Drop`]. Members are sorted by path, the
+/// first one listed in a split wins so the sample stays in the dataset, the first one overall
+/// when none is listed
+fn kept_member(cluster: &DuplicateCluster) -> usize {
+    cluster
+        .members
+        .iter()
+        .position(|m| m.split.is_some())
+        .unwrap_or(0)
+}
+
+/// Write every list to `<dataset_path>/<split>_deduped.json`, the original lists are kept
+fn write_list_files(dataset_path: &Path, lists: &BTreeMap<String, Vec<DatasetItem>>) -> Result<()> {
+    for (split, items) in lists {
+        let path = dataset_path.join(format!("{split}{DEDUPED_SUFFIX}.json"));
+        fs::write(&path, serde_json::to_string(items)?)
+            .with_context(|| format!("Writing {}", path.display()))?;
+        tracing::info!("Saved {} items to {}", items.len(), path.display());
+    }
+    Ok(())
+}
+
+/// Canonical form of a list entry, relative entries are relative to the list folder
+fn canonical_string(dataset_path: &Path, path: &str) -> String {
+    fs::canonicalize(dataset_path.join(path))
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_else(|_| path.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{kept_member, DuplicateCluster, DuplicateMember};
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::first_listed(&[None, Some("train")], 1)]
+    #[case::first_of_several_splits(&[Some("val"), Some("train")], 0)]
+    #[case::none_listed(&[None, None], 0)]
+    #[case::listed_last(&[None, None, Some("test")], 2)]
+    fn kept_member_of_cluster(#[case] splits: &[Option<&str>], #[case] expected: usize) {
+        let cluster = DuplicateCluster {
+            members: splits
+                .iter()
+                .enumerate()
+                .map(|(index, split)| DuplicateMember {
+                    image: format!("/data/images/{index}.png"),
+                    hash: "0000000000000000".to_string(),
+                    split: split.map(str::to_string),
+                })
+                .collect(),
+            splits: Vec::new(),
+            cross_split: false,
+        };
+        assert_eq!(kept_member(&cluster), expected);
+    }
+}
@@ -0,0 +1,373 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use indicatif::ProgressStyle;
+use opencv::{
+    core::{MatTraitConst, MatTraitConstManual},
+    imgcodecs,
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::info_span;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+use super::merge::LabelKind;
+use crate::common::walk::{WalkOptions, IMAGE_EXTENSIONS};
+use crate::THREAD_POOL;
+
+/// Column of the pixels not matching any class of the class list
+pub const OTHER_CLASS: &str = "other";
+
+/// Class pixel counts of one label image
+#[derive(Debug, Clone)]
+pub struct ImageHistogram {
+    /// Canonical path of the label, as listed by `generate-dataset-json`
+    pub label: String,
+    pub width: i32,
+    pub height: i32,
+    /// Class -> pixels, class is the id or "R,G,B"
+    pub counts: BTreeMap<String, u64>,
+}
+
+impl ImageHistogram {
+    pub fn pixels(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+
+    pub fn fraction(&self, class: &str) -> f64 {
+        self.counts.get(class).copied().unwrap_or(0) as f64 / self.pixels().max(1) as f64
+    }
+}
+
+/// Write a CSV with one row per label image and, for every class, its pixel count and
+/// fraction of the image, e.g. `label,width,height,pixels,0_pixels,0_fraction,...`.
+/// RGB classes are written as `R_G_B` in the column names.
+///
+/// With `class_list` (V0;V1 format) the columns follow that order and every other value
+/// is counted in an `other` column, otherwise every value found is a column.
+/// Saved to `save_path`, defaults to `<dataset_path>/class_histogram.csv`.
+pub async fn class_histogram(
+    dataset_path: &String,
+    label_kind: LabelKind,
+    class_list: Option<&str>,
+    save_path: Option<&str>,
+    walk: &WalkOptions,
+) -> Result<()> {
+    let dataset_path = PathBuf::from(dataset_path);
+    let class_list = match class_list {
+        Some(class_list) => Some(parse_class_list(class_list, label_kind)?),
+        None => None,
+    };
+    let entries = walk.collect_files(&dataset_path, IMAGE_EXTENSIONS, &[])?;
+
+    let sem = Arc::new(Semaphore::new(
+        (*THREAD_POOL
+            .read()
+            .map_err(|_| anyhow!("THREAD_POOL lock poisoned"))?)
+        .into(),
+    ));
+    let mut threads = JoinSet::new();
+
+    let header_span = info_span!("class_histogram_threads");
+    header_span.pb_set_style(&ProgressStyle::with_template(
+        "{spinner} Processing {msg}\n{wide_bar} {pos}/{len}",
+    )?);
+    header_span.pb_set_length(entries.len() as u64);
+
+    let header_span_enter = header_span.enter();
+
+    for entry in entries {
+        let permit = sem.clone().acquire_owned().await?;
+        let header_span = header_span.clone();
+        threads.spawn_blocking(move || -> Result<ImageHistogram> {
+            let _permit = permit;
+            let histogram = label_histogram(&entry, label_kind)
+                .with_context(|| format!("Processing {}", entry.display()))?;
+            header_span.pb_set_message(&entry.to_string_lossy());
+            header_span.pb_inc(1);
+            Ok(histogram)
+        });
+    }
+
+    let mut histograms = Vec::new();
+    while let Some(result) = threads.join_next().await {
+        histograms.push(result??);
+    }
+    drop(header_span_enter);
+    drop(header_span);
+    histograms.sort_by(|a, b| a.label.cmp(&b.label));
+
+    let classes = match class_list {
+        Some(class_list) => {
+            let known = class_list.iter().cloned().collect::<BTreeSet<_>>();
+            let mut other_pixels = 0;
+            for histogram in &mut histograms {
+                let other = histogram
+                    .counts
+                    .iter()
+                    .filter(|(class, _)| !known.contains(*class))
+                    .map(|(_, count)| *count)
+                    .sum::<u64>();
+                histogram.counts.retain(|class, _| known.contains(class));
+                if other > 0 {
+                    histogram.counts.insert(OTHER_CLASS.to_string(), other);
+                    other_pixels += other;
+                }
+            }
+            if other_pixels > 0 {
+                tracing::warn!(
+                    "{} pixels are not in the class list, counted as {}",
+                    other_pixels,
+                    OTHER_CLASS
+                );
+            }
+            let mut classes = class_list;
+            classes.push(OTHER_CLASS.to_string());
+            classes
+        }
+        None => {
+            let mut classes = histograms
+                .iter()
+                .flat_map(|h| h.counts.keys().cloned())
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect::<Vec<_>>();
+            sort_classes(&mut classes);
+            classes
+        }
+    };
+
+    let save_path = match save_path {
+        Some(path) => PathBuf::from(path),
+        None if dataset_path.is_file() => dataset_path
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join("class_histogram.csv"),
+        None => dataset_path.join("class_histogram.csv"),
+    };
+    write_histogram_csv(&save_path, &classes, &histograms)?;
+    tracing::info!(
+        "Histogram of {} labels and {} classes saved to {}",
+        histograms.len(),
+        classes.len(),
+        save_path.display()
+    );
+    Ok(())
+}
+
+/// Class values in V0;V1 format, ids or R,G,B
+fn parse_class_list(class_list: &str, label_kind: LabelKind) -> Result<Vec<String>> {
+    let mut classes = Vec::new();
+    for value in class_list.split(';') {
+        let parts = value
+            .split(',')
+            .map(|s| s.trim().parse::<u8>())
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Parsing class {}", value))?;
+        match (label_kind, parts.len()) {
+            (LabelKind::Class, 1) | (LabelKind::Rgb, 3) => {}
+            _ => bail!(
+                "Class {} does not match the label kind {:?}",
+                value,
+                label_kind
+            ),
+        }
+        classes.push(
+            parts
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+    }
+    Ok(classes)
+}
+
+/// Numeric order for class ids and R,G,B values, `other` last
+fn sort_classes(classes: &mut [String]) {
+    classes.sort_by_key(|class| {
+        (
+            class == OTHER_CLASS,
+            class
+                .split(',')
+                .map(|v| v.parse::<u16>().unwrap_or(u16::MAX))
+                .collect::<Vec<_>>(),
+        )
+    });
+}
+
+fn label_histogram(path: &Path, label_kind: LabelKind) -> Result<ImageHistogram> {
+    let flag = match label_kind {
+        LabelKind::Rgb => imgcodecs::IMREAD_COLOR,
+        LabelKind::Class => imgcodecs::IMREAD_GRAYSCALE,
+    };
+    let label = imgcodecs::imread(path.to_str().ok_or(anyhow!("Invalid path"))?, flag)?;
+    if label.empty() {
+        bail!("Label is not readable");
+    }
+    let label = if label.is_continuous() {
+        label
+    } else {
+        label.try_clone()?
+    };
+
+    let channels = label.channels() as usize;
+    let flat = label.reshape(1, 0)?;
+    let mut counts = BTreeMap::<Vec<u8>, u64>::new();
+    for pixel in flat.data_typed::<u8>()?.chunks_exact(channels) {
+        *counts.entry(pixel.to_vec()).or_insert(0) += 1;
+    }
+
+    Ok(ImageHistogram {
+        label: fs::canonicalize(path)?.to_string_lossy().to_string(),
+        width: label.cols(),
+        height: label.rows(),
+        counts: counts
+            .into_iter()
+            .map(|(value, count)| {
+                let class = match value.as_slice() {
+                    // BGR in OpenCV
+                    [b, g, r] => format!("{},{},{}", r, g, b),
+                    _ => value[0].to_string(),
+                };
+                (class, count)
+            })
+            .collect(),
+    })
+}
+
+fn column_name(class: &str) -> String {
+    class.replace(',', "_")
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn write_histogram_csv(
+    path: &Path,
+    classes: &[String],
+    histograms: &[ImageHistogram],
+) -> Result<()> {
+    let mut header = vec![
+        "label".to_string(),
+        "width".to_string(),
+        "height".to_string(),
+        "pixels".to_string(),
+    ];
+    for class in classes {
+        header.push(format!("{}_pixels", column_name(class)));
+    }
+    for class in classes {
+        header.push(format!("{}_fraction", column_name(class)));
+    }
+
+    let mut lines = vec![header.join(",")];
+    for histogram in histograms {
+        let mut row = vec![
+            csv_field(&histogram.label),
+            histogram.width.to_string(),
+            histogram.height.to_string(),
+            histogram.pixels().to_string(),
+        ];
+        for class in classes {
+            row.push(
+                histogram
+                    .counts
+                    .get(class)
+                    .copied()
+                    .unwrap_or(0)
+                    .to_string(),
+            );
+        }
+        for class in classes {
+            row.push(format!("{:.6}", histogram.fraction(class)));
+        }
+        lines.push(row.join(","));
+    }
+    fs::write(path, lines.join("\n")).with_context(|| format!("Writing {}", path.display()))?;
+    Ok(())
+}
+
+/// Read a CSV written by [`class_histogram`], returns the classes in column order
+/// (as `R,G,B` / id) and the rows
+pub fn read_histogram_csv(path: &Path) -> Result<(Vec<String>, Vec<ImageHistogram>)> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Reading {}", path.display()))?;
+    let mut lines = content.lines();
+    let header = split_csv_line(lines.next().ok_or(anyhow!("Empty histogram CSV"))?);
+    if header.len() < 4 || header[0] != "label" {
+        bail!("{} is not a class histogram CSV", path.display());
+    }
+    let class_columns = header
+        .iter()
+        .enumerate()
+        .filter_map(|(index, column)| {
+            column
+                .strip_suffix("_pixels")
+                .map(|class| (index, class.replace('_', ",")))
+        })
+        .collect::<Vec<_>>();
+
+    let mut histograms = Vec::new();
+    for (line_number, line) in lines.enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = split_csv_line(line);
+        if fields.len() != header.len() {
+            bail!(
+                "Line {} of {} has {} fields, expected {}",
+                line_number + 2,
+                path.display(),
+                fields.len(),
+                header.len()
+            );
+        }
+        let mut counts = BTreeMap::new();
+        for (index, class) in &class_columns {
+            let count = fields[*index]
+                .parse::<u64>()
+                .with_context(|| format!("Parsing line {}", line_number + 2))?;
+            if count > 0 {
+                counts.insert(class.clone(), count);
+            }
+        }
+        histograms.push(ImageHistogram {
+            label: fields[0].clone(),
+            width: fields[1].parse()?,
+            height: fields[2].parse()?,
+            counts,
+        });
+    }
+    Ok((
+        class_columns.into_iter().map(|(_, class)| class).collect(),
+        histograms,
+    ))
+}
+
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
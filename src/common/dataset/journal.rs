@@ -0,0 +1,362 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// One planned `fs::rename`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalMove {
+    pub from: String,
+    pub to: String,
+}
+
+/// File written once every move is done, removed again on rollback
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalArtifact {
+    pub path: String,
+    pub content: String,
+}
+
+/// First line of a journal file, the whole plan of the operation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalPlan {
+    pub version: u32,
+    pub operation: String,
+    pub moves: Vec<JournalMove>,
+    #[serde(default)]
+    pub artifacts: Vec<JournalArtifact>,
+}
+
+/// Following lines of a journal file, appended while the plan is carried out
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum JournalRecord {
+    Done(usize),
+    Completed,
+}
+
+/// Journal of destructive file operations (renames), stored as JSON lines:
+/// - The first line is the [`JournalPlan`] with every planned move
+/// - A `{"done": index}` line is appended right after each move
+/// - A `"completed"` line is appended once all moves and artifacts are written
+///
+/// An interrupted run can then be resumed with [`resume_journal`] or undone with [`rollback_journal`].
+pub struct Journal {
+    path: PathBuf,
+    plan: JournalPlan,
+}
+
+impl Journal {
+    pub fn new(path: PathBuf, operation: &str) -> Self {
+        Self {
+            path,
+            plan: JournalPlan {
+                version: 1,
+                operation: operation.to_string(),
+                moves: Vec::new(),
+                artifacts: Vec::new(),
+            },
+        }
+    }
+
+    pub fn push_move(&mut self, from: &Path, to: &Path) {
+        self.plan.moves.push(JournalMove {
+            from: from.to_string_lossy().to_string(),
+            to: to.to_string_lossy().to_string(),
+        });
+    }
+
+    pub fn push_artifact(&mut self, path: &Path, content: String) {
+        self.plan.artifacts.push(JournalArtifact {
+            path: path.to_string_lossy().to_string(),
+            content,
+        });
+    }
+
+    /// Carry out the plan, or only print it when `dry_run` is set.
+    ///
+    /// Refuses to start when an unfinished journal is already present at the same path,
+    /// or when two moves share a target or a target is already taken (dry run included).
+    pub fn execute(self, dry_run: bool) -> Result<()> {
+        check_targets(&self.plan)?;
+        if dry_run {
+            for m in &self.plan.moves {
+                tracing::info!("[dry-run] Renaming {} -> {}", m.from, m.to);
+            }
+            for a in &self.plan.artifacts {
+                tracing::info!("[dry-run] Writing {}", a.path);
+            }
+            tracing::info!(
+                "[dry-run] {} planned {} moves, nothing changed",
+                self.plan.operation,
+                self.plan.moves.len()
+            );
+            return Ok(());
+        }
+
+        if self.path.exists() {
+            let (plan, done, completed) = read_journal(&self.path)?;
+            if !completed {
+                bail!(
+                    "Unfinished {} journal found at {} ({}/{} moves done), resume or rollback it first",
+                    plan.operation,
+                    self.path.display(),
+                    done.len(),
+                    plan.moves.len()
+                );
+            }
+            tracing::info!(
+                "Replacing completed journal {}, previous run can no longer be rolled back",
+                self.path.display()
+            );
+        }
+
+        let mut file = File::create(&self.path)
+            .with_context(|| format!("Creating journal {}", self.path.display()))?;
+        writeln!(file, "{}", serde_json::to_string(&self.plan)?)?;
+        file.sync_all()?;
+        tracing::info!("Journal written to {}", self.path.display());
+
+        apply_moves(&self.path, &mut file, &self.plan, &BTreeSet::new())
+    }
+}
+
+/// Finish the moves of an interrupted journal
+pub fn resume_journal(journal_path: &str) -> Result<()> {
+    let journal_path = PathBuf::from(journal_path);
+    let (plan, done, completed) = read_journal(&journal_path)?;
+    if completed {
+        tracing::info!("Journal {} already completed", journal_path.display());
+        return Ok(());
+    }
+    tracing::info!(
+        "Resuming {}, {}/{} moves done",
+        plan.operation,
+        done.len(),
+        plan.moves.len()
+    );
+
+    let mut file = OpenOptions::new().append(true).open(&journal_path)?;
+    apply_moves(&journal_path, &mut file, &plan, &done)
+}
+
+/// Undo every move recorded as done (in reverse order) and remove the artifacts,
+/// the journal itself is removed afterwards
+pub fn rollback_journal(journal_path: &str) -> Result<()> {
+    let journal_path = PathBuf::from(journal_path);
+    let (plan, done, _) = read_journal(&journal_path)?;
+    tracing::info!(
+        "Rolling back {}, {}/{} moves done",
+        plan.operation,
+        done.len(),
+        plan.moves.len()
+    );
+
+    for artifact in &plan.artifacts {
+        let path = Path::new(&artifact.path);
+        if path.is_file() {
+            fs::remove_file(path).with_context(|| format!("Removing {}", path.display()))?;
+            tracing::info!("Removed {}", path.display());
+        }
+    }
+
+    for index in done.iter().rev() {
+        let m = plan
+            .moves
+            .get(*index)
+            .ok_or(anyhow!("Journal records unknown move {}", index))?;
+        let (from, to) = (Path::new(&m.to), Path::new(&m.from));
+        if to.exists() {
+            bail!(
+                "Rollback target already exists (refusing to overwrite):\n  {}",
+                to.display()
+            );
+        }
+        fs::rename(from, to)
+            .with_context(|| format!("Renaming {} -> {}", from.display(), to.display()))?;
+        tracing::info!("Renaming {} -> {}", from.display(), to.display());
+    }
+
+    fs::remove_file(&journal_path)
+        .with_context(|| format!("Removing journal {}", journal_path.display()))?;
+    tracing::info!("Rollback done, journal {} removed", journal_path.display());
+    Ok(())
+}
+
+/// Every target must be unique and free when its move runs, a path moved away by an
+/// earlier move of the plan counts as free
+fn check_targets(plan: &JournalPlan) -> Result<()> {
+    let mut targets = HashSet::new();
+    let mut moved_away = HashSet::new();
+    let mut collisions = Vec::new();
+    for m in &plan.moves {
+        if !targets.insert(m.to.as_str()) {
+            collisions.push(format!("{} (planned twice)", m.to));
+        } else if !moved_away.contains(m.to.as_str()) && Path::new(&m.to).exists() {
+            collisions.push(format!("{} (already exists)", m.to));
+        }
+        moved_away.insert(m.from.as_str());
+    }
+    if !collisions.is_empty() {
+        let mut msg = String::new();
+        for collision in collisions.iter().take(50) {
+            msg.push_str(&format!("  - {}\n", collision));
+        }
+        if collisions.len() > 50 {
+            msg.push_str(&format!("  ... and {} more\n", collisions.len() - 50));
+        }
+        bail!(
+            "{} planned {} targets collide, nothing changed:\n{}",
+            plan.operation,
+            collisions.len(),
+            msg
+        );
+    }
+    Ok(())
+}
+
+fn apply_moves(
+    journal_path: &Path,
+    file: &mut File,
+    plan: &JournalPlan,
+    done: &BTreeSet<usize>,
+) -> Result<()> {
+    for (index, m) in plan.moves.iter().enumerate() {
+        if done.contains(&index) {
+            continue;
+        }
+        let (from, to) = (Path::new(&m.from), Path::new(&m.to));
+        if to.exists() {
+            bail!(
+                "Target path already exists (refusing to overwrite):\n  {}",
+                to.display()
+            );
+        }
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(from, to)
+            .with_context(|| format!("Renaming {} -> {}", from.display(), to.display()))?;
+        tracing::debug!("Renaming {} -> {}", from.display(), to.display());
+        writeln!(
+            file,
+            "{}",
+            serde_json::to_string(&JournalRecord::Done(index))?
+        )?;
+    }
+
+    for artifact in &plan.artifacts {
+        fs::write(&artifact.path, &artifact.content)
+            .with_context(|| format!("Writing {}", artifact.path))?;
+        tracing::info!("Saved to {}", artifact.path);
+    }
+
+    writeln!(
+        file,
+        "{}",
+        serde_json::to_string(&JournalRecord::Completed)?
+    )?;
+    file.sync_all()?;
+    tracing::info!(
+        "{} done, {} moves, journal kept at {}",
+        plan.operation,
+        plan.moves.len(),
+        journal_path.display()
+    );
+    Ok(())
+}
+
+/// Returns the plan, the indices of the moves done and whether the journal completed
+fn read_journal(journal_path: &Path) -> Result<(JournalPlan, BTreeSet<usize>, bool)> {
+    let file = File::open(journal_path)
+        .with_context(|| format!("Reading journal {}", journal_path.display()))?;
+    let mut lines = BufReader::new(file).lines();
+
+    let plan: JournalPlan = serde_json::from_str(
+        &lines
+            .next()
+            .ok_or(anyhow!("Empty journal {}", journal_path.display()))??,
+    )
+    .with_context(|| format!("Parsing journal plan {}", journal_path.display()))?;
+    if plan.version != 1 {
+        bail!("Unsupported journal version {}", plan.version);
+    }
+
+    let mut done = BTreeSet::new();
+    let mut completed = false;
+    for line in lines {
+        let line = line?;
+        // A crash while appending can leave a truncated last line, the move it
+        // describes is then checked on disk instead
+        match serde_json::from_str::<JournalRecord>(&line) {
+            Ok(JournalRecord::Done(index)) => {
+                done.insert(index);
+            }
+            Ok(JournalRecord::Completed) => completed = true,
+            Err(_) => tracing::warn!("Ignoring malformed journal line: {}", line),
+        }
+    }
+
+    // The crash may also happen between the rename and the journal append,
+    // moves are carried out in order so done moves always form a prefix
+    for (index, m) in plan.moves.iter().enumerate() {
+        if !done.contains(&index) && !Path::new(&m.from).exists() && Path::new(&m.to).exists() {
+            tracing::warn!("Move {} -> {} found done on disk", m.from, m.to);
+            done.insert(index);
+        }
+    }
+
+    Ok((plan, done, completed))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::{check_targets, Journal};
+
+    #[test]
+    fn same_target_twice() {
+        let mut journal = Journal::new("unused.journal.jsonl".into(), "split_dataset");
+        journal.push_move(
+            Path::new("/data/images/a/x.png"),
+            Path::new("/data/train/x.png"),
+        );
+        journal.push_move(
+            Path::new("/data/images/b/x.png"),
+            Path::new("/data/train/x.png"),
+        );
+        assert!(check_targets(&journal.plan).is_err());
+    }
+
+    #[test]
+    fn existing_target_unless_moved_away() {
+        let root = std::env::temp_dir().join(format!("journal_targets_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let (a, b, tmp) = (
+            root.join("a.png"),
+            root.join("b.png"),
+            root.join(".a.png.tmp"),
+        );
+        fs::write(&a, "").unwrap();
+        fs::write(&b, "").unwrap();
+
+        let mut journal = Journal::new(root.join("unused.journal.jsonl"), "mask_dataset");
+        journal.push_move(&b, &a);
+        assert!(check_targets(&journal.plan).is_err());
+
+        // Swapping through a temporary name frees every target before it is used
+        let mut journal = Journal::new(root.join("unused.journal.jsonl"), "mask_dataset");
+        journal.push_move(&a, &tmp);
+        journal.push_move(&b, &a);
+        journal.push_move(&tmp, &b);
+        assert!(check_targets(&journal.plan).is_ok());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,424 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use indicatif::ProgressStyle;
+use opencv::{
+    core::{self, MatTraitConst, MatTraitConstManual, Vec3b},
+    imgcodecs,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::info_span;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+use crate::common::walk::{WalkOptions, IMAGE_EXTENSIONS};
+use crate::THREAD_POOL;
+
+/// One file of the dataset root
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Relative to the dataset root, `/` separated
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channels: Option<i32>,
+    /// OpenCV depth name, e.g. "8U", "16U", "32F"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depth: Option<String>,
+    /// Pixel count per class, only for 8 bit files under a `labels` folder.
+    /// Keys are the class id for single channel labels and "R,G,B" for color labels
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class_histogram: Option<BTreeMap<String, u64>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub root: String,
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// Write a manifest of every file under `dataset_path` (recursively):
+/// relative path, size, SHA-256, image dimensions, channels / depth and,
+/// for label files, the class histogram. `include` / `exclude` globs filter the files
+/// like [`WalkOptions`].
+///
+/// Saved to `save_path`, defaults to `<dataset_path>/manifest.json`.
+pub async fn generate_manifest(
+    dataset_path: &str,
+    save_path: Option<&str>,
+    include: &[String],
+    exclude: &[String],
+) -> Result<()> {
+    let dataset_path = PathBuf::from(dataset_path);
+    if !dataset_path.is_dir() {
+        bail!("Dataset path {} is not a directory", dataset_path.display());
+    }
+    let save_path = save_path
+        .map(PathBuf::from)
+        .unwrap_or_else(|| dataset_path.join("manifest.json"));
+
+    // The manifest always describes the whole tree
+    let walk = WalkOptions {
+        recursive: true,
+        include: include.to_vec(),
+        exclude: exclude.to_vec(),
+    };
+    let mut entries = walk.collect_files(&dataset_path, &[], &[])?;
+    // Never describe the manifest itself
+    if let Ok(save_path) = fs::canonicalize(&save_path) {
+        entries.retain(|x| fs::canonicalize(x).ok().as_ref() != Some(&save_path));
+    }
+    tracing::info!("Describing {} files", entries.len());
+
+    let mut threads = JoinSet::new();
+    let sem = Arc::new(Semaphore::new(
+        (*THREAD_POOL
+            .read()
+            .map_err(|_| anyhow!("THREAD_POOL lock poisoned"))?)
+        .into(),
+    ));
+
+    let header_span = info_span!("generate_manifest_threads");
+    header_span.pb_set_style(&ProgressStyle::with_template(
+        "{spinner} Processing {msg}\n{wide_bar} {pos}/{len}",
+    )?);
+    header_span.pb_set_length(entries.len() as u64);
+
+    let header_span_enter = header_span.enter();
+
+    for entry in entries {
+        let permit = sem.clone().acquire_owned().await?;
+        let header_span = header_span.clone();
+        let dataset_path = dataset_path.clone();
+        threads.spawn_blocking(move || -> Result<ManifestEntry> {
+            let _permit = permit;
+            let entry = describe_file(&dataset_path, &entry)?;
+            header_span.pb_set_message(&entry.path);
+            header_span.pb_inc(1);
+            Ok(entry)
+        });
+    }
+
+    let mut manifest_entries = Vec::new();
+    while let Some(result) = threads.join_next().await {
+        manifest_entries.push(result??);
+    }
+    drop(header_span_enter);
+    manifest_entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let manifest = Manifest {
+        version: 1,
+        root: fs::canonicalize(&dataset_path)?
+            .to_string_lossy()
+            .to_string(),
+        entries: manifest_entries,
+    };
+    fs::write(&save_path, serde_json::to_string_pretty(&manifest)?)
+        .with_context(|| format!("Writing {}", save_path.display()))?;
+    tracing::info!(
+        "Manifest of {} files saved to {}",
+        manifest.entries.len(),
+        save_path.display()
+    );
+    Ok(())
+}
+
+fn describe_file(root: &Path, path: &Path) -> Result<ManifestEntry> {
+    let rel = path.strip_prefix(root)?;
+    let rel = rel
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    let mut hasher = Sha256::new();
+    let mut file = File::open(path).with_context(|| format!("Reading {}", path.display()))?;
+    let size = io::copy(&mut file, &mut hasher)?;
+    let sha256 = format!("{:x}", hasher.finalize());
+
+    let mut entry = ManifestEntry {
+        path: rel,
+        size,
+        sha256,
+        width: None,
+        height: None,
+        channels: None,
+        depth: None,
+        class_histogram: None,
+    };
+
+    let is_image = path
+        .extension()
+        .and_then(|s| s.to_str())
+        .is_some_and(|ext| IMAGE_EXTENSIONS.iter().any(|e| e.eq_ignore_ascii_case(ext)));
+    if !is_image {
+        return Ok(entry);
+    }
+
+    let img = imgcodecs::imread(
+        path.to_str().ok_or(anyhow!("Failed to get entry path"))?,
+        imgcodecs::IMREAD_UNCHANGED,
+    )?;
+    if img.empty() {
+        tracing::warn!("{} is not a readable image", path.display());
+        return Ok(entry);
+    }
+    entry.width = Some(img.cols());
+    entry.height = Some(img.rows());
+    entry.channels = Some(img.channels());
+    entry.depth = Some(depth_name(img.depth()).to_string());
+
+    let is_label = path
+        .strip_prefix(root)?
+        .components()
+        .any(|c| c.as_os_str() == "labels");
+    if is_label && img.depth() == core::CV_8U {
+        let img = if img.is_continuous() {
+            img
+        } else {
+            img.try_clone()?
+        };
+        let mut histogram = BTreeMap::<String, u64>::new();
+        match img.channels() {
+            1 => {
+                let mut counts = [0u64; 256];
+                for pixel in img.data_typed::<u8>()? {
+                    counts[*pixel as usize] += 1;
+                }
+                for (class_id, count) in counts.iter().enumerate().filter(|(_, c)| **c > 0) {
+                    histogram.insert(class_id.to_string(), *count);
+                }
+            }
+            3 => {
+                let mut counts = BTreeMap::<[u8; 3], u64>::new();
+                for pixel in img.data_typed::<Vec3b>()? {
+                    // BGR in OpenCV
+                    *counts.entry([pixel[2], pixel[1], pixel[0]]).or_insert(0) += 1;
+                }
+                for (rgb, count) in counts {
+                    histogram.insert(format!("{},{},{}", rgb[0], rgb[1], rgb[2]), count);
+                }
+            }
+            _ => {}
+        }
+        if !histogram.is_empty() {
+            entry.class_histogram = Some(histogram);
+        }
+    }
+
+    Ok(entry)
+}
+
+pub(super) fn depth_name(depth: i32) -> &'static str {
+    match depth {
+        core::CV_8U => "8U",
+        core::CV_8S => "8S",
+        core::CV_16U => "16U",
+        core::CV_16S => "16S",
+        core::CV_32S => "32S",
+        core::CV_32F => "32F",
+        core::CV_64F => "64F",
+        core::CV_16F => "16F",
+        _ => "unknown",
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModifiedPair {
+    pub key: String,
+    /// Relative paths of the modified files of the pair
+    pub files: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClassChange {
+    pub class: String,
+    pub old_pixels: u64,
+    pub new_pixels: u64,
+    pub old_ratio: f64,
+    pub new_ratio: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestDiff {
+    pub old_root: String,
+    pub new_root: String,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<ModifiedPair>,
+    pub class_changes: Vec<ClassChange>,
+}
+
+/// Compare two manifests written by [`generate_manifest`].
+///
+/// Files are grouped into image/label pairs by their path without the
+/// `images` / `labels` folder and the extension, so `images/train/0001.tif` and
+/// `labels/train/0001.png` form the pair `train/0001`. Files outside those folders
+/// are their own pair. Reports added, removed and modified pairs and the change of the
+/// class distribution, saved to `save_path` (defaults to `manifest_diff.json` next to
+/// the new manifest).
+pub fn diff_manifests(old_path: &str, new_path: &str, save_path: Option<&str>) -> Result<()> {
+    let read = |path: &str| -> Result<Manifest> {
+        let manifest: Manifest = serde_json::from_str(
+            &fs::read_to_string(path).with_context(|| format!("Reading {}", path))?,
+        )
+        .with_context(|| format!("Parsing manifest {}", path))?;
+        if manifest.version != 1 {
+            bail!("Unsupported manifest version {}", manifest.version);
+        }
+        Ok(manifest)
+    };
+    let old = read(old_path)?;
+    let new = read(new_path)?;
+
+    let old_pairs = group_pairs(&old);
+    let new_pairs = group_pairs(&new);
+
+    let added = new_pairs
+        .keys()
+        .filter(|k| !old_pairs.contains_key(*k))
+        .cloned()
+        .collect::<Vec<_>>();
+    let removed = old_pairs
+        .keys()
+        .filter(|k| !new_pairs.contains_key(*k))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let mut modified = Vec::new();
+    for (key, new_files) in &new_pairs {
+        let Some(old_files) = old_pairs.get(key) else {
+            continue;
+        };
+        // Changed content, or a file of the pair added / removed / renamed
+        let paths = old_files
+            .keys()
+            .chain(new_files.keys())
+            .collect::<BTreeSet<_>>();
+        let files = paths
+            .into_iter()
+            .filter(|p| old_files.get(*p) != new_files.get(*p))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !files.is_empty() {
+            modified.push(ModifiedPair {
+                key: key.clone(),
+                files,
+            });
+        }
+    }
+
+    let old_histogram = total_histogram(&old);
+    let new_histogram = total_histogram(&new);
+    let old_total = old_histogram.values().sum::<u64>().max(1) as f64;
+    let new_total = new_histogram.values().sum::<u64>().max(1) as f64;
+    let mut class_changes = Vec::new();
+    for class in old_histogram
+        .keys()
+        .chain(new_histogram.keys())
+        .collect::<BTreeSet<_>>()
+    {
+        let old_pixels = old_histogram.get(class).copied().unwrap_or(0);
+        let new_pixels = new_histogram.get(class).copied().unwrap_or(0);
+        class_changes.push(ClassChange {
+            class: class.clone(),
+            old_pixels,
+            new_pixels,
+            old_ratio: old_pixels as f64 / old_total,
+            new_ratio: new_pixels as f64 / new_total,
+        });
+    }
+
+    tracing::info!(
+        "{} pairs added, {} removed, {} modified",
+        added.len(),
+        removed.len(),
+        modified.len()
+    );
+    for change in class_changes
+        .iter()
+        .filter(|c| c.old_pixels != c.new_pixels)
+    {
+        tracing::info!(
+            "Class {}: {} -> {} pixels ({:.4} -> {:.4})",
+            change.class,
+            change.old_pixels,
+            change.new_pixels,
+            change.old_ratio,
+            change.new_ratio
+        );
+    }
+
+    let diff = ManifestDiff {
+        old_root: old.root,
+        new_root: new.root,
+        added,
+        removed,
+        modified,
+        class_changes,
+    };
+    let save_path = match save_path {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(new_path)
+            .parent()
+            .ok_or(anyhow!("Failed to get parent of {}", new_path))?
+            .join("manifest_diff.json"),
+    };
+    fs::write(&save_path, serde_json::to_string_pretty(&diff)?)
+        .with_context(|| format!("Writing {}", save_path.display()))?;
+    tracing::info!("Diff saved to {}", save_path.display());
+    Ok(())
+}
+
+/// pair key -> relative path -> sha256
+fn group_pairs(manifest: &Manifest) -> BTreeMap<String, BTreeMap<String, String>> {
+    let mut pairs = BTreeMap::<String, BTreeMap<String, String>>::new();
+    for entry in &manifest.entries {
+        pairs
+            .entry(pair_key(&entry.path))
+            .or_default()
+            .insert(entry.path.clone(), entry.sha256.clone());
+    }
+    pairs
+}
+
+fn pair_key(path: &str) -> String {
+    let parts = path.split('/').collect::<Vec<_>>();
+    match parts.iter().position(|p| *p == "images" || *p == "labels") {
+        Some(index) => {
+            let mut key = parts[..index].to_vec();
+            key.extend_from_slice(&parts[index + 1..]);
+            let key = key.join("/");
+            match key.rsplit_once('.') {
+                Some((stem, _)) if !stem.ends_with('/') && !stem.is_empty() => stem.to_string(),
+                _ => key,
+            }
+        }
+        None => path.to_string(),
+    }
+}
+
+fn total_histogram(manifest: &Manifest) -> BTreeMap<String, u64> {
+    let mut total = BTreeMap::<String, u64>::new();
+    for histogram in manifest
+        .entries
+        .iter()
+        .filter_map(|e| e.class_histogram.as_ref())
+    {
+        for (class, count) in histogram {
+            *total.entry(class.clone()).or_insert(0) += count;
+        }
+    }
+    total
+}
@@ -1,11 +1,16 @@
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, bail, Context, Result};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use walkdir::WalkDir;
 
+use super::journal::Journal;
+use super::pairing::{is_bookkeeping_file, pair_images_labels};
+use super::paths::UNMASKED_SUFFIX;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RemapEntry {
     pub id: String,
@@ -26,78 +31,77 @@ pub struct RemapJson {
 }
 
 /// Mask (rename) an image/label dataset pair:
-/// - Recursively reads all files under `image_dir` and `label_dir`
+/// - Recursively reads all files with the given extensions under `image_dir` and `label_dir`
 /// - Ensures every image has a corresponding label *by relative folder + stem*
 ///   (stem = filename without extension; label extension can differ)
 /// - Renames both to the same numeric masked ID like "0001", "0002", ... (zero-padded)
 /// - Preserves extensions and subfolder structure
+/// - Fails, listing them, when any other file is left under either folder, since a file keeping
+///   its original name defeats the masking
 /// - Writes a `remap.json` at `<image_dir>/remap.json` containing old->new mapping
+/// - Records every rename in `<image_dir>/mask_dataset.journal.jsonl` before acting
 ///
 /// Uses synchronous filesystem operations (std::fs).
-pub async fn mask_dataset(image_dir: &String, label_dir: &String) -> Result<()> {
+pub async fn mask_dataset(
+    image_dir: &String,
+    label_dir: &String,
+    image_extensions: &[String],
+    label_extensions: &[String],
+    dry_run: bool,
+) -> Result<()> {
     // Function signature kept async per your original; body is sync FS.
     let image_root = PathBuf::from(&image_dir);
     let label_root = PathBuf::from(&label_dir);
 
-    if !image_root.is_dir() {
-        bail!("image_dir is not a directory: {}", image_root.display());
-    }
-    if !label_root.is_dir() {
-        bail!("label_dir is not a directory: {}", label_root.display());
+    // Key: "<relative parent>/<stem>", ensures 1:1 correspondence
+    let pairs = pair_images_labels(
+        &image_root,
+        &label_root,
+        image_extensions,
+        label_extensions,
+        true,
+    )?;
+
+    let paired = pairs
+        .iter()
+        .flat_map(|pair| [pair.image.clone(), pair.label.clone()])
+        .collect::<HashSet<_>>();
+    let mut unmasked = Vec::new();
+    for root in [&image_root, &label_root] {
+        for entry in WalkDir::new(root).follow_links(false) {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type().is_file() && !is_bookkeeping_file(path) && !paired.contains(path) {
+                unmasked.push(path.to_path_buf());
+            }
+        }
     }
-
-    // Key: "<relative parent>/<stem>"
-    let image_index = index_by_relparent_and_stem(&image_root)
-        .with_context(|| format!("Indexing image_dir {}", image_root.display()))?;
-    let label_index = index_by_relparent_and_stem(&label_root)
-        .with_context(|| format!("Indexing label_dir {}", label_root.display()))?;
-
-    // Ensure 1:1 correspondence
-    let image_keys: BTreeSet<_> = image_index.keys().cloned().collect();
-    let label_keys: BTreeSet<_> = label_index.keys().cloned().collect();
-
-    if image_keys != label_keys {
-        let only_in_images: Vec<_> = image_keys
-            .difference(&label_keys)
-            .take(50)
-            .cloned()
-            .collect();
-        let only_in_labels: Vec<_> = label_keys
-            .difference(&image_keys)
-            .take(50)
-            .cloned()
-            .collect();
-
+    if !unmasked.is_empty() {
+        unmasked.sort();
+        unmasked.dedup();
         let mut msg = String::new();
-        if !only_in_images.is_empty() {
-            msg.push_str("Missing in label_dir (present in image_dir):\n");
-            for k in &only_in_images {
-                msg.push_str(&format!("  - {k}\n"));
-            }
+        for path in unmasked.iter().take(50) {
+            msg.push_str(&format!("  - {}\n", path.display()));
         }
-        if !only_in_labels.is_empty() {
-            msg.push_str("Missing in image_dir (present in label_dir):\n");
-            for k in &only_in_labels {
-                msg.push_str(&format!("  - {k}\n"));
-            }
+        if unmasked.len() > 50 {
+            msg.push_str(&format!("  ... and {} more\n", unmasked.len() - 50));
         }
         bail!(
-            "image_dir and label_dir are not correspondent (by relpath parent + stem).\n{}",
+            "{} files would keep their original names, adjust --image-extensions / --label-extensions or move them out:\n{}",
+            unmasked.len(),
             msg
         );
     }
 
     // Choose padding width based on total count, minimum 4 (0001 style).
-    let total = image_index.len();
+    let total = pairs.len();
     let width = std::cmp::max(3, digits_needed(total));
 
-    // Build mapping entries (deterministic order via BTreeMap)
+    // Build mapping entries (deterministic order, pairs are sorted by key)
     let mut entries: Vec<RemapEntry> = Vec::with_capacity(total);
 
-    for (i, (key, image_path)) in image_index.iter().enumerate() {
-        let label_path = label_index
-            .get(key)
-            .ok_or_else(|| anyhow!("Internal error: key missing from label_index: {key}"))?;
+    for (i, pair) in pairs.iter().enumerate() {
+        let (image_path, label_path) = (&pair.image, &pair.label);
 
         let id = format!("{:0width$}", i + 1, width = width);
 
@@ -174,13 +178,14 @@ pub async fn mask_dataset(image_dir: &String, label_dir: &String) -> Result<()>
         final_moves.push((label_tmp, label_new));
     }
 
-    for (from, to) in &temp_moves {
-        fs::rename(from, to)
-            .with_context(|| format!("Renaming {} -> {}", from.display(), to.display()))?;
-    }
-    for (from, to) in &final_moves {
-        fs::rename(from, to)
-            .with_context(|| format!("Renaming {} -> {}", from.display(), to.display()))?;
+    // Moves go through the journal so an interrupted run can be resumed or rolled back,
+    // remap.json is only written once every file is renamed
+    let mut journal = Journal::new(
+        image_root.join("mask_dataset.journal.jsonl"),
+        "mask_dataset",
+    );
+    for (from, to) in temp_moves.iter().chain(final_moves.iter()) {
+        journal.push_move(from, to);
     }
 
     // Write remap.json at image_dir root
@@ -190,56 +195,203 @@ pub async fn mask_dataset(image_dir: &String, label_dir: &String) -> Result<()>
         label_dir: canonical_or_lossy(&label_root),
         entries,
     };
+    journal.push_artifact(
+        &image_root.join("remap.json"),
+        serde_json::to_string_pretty(&remap)?,
+    );
 
-    let remap_path = image_root.join("remap.json");
-    let json = serde_json::to_string_pretty(&remap)?;
-    fs::write(&remap_path, json).with_context(|| format!("Writing {}", remap_path.display()))?;
-
-    Ok(())
+    journal.execute(dry_run)
 }
 
-/// Build an index of all files under `root` by:
-/// key = "<relative parent>/<stem>"
-fn index_by_relparent_and_stem(root: &Path) -> Result<BTreeMap<String, PathBuf>> {
-    let mut out: BTreeMap<String, PathBuf> = BTreeMap::new();
+/// Unmask (rename back) a dataset masked by [`mask_dataset`]:
+/// - Reads the `remap.json` written by [`mask_dataset`]
+/// - Ensures every masked file still exists and no original name is taken again
+/// - Renames both images and labels back with the same 2-phase rename
+/// - Maps every given list / prediction file using masked names back to the original names,
+///   the result is written next to it as `<stem>_unmasked.<ext>`. Only masked paths and file
+///   names with their extension are mapped, bare stems like `0001` are plain numbers just as
+///   often, they are mapped only with `match_stems`
+/// - Records every rename in `unmask_dataset.journal.jsonl` next to `remap.json` before acting
+pub async fn unmask_dataset(
+    remap_path: &String,
+    list_paths: &[String],
+    match_stems: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let remap_path = PathBuf::from(remap_path);
+    let remap: RemapJson = serde_json::from_str(
+        &fs::read_to_string(&remap_path)
+            .with_context(|| format!("Reading {}", remap_path.display()))?,
+    )
+    .with_context(|| format!("Parsing {}", remap_path.display()))?;
+
+    if remap.version != 1 {
+        bail!("Unsupported remap.json version {}", remap.version);
+    }
+
+    let mut temp_moves: Vec<(PathBuf, PathBuf)> = Vec::with_capacity(remap.entries.len() * 2);
+    let mut final_moves: Vec<(PathBuf, PathBuf)> = Vec::with_capacity(remap.entries.len() * 2);
+
+    for e in &remap.entries {
+        for (masked, original) in [(&e.image_new, &e.image_old), (&e.label_new, &e.label_old)] {
+            let masked = PathBuf::from(masked);
+            let original = PathBuf::from(original);
+
+            if !masked.is_file() {
+                bail!(
+                    "Masked file missing for id {}, dataset changed after masking:\n  {}",
+                    e.id,
+                    masked.display()
+                );
+            }
+            // Refuse to overwrite existing files
+            if original.exists() {
+                bail!(
+                    "Original path already exists (refusing to overwrite):\n  {}",
+                    original.display()
+                );
+            }
+
+            let tmp = temp_path_for_target(&masked, &original)?;
+            if tmp.exists() {
+                bail!(
+                    "Temp path already exists (refusing to proceed):\n  {}",
+                    tmp.display()
+                );
+            }
 
-    for entry in WalkDir::new(root).follow_links(false) {
-        let entry = entry?;
-        if !entry.file_type().is_file() {
-            continue;
+            temp_moves.push((masked, tmp.clone()));
+            final_moves.push((tmp, original));
         }
-        let path = entry.path().to_path_buf();
+    }
 
-        let rel = path
-            .strip_prefix(root)
-            .with_context(|| format!("strip_prefix failed for {}", path.display()))?;
+    let mut journal = Journal::new(
+        remap_path
+            .parent()
+            .ok_or_else(|| anyhow!("No parent for {}", remap_path.display()))?
+            .join("unmask_dataset.journal.jsonl"),
+        "unmask_dataset",
+    );
+    for (from, to) in temp_moves.iter().chain(final_moves.iter()) {
+        journal.push_move(from, to);
+    }
 
-        let parent = rel.parent().unwrap_or(Path::new(""));
-        let stem = rel
+    let mapping = UnmaskMapping::new(&remap.entries, match_stems);
+    for list_path in list_paths {
+        let list_path = PathBuf::from(list_path);
+        let content = fs::read_to_string(&list_path)
+            .with_context(|| format!("Reading {}", list_path.display()))?;
+
+        let content = if list_path.extension().and_then(|s| s.to_str()) == Some("json") {
+            let mut value: serde_json::Value = serde_json::from_str(&content)
+                .with_context(|| format!("Parsing {}", list_path.display()))?;
+            mapping.map_json(&mut value);
+            serde_json::to_string(&value)?
+        } else {
+            mapping.map_text(&content)
+        };
+
+        let stem = list_path
             .file_stem()
             .and_then(|s| s.to_str())
-            .ok_or_else(|| anyhow!("Non-UTF8 filename stem: {}", rel.display()))?;
+            .ok_or_else(|| anyhow!("Non-UTF8 list file name: {}", list_path.display()))?;
+        let save_path = match list_path.extension().and_then(|s| s.to_str()) {
+            Some(ext) => list_path.with_file_name(format!("{stem}{UNMASKED_SUFFIX}.{ext}")),
+            None => list_path.with_file_name(format!("{stem}{UNMASKED_SUFFIX}")),
+        };
+        journal.push_artifact(&save_path, content);
+    }
 
-        let key = if parent.as_os_str().is_empty() {
-            stem.to_string()
-        } else {
-            format!("{}/{}", parent.to_string_lossy(), stem)
+    journal.execute(dry_run)?;
+    if !dry_run {
+        tracing::info!("Restored {} image/label pairs", remap.entries.len());
+    }
+    Ok(())
+}
+
+/// Masked -> original lookup by full path, file name and, when enabled, stem
+struct UnmaskMapping {
+    paths: HashMap<String, String>,
+    names: HashMap<String, String>,
+    stems: HashMap<String, String>,
+    match_stems: bool,
+}
+
+impl UnmaskMapping {
+    fn new(entries: &[RemapEntry], match_stems: bool) -> Self {
+        let mut mapping = Self {
+            paths: HashMap::new(),
+            names: HashMap::new(),
+            stems: HashMap::new(),
+            match_stems,
         };
+        for e in entries {
+            for (masked, original) in [(&e.image_new, &e.image_old), (&e.label_new, &e.label_old)] {
+                let (masked, original) = (Path::new(masked), Path::new(original));
+                mapping
+                    .paths
+                    .insert(to_string_path(masked), to_string_path(original));
+                if let (Some(m), Some(o)) = (masked.file_name(), original.file_name()) {
+                    mapping.names.insert(
+                        m.to_string_lossy().to_string(),
+                        o.to_string_lossy().to_string(),
+                    );
+                }
+                if let (Some(m), Some(o)) = (masked.file_stem(), original.file_stem()) {
+                    mapping.stems.insert(
+                        m.to_string_lossy().to_string(),
+                        o.to_string_lossy().to_string(),
+                    );
+                }
+            }
+        }
+        mapping
+    }
 
-        if let Some(existing) = out.get(&key) {
-            bail!(
-                "Duplicate key '{}' under {}:\n  - {}\n  - {}",
-                key,
-                root.display(),
-                existing.display(),
-                path.display()
-            );
+    /// Map a single path-like token, keeping its directory part untouched
+    /// when only the file name (or stem with `match_stems`) is known.
+    fn map_token(&self, token: &str) -> Option<String> {
+        if let Some(original) = self.paths.get(token) {
+            return Some(original.clone());
         }
+        let (dir, name) = match token.rfind(['/', '\\']) {
+            Some(i) => token.split_at(i + 1),
+            None => ("", token),
+        };
+        self.names
+            .get(name)
+            .or_else(|| self.match_stems.then(|| self.stems.get(name)).flatten())
+            .map(|original| format!("{dir}{original}"))
+    }
 
-        out.insert(key, path);
+    fn map_text(&self, content: &str) -> String {
+        let token = Regex::new(r"[^\s,;]+").expect("Static regex");
+        token
+            .replace_all(content, |caps: &regex::Captures| {
+                self.map_token(&caps[0])
+                    .unwrap_or_else(|| caps[0].to_string())
+            })
+            .into_owned()
     }
 
-    Ok(out)
+    fn map_json(&self, value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::String(s) => {
+                if let Some(original) = self.map_token(s) {
+                    *s = original;
+                }
+            }
+            serde_json::Value::Array(values) => values.iter_mut().for_each(|v| self.map_json(v)),
+            serde_json::Value::Object(map) => {
+                let old = std::mem::take(map);
+                for (key, mut v) in old {
+                    self.map_json(&mut v);
+                    map.insert(self.map_token(&key).unwrap_or(key), v);
+                }
+            }
+            _ => {}
+        }
+    }
 }
 
 fn digits_needed(n: usize) -> usize {
@@ -275,3 +427,69 @@ fn canonical_or_lossy(p: &Path) -> String {
         .map(|c| c.to_string_lossy().to_string())
         .unwrap_or_else(|_| p.to_string_lossy().to_string())
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{RemapEntry, UnmaskMapping};
+    use rstest::rstest;
+
+    fn mapping(match_stems: bool) -> UnmaskMapping {
+        let entries = [("0001", "cat"), ("0002", "dog")].map(|(masked, original)| RemapEntry {
+            id: masked.to_string(),
+            image_old: format!("/data/images/{original}.jpg"),
+            image_new: format!("/data/images/{masked}.jpg"),
+            label_old: format!("/data/labels/{original}.png"),
+            label_new: format!("/data/labels/{masked}.png"),
+        });
+        UnmaskMapping::new(&entries, match_stems)
+    }
+
+    #[rstest]
+    #[case::full_path("/data/images/0001.jpg", Some("/data/images/cat.jpg"))]
+    #[case::label_path("/data/labels/0002.png", Some("/data/labels/dog.png"))]
+    #[case::file_name("0001.jpg", Some("cat.jpg"))]
+    #[case::other_dir("train/images/0002.jpg", Some("train/images/dog.jpg"))]
+    #[case::windows_dir("C:\\data\\0001.png", Some("C:\\data\\cat.png"))]
+    #[case::bare_stem("0001", None)]
+    #[case::number("2", None)]
+    #[case::unknown_name("0003.jpg", None)]
+    #[case::other_extension("0001.tif", None)]
+    fn map_token_without_stems(#[case] token: &str, #[case] expected: Option<&str>) {
+        assert_eq!(mapping(false).map_token(token).as_deref(), expected);
+    }
+
+    #[rstest]
+    #[case::file_name("0001.jpg", Some("cat.jpg"))]
+    #[case::bare_stem("0001", Some("cat"))]
+    #[case::stem_in_dir("crops/0002", Some("crops/dog"))]
+    #[case::unknown_stem("0003", None)]
+    fn map_token_with_stems(#[case] token: &str, #[case] expected: Option<&str>) {
+        assert_eq!(mapping(true).map_token(token).as_deref(), expected);
+    }
+
+    #[test]
+    fn map_text_keeps_numbers() {
+        let content = "0001.jpg,0001,0.95\n/data/labels/0002.png 0002 1\n";
+        assert_eq!(
+            mapping(false).map_text(content),
+            "cat.jpg,0001,0.95\n/data/labels/dog.png 0002 1\n"
+        );
+    }
+
+    #[test]
+    fn map_json_keys_and_values() {
+        let mut value = serde_json::json!({
+            "0001.jpg": {"label": "/data/labels/0001.png", "id": "0001", "score": 1},
+            "items": ["0002.jpg", 2],
+        });
+        mapping(false).map_json(&mut value);
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "cat.jpg": {"label": "/data/labels/cat.png", "id": "0001", "score": 1},
+                "items": ["dog.jpg", 2],
+            })
+        );
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use indicatif::ProgressStyle;
+use opencv::{
+    core::{Mat, MatTraitConst, MatTraitManual, Vec3b, Vector},
+    imgcodecs,
+};
+use serde::Serialize;
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::info_span;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+use super::pairing::{pair_images_labels, ImageLabelPair};
+use crate::THREAD_POOL;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LabelKind {
+    /// RGB color labels, values written as "R,G,B"
+    Rgb,
+    /// 8 bit class id labels, values written as the id
+    Class,
+}
+
+/// Label value mapping of one source
+#[derive(Debug, Clone)]
+enum ValueMap {
+    Rgb(HashMap<[u8; 3], [u8; 3]>),
+    Class([u8; 256]),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MergeSource {
+    pub name: String,
+    pub path: String,
+    /// Original value -> unified value, empty when the labels are copied as is
+    pub remap: BTreeMap<String, String>,
+    /// Label values found after remapping
+    pub values: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MergedSample {
+    pub source: String,
+    pub source_image: String,
+    pub source_label: String,
+    /// Relative to the merged root
+    pub image: String,
+    pub label: String,
+    pub renamed: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SkippedSample {
+    pub source: String,
+    pub source_image: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Provenance {
+    pub label_kind: String,
+    pub sources: Vec<MergeSource>,
+    /// Label values not present in every source after remapping, with the sources having them
+    pub palette_mismatches: BTreeMap<String, Vec<String>>,
+    pub samples: Vec<MergedSample>,
+    pub skipped: Vec<SkippedSample>,
+}
+
+/// Merge several `images` / `labels` datasets into `save_path`.
+///
+/// `remap_path` is a JSON object keyed by source folder name (or the path as given)
+/// mapping the source label values onto the unified palette, e.g.
+/// `{"city_a": {"255,0,0": "0,0,255"}, "city_b": {"3": "1"}}`.
+/// Values missing from a mapping are kept. File names colliding with an already merged
+/// sample get the source name appended, the same image given twice is only merged once.
+/// Where every sample came from is written to `<save_path>/provenance.json`.
+///
+/// Label values not found in every source are reported, `strict` fails before
+/// anything is written.
+#[allow(clippy::too_many_arguments)]
+pub async fn merge_datasets(
+    dataset_paths: &[String],
+    save_path: &str,
+    label_kind: LabelKind,
+    remap_path: Option<&str>,
+    image_extensions: &[String],
+    label_extensions: &[String],
+    strict: bool,
+) -> Result<()> {
+    if dataset_paths.len() < 2 {
+        bail!("At least two datasets are needed for a merge");
+    }
+    let save_path = PathBuf::from(save_path);
+    for folder in ["images", "labels"] {
+        let dir = save_path.join(folder);
+        if dir.is_dir() && fs::read_dir(&dir)?.next().is_some() {
+            bail!("{} already exists and is not empty", dir.display());
+        }
+    }
+
+    let remaps: BTreeMap<String, BTreeMap<String, String>> = match remap_path {
+        Some(path) => serde_json::from_str(
+            &fs::read_to_string(path).with_context(|| format!("Reading {}", path))?,
+        )
+        .with_context(|| format!("Parsing remap file {}", path))?,
+        None => BTreeMap::new(),
+    };
+
+    // Unique source names, used as suffix on collision
+    let mut sources = Vec::new();
+    let mut used_names = HashSet::new();
+    let mut used_remaps = HashSet::new();
+    for (index, dataset_path) in dataset_paths.iter().enumerate() {
+        let root = PathBuf::from(dataset_path);
+        let mut name = root
+            .file_name()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| format!("source{}", index));
+        if !used_names.insert(name.clone()) {
+            name = format!("{}_{}", name, index);
+            used_names.insert(name.clone());
+        }
+
+        let remap_key = [dataset_path, &name]
+            .into_iter()
+            .find(|k| remaps.contains_key(*k));
+        let remap = remap_key
+            .map(|k| {
+                used_remaps.insert(k.clone());
+                remaps[k].clone()
+            })
+            .unwrap_or_default();
+        let value_map = if remap.is_empty() {
+            None
+        } else {
+            Some(
+                parse_value_map(label_kind, &remap)
+                    .with_context(|| format!("Remap of {}", name))?,
+            )
+        };
+
+        let pairs = pair_images_labels(
+            &root.join("images"),
+            &root.join("labels"),
+            image_extensions,
+            label_extensions,
+            true,
+        )
+        .with_context(|| format!("Pairing dataset {}", root.display()))?;
+        tracing::info!("{}: {} samples", name, pairs.len());
+
+        sources.push((
+            MergeSource {
+                name,
+                path: dataset_path.clone(),
+                remap,
+                values: BTreeSet::new(),
+            },
+            value_map,
+            pairs,
+        ));
+    }
+    for key in remaps.keys().filter(|k| !used_remaps.contains(*k)) {
+        tracing::warn!("Remap entry {} does not match any source", key);
+    }
+
+    let semaphore = Arc::new(Semaphore::new(
+        (*THREAD_POOL
+            .read()
+            .map_err(|_| anyhow!("THREAD_POOL lock poisoned"))?)
+        .into(),
+    ));
+
+    // Scan the labels first so palette conflicts are found before writing anything
+    let total = sources
+        .iter()
+        .map(|(_, _, pairs)| pairs.len())
+        .sum::<usize>();
+    let header_span = info_span!("merge_scan_threads");
+    header_span.pb_set_style(&ProgressStyle::with_template(
+        "{spinner} Scanning {msg}\n{wide_bar} {pos}/{len}",
+    )?);
+    header_span.pb_set_length(total as u64);
+    let header_span_enter = header_span.enter();
+
+    let mut threads = JoinSet::new();
+    for (index, (_, value_map, pairs)) in sources.iter().enumerate() {
+        for pair in pairs {
+            let permit = semaphore.clone().acquire_owned().await?;
+            let label = pair.label.clone();
+            let value_map = value_map.clone();
+            let header_span = header_span.clone();
+            threads.spawn_blocking(move || -> Result<(usize, BTreeSet<String>)> {
+                let _permit = permit;
+                let (_, values) = read_label(&label, label_kind, value_map.as_ref())?;
+                header_span.pb_set_message(&label.to_string_lossy());
+                header_span.pb_inc(1);
+                Ok((index, values))
+            });
+        }
+    }
+    while let Some(result) = threads.join_next().await {
+        let (index, values) = result??;
+        sources[index].0.values.extend(values);
+    }
+    drop(header_span_enter);
+    drop(header_span);
+
+    let mut value_sources = BTreeMap::<String, Vec<String>>::new();
+    for (source, _, _) in &sources {
+        for value in &source.values {
+            value_sources
+                .entry(value.clone())
+                .or_default()
+                .push(source.name.clone());
+        }
+    }
+    let palette_mismatches = value_sources
+        .into_iter()
+        .filter(|(_, names)| names.len() != sources.len())
+        .collect::<BTreeMap<_, _>>();
+    for (value, names) in &palette_mismatches {
+        tracing::warn!("Label value {} only found in {}", value, names.join(", "));
+    }
+    if strict && !palette_mismatches.is_empty() {
+        bail!(
+            "{} label values are not shared by every source, fix the remap file or drop --strict",
+            palette_mismatches.len()
+        );
+    }
+
+    // Plan the target names sequentially so the result does not depend on thread order
+    let mut taken = HashSet::new();
+    let mut seen_images = HashSet::new();
+    let mut samples = Vec::new();
+    let mut skipped = Vec::new();
+    let mut jobs = Vec::new();
+    for (source, value_map, pairs) in &sources {
+        for ImageLabelPair { key, image, label } in pairs {
+            let canonical = fs::canonicalize(image)?;
+            if !seen_images.insert(canonical) {
+                tracing::warn!("{} is already merged, skipped", image.display());
+                skipped.push(SkippedSample {
+                    source: source.name.clone(),
+                    source_image: image.to_string_lossy().to_string(),
+                    reason: "duplicate path".to_string(),
+                });
+                continue;
+            }
+
+            let mut target_key = key.clone();
+            let mut counter = 1;
+            while taken.contains(&target_key) {
+                target_key = if counter == 1 {
+                    format!("{}_{}", key, source.name)
+                } else {
+                    format!("{}_{}_{}", key, source.name, counter)
+                };
+                counter += 1;
+            }
+            taken.insert(target_key.clone());
+
+            let image_rel = with_extension_of(&target_key, image);
+            let label_rel = with_extension_of(&target_key, label);
+            samples.push(MergedSample {
+                source: source.name.clone(),
+                source_image: image.to_string_lossy().to_string(),
+                source_label: label.to_string_lossy().to_string(),
+                image: format!("images/{}", image_rel),
+                label: format!("labels/{}", label_rel),
+                renamed: target_key != *key,
+            });
+            jobs.push((
+                image.clone(),
+                label.clone(),
+                save_path.join("images").join(image_rel),
+                save_path.join("labels").join(label_rel),
+                value_map.clone(),
+            ));
+        }
+    }
+    let renamed = samples.iter().filter(|s| s.renamed).count();
+    if renamed > 0 {
+        tracing::warn!("{} samples renamed because of name collisions", renamed);
+    }
+
+    let header_span = info_span!("merge_copy_threads");
+    header_span.pb_set_style(&ProgressStyle::with_template(
+        "{spinner} Merging {msg}\n{wide_bar} {pos}/{len}",
+    )?);
+    header_span.pb_set_length(jobs.len() as u64);
+    let header_span_enter = header_span.enter();
+
+    let mut threads = JoinSet::new();
+    for (image, label, image_target, label_target, value_map) in jobs {
+        let permit = semaphore.clone().acquire_owned().await?;
+        let header_span = header_span.clone();
+        threads.spawn_blocking(move || -> Result<()> {
+            let _permit = permit;
+            for target in [&image_target, &label_target] {
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+            }
+            fs::copy(&image, &image_target)
+                .with_context(|| format!("Copying {}", image.display()))?;
+            match value_map {
+                Some(value_map) => {
+                    let (mat, _) = read_label(&label, label_kind, Some(&value_map))?;
+                    let target = label_target.to_str().ok_or(anyhow!("Invalid path"))?;
+                    if !imgcodecs::imwrite(target, &mat, &Vector::new())? {
+                        bail!("Failed to write {}", label_target.display());
+                    }
+                }
+                None => {
+                    fs::copy(&label, &label_target)
+                        .with_context(|| format!("Copying {}", label.display()))?;
+                }
+            }
+            header_span.pb_set_message(&image.to_string_lossy());
+            header_span.pb_inc(1);
+            Ok(())
+        });
+    }
+    while let Some(result) = threads.join_next().await {
+        result??;
+    }
+    drop(header_span_enter);
+
+    let provenance = Provenance {
+        label_kind: format!("{:?}", label_kind).to_lowercase(),
+        sources: sources.into_iter().map(|(source, _, _)| source).collect(),
+        palette_mismatches,
+        samples,
+        skipped,
+    };
+    let provenance_path = save_path.join("provenance.json");
+    fs::write(&provenance_path, serde_json::to_string_pretty(&provenance)?)
+        .with_context(|| format!("Writing {}", provenance_path.display()))?;
+    tracing::info!(
+        "Merged {} samples into {}, provenance saved to {}",
+        provenance.samples.len(),
+        save_path.display(),
+        provenance_path.display()
+    );
+    Ok(())
+}
+
+fn parse_value_map(kind: LabelKind, remap: &BTreeMap<String, String>) -> Result<ValueMap> {
+    match kind {
+        LabelKind::Rgb => {
+            let mut map = HashMap::new();
+            for (from, to) in remap {
+                map.insert(parse_rgb(from)?, parse_rgb(to)?);
+            }
+            Ok(ValueMap::Rgb(map))
+        }
+        LabelKind::Class => {
+            let mut map = [0u8; 256];
+            for (i, value) in map.iter_mut().enumerate() {
+                *value = i as u8;
+            }
+            for (from, to) in remap {
+                let from = from
+                    .trim()
+                    .parse::<u8>()
+                    .with_context(|| format!("Parsing class id {}", from))?;
+                let to = to
+                    .trim()
+                    .parse::<u8>()
+                    .with_context(|| format!("Parsing class id {}", to))?;
+                map[from as usize] = to;
+            }
+            Ok(ValueMap::Class(map))
+        }
+    }
+}
+
+fn parse_rgb(value: &str) -> Result<[u8; 3]> {
+    let parts = value
+        .split(',')
+        .map(|s| s.trim().parse::<u8>())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Parsing color {}", value))?;
+    if parts.len() != 3 {
+        bail!("Malformed color {}, please use R,G,B format", value);
+    }
+    Ok([parts[0], parts[1], parts[2]])
+}
+
+/// Read a label, apply `value_map` and return the distinct values after remapping
+fn read_label(
+    path: &Path,
+    kind: LabelKind,
+    value_map: Option<&ValueMap>,
+) -> Result<(Mat, BTreeSet<String>)> {
+    let flag = match kind {
+        LabelKind::Rgb => imgcodecs::IMREAD_COLOR,
+        LabelKind::Class => imgcodecs::IMREAD_GRAYSCALE,
+    };
+    let mut mat = imgcodecs::imread(path.to_str().ok_or(anyhow!("Invalid path"))?, flag)?;
+    if mat.empty() {
+        bail!("Failed to read label {}", path.display());
+    }
+    if !mat.is_continuous() {
+        mat = mat.try_clone()?;
+    }
+
+    let mut values = BTreeSet::new();
+    match kind {
+        LabelKind::Rgb => {
+            let map = match value_map {
+                Some(ValueMap::Rgb(map)) => Some(map),
+                _ => None,
+            };
+            let mut seen = HashSet::new();
+            for pixel in mat.data_typed_mut::<Vec3b>()? {
+                // BGR in OpenCV
+                let mut rgb = [pixel[2], pixel[1], pixel[0]];
+                if let Some(to) = map.and_then(|m| m.get(&rgb)) {
+                    rgb = *to;
+                    pixel[0] = rgb[2];
+                    pixel[1] = rgb[1];
+                    pixel[2] = rgb[0];
+                }
+                seen.insert(rgb);
+            }
+            values.extend(
+                seen.into_iter()
+                    .map(|rgb| format!("{},{},{}", rgb[0], rgb[1], rgb[2])),
+            );
+        }
+        LabelKind::Class => {
+            let map = match value_map {
+                Some(ValueMap::Class(map)) => Some(map),
+                _ => None,
+            };
+            let mut seen = [false; 256];
+            for pixel in mat.data_typed_mut::<u8>()? {
+                if let Some(map) = map {
+                    *pixel = map[*pixel as usize];
+                }
+                seen[*pixel as usize] = true;
+            }
+            values.extend(
+                seen.iter()
+                    .enumerate()
+                    .filter(|(_, s)| **s)
+                    .map(|(i, _)| i.to_string()),
+            );
+        }
+    }
+    Ok((mat, values))
+}
+
+/// `key` with the extension of `file`
+fn with_extension_of(key: &str, file: &Path) -> String {
+    match file.extension() {
+        Some(ext) => format!("{}.{}", key, ext.to_string_lossy()),
+        None => key.to_string(),
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use walkdir::WalkDir;
+
+pub const DEFAULT_IMAGE_EXTENSIONS: &str = "tif,tiff,png,jpg,jpeg,bmp";
+pub const DEFAULT_LABEL_EXTENSIONS: &str = "png,tif,tiff,bmp";
+
+/// One image and its label, matched by relative folder + stem
+#[derive(Debug, Clone)]
+pub struct ImageLabelPair {
+    /// "<relative parent>/<stem>"
+    pub key: String,
+    pub image: PathBuf,
+    pub label: PathBuf,
+}
+
+/// Pair every image under `image_root` with the label under `label_root`
+/// having the same relative folder + stem, the extensions may differ.
+///
+/// Only files with one of the given extensions (case insensitive) are considered,
+/// an empty extension list accepts every file.
+/// Fails when the two folders are not 1:1 correspondent.
+pub fn pair_images_labels(
+    image_root: &Path,
+    label_root: &Path,
+    image_extensions: &[String],
+    label_extensions: &[String],
+    recursive: bool,
+) -> Result<Vec<ImageLabelPair>> {
+    if !image_root.is_dir() {
+        bail!("image dir is not a directory: {}", image_root.display());
+    }
+    if !label_root.is_dir() {
+        bail!("label dir is not a directory: {}", label_root.display());
+    }
+
+    let image_index = index_by_relparent_and_stem(image_root, image_extensions, recursive)
+        .with_context(|| format!("Indexing image dir {}", image_root.display()))?;
+    let label_index = index_by_relparent_and_stem(label_root, label_extensions, recursive)
+        .with_context(|| format!("Indexing label dir {}", label_root.display()))?;
+
+    // Ensure 1:1 correspondence
+    let image_keys: BTreeSet<_> = image_index.keys().cloned().collect();
+    let label_keys: BTreeSet<_> = label_index.keys().cloned().collect();
+
+    if image_keys != label_keys {
+        let only_in_images: Vec<_> = image_keys
+            .difference(&label_keys)
+            .take(50)
+            .cloned()
+            .collect();
+        let only_in_labels: Vec<_> = label_keys
+            .difference(&image_keys)
+            .take(50)
+            .cloned()
+            .collect();
+
+        let mut msg = String::new();
+        if !only_in_images.is_empty() {
+            msg.push_str("Missing in label dir (present in image dir):\n");
+            for k in &only_in_images {
+                msg.push_str(&format!("  - {k}\n"));
+            }
+        }
+        if !only_in_labels.is_empty() {
+            msg.push_str("Missing in image dir (present in label dir):\n");
+            for k in &only_in_labels {
+                msg.push_str(&format!("  - {k}\n"));
+            }
+        }
+        bail!(
+            "image dir and label dir are not correspondent (by relpath parent + stem).\n{}",
+            msg
+        );
+    }
+
+    image_index
+        .into_iter()
+        .map(|(key, image)| {
+            let label = label_index
+                .get(&key)
+                .cloned()
+                .ok_or_else(|| anyhow!("Internal error: key missing from label index: {key}"))?;
+            Ok(ImageLabelPair { key, image, label })
+        })
+        .collect()
+}
+
+/// Build an index of all files under `root` by:
+/// key = "<relative parent>/<stem>"
+pub fn index_by_relparent_and_stem(
+    root: &Path,
+    extensions: &[String],
+    recursive: bool,
+) -> Result<BTreeMap<String, PathBuf>> {
+    let mut out: BTreeMap<String, PathBuf> = BTreeMap::new();
+
+    let walker = if recursive {
+        WalkDir::new(root)
+    } else {
+        WalkDir::new(root).max_depth(1)
+    };
+    for entry in walker.follow_links(false) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path().to_path_buf();
+
+        if is_bookkeeping_file(&path) || !has_extension(&path, extensions) {
+            continue;
+        }
+
+        let key = relparent_and_stem(root, &path)?;
+
+        if let Some(existing) = out.get(&key) {
+            bail!(
+                "Duplicate key '{}' under {}:\n  - {}\n  - {}",
+                key,
+                root.display(),
+                existing.display(),
+                path.display()
+            );
+        }
+
+        out.insert(key, path);
+    }
+
+    Ok(out)
+}
+
+/// key = "<relative parent>/<stem>" of `path` under `root`
+pub fn relparent_and_stem(root: &Path, path: &Path) -> Result<String> {
+    let rel = path
+        .strip_prefix(root)
+        .with_context(|| format!("strip_prefix failed for {}", path.display()))?;
+
+    let parent = rel.parent().unwrap_or(Path::new(""));
+    let stem = rel
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .ok_or_else(|| anyhow!("Non-UTF8 filename stem: {}", rel.display()))?;
+
+    Ok(join_key(parent, stem))
+}
+
+/// key = "<parent>/<stem>", just "<stem>" for an empty parent
+pub fn join_key(parent: &Path, stem: &str) -> String {
+    if parent.as_os_str().is_empty() {
+        stem.to_string()
+    } else {
+        format!("{}/{}", parent.to_string_lossy(), stem)
+    }
+}
+
+/// Bookkeeping files written by mask / unmask themselves
+pub fn is_bookkeeping_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|s| s.to_str())
+        .map(|name| name == "remap.json" || name.ends_with(".journal.jsonl"))
+        .unwrap_or(false)
+}
+
+/// Case insensitive extension check, an empty list accepts every file
+pub fn has_extension(path: &Path, extensions: &[String]) -> bool {
+    if extensions.is_empty() {
+        return true;
+    }
+    path.extension()
+        .and_then(|s| s.to_str())
+        .map(|ext| {
+            extensions
+                .iter()
+                .any(|e| e.trim_start_matches('.').eq_ignore_ascii_case(ext))
+        })
+        .unwrap_or(false)
+}
@@ -0,0 +1,351 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Args;
+
+use super::DatasetItem;
+
+/// How image and label paths are written into dataset lists
+#[derive(Args, Debug, Clone, Default)]
+pub struct PathOptions {
+    #[arg(
+        long,
+        conflicts_with = "path_prefix",
+        help = "Write paths relative to the folder of the list file instead of absolute ones"
+    )]
+    pub relative: bool,
+
+    #[arg(
+        long,
+        value_name = "FROM=TO",
+        help = "Rewrite the absolute path prefix FROM to TO, e.g. /data/foo=/mnt/datasets/foo, can be given multiple times"
+    )]
+    pub path_prefix: Vec<String>,
+}
+
+/// Suffix of the `<split>_oversampled` lists written by `sample-weights`
+pub const OVERSAMPLED_SUFFIX: &str = "_oversampled";
+/// Suffix of the `<stem>_rebased` lists written by `rebase`
+pub const REBASED_SUFFIX: &str = "_rebased";
+/// Suffix of the `<stem>_unmasked` lists written by `unmask-dataset`
+pub const UNMASKED_SUFFIX: &str = "_unmasked";
+/// Suffix of the `<split>_deduped` lists written by `find-duplicates`
+pub const DEDUPED_SUFFIX: &str = "_deduped";
+
+/// Whether `path` is a list derived from a split list (oversampled, rebased, unmasked, deduped),
+/// these repeat the samples of their source and are no split of their own
+pub fn is_derived_list(path: &Path) -> bool {
+    path.file_stem()
+        .and_then(|s| s.to_str())
+        .map(|stem| {
+            [
+                OVERSAMPLED_SUFFIX,
+                REBASED_SUFFIX,
+                UNMASKED_SUFFIX,
+                DEDUPED_SUFFIX,
+            ]
+            .iter()
+            .any(|suffix| stem.ends_with(suffix))
+        })
+        .unwrap_or(false)
+}
+
+pub struct PathRewriter {
+    relative: bool,
+    prefixes: Vec<(PathBuf, PathBuf)>,
+}
+
+impl PathOptions {
+    pub fn rewriter(&self) -> Result<PathRewriter> {
+        let mut prefixes = Vec::new();
+        for prefix in &self.path_prefix {
+            let (from, to) = prefix
+                .split_once('=')
+                .with_context(|| format!("Malformed prefix {}, please use FROM=TO", prefix))?;
+            if from.is_empty() || to.is_empty() {
+                bail!("Malformed prefix {}, please use FROM=TO", prefix);
+            }
+            prefixes.push((normalize(Path::new(from)), PathBuf::from(to)));
+        }
+        // Longest prefix wins when several match
+        prefixes.sort_by_key(|(from, _)| std::cmp::Reverse(from.components().count()));
+        Ok(PathRewriter {
+            relative: self.relative,
+            prefixes,
+        })
+    }
+}
+
+impl PathRewriter {
+    /// Rewrite an absolute `path` for a list written into `list_dir`
+    pub fn rewrite(&self, path: &Path, list_dir: &Path) -> Result<String> {
+        if self.relative {
+            let list_dir = fs::canonicalize(list_dir)
+                .with_context(|| format!("Resolving {}", list_dir.display()))?;
+            return Ok(relative_path(path, &list_dir).display().to_string());
+        }
+        if self.prefixes.is_empty() {
+            return Ok(path.display().to_string());
+        }
+        let (rest, to) = self
+            .prefixes
+            .iter()
+            .find_map(|(from, to)| path.strip_prefix(from).ok().map(|rest| (rest, to)))
+            .ok_or(anyhow!(
+                "{} does not start with any of the given prefixes",
+                path.display()
+            ))?;
+        Ok(to.join(rest).display().to_string())
+    }
+}
+
+/// Remove `.` and resolve `..` without touching the file system,
+/// the files may not exist on this machine
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                // Nothing is above the root
+                Some(Component::RootDir | Component::Prefix(_)) => {}
+                // Leading `..` of a relative path are kept
+                _ => normalized.push(".."),
+            },
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/// Only the plain components of `path`, e.g. a file name taken from an annotation file,
+/// so joining it to an output folder can not escape that folder
+pub fn contained_path(path: &str) -> PathBuf {
+    Path::new(path)
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+/// Path of `path` seen from `base`, both absolute
+fn relative_path(path: &Path, base: &Path) -> PathBuf {
+    let path = normalize(path);
+    let base = normalize(base);
+    let common = path
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut relative = PathBuf::new();
+    for _ in base.components().skip(common) {
+        relative.push("..");
+    }
+    for component in path.components().skip(common) {
+        relative.push(component);
+    }
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+    relative
+}
+
+/// Rewrite the paths of existing JSON / CSV / TXT lists.
+///
+/// Relative entries are resolved against `base` (defaults to the folder of the list)
+/// first, then written absolute, relative to the folder of the output or with their
+/// prefix rewritten, following `path_options`.
+/// Saved to `save_path` when a single list is given, otherwise `<stem>_rebased.<ext>`
+/// next to every list.
+pub fn rebase_lists(
+    list_paths: &[String],
+    base: Option<&str>,
+    save_path: Option<&str>,
+    path_options: &PathOptions,
+) -> Result<()> {
+    if save_path.is_some() && list_paths.len() > 1 {
+        bail!("A save path can only be given with a single list");
+    }
+    let rewriter = path_options.rewriter()?;
+    for list_path in list_paths {
+        let list_path = PathBuf::from(list_path);
+        let list_dir = list_path
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let base = fs::canonicalize(base.map(Path::new).unwrap_or(list_dir))
+            .with_context(|| format!("Resolving base folder of {}", list_path.display()))?;
+
+        let extension = list_path
+            .extension()
+            .and_then(|s| s.to_str())
+            .map(|s| s.to_lowercase());
+        let stem = list_path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or(anyhow!("Non-UTF8 list file name {}", list_path.display()))?;
+        let save_path = match (save_path, &extension) {
+            (Some(path), _) => PathBuf::from(path),
+            (None, Some(ext)) => {
+                list_path.with_file_name(format!("{}{}.{}", stem, REBASED_SUFFIX, ext))
+            }
+            (None, None) => list_path.with_file_name(format!("{}{}", stem, REBASED_SUFFIX)),
+        };
+        let save_dir = save_path
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+            .to_path_buf();
+        fs::create_dir_all(&save_dir)?;
+        let rebase = |path: &str| -> Result<String> {
+            rewriter
+                .rewrite(&normalize(&base.join(path.trim())), &save_dir)
+                .with_context(|| format!("Rebasing {} of {}", path, list_path.display()))
+        };
+
+        let content = fs::read_to_string(&list_path)
+            .with_context(|| format!("Reading {}", list_path.display()))?;
+        let (content, count) = match extension.as_deref() {
+            Some("json") => {
+                let items: Vec<DatasetItem> = serde_json::from_str(&content)
+                    .with_context(|| format!("Parsing {}", list_path.display()))?;
+                let items = items
+                    .iter()
+                    .map(|item| {
+                        Ok(DatasetItem {
+                            image: rebase(&item.image)?,
+                            label: rebase(&item.label)?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                (serde_json::to_string(&items)?, items.len())
+            }
+            Some("csv") => {
+                let mut lines = content.lines().filter(|x| !x.trim().is_empty());
+                let header = lines
+                    .next()
+                    .ok_or(anyhow!("{} is empty", list_path.display()))?;
+                let mut rows = vec![header.to_string()];
+                for line in lines {
+                    rows.push(
+                        line.split(',')
+                            .map(rebase)
+                            .collect::<Result<Vec<_>>>()?
+                            .join(","),
+                    );
+                }
+                let count = rows.len() - 1;
+                (rows.join("\n"), count)
+            }
+            _ => {
+                let lines = content
+                    .lines()
+                    .filter(|x| !x.trim().is_empty())
+                    .map(|line| rebase(line).map(|x| format!("{}\n", x)))
+                    .collect::<Result<Vec<_>>>()?;
+                (lines.concat(), lines.len())
+            }
+        };
+
+        fs::write(&save_path, content)
+            .with_context(|| format!("Writing {}", save_path.display()))?;
+        tracing::info!("Rebased {} items to {}", count, save_path.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::path::Path;
+
+    use super::{is_derived_list, normalize, relative_path, PathOptions};
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::current_dir("/data/./images/a.png", "/data/images/a.png")]
+    #[case::parent_dir("/data/images/../labels/a.png", "/data/labels/a.png")]
+    #[case::leading_parent_dirs("../../data/a.png", "../../data/a.png")]
+    #[case::parent_beyond_relative_root("a/../../b.png", "../b.png")]
+    #[case::trailing_parent_dir("/data/images/..", "/data")]
+    #[case::parent_of_root("/../data/a.png", "/data/a.png")]
+    fn normalize_path(#[case] path: &str, #[case] expected: &str) {
+        assert_eq!(normalize(Path::new(path)), Path::new(expected));
+    }
+
+    #[rstest]
+    #[case::same_dir("/data/train/a.png", "/data/train", "a.png")]
+    #[case::sibling_dir("/data/images/a.png", "/data/lists", "../images/a.png")]
+    #[case::nested_base("/data/images/a.png", "/data/lists/v2/train", "../../../images/a.png")]
+    #[case::dots_in_both("/data/x/../images/./a.png", "/data/lists/./v2/..", "../images/a.png")]
+    #[case::base_itself("/data/lists", "/data/lists", ".")]
+    #[case::only_root_shared("/mnt/a.png", "/data/lists", "../../mnt/a.png")]
+    fn relative_to_base(#[case] path: &str, #[case] base: &str, #[case] expected: &str) {
+        assert_eq!(
+            relative_path(Path::new(path), Path::new(base)),
+            Path::new(expected)
+        );
+    }
+
+    #[rstest]
+    #[case::shorter_prefix("/data/bar/a.png", "/mnt/bar/a.png")]
+    #[case::longest_prefix_wins("/data/foo/a.png", "/srv/foo/a.png")]
+    // Prefixes match whole components, /data/foo is no prefix of /data/foobar
+    #[case::partial_component("/data/foobar/a.png", "/mnt/foobar/a.png")]
+    #[case::normalized_prefix("/data/baz/a.png", "/srv/baz/a.png")]
+    fn rewrite_prefix(#[case] path: &str, #[case] expected: &str) {
+        let rewriter = PathOptions {
+            relative: false,
+            path_prefix: vec![
+                "/data=/mnt".to_string(),
+                "/data/foo=/srv/foo".to_string(),
+                "/data/x/../baz=/srv/baz".to_string(),
+            ],
+        }
+        .rewriter()
+        .unwrap();
+        assert_eq!(
+            rewriter.rewrite(Path::new(path), Path::new(".")).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn rewrite_without_matching_prefix() {
+        let rewriter = PathOptions {
+            relative: false,
+            path_prefix: vec!["/data=/mnt".to_string()],
+        }
+        .rewriter()
+        .unwrap();
+        assert!(rewriter
+            .rewrite(Path::new("/other/a.png"), Path::new("."))
+            .is_err());
+    }
+
+    #[rstest]
+    #[case::missing_separator("/data")]
+    #[case::empty_from("=/mnt")]
+    #[case::empty_to("/data=")]
+    fn malformed_prefix(#[case] prefix: &str) {
+        let options = PathOptions {
+            relative: false,
+            path_prefix: vec![prefix.to_string()],
+        };
+        assert!(options.rewriter().is_err());
+    }
+
+    #[rstest]
+    #[case::split("train.json", false)]
+    #[case::oversampled("train_oversampled.json", true)]
+    #[case::rebased("val_rebased.csv", true)]
+    #[case::unmasked("/data/test_unmasked.txt", true)]
+    #[case::deduped("train_deduped.json", true)]
+    #[case::suffix_inside_stem("train_rebased_v2.json", false)]
+    fn derived_list(#[case] path: &str, #[case] expected: bool) {
+        assert_eq!(is_derived_list(Path::new(path)), expected);
+    }
+}
@@ -0,0 +1,766 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use indicatif::ProgressStyle;
+use opencv::{
+    core::{self, Mat, MatTraitConst, MatTraitConstManual, Scalar},
+    imgcodecs, imgproc,
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::info_span;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+use walkdir::WalkDir;
+
+use super::merge::LabelKind;
+use super::pairing::{has_extension, pair_images_labels, relparent_and_stem};
+use super::stats::{accumulate_channels, ChannelAccumulator};
+use super::weights::ClassCount;
+use crate::THREAD_POOL;
+
+/// Component areas are bucketed by power of two, 1, 2-3, 4-7, ...
+const COMPONENT_BUCKETS: usize = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    Html,
+    Markdown,
+}
+
+/// Per class statistics of one label
+#[derive(Debug, Clone)]
+struct ClassSample {
+    pixels: u64,
+    components: u64,
+    component_buckets: [u64; COMPONENT_BUCKETS],
+}
+
+impl Default for ClassSample {
+    fn default() -> Self {
+        Self {
+            pixels: 0,
+            components: 0,
+            component_buckets: [0; COMPONENT_BUCKETS],
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SampleStats {
+    split: String,
+    width: i32,
+    height: i32,
+    channels: i32,
+    depth: String,
+    accumulators: Vec<ChannelAccumulator>,
+    /// `None` when the dataset has no labels
+    classes: Option<BTreeMap<String, ClassSample>>,
+}
+
+/// Class totals of the whole dataset or of one split
+#[derive(Debug, Clone, Default)]
+struct ClassTotals {
+    count: ClassCount,
+    images: u64,
+    components: u64,
+    component_buckets: Vec<u64>,
+}
+
+#[derive(Debug, Default)]
+struct SplitTotals {
+    images: u64,
+    labeled_pixels: u64,
+    classes: BTreeMap<String, ClassTotals>,
+}
+
+impl SplitTotals {
+    fn add(&mut self, classes: &BTreeMap<String, ClassSample>, image_pixels: u64) {
+        self.images += 1;
+        for (class, sample) in classes {
+            let totals = self.classes.entry(class.clone()).or_default();
+            if totals.component_buckets.is_empty() {
+                totals.component_buckets = vec![0; COMPONENT_BUCKETS];
+            }
+            totals.count.pixels += sample.pixels;
+            totals.count.image_pixels += image_pixels;
+            totals.count.images += 1;
+            totals.images += 1;
+            totals.components += sample.components;
+            for (total, bucket) in totals
+                .component_buckets
+                .iter_mut()
+                .zip(sample.component_buckets)
+            {
+                *total += bucket;
+            }
+            self.labeled_pixels += sample.pixels;
+        }
+    }
+}
+
+/// Building blocks of a report, rendered as HTML or Markdown
+enum Block {
+    Heading(String),
+    Paragraph(String),
+    Table {
+        headers: Vec<String>,
+        rows: Vec<Vec<String>>,
+    },
+    /// Horizontal bar chart, bars are (label, value, RGB fill)
+    Chart {
+        title: String,
+        bars: Vec<(String, f64, Option<[u8; 3]>)>,
+        unit: String,
+    },
+}
+
+/// Summarise an `images` / `labels` dataset root into a self-contained HTML or Markdown report:
+/// image sizes, aspect ratios, bit depth / channels, pooled channel mean / std, per class
+/// pixel share, presence rate and connected components, and the same per split
+/// (first folder under `images`, e.g. `train` / `val`).
+pub async fn dataset_report(
+    dataset_path: &String,
+    format: ReportFormat,
+    label_kind: LabelKind,
+    save_path: Option<&str>,
+    image_extensions: &[String],
+    label_extensions: &[String],
+) -> Result<()> {
+    let dataset_path = PathBuf::from(dataset_path);
+    let image_root = dataset_path.join("images");
+    let label_root = dataset_path.join("labels");
+    if !image_root.is_dir() {
+        bail!(
+            "Invalid dataset path: {}, should contain images folder",
+            dataset_path.display()
+        );
+    }
+
+    let samples: Vec<(String, PathBuf, Option<PathBuf>)> = if label_root.is_dir() {
+        pair_images_labels(
+            &image_root,
+            &label_root,
+            image_extensions,
+            label_extensions,
+            true,
+        )?
+        .into_iter()
+        .map(|pair| (pair.key, pair.image, Some(pair.label)))
+        .collect()
+    } else {
+        tracing::warn!("No labels folder, only images are summarised");
+        let mut samples = Vec::new();
+        for entry in WalkDir::new(&image_root).sort_by_file_name() {
+            let entry = entry?;
+            if entry.file_type().is_file() && has_extension(entry.path(), image_extensions) {
+                let key = relparent_and_stem(&image_root, entry.path())?;
+                samples.push((key, entry.path().to_path_buf(), None));
+            }
+        }
+        samples
+    };
+    if samples.is_empty() {
+        bail!("No image found under {}", image_root.display());
+    }
+
+    let sem = Arc::new(Semaphore::new(
+        (*THREAD_POOL
+            .read()
+            .map_err(|_| anyhow!("THREAD_POOL lock poisoned"))?)
+        .into(),
+    ));
+    let mut threads = JoinSet::new();
+
+    let header_span = info_span!("dataset_report_threads");
+    header_span.pb_set_style(&ProgressStyle::with_template(
+        "{spinner} Processing {msg}\n{wide_bar} {pos}/{len}",
+    )?);
+    header_span.pb_set_length(samples.len() as u64);
+
+    let header_span_enter = header_span.enter();
+
+    for (key, image, label) in samples {
+        let permit = sem.clone().acquire_owned().await?;
+        let header_span = header_span.clone();
+        threads.spawn_blocking(move || -> Result<SampleStats> {
+            let _permit = permit;
+            let split = match key.split_once('/') {
+                Some((split, _)) => split.to_string(),
+                None => "(root)".to_string(),
+            };
+            let stats = sample_stats(&image, label.as_deref(), split, label_kind)
+                .with_context(|| format!("Processing {}", image.display()))?;
+            header_span.pb_set_message(&key);
+            header_span.pb_inc(1);
+            Ok(stats)
+        });
+    }
+
+    let mut stats = Vec::new();
+    while let Some(result) = threads.join_next().await {
+        stats.push(result??);
+    }
+    drop(header_span_enter);
+    drop(header_span);
+
+    let blocks = build_report(&dataset_path, &stats, label_kind);
+    let (content, extension) = match format {
+        ReportFormat::Html => (render_html(&blocks), "html"),
+        ReportFormat::Markdown => (render_markdown(&blocks), "md"),
+    };
+    let save_path = match save_path {
+        Some(path) => PathBuf::from(path),
+        None => dataset_path.join(format!("dataset_report.{}", extension)),
+    };
+    fs::write(&save_path, content).with_context(|| format!("Writing {}", save_path.display()))?;
+    tracing::info!("Report saved to {}", save_path.display());
+    Ok(())
+}
+
+fn sample_stats(
+    image: &Path,
+    label: Option<&Path>,
+    split: String,
+    label_kind: LabelKind,
+) -> Result<SampleStats> {
+    let mat = imgcodecs::imread(
+        image.to_str().ok_or(anyhow!("Invalid path"))?,
+        imgcodecs::IMREAD_UNCHANGED,
+    )?;
+    if mat.empty() {
+        bail!("Image is not readable");
+    }
+    // Only mean / std are reported, every sample is kept until the end so no histogram
+    let accumulators = accumulate_channels(&mat, None, &[], &HashSet::new(), false)?;
+
+    let classes = match label {
+        Some(label) => Some(label_stats(label, label_kind)?),
+        None => None,
+    };
+
+    Ok(SampleStats {
+        split,
+        width: mat.cols(),
+        height: mat.rows(),
+        channels: mat.channels(),
+        depth: super::manifest::depth_name(mat.depth()).to_string(),
+        accumulators,
+        classes,
+    })
+}
+
+/// Pixel count and connected components (8-connectivity) of every class in a label
+fn label_stats(path: &Path, label_kind: LabelKind) -> Result<BTreeMap<String, ClassSample>> {
+    let flag = match label_kind {
+        LabelKind::Rgb => imgcodecs::IMREAD_COLOR,
+        LabelKind::Class => imgcodecs::IMREAD_GRAYSCALE,
+    };
+    let label = imgcodecs::imread(path.to_str().ok_or(anyhow!("Invalid path"))?, flag)?;
+    if label.empty() {
+        bail!("Label {} is not readable", path.display());
+    }
+    let label = if label.is_continuous() {
+        label
+    } else {
+        label.try_clone()?
+    };
+
+    // Distinct values first, each one is then masked for the components
+    let mut values = BTreeMap::<Vec<u8>, u64>::new();
+    let channels = label.channels() as usize;
+    let flat = label.reshape(1, 0)?;
+    for pixel in flat.data_typed::<u8>()?.chunks_exact(channels) {
+        *values.entry(pixel.to_vec()).or_insert(0) += 1;
+    }
+
+    let mut classes = BTreeMap::new();
+    for (value, pixels) in values {
+        let scalar = match value.as_slice() {
+            [b, g, r] => Scalar::new(*b as f64, *g as f64, *r as f64, 0.0),
+            [v] => Scalar::all(*v as f64),
+            _ => bail!("Unsupported label channels"),
+        };
+        let mut mask = Mat::default();
+        core::in_range(&label, &scalar, &scalar, &mut mask)?;
+
+        let mut components = Mat::default();
+        let mut component_stats = Mat::default();
+        let mut centroids = Mat::default();
+        let count = imgproc::connected_components_with_stats(
+            &mask,
+            &mut components,
+            &mut component_stats,
+            &mut centroids,
+            8,
+            core::CV_32S,
+        )?;
+
+        let mut sample = ClassSample {
+            pixels,
+            ..Default::default()
+        };
+        // Component 0 is the background of the mask
+        for index in 1..count {
+            let area = *component_stats.at_2d::<i32>(index, imgproc::CC_STAT_AREA)? as u64;
+            sample.components += 1;
+            let bucket = (63 - area.max(1).leading_zeros() as usize).min(COMPONENT_BUCKETS - 1);
+            sample.component_buckets[bucket] += 1;
+        }
+
+        let name = match value.as_slice() {
+            // BGR in OpenCV
+            [b, g, r] => format!("{},{},{}", r, g, b),
+            [v] => v.to_string(),
+            _ => unreachable!(),
+        };
+        classes.insert(name, sample);
+    }
+    Ok(classes)
+}
+
+fn build_report(dataset_path: &Path, stats: &[SampleStats], label_kind: LabelKind) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    let images = stats.len() as u64;
+    let labeled = stats.iter().filter(|s| s.classes.is_some()).count() as u64;
+
+    blocks.push(Block::Heading(format!(
+        "Dataset report: {}",
+        dataset_path.display()
+    )));
+    blocks.push(Block::Paragraph(format!(
+        "{} images, {} with labels",
+        images, labeled
+    )));
+
+    // Sizes
+    let mut sizes = BTreeMap::<(i32, i32), u64>::new();
+    let mut aspects = BTreeMap::<i64, u64>::new();
+    let mut formats = BTreeMap::<(String, i32), u64>::new();
+    for sample in stats {
+        *sizes.entry((sample.width, sample.height)).or_insert(0) += 1;
+        // Width / height rounded to 0.05
+        let aspect = (sample.width as f64 / sample.height.max(1) as f64 * 20.0).round() as i64;
+        *aspects.entry(aspect).or_insert(0) += 1;
+        *formats
+            .entry((sample.depth.clone(), sample.channels))
+            .or_insert(0) += 1;
+    }
+    let mut sizes = sizes.into_iter().collect::<Vec<_>>();
+    sizes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    blocks.push(Block::Heading("Image sizes".to_string()));
+    let widths = stats.iter().map(|s| s.width);
+    let heights = stats.iter().map(|s| s.height);
+    blocks.push(Block::Paragraph(format!(
+        "{} distinct sizes, width {} - {}, height {} - {}",
+        sizes.len(),
+        widths.clone().min().unwrap_or(0),
+        widths.max().unwrap_or(0),
+        heights.clone().min().unwrap_or(0),
+        heights.max().unwrap_or(0)
+    )));
+    blocks.push(Block::Chart {
+        title: "Most frequent sizes (width x height)".to_string(),
+        bars: sizes
+            .iter()
+            .take(15)
+            .map(|((w, h), count)| (format!("{}x{}", w, h), *count as f64, None))
+            .collect(),
+        unit: "images".to_string(),
+    });
+    blocks.push(Block::Chart {
+        title: "Aspect ratio (width / height)".to_string(),
+        bars: aspects
+            .iter()
+            .map(|(aspect, count)| (format!("{:.2}", *aspect as f64 / 20.0), *count as f64, None))
+            .collect(),
+        unit: "images".to_string(),
+    });
+
+    // Formats and channel statistics
+    blocks.push(Block::Heading("Bit depth and channels".to_string()));
+    blocks.push(Block::Table {
+        headers: vec!["Depth".into(), "Channels".into(), "Images".into()],
+        rows: formats
+            .iter()
+            .map(|((depth, channels), count)| {
+                vec![depth.clone(), channels.to_string(), count.to_string()]
+            })
+            .collect(),
+    });
+    if formats.keys().map(|(_, c)| c).collect::<HashSet<_>>().len() == 1 {
+        let mut accumulators = Vec::<ChannelAccumulator>::new();
+        for sample in stats {
+            if accumulators.is_empty() {
+                accumulators = vec![ChannelAccumulator::default(); sample.accumulators.len()];
+            }
+            for (total, accumulator) in accumulators.iter_mut().zip(&sample.accumulators) {
+                total.merge(accumulator.clone());
+            }
+        }
+        let order = match accumulators.len() {
+            3 => vec!["R", "G", "B"],
+            4 => vec!["R", "G", "B", "A"],
+            n => (0..n).map(|_| "").collect(),
+        };
+        blocks.push(Block::Table {
+            headers: vec!["Channel".into(), "Mean".into(), "Std".into()],
+            rows: accumulators
+                .iter()
+                .enumerate()
+                .map(|(index, accumulator)| {
+                    vec![
+                        format!("{} {}", index, order[index]).trim().to_string(),
+                        format!("{:.4}", accumulator.mean),
+                        format!("{:.4}", accumulator.std()),
+                    ]
+                })
+                .collect(),
+        });
+    } else {
+        blocks.push(Block::Paragraph(
+            "Images have different channel counts, channel statistics are skipped".to_string(),
+        ));
+    }
+
+    if labeled == 0 {
+        return blocks;
+    }
+
+    // Classes
+    let mut total = SplitTotals::default();
+    let mut splits = BTreeMap::<String, SplitTotals>::new();
+    for sample in stats {
+        if let Some(classes) = &sample.classes {
+            let image_pixels = sample.width as u64 * sample.height as u64;
+            total.add(classes, image_pixels);
+            splits
+                .entry(sample.split.clone())
+                .or_default()
+                .add(classes, image_pixels);
+        }
+    }
+    let color = |class: &str| -> Option<[u8; 3]> {
+        if label_kind != LabelKind::Rgb {
+            return None;
+        }
+        let rgb = class
+            .split(',')
+            .map(|v| v.parse::<u8>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        Some([rgb[0], rgb[1], rgb[2]])
+    };
+
+    blocks.push(Block::Heading("Classes".to_string()));
+    blocks.push(Block::Table {
+        headers: vec![
+            "Class".into(),
+            "Pixels".into(),
+            "Pixel share".into(),
+            "Images".into(),
+            "Presence rate".into(),
+            "Components".into(),
+            "Mean component size".into(),
+        ],
+        rows: total
+            .classes
+            .iter()
+            .map(|(class, totals)| {
+                vec![
+                    class.clone(),
+                    totals.count.pixels.to_string(),
+                    percent(totals.count.pixels, total.labeled_pixels),
+                    totals.images.to_string(),
+                    percent(totals.images, total.images),
+                    totals.components.to_string(),
+                    format!(
+                        "{:.1}",
+                        totals.count.pixels as f64 / totals.components.max(1) as f64
+                    ),
+                ]
+            })
+            .collect(),
+    });
+    blocks.push(Block::Chart {
+        title: "Pixel share".to_string(),
+        bars: total
+            .classes
+            .iter()
+            .map(|(class, totals)| {
+                (
+                    class.clone(),
+                    totals.count.pixels as f64 / total.labeled_pixels.max(1) as f64 * 100.0,
+                    color(class),
+                )
+            })
+            .collect(),
+        unit: "%".to_string(),
+    });
+    blocks.push(Block::Chart {
+        title: "Image presence rate".to_string(),
+        bars: total
+            .classes
+            .iter()
+            .map(|(class, totals)| {
+                (
+                    class.clone(),
+                    totals.images as f64 / total.images.max(1) as f64 * 100.0,
+                    color(class),
+                )
+            })
+            .collect(),
+        unit: "%".to_string(),
+    });
+
+    // Component size histogram, only the buckets used by some class
+    let used_buckets = (0..COMPONENT_BUCKETS)
+        .filter(|bucket| {
+            total
+                .classes
+                .values()
+                .any(|t| t.component_buckets[*bucket] > 0)
+        })
+        .collect::<Vec<_>>();
+    blocks.push(Block::Heading(
+        "Connected component sizes (pixels)".to_string(),
+    ));
+    let mut headers = vec!["Class".to_string()];
+    headers.extend(used_buckets.iter().map(|bucket| bucket_name(*bucket)));
+    blocks.push(Block::Table {
+        headers,
+        rows: total
+            .classes
+            .iter()
+            .map(|(class, totals)| {
+                let mut row = vec![class.clone()];
+                row.extend(
+                    used_buckets
+                        .iter()
+                        .map(|bucket| totals.component_buckets[*bucket].to_string()),
+                );
+                row
+            })
+            .collect(),
+    });
+    blocks.push(Block::Chart {
+        title: "Components per class".to_string(),
+        bars: total
+            .classes
+            .iter()
+            .map(|(class, totals)| (class.clone(), totals.components as f64, color(class)))
+            .collect(),
+        unit: "components".to_string(),
+    });
+
+    // Splits
+    blocks.push(Block::Heading("Splits".to_string()));
+    let mut headers = vec!["Split".to_string(), "Images".to_string()];
+    headers.extend(total.classes.keys().map(|class| format!("{} share", class)));
+    blocks.push(Block::Table {
+        headers,
+        rows: splits
+            .iter()
+            .map(|(split, totals)| {
+                let mut row = vec![split.clone(), totals.images.to_string()];
+                row.extend(total.classes.keys().map(|class| {
+                    percent(
+                        totals
+                            .classes
+                            .get(class)
+                            .map(|t| t.count.pixels)
+                            .unwrap_or(0),
+                        totals.labeled_pixels,
+                    )
+                }));
+                row
+            })
+            .collect(),
+    });
+    blocks.push(Block::Chart {
+        title: "Images per split".to_string(),
+        bars: splits
+            .iter()
+            .map(|(split, totals)| (split.clone(), totals.images as f64, None))
+            .collect(),
+        unit: "images".to_string(),
+    });
+
+    blocks
+}
+
+fn percent(value: u64, total: u64) -> String {
+    format!("{:.2}%", value as f64 / total.max(1) as f64 * 100.0)
+}
+
+fn bucket_name(bucket: usize) -> String {
+    let low = 1u64 << bucket;
+    let high = (1u64 << (bucket + 1)) - 1;
+    if low == high {
+        low.to_string()
+    } else {
+        format!("{}-{}", low, high)
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Inline SVG horizontal bar chart
+fn svg_chart(bars: &[(String, f64, Option<[u8; 3]>)], unit: &str) -> String {
+    const LABEL_WIDTH: f64 = 140.0;
+    const BAR_WIDTH: f64 = 420.0;
+    const ROW_HEIGHT: f64 = 20.0;
+    let max = bars.iter().map(|(_, v, _)| *v).fold(0.0, f64::max);
+    let height = ROW_HEIGHT * bars.len() as f64 + 4.0;
+
+    let mut svg = String::new();
+    let _ = write!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" font-family="sans-serif" font-size="12">"#,
+        LABEL_WIDTH + BAR_WIDTH + 120.0,
+        height
+    );
+    for (index, (label, value, color)) in bars.iter().enumerate() {
+        let y = index as f64 * ROW_HEIGHT + 2.0;
+        let width = if max > 0.0 {
+            value / max * BAR_WIDTH
+        } else {
+            0.0
+        };
+        let fill = match color {
+            Some([r, g, b]) => format!("rgb({},{},{})", r, g, b),
+            None => "#4c78a8".to_string(),
+        };
+        let _ = write!(
+            svg,
+            r##"<text x="{}" y="{}" text-anchor="end">{}</text><rect x="{}" y="{}" width="{:.1}" height="{}" fill="{}" stroke="#333" stroke-width="0.5"/><text x="{:.1}" y="{}">{} {}</text>"##,
+            LABEL_WIDTH - 6.0,
+            y + 13.0,
+            escape_html(label),
+            LABEL_WIDTH,
+            y,
+            width,
+            ROW_HEIGHT - 4.0,
+            fill,
+            LABEL_WIDTH + width + 6.0,
+            y + 13.0,
+            format_value(*value),
+            escape_html(unit)
+        );
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+fn format_value(value: f64) -> String {
+    if value.fract() == 0.0 {
+        format!("{}", value)
+    } else {
+        format!("{:.2}", value)
+    }
+}
+
+fn render_html(blocks: &[Block]) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Dataset report</title>\n<style>\nbody { font-family: sans-serif; margin: 2em; }\ntable { border-collapse: collapse; margin: 1em 0; }\nth, td { border: 1px solid #ccc; padding: 4px 8px; text-align: right; }\nth:first-child, td:first-child { text-align: left; }\n</style>\n</head>\n<body>\n",
+    );
+    let mut first = true;
+    for block in blocks {
+        match block {
+            Block::Heading(text) => {
+                let level = if first { 1 } else { 2 };
+                first = false;
+                let _ = writeln!(html, "<h{0}>{1}</h{0}>", level, escape_html(text));
+            }
+            Block::Paragraph(text) => {
+                let _ = writeln!(html, "<p>{}</p>", escape_html(text));
+            }
+            Block::Table { headers, rows } => {
+                html.push_str("<table>\n<tr>");
+                for header in headers {
+                    let _ = write!(html, "<th>{}</th>", escape_html(header));
+                }
+                html.push_str("</tr>\n");
+                for row in rows {
+                    html.push_str("<tr>");
+                    for cell in row {
+                        let _ = write!(html, "<td>{}</td>", escape_html(cell));
+                    }
+                    html.push_str("</tr>\n");
+                }
+                html.push_str("</table>\n");
+            }
+            Block::Chart { title, bars, unit } => {
+                let _ = writeln!(
+                    html,
+                    "<h3>{}</h3>\n{}",
+                    escape_html(title),
+                    svg_chart(bars, unit)
+                );
+            }
+        }
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Markdown with text bar charts, readable without any renderer
+fn render_markdown(blocks: &[Block]) -> String {
+    const BAR_WIDTH: f64 = 40.0;
+    let mut markdown = String::new();
+    let mut first = true;
+    for block in blocks {
+        match block {
+            Block::Heading(text) => {
+                let level = if first { "#" } else { "##" };
+                first = false;
+                let _ = writeln!(markdown, "{} {}\n", level, text);
+            }
+            Block::Paragraph(text) => {
+                let _ = writeln!(markdown, "{}\n", text);
+            }
+            Block::Table { headers, rows } => {
+                let _ = writeln!(markdown, "| {} |", headers.join(" | "));
+                let _ = writeln!(
+                    markdown,
+                    "|{}",
+                    headers.iter().map(|_| " --- |").collect::<String>()
+                );
+                for row in rows {
+                    let _ = writeln!(markdown, "| {} |", row.join(" | "));
+                }
+                markdown.push('\n');
+            }
+            Block::Chart { title, bars, unit } => {
+                let _ = writeln!(markdown, "### {}\n\n```text", title);
+                let max = bars.iter().map(|(_, v, _)| *v).fold(0.0, f64::max);
+                let label_width = bars.iter().map(|(l, _, _)| l.len()).max().unwrap_or(0);
+                for (label, value, _) in bars {
+                    let length = if max > 0.0 {
+                        (value / max * BAR_WIDTH).round() as usize
+                    } else {
+                        0
+                    };
+                    let _ = writeln!(
+                        markdown,
+                        "{:>width$} | {} {} {}",
+                        label,
+                        "█".repeat(length),
+                        format_value(*value),
+                        unit,
+                        width = label_width
+                    );
+                }
+                markdown.push_str("```\n\n");
+            }
+        }
+    }
+    markdown
+}
@@ -0,0 +1,219 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+
+use super::histogram::read_histogram_csv;
+use super::paths::OVERSAMPLED_SUFFIX;
+use super::weights::{compute_class_weights, save_class_weights, ClassCount, WeightOptions};
+use super::DatasetItem;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WeightScheme {
+    InverseFrequency,
+    MedianFrequency,
+    Enet,
+    EffectiveNumber,
+}
+
+impl WeightScheme {
+    /// Key of the scheme in [`super::weights::ClassWeights::weights`]
+    fn key(&self) -> &'static str {
+        match self {
+            WeightScheme::InverseFrequency => "inverse_frequency",
+            WeightScheme::MedianFrequency => "median_frequency",
+            WeightScheme::Enet => "enet",
+            WeightScheme::EffectiveNumber => "effective_number",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WeightReduce {
+    /// Weight of the rarest class present in the image
+    Max,
+    /// Class weights averaged by their pixel fraction in the image
+    Mean,
+}
+
+/// Per-sample weights and an oversampled list for `<dataset_path>/<split>.json`.
+///
+/// Class weights are computed with `scheme` from the class histogram CSV of the split
+/// labels (see `class-histogram`), each sample then gets the weight of its classes reduced
+/// with `reduce`, normalized to a mean of 1. Writes next to the list:
+/// - `<split>_weights.json`: weights in list order, for `WeightedRandomSampler`
+/// - `<split>_oversampled.json`: the list with every sample repeated weight / min weight
+///   times, at most `max_repeat`
+/// - the class weights of the split, only with `--weights-path` so the `class_weights.json`
+///   of `count-classes` / `count-rgb` is not replaced, see [`save_class_weights`]
+#[allow(clippy::too_many_arguments)]
+pub fn sample_weights(
+    dataset_path: &String,
+    split: &str,
+    histogram_path: &str,
+    scheme: WeightScheme,
+    reduce: WeightReduce,
+    ignore_classes: Option<&str>,
+    min_fraction: f64,
+    max_repeat: u32,
+    weight_options: &WeightOptions,
+) -> Result<()> {
+    if max_repeat == 0 {
+        bail!("max repeat should be at least 1");
+    }
+    let dataset_path = PathBuf::from(dataset_path);
+    let list_path = dataset_path.join(format!("{}.json", split));
+    let items: Vec<DatasetItem> = serde_json::from_str(
+        &fs::read_to_string(&list_path)
+            .with_context(|| format!("Reading {}", list_path.display()))?,
+    )
+    .with_context(|| format!("Parsing {}", list_path.display()))?;
+    if items.is_empty() {
+        bail!("{} is empty", list_path.display());
+    }
+
+    let (classes, histograms) = read_histogram_csv(Path::new(histogram_path))?;
+    let histograms = histograms
+        .into_iter()
+        .map(|h| (h.label.clone(), h))
+        .collect::<HashMap<_, _>>();
+    let ignored = ignore_classes
+        .map(|list| {
+            list.split(';')
+                .map(|v| v.split(',').map(|s| s.trim()).collect::<Vec<_>>().join(","))
+                .collect::<BTreeSet<_>>()
+        })
+        .unwrap_or_default();
+    let classes = classes
+        .into_iter()
+        .filter(|class| !ignored.contains(class))
+        .collect::<Vec<_>>();
+
+    // The histogram rows are keyed by canonical label path,
+    // relative list entries are relative to the list folder
+    let mut item_histograms = Vec::with_capacity(items.len());
+    let mut missing = Vec::new();
+    for item in &items {
+        let key = fs::canonicalize(dataset_path.join(&item.label))
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_else(|_| item.label.clone());
+        match histograms.get(&key).or_else(|| histograms.get(&item.label)) {
+            Some(histogram) => item_histograms.push(histogram),
+            None => missing.push(item.label.clone()),
+        }
+    }
+    if !missing.is_empty() {
+        bail!(
+            "{} labels of {} are not in {}, e.g. {}",
+            missing.len(),
+            list_path.display(),
+            histogram_path,
+            missing[..missing.len().min(5)].join(", ")
+        );
+    }
+
+    // Class weights over the split only
+    let counts = classes
+        .iter()
+        .map(|class| {
+            let mut count = ClassCount::default();
+            for histogram in &item_histograms {
+                let pixels = histogram.counts.get(class).copied().unwrap_or(0);
+                if pixels > 0 {
+                    count.pixels += pixels;
+                    count.image_pixels += histogram.pixels();
+                    count.images += 1;
+                }
+            }
+            (class.clone(), count)
+        })
+        .collect::<Vec<_>>();
+    let class_weights = compute_class_weights(&counts, weight_options);
+    if weight_options.weights_path.is_some() {
+        save_class_weights(&class_weights, &dataset_path, weight_options)?;
+    } else {
+        tracing::info!(
+            "Class weights of {} are not saved without --weights-path",
+            split
+        );
+    }
+    let weights = &class_weights.weights[scheme.key()];
+    let min_class_weight = weights
+        .iter()
+        .copied()
+        .filter(|w| *w > 0.0)
+        .fold(f64::INFINITY, f64::min);
+    if !min_class_weight.is_finite() {
+        bail!("No class has pixels in {}", list_path.display());
+    }
+
+    let mut sample_weights = Vec::with_capacity(items.len());
+    let mut empty = 0;
+    for histogram in &item_histograms {
+        let present = classes
+            .iter()
+            .zip(weights)
+            .map(|(class, weight)| (histogram.fraction(class), *weight))
+            .filter(|(fraction, weight)| {
+                *fraction > 0.0 && *fraction >= min_fraction && *weight > 0.0
+            })
+            .collect::<Vec<_>>();
+        let weight = match (present.is_empty(), reduce) {
+            // Only ignored classes, kept with the most common class weight
+            (true, _) => {
+                empty += 1;
+                min_class_weight
+            }
+            (false, WeightReduce::Max) => present.iter().map(|(_, w)| *w).fold(0.0, f64::max),
+            (false, WeightReduce::Mean) => {
+                present.iter().map(|(f, w)| f * w).sum::<f64>()
+                    / present.iter().map(|(f, _)| f).sum::<f64>()
+            }
+        };
+        sample_weights.push(weight);
+    }
+    if empty > 0 {
+        tracing::warn!(
+            "{} samples have no counted class, they get the lowest class weight",
+            empty
+        );
+    }
+
+    let mean = sample_weights.iter().sum::<f64>() / sample_weights.len() as f64;
+    for weight in &mut sample_weights {
+        *weight /= mean;
+    }
+    let min_weight = sample_weights.iter().copied().fold(f64::INFINITY, f64::min);
+
+    let mut oversampled = Vec::new();
+    for (item, weight) in items.iter().zip(&sample_weights) {
+        let repeat = ((weight / min_weight).round() as u32).clamp(1, max_repeat);
+        for _ in 0..repeat {
+            oversampled.push(item.clone());
+        }
+    }
+
+    let weights_path = dataset_path.join(format!("{}_weights.json", split));
+    fs::write(&weights_path, serde_json::to_string(&sample_weights)?)
+        .with_context(|| format!("Writing {}", weights_path.display()))?;
+    let oversampled_path = dataset_path.join(format!("{}{}.json", split, OVERSAMPLED_SUFFIX));
+    fs::write(&oversampled_path, serde_json::to_string(&oversampled)?)
+        .with_context(|| format!("Writing {}", oversampled_path.display()))?;
+
+    let max_weight = sample_weights.iter().copied().fold(0.0, f64::max);
+    tracing::info!(
+        "Sample weights {:.4} - {:.4} saved to {}",
+        min_weight,
+        max_weight,
+        weights_path.display()
+    );
+    tracing::info!(
+        "Oversampled list of {} items (from {}) saved to {}",
+        oversampled.len(),
+        items.len(),
+        oversampled_path.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,191 @@
+use std::collections::HashSet;
+
+use anyhow::{bail, Context, Result};
+use clap::Args;
+
+/// How a dataset list is divided, either a train ratio or named splits
+#[derive(Args, Debug, Clone)]
+pub struct SplitOptions {
+    #[arg(
+        short,
+        long,
+        required_unless_present = "splits",
+        conflicts_with = "splits",
+        help = "The ratio of train set, should be between 0 and 1, same as --splits train=R,val=1-R"
+    )]
+    pub train_ratio: Option<f32>,
+
+    #[arg(
+        long,
+        help = "Named splits with their ratios summing to 1, e.g. train=0.7,val=0.15,test=0.15"
+    )]
+    pub splits: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Split {
+    pub name: String,
+    pub ratio: f64,
+}
+
+impl SplitOptions {
+    pub fn resolve(&self) -> Result<Vec<Split>> {
+        match (&self.splits, self.train_ratio) {
+            (Some(splits), _) => parse_splits(splits),
+            (None, Some(train_ratio)) => {
+                if !(0.0..=1.0).contains(&train_ratio) {
+                    bail!("Train ratio {} should be between 0 and 1", train_ratio);
+                }
+                Ok(vec![
+                    Split {
+                        name: "train".to_string(),
+                        ratio: train_ratio as f64,
+                    },
+                    Split {
+                        name: "val".to_string(),
+                        ratio: 1.0 - train_ratio as f64,
+                    },
+                ])
+            }
+            (None, None) => bail!("Either a train ratio or splits are needed"),
+        }
+    }
+}
+
+/// Parse `name=ratio` pairs, comma separated. Names become file and folder names,
+/// so only letters, digits, `_` and `-` are accepted, ratios must sum to 1
+pub fn parse_splits(spec: &str) -> Result<Vec<Split>> {
+    let mut splits = Vec::new();
+    let mut names = HashSet::new();
+    for part in spec.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        let (name, ratio) = part
+            .split_once('=')
+            .with_context(|| format!("Malformed split {}, please use name=ratio", part))?;
+        let name = name.trim();
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            bail!("Invalid split name '{}'", name);
+        }
+        if !names.insert(name.to_string()) {
+            bail!("Split {} is given twice", name);
+        }
+        let ratio = ratio
+            .trim()
+            .parse::<f64>()
+            .with_context(|| format!("Parsing ratio of split {}", name))?;
+        if !(0.0..=1.0).contains(&ratio) {
+            bail!(
+                "Ratio {} of split {} should be between 0 and 1",
+                ratio,
+                name
+            );
+        }
+        splits.push(Split {
+            name: name.to_string(),
+            ratio,
+        });
+    }
+    if splits.is_empty() {
+        bail!("No split given");
+    }
+    let total = splits.iter().map(|x| x.ratio).sum::<f64>();
+    if (total - 1.0).abs() > 1e-6 {
+        bail!("Split ratios sum to {}, should be 1", total);
+    }
+    Ok(splits)
+}
+
+/// Divide `items` (already shuffled) by the split ratios, in split order.
+///
+/// Every split gets floor(len * ratio) items, the remaining ones go to the splits
+/// with the largest fractional parts, so no item is dropped.
+pub fn assign_splits<T>(items: Vec<T>, splits: &[Split]) -> Vec<(String, Vec<T>)> {
+    let len = items.len();
+    let exact = splits
+        .iter()
+        .map(|x| len as f64 * x.ratio)
+        .collect::<Vec<_>>();
+    let mut counts = exact.iter().map(|x| x.floor() as usize).collect::<Vec<_>>();
+    let mut remainder = len.saturating_sub(counts.iter().sum::<usize>());
+    let mut order = (0..splits.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| {
+        (exact[*b] - exact[*b].floor()).total_cmp(&(exact[*a] - exact[*a].floor()))
+    });
+    for index in order.into_iter().cycle() {
+        if remainder == 0 {
+            break;
+        }
+        counts[index] += 1;
+        remainder -= 1;
+    }
+
+    let mut items = items.into_iter();
+    splits
+        .iter()
+        .zip(counts)
+        .map(|(split, count)| (split.name.clone(), items.by_ref().take(count).collect()))
+        .collect()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{assign_splits, parse_splits};
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::train_val("train=0.8,val=0.2", &[("train", 0.8), ("val", 0.2)])]
+    #[case::three_splits(
+        "train=0.7, val=0.15, test=0.15",
+        &[("train", 0.7), ("val", 0.15), ("test", 0.15)]
+    )]
+    // 0.1 + 0.2 + 0.7 is not exactly 1 in f64
+    #[case::float_sum("a=0.1,b=0.2,c=0.7", &[("a", 0.1), ("b", 0.2), ("c", 0.7)])]
+    #[case::single_split("all=1", &[("all", 1.0)])]
+    fn parse_valid_splits(#[case] spec: &str, #[case] expected: &[(&str, f64)]) {
+        let splits = parse_splits(spec).unwrap();
+        let splits = splits
+            .iter()
+            .map(|x| (x.name.as_str(), x.ratio))
+            .collect::<Vec<_>>();
+        assert_eq!(splits, expected);
+    }
+
+    #[rstest]
+    #[case::sum_below_one("train=0.5,val=0.4")]
+    #[case::sum_above_one("train=0.8,val=0.3")]
+    #[case::sum_off_by_more_than_tolerance("train=0.7,val=0.29999")]
+    #[case::duplicate_name("train=0.5,train=0.5")]
+    #[case::invalid_name("train/a=0.5,val=0.5")]
+    #[case::missing_ratio("train,val=1")]
+    #[case::ratio_out_of_range("train=1.5,val=-0.5")]
+    #[case::empty("")]
+    fn parse_invalid_splits(#[case] spec: &str) {
+        assert!(parse_splits(spec).is_err());
+    }
+
+    #[rstest]
+    #[case::exact("train=0.8,val=0.2", 10, &[8, 2])]
+    // 1.5 + 1.5, the tie goes to the first split
+    #[case::tie("train=0.7,val=0.15,test=0.15", 10, &[7, 2, 1])]
+    // 2.4 / 0.3 / 0.3, the largest fractional part gets the remaining item
+    #[case::largest_fraction("train=0.8,val=0.1,test=0.1", 3, &[3, 0, 0])]
+    #[case::near_thirds("a=0.34,b=0.33,c=0.33", 100, &[34, 33, 33])]
+    #[case::fewer_items_than_splits("a=0.4,b=0.3,c=0.3", 2, &[1, 1, 0])]
+    #[case::no_item("train=0.8,val=0.2", 0, &[0, 0])]
+    fn assign_split_counts(#[case] spec: &str, #[case] len: usize, #[case] expected: &[usize]) {
+        let splits = parse_splits(spec).unwrap();
+        let assigned = assign_splits((0..len).collect(), &splits);
+        let counts = assigned.iter().map(|(_, x)| x.len()).collect::<Vec<_>>();
+        assert_eq!(counts, expected);
+        // Every item is kept, in order
+        let items = assigned
+            .into_iter()
+            .flat_map(|(_, x)| x)
+            .collect::<Vec<_>>();
+        assert_eq!(items, (0..len).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,476 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Args;
+use indicatif::ProgressStyle;
+use opencv::{
+    core::{self, Mat, MatTraitConst, MatTraitConstManual},
+    imgcodecs,
+};
+use serde::Serialize;
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::info_span;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+use super::pairing::{index_by_relparent_and_stem, relparent_and_stem, DEFAULT_LABEL_EXTENSIONS};
+use crate::common::walk::{WalkOptions, IMAGE_EXTENSIONS};
+use crate::THREAD_POOL;
+
+/// Float images keep about this many valid pixels per image for the percentiles
+const FLOAT_SAMPLES_PER_IMAGE: u64 = 100_000;
+
+#[derive(Args, Debug, Clone)]
+pub struct MeanStdOptions {
+    #[arg(
+        long,
+        value_delimiter = ',',
+        allow_hyphen_values = true,
+        help = "Nodata values, a pixel is skipped when all its channels equal one of them, comma separated"
+    )]
+    pub nodata: Vec<f64>,
+
+    #[arg(
+        long,
+        help = "The path for the labels folder, pixels with one of --ignore-labels are skipped"
+    )]
+    pub label_path: Option<String>,
+
+    #[arg(
+        long,
+        value_delimiter = ';',
+        requires = "label_path",
+        help = "Ignored label values, class id or R,G,B, in V0;V1 format, none by default"
+    )]
+    pub ignore_labels: Vec<String>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "1,5,50,95,99",
+        help = "Percentiles to report, comma separated"
+    )]
+    pub percentiles: Vec<f64>,
+
+    #[arg(
+        short,
+        long,
+        help = "The path for the statistics JSON, defaults to <dataset_path>/mean_std.json"
+    )]
+    pub save_path: Option<String>,
+}
+
+/// Running statistics of one channel
+#[derive(Debug, Clone, Default)]
+pub(super) struct ChannelAccumulator {
+    pub(super) count: u64,
+    pub(super) mean: f64,
+    /// Sum of squared differences to the mean (Welford)
+    m2: f64,
+    min: f64,
+    max: f64,
+    /// Value bits -> weight, exact counts for integer images, strided samples for float ones
+    histogram: HashMap<u64, u64>,
+}
+
+impl ChannelAccumulator {
+    fn push(&mut self, value: f64) {
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    /// Chan et al. pooled merge, exact whatever the image sizes
+    pub(super) fn merge(&mut self, other: ChannelAccumulator) {
+        if other.count == 0 {
+            return;
+        }
+        if self.count == 0 {
+            *self = other;
+            return;
+        }
+        let count = self.count + other.count;
+        let delta = other.mean - self.mean;
+        self.mean += delta * other.count as f64 / count as f64;
+        self.m2 += other.m2 + delta * delta * self.count as f64 * other.count as f64 / count as f64;
+        self.count = count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        for (value, weight) in other.histogram {
+            *self.histogram.entry(value).or_insert(0) += weight;
+        }
+    }
+
+    pub(super) fn std(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            (self.m2 / self.count as f64).sqrt()
+        }
+    }
+
+    fn percentile(&self, sorted: &[(f64, u64)], total: u64, p: f64) -> f64 {
+        if sorted.is_empty() {
+            return 0.0;
+        }
+        let target = (p / 100.0 * total as f64).ceil().max(1.0) as u64;
+        let mut cumulative = 0;
+        for (value, weight) in sorted {
+            cumulative += weight;
+            if cumulative >= target {
+                return *value;
+            }
+        }
+        self.max
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelStats {
+    pub valid_pixels: u64,
+    pub mean: f64,
+    /// Population std over all valid pixels
+    pub std: f64,
+    pub min: f64,
+    pub max: f64,
+    pub percentiles: BTreeMap<String, f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DatasetStats {
+    pub images: usize,
+    /// "rgb" / "rgba" when 3 / 4 channel images were swapped from OpenCV BGR, otherwise "file"
+    pub channel_order: String,
+    /// Per channel, ready for a normalization transform
+    pub mean: Vec<f64>,
+    pub std: Vec<f64>,
+    pub channels: Vec<ChannelStats>,
+    pub nodata: Vec<f64>,
+    pub ignore_labels: Vec<String>,
+}
+
+/// Exact pooled per-channel mean / std over every valid pixel of the dataset,
+/// with min / max and percentiles (sampled for float images), saved as JSON.
+///
+/// Pixels whose channels all equal a nodata value, or whose label (paired by
+/// relative folder + stem under `label_path`) is ignored, are left out.
+pub async fn calc_mean_std(
+    dataset_path: &String,
+    walk: &WalkOptions,
+    options: &MeanStdOptions,
+) -> Result<()> {
+    let dataset_path = PathBuf::from(dataset_path);
+    let label_root = options.label_path.as_ref().map(PathBuf::from);
+    let skip_dirs = label_root.iter().map(PathBuf::as_path).collect::<Vec<_>>();
+    let entries = walk.collect_files(&dataset_path, IMAGE_EXTENSIONS, &skip_dirs)?;
+
+    let label_index = match &label_root {
+        Some(label_root) => {
+            let extensions = DEFAULT_LABEL_EXTENSIONS
+                .split(',')
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            Some(
+                index_by_relparent_and_stem(label_root, &extensions, walk.recursive)
+                    .with_context(|| format!("Indexing labels {}", label_root.display()))?,
+            )
+        }
+        None => None,
+    };
+    let ignore_labels = Arc::new(
+        options
+            .ignore_labels
+            .iter()
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(|s| s.trim().parse::<u8>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .with_context(|| format!("Parsing ignored label {}", value))
+            })
+            .collect::<Result<HashSet<_>>>()?,
+    );
+    if label_root.is_some() && ignore_labels.is_empty() {
+        tracing::warn!("No --ignore-labels given, the labels skip no pixel");
+    }
+    let nodata = Arc::new(options.nodata.clone());
+
+    let sem = Arc::new(Semaphore::new(
+        (*THREAD_POOL
+            .read()
+            .map_err(|_| anyhow!("THREAD_POOL lock poisoned"))?)
+        .into(),
+    ));
+    let mut threads = JoinSet::new();
+
+    let header_span = info_span!("calc_mean_std_thread");
+    header_span.pb_set_style(&ProgressStyle::with_template(
+        "{spinner} Processing {msg}\n{wide_bar} {pos}/{len}",
+    )?);
+    header_span.pb_set_length(entries.len() as u64);
+
+    let header_span_enter = header_span.enter();
+
+    for entry in entries {
+        let label = match &label_index {
+            Some(label_index) => {
+                let key = if entry == dataset_path {
+                    entry
+                        .file_stem()
+                        .map(|s| s.to_string_lossy().to_string())
+                        .unwrap_or_default()
+                } else {
+                    relparent_and_stem(&dataset_path, &entry)?
+                };
+                match label_index.get(&key) {
+                    Some(label) => Some(label.clone()),
+                    None => {
+                        tracing::warn!("No label for {}, skipped", entry.display());
+                        header_span.pb_inc(1);
+                        continue;
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let permit = sem.clone().acquire_owned().await?;
+        let header_span = header_span.clone();
+        let nodata = nodata.clone();
+        let ignore_labels = ignore_labels.clone();
+        threads.spawn_blocking(
+            move || -> Result<Option<(usize, Vec<ChannelAccumulator>)>> {
+                let _permit = permit;
+                let result = image_stats(&entry, label.as_deref(), &nodata, &ignore_labels)
+                    .with_context(|| format!("Processing {}", entry.display()))?;
+                header_span.pb_set_message(&entry.to_string_lossy());
+                header_span.pb_inc(1);
+                Ok(result)
+            },
+        );
+    }
+
+    let mut images = 0;
+    let mut channel_count = None;
+    let mut accumulators = Vec::<ChannelAccumulator>::new();
+    while let Some(result) = threads.join_next().await {
+        let Some((channels, image_accumulators)) = result?? else {
+            continue;
+        };
+        match channel_count {
+            None => {
+                channel_count = Some(channels);
+                accumulators = vec![ChannelAccumulator::default(); image_accumulators.len()];
+            }
+            Some(count) if count != channels => {
+                bail!(
+                    "Images have {} and {} channels, can not pool them",
+                    count,
+                    channels
+                );
+            }
+            _ => {}
+        }
+        for (accumulator, image_accumulator) in accumulators.iter_mut().zip(image_accumulators) {
+            accumulator.merge(image_accumulator);
+        }
+        images += 1;
+    }
+    drop(header_span_enter);
+    drop(header_span);
+
+    let channels = accumulators
+        .iter()
+        .map(|accumulator| {
+            let mut sorted = accumulator
+                .histogram
+                .iter()
+                .map(|(bits, weight)| (f64::from_bits(*bits), *weight))
+                .collect::<Vec<_>>();
+            sorted.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let total = sorted.iter().map(|(_, w)| w).sum::<u64>();
+            ChannelStats {
+                valid_pixels: accumulator.count,
+                mean: accumulator.mean,
+                std: accumulator.std(),
+                min: accumulator.min,
+                max: accumulator.max,
+                percentiles: options
+                    .percentiles
+                    .iter()
+                    .map(|p| (p.to_string(), accumulator.percentile(&sorted, total, *p)))
+                    .collect(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let stats = DatasetStats {
+        images,
+        channel_order: match channel_count {
+            Some(3) => "rgb",
+            Some(4) => "rgba",
+            _ => "file",
+        }
+        .to_string(),
+        mean: channels.iter().map(|c| c.mean).collect(),
+        std: channels.iter().map(|c| c.std).collect(),
+        channels,
+        nodata: options.nodata.clone(),
+        ignore_labels: if label_root.is_some() {
+            options.ignore_labels.clone()
+        } else {
+            vec![]
+        },
+    };
+
+    tracing::info!("Mean: {:?}", stats.mean);
+    tracing::info!("Std: {:?}", stats.std);
+    tracing::info!(
+        "Min: {:?}",
+        stats.channels.iter().map(|c| c.min).collect::<Vec<_>>()
+    );
+    tracing::info!(
+        "Max: {:?}",
+        stats.channels.iter().map(|c| c.max).collect::<Vec<_>>()
+    );
+
+    let save_path = match &options.save_path {
+        Some(path) => PathBuf::from(path),
+        None if dataset_path.is_file() => dataset_path
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join("mean_std.json"),
+        None => dataset_path.join("mean_std.json"),
+    };
+    fs::write(&save_path, serde_json::to_string_pretty(&stats)?)
+        .with_context(|| format!("Writing {}", save_path.display()))?;
+    tracing::info!("Dataset calculated, saved to {}", save_path.display());
+    Ok(())
+}
+
+/// Statistics of the valid pixels of one image, `None` for unreadable images
+fn image_stats(
+    path: &Path,
+    label: Option<&Path>,
+    nodata: &[f64],
+    ignore_labels: &HashSet<Vec<u8>>,
+) -> Result<Option<(usize, Vec<ChannelAccumulator>)>> {
+    let image = imgcodecs::imread(
+        path.to_str().ok_or(anyhow!("Invalid path"))?,
+        imgcodecs::IMREAD_UNCHANGED,
+    )?;
+    if image.empty() {
+        tracing::error!("Image {} is empty", path.display());
+        return Ok(None);
+    }
+
+    let label = match label {
+        Some(label) => {
+            let label = imgcodecs::imread(
+                label.to_str().ok_or(anyhow!("Invalid path"))?,
+                imgcodecs::IMREAD_UNCHANGED,
+            )?;
+            if label.empty() || label.depth() != core::CV_8U {
+                bail!("Label is not a readable 8 bit image");
+            }
+            if label.rows() != image.rows() || label.cols() != image.cols() {
+                bail!("Label size does not match the image");
+            }
+            let label = if label.is_continuous() {
+                label
+            } else {
+                label.try_clone()?
+            };
+            Some(label)
+        }
+        None => None,
+    };
+
+    let accumulators = accumulate_channels(&image, label.as_ref(), nodata, ignore_labels, true)?;
+    Ok(Some((image.channels() as usize, accumulators)))
+}
+
+/// Per channel statistics of the pixels of `image` not matching a nodata value
+/// or an ignored value of `label` (same size, 8 bit). The value histogram behind the
+/// percentiles is only filled with `histogram`
+pub(super) fn accumulate_channels(
+    image: &Mat,
+    label: Option<&Mat>,
+    nodata: &[f64],
+    ignore_labels: &HashSet<Vec<u8>>,
+    histogram: bool,
+) -> Result<Vec<ChannelAccumulator>> {
+    let channels = image.channels() as usize;
+    let is_float = matches!(image.depth(), core::CV_32F | core::CV_64F | core::CV_16F);
+
+    let mut values = Mat::default();
+    image.convert_to(&mut values, core::CV_64F, 1.0, 0.0)?;
+    let values = values.reshape(1, 0)?;
+    let values = values.data_typed::<f64>()?;
+    let pixels = values.len() / channels;
+
+    let label_values = match label {
+        Some(label) => Some((label.channels() as usize, label.reshape(1, 0)?)),
+        None => None,
+    };
+    let label_values = match &label_values {
+        Some((label_channels, label)) => Some((*label_channels, label.data_typed::<u8>()?)),
+        None => None,
+    };
+
+    let is_valid = |index: usize| -> bool {
+        let pixel = &values[index * channels..(index + 1) * channels];
+        if nodata.iter().any(|n| pixel.iter().all(|v| v == n)) {
+            return false;
+        }
+        if let Some((label_channels, label)) = label_values {
+            let label = &label[index * label_channels..(index + 1) * label_channels];
+            let key = match label_channels {
+                // BGR in OpenCV
+                3 => vec![label[2], label[1], label[0]],
+                4 => vec![label[2], label[1], label[0]],
+                _ => vec![label[0]],
+            };
+            if ignore_labels.contains(&key) {
+                return false;
+            }
+        }
+        true
+    };
+    // Float images are sampled with a fixed stride for the percentiles
+    let stride = if is_float && histogram {
+        let valid = (0..pixels).filter(|i| is_valid(*i)).count() as u64;
+        (valid / FLOAT_SAMPLES_PER_IMAGE).max(1)
+    } else {
+        1
+    };
+
+    let mut accumulators = vec![ChannelAccumulator::default(); channels];
+    for (n, index) in (0..pixels).filter(|i| is_valid(*i)).enumerate() {
+        let pixel = &values[index * channels..(index + 1) * channels];
+        for (channel, value) in pixel.iter().enumerate() {
+            // 3 / 4 channel images are reported in RGB(A) order
+            let channel = match (channels, channel) {
+                (3 | 4, 0) => 2,
+                (3 | 4, 2) => 0,
+                _ => channel,
+            };
+            let accumulator = &mut accumulators[channel];
+            accumulator.push(*value);
+            if histogram && n as u64 % stride == 0 {
+                *accumulator.histogram.entry(value.to_bits()).or_insert(0) += stride;
+            }
+        }
+    }
+    Ok(accumulators)
+}
@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::Serialize;
+
+/// Parameters of the class weighting schemes
+#[derive(Args, Debug, Clone)]
+pub struct WeightOptions {
+    #[arg(
+        long,
+        help = "The path for the class weights JSON, defaults to <dataset_path>/class_weights.json"
+    )]
+    pub weights_path: Option<String>,
+
+    #[arg(
+        long,
+        default_value = "0.9999",
+        help = "Beta of the effective number of samples weighting, over the number of images containing each class"
+    )]
+    pub beta: f64,
+
+    #[arg(
+        long,
+        default_value = "1.02",
+        help = "c of the ENet weighting 1 / ln(c + p)"
+    )]
+    pub enet_c: f64,
+}
+
+/// Pixel statistics of one class
+#[derive(Debug, Clone, Default)]
+pub struct ClassCount {
+    /// Pixels of the class over the dataset
+    pub pixels: u64,
+    /// Total pixels of the images the class appears in
+    pub image_pixels: u64,
+    /// Images the class appears in, the samples of the effective number weighting
+    pub images: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClassWeights {
+    /// Class names in weight order, the index of a class in the loss
+    pub classes: Vec<String>,
+    pub pixel_counts: Vec<u64>,
+    /// Images containing the class
+    pub image_counts: Vec<u64>,
+    /// Pixel count over all counted pixels
+    pub frequencies: Vec<f64>,
+    /// Weight vectors keyed by scheme, usable as `CrossEntropyLoss(weight=...)`.
+    /// Classes without pixels get a weight of 0
+    pub weights: BTreeMap<String, Vec<f64>>,
+    pub beta: f64,
+    pub enet_c: f64,
+}
+
+/// Compute the weighting schemes for `counts`, given in class index order
+///
+/// - `inverse_frequency`: total / (pixels * classes)
+/// - `median_frequency`: median(freq) / freq, freq being pixels / pixels of the images
+///   containing the class (Eigen & Fergus)
+/// - `enet`: 1 / ln(c + frequency) (Paszke et al.)
+/// - `effective_number`: (1 - beta) / (1 - beta^n) normalized to sum to the class count,
+///   n being the images containing the class (Cui et al.). Pixel counts would put
+///   beta^n at ~0 for every class and give uniform weights
+pub fn compute_class_weights(
+    counts: &[(String, ClassCount)],
+    options: &WeightOptions,
+) -> ClassWeights {
+    let total = counts.iter().map(|(_, c)| c.pixels).sum::<u64>();
+    let present = counts.iter().filter(|(_, c)| c.pixels > 0).count();
+    for (class, _) in counts.iter().filter(|(_, c)| c.pixels == 0) {
+        tracing::warn!("Class {} has no pixel, its weight is set to 0", class);
+    }
+
+    let frequencies = counts
+        .iter()
+        .map(|(_, c)| {
+            if total == 0 {
+                0.0
+            } else {
+                c.pixels as f64 / total as f64
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let inverse_frequency = counts
+        .iter()
+        .map(|(_, c)| {
+            if c.pixels == 0 {
+                0.0
+            } else {
+                total as f64 / (c.pixels as f64 * present as f64)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let image_frequencies = counts
+        .iter()
+        .map(|(_, c)| {
+            if c.pixels == 0 || c.image_pixels == 0 {
+                None
+            } else {
+                Some(c.pixels as f64 / c.image_pixels as f64)
+            }
+        })
+        .collect::<Vec<_>>();
+    let mut sorted = image_frequencies
+        .iter()
+        .flatten()
+        .copied()
+        .collect::<Vec<_>>();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let median = match sorted.len() {
+        0 => 0.0,
+        n if n % 2 == 1 => sorted[n / 2],
+        n => (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0,
+    };
+    let median_frequency = image_frequencies
+        .iter()
+        .map(|f| f.map(|f| median / f).unwrap_or(0.0))
+        .collect::<Vec<_>>();
+
+    let enet = counts
+        .iter()
+        .zip(&frequencies)
+        .map(|((_, c), f)| {
+            if c.pixels == 0 {
+                0.0
+            } else {
+                1.0 / (options.enet_c + f).ln()
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let effective = counts
+        .iter()
+        .map(|(_, c)| {
+            if c.pixels == 0 {
+                0.0
+            } else {
+                let effective_number = 1.0 - options.beta.powf(c.images as f64);
+                if effective_number <= 0.0 {
+                    0.0
+                } else {
+                    (1.0 - options.beta) / effective_number
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    let effective_sum = effective.iter().sum::<f64>();
+    let effective_number = effective
+        .iter()
+        .map(|w| {
+            if effective_sum > 0.0 {
+                w / effective_sum * present as f64
+            } else {
+                0.0
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut weights = BTreeMap::new();
+    weights.insert("inverse_frequency".to_string(), inverse_frequency);
+    weights.insert("median_frequency".to_string(), median_frequency);
+    weights.insert("enet".to_string(), enet);
+    weights.insert("effective_number".to_string(), effective_number);
+
+    ClassWeights {
+        classes: counts.iter().map(|(class, _)| class.clone()).collect(),
+        pixel_counts: counts.iter().map(|(_, c)| c.pixels).collect(),
+        image_counts: counts.iter().map(|(_, c)| c.images).collect(),
+        frequencies,
+        weights,
+        beta: options.beta,
+        enet_c: options.enet_c,
+    }
+}
+
+/// Log the weights and save them as JSON to `options.weights_path`,
+/// defaulting to `<dataset_path>/class_weights.json` (next to it for a single file)
+pub fn save_class_weights(
+    weights: &ClassWeights,
+    dataset_path: &Path,
+    options: &WeightOptions,
+) -> Result<()> {
+    for (scheme, values) in &weights.weights {
+        tracing::info!("{} weights: {:?}", scheme, values);
+    }
+
+    let save_path = match &options.weights_path {
+        Some(path) => Path::new(path).to_path_buf(),
+        None if dataset_path.is_file() => dataset_path
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join("class_weights.json"),
+        None => dataset_path.join("class_weights.json"),
+    };
+    fs::write(&save_path, serde_json::to_string_pretty(weights)?)
+        .with_context(|| format!("Writing {}", save_path.display()))?;
+    tracing::info!("Class weights saved to {}", save_path.display());
+    Ok(())
+}
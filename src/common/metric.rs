@@ -10,7 +10,22 @@ use std::{
 };
 use tracing_unwrap::ResultExt;
 
-pub fn calc_iou(target_img: &str, gt_img: &str) {
+use super::palette::Palette;
+
+/// Colors are (B, G, R) as read by OpenCV
+fn color_name(color: &(u8, u8, u8), palette: Option<&Palette>) -> String {
+    let rgb = [color.2, color.1, color.0];
+    match palette.and_then(|p| p.by_rgb(rgb)) {
+        Some(class) => format!("{} RGB({},{},{})", class.name, rgb[0], rgb[1], rgb[2]),
+        None => format!("RGB({},{},{})", rgb[0], rgb[1], rgb[2]),
+    }
+}
+
+/// Per color IoU, mean IoU and confusion matrix of two RGB label images.
+///
+/// With a palette the classes are named, ground truth pixels of ignored classes are skipped
+/// and the mean only covers the palette classes without the ignore flag.
+pub fn calc_iou(target_img: &str, gt_img: &str, palette: Option<&Palette>) {
     tracing::info!("Start loading images");
     let target_img = imgcodecs::imread(target_img, imgcodecs::IMREAD_COLOR)
         .expect_or_log("Open output image error");
@@ -32,6 +47,16 @@ pub fn calc_iou(target_img: &str, gt_img: &str) {
     let rows = gt_img.rows();
     let cols = gt_img.cols();
 
+    let ignored = palette
+        .map(|p| {
+            p.classes
+                .iter()
+                .filter(|class| class.ignore)
+                .map(|class| (class.rgb[2], class.rgb[1], class.rgb[0]))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
     let row_iter = ProgressAdaptor::new(0..rows);
     let row_progress = row_iter.items_processed();
     let row_total = row_iter.len();
@@ -51,6 +76,9 @@ pub fn calc_iou(target_img: &str, gt_img: &str) {
 
             let color1 = (pixel1[0], pixel1[1], pixel1[2]);
             let color2 = (pixel2[0], pixel2[1], pixel2[2]);
+            if ignored.contains(&color2) {
+                continue;
+            }
 
             {
                 *row_union.entry(color1).or_insert(0) += 1;
@@ -98,8 +126,16 @@ pub fn calc_iou(target_img: &str, gt_img: &str) {
         if *uni > 0 {
             let iou_value = inter as f64 / *uni as f64;
             iou.insert(color, iou_value);
-            total_iou += iou_value;
-            num_categories += 1;
+            let counted = match palette {
+                Some(palette) => palette
+                    .by_rgb([color.2, color.1, color.0])
+                    .is_some_and(|class| !class.ignore),
+                None => true,
+            };
+            if counted {
+                total_iou += iou_value;
+                num_categories += 1;
+            }
         }
     }
 
@@ -110,26 +146,16 @@ pub fn calc_iou(target_img: &str, gt_img: &str) {
     };
 
     for (color, &iou_value) in &iou {
-        tracing::info!(
-            "IoU for color RGB({},{},{}): {}",
-            color.0,
-            color.1,
-            color.2,
-            iou_value
-        );
+        tracing::info!("IoU for {}: {}", color_name(color, palette), iou_value);
     }
     tracing::info!("Mean IoU: {}", mean_iou);
     tracing::info!("Confusion Matrix:");
     for (true_color, predictions) in &*confusion_matrix {
         for (predicted_color, count) in predictions {
             tracing::info!(
-                "True: RGB({},{},{}) Predicted: RGB({},{},{}) Count: {}",
-                true_color.0,
-                true_color.1,
-                true_color.2,
-                predicted_color.0,
-                predicted_color.1,
-                predicted_color.2,
+                "True: {} Predicted: {} Count: {}",
+                color_name(true_color, palette),
+                color_name(predicted_color, palette),
                 count
             );
         }
@@ -7,6 +7,7 @@ use std::{fs, path::PathBuf, sync::Arc};
 use tokio::{sync::Semaphore, task::JoinSet};
 use tracing_unwrap::{OptionExt, ResultExt};
 
+use super::walk::{output_path, output_root, WalkOptions, IMAGE_EXTENSIONS};
 use crate::THREAD_POOL;
 
 #[deprecated]
@@ -182,22 +183,17 @@ pub fn crop_rectangle_region(source_path: &String, target_path: &str, corners: &
     tracing::info!("Image {} done", source_path);
 }
 
-pub fn normalize(dataset_path: &String, target_max: &f64, target_min: &f64) {
-    let entries = fs::read_dir(dataset_path).expect_or_log("Failed to read directory");
-
-    fs::create_dir_all(format!("{}/output/", dataset_path))
-        .expect_or_log("Failed to create directory");
+pub fn normalize(dataset_path: &String, target_max: &f64, target_min: &f64, walk: &WalkOptions) {
+    let dataset_path = PathBuf::from(dataset_path);
+    let output_root =
+        output_root(&dataset_path, "output").expect_or_log("Failed to get output dir");
+    let entries = walk
+        .collect_files(&dataset_path, IMAGE_EXTENSIONS, &[&output_root])
+        .expect_or_log("Failed to read directory");
 
     for entry in entries {
-        let entry = entry.unwrap();
-        if entry.path().is_dir() {
-            continue;
-        }
         let img = imgcodecs::imread(
-            entry
-                .path()
-                .to_str()
-                .expect_or_log("Failed to get image path"),
+            entry.to_str().expect_or_log("Failed to get image path"),
             imgcodecs::IMREAD_UNCHANGED,
         )
         .expect_or_log("Failed to read image");
@@ -221,18 +217,12 @@ pub fn normalize(dataset_path: &String, target_max: &f64, target_min: &f64) {
         )
         .expect_or_log("Failed to normalize");
 
-        let dst_path = format!(
-            "{}/output/{}",
-            dataset_path,
-            entry
-                .path()
-                .file_name()
-                .expect_or_log("Failed to get file name")
-                .to_str()
-                .expect_or_log("Failed to convert file name to string")
-                .to_owned()
-        );
-        imgcodecs::imwrite(&dst_path, &dst, &core::Vector::new())
+        let dst_path = output_path(&dataset_path, &entry, &output_root)
+            .expect_or_log("Failed to create output dir");
+        let dst_path = dst_path
+            .to_str()
+            .expect_or_log("Failed to convert path to string");
+        imgcodecs::imwrite(dst_path, &dst, &core::Vector::new())
             .expect_or_log("Failed to write image");
         tracing::info!("Image {} done", dst_path);
     }
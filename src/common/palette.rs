@@ -0,0 +1,164 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+
+/// The classes of a label set, either from a palette file or an inline RGB list
+#[derive(Args, Debug, Clone)]
+pub struct PaletteArgs {
+    #[arg(
+        short,
+        long,
+        required_unless_present = "palette",
+        conflicts_with = "palette",
+        help = "RGB list, in R0,G0,B0;R1,G1,B1 format, a class name can follow as R,G,B,name, ids follow the list order"
+    )]
+    pub rgb_list: Option<String>,
+
+    #[arg(
+        long,
+        help = "Palette file (JSON / TOML) with the id, name, rgb and ignore flag of every class"
+    )]
+    pub palette: Option<String>,
+}
+
+impl PaletteArgs {
+    pub fn resolve(&self) -> Result<Palette> {
+        match (&self.palette, &self.rgb_list) {
+            (Some(path), _) => Palette::load(Path::new(path)),
+            (None, Some(rgb_list)) => Palette::parse_rgb_list(rgb_list),
+            (None, None) => bail!("Either an RGB list or a palette file is needed"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PaletteClass {
+    pub id: u8,
+    pub name: String,
+    /// R, G, B
+    pub rgb: [u8; 3],
+    /// Left out of exports, weights and metrics
+    #[serde(default)]
+    pub ignore: bool,
+}
+
+/// Palette file, e.g. in TOML
+/// ```toml
+/// [[classes]]
+/// id = 0
+/// name = "background"
+/// rgb = [0, 0, 0]
+/// ignore = true
+/// ```
+/// or the same `{"classes": [...]}` layout in JSON
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Palette {
+    pub classes: Vec<PaletteClass>,
+}
+
+impl Palette {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content =
+            fs::read_to_string(path).with_context(|| format!("Reading {}", path.display()))?;
+        let palette: Palette = match path.extension().and_then(|s| s.to_str()) {
+            Some("toml") => {
+                toml::from_str(&content).with_context(|| format!("Parsing {}", path.display()))?
+            }
+            _ => serde_json::from_str(&content)
+                .with_context(|| format!("Parsing {}", path.display()))?,
+        };
+        palette
+            .validate()
+            .with_context(|| format!("Invalid palette {}", path.display()))?;
+        Ok(palette)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let content = match path.extension().and_then(|s| s.to_str()) {
+            Some("toml") => toml::to_string(self)?,
+            _ => serde_json::to_string_pretty(self)?,
+        };
+        fs::write(path, content).with_context(|| format!("Writing {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Parse the `R,G,B[,name];...` format, ids follow the list order,
+    /// names default to `R,G,B`
+    pub fn parse_rgb_list(rgb_list: &str) -> Result<Self> {
+        let mut classes = Vec::new();
+        for (id, entry) in rgb_list
+            .split(';')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .enumerate()
+        {
+            let parts = entry.split(',').map(str::trim).collect::<Vec<_>>();
+            if parts.len() != 3 && parts.len() != 4 {
+                bail!("Malformed color {}, please use R,G,B or R,G,B,name", entry);
+            }
+            let mut rgb = [0u8; 3];
+            for (value, part) in rgb.iter_mut().zip(&parts) {
+                *value = part
+                    .parse::<u8>()
+                    .with_context(|| format!("Malformed color {}, please use R,G,B", entry))?;
+            }
+            let id = u8::try_from(id).context("At most 256 classes are supported")?;
+            classes.push(PaletteClass {
+                id,
+                name: match parts.get(3) {
+                    Some(name) => name.to_string(),
+                    None => format!("{},{},{}", rgb[0], rgb[1], rgb[2]),
+                },
+                rgb,
+                ignore: false,
+            });
+        }
+        let palette = Palette { classes };
+        palette.validate()?;
+        Ok(palette)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.classes.is_empty() {
+            bail!("The palette has no class");
+        }
+        let mut ids = HashSet::new();
+        let mut colors = HashSet::new();
+        for class in &self.classes {
+            if !ids.insert(class.id) {
+                bail!("Class id {} is used twice", class.id);
+            }
+            if !colors.insert(class.rgb) {
+                bail!(
+                    "Color {},{},{} is used twice",
+                    class.rgb[0],
+                    class.rgb[1],
+                    class.rgb[2]
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Classes without the ignore flag
+    pub fn active(&self) -> impl Iterator<Item = &PaletteClass> {
+        self.classes.iter().filter(|class| !class.ignore)
+    }
+
+    pub fn rgb_to_id(&self) -> HashMap<[u8; 3], u8> {
+        self.classes
+            .iter()
+            .map(|class| (class.rgb, class.id))
+            .collect()
+    }
+
+    pub fn by_rgb(&self, rgb: [u8; 3]) -> Option<&PaletteClass> {
+        self.classes.iter().find(|class| class.rgb == rgb)
+    }
+}
+
+pub mod discover;
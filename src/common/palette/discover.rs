@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use indicatif::ProgressStyle;
+use opencv::{
+    core::{MatTraitConst, MatTraitConstManual},
+    imgcodecs,
+};
+use serde::Serialize;
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::info_span;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+use super::{Palette, PaletteClass};
+use crate::common::walk::{WalkOptions, IMAGE_EXTENSIONS};
+use crate::THREAD_POOL;
+
+#[derive(Debug, Clone, Serialize)]
+struct ColorStats {
+    rgb: [u8; 3],
+    pixels: u64,
+    /// Label images containing the color
+    images: u64,
+    fraction: f64,
+    /// The frequent color this one is likely noise of, e.g. anti-aliasing or JPEG artifacts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    noise_of: Option<[u8; 3]>,
+}
+
+fn distance(a: &[u8; 3], b: &[u8; 3]) -> f64 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (*a as f64 - *b as f64).powi(2))
+        .sum::<f64>()
+        .sqrt()
+}
+
+/// Scan the RGB label images under `dataset_path` and write a draft palette.
+///
+/// Every distinct color is counted in pixels and images. A color is flagged as noise when
+/// its pixel fraction is below `rare_fraction` and a color reaching `rare_fraction`, at
+/// least `frequent_factor` times more frequent, lies within `max_distance` (euclidean, in
+/// RGB). The other colors become classes named `class_<id>`, ids by decreasing pixel
+/// count, ready to be renamed.
+///
+/// The palette is saved to `save_path` (JSON / TOML by extension), defaulting to
+/// `<dataset_path>/palette_draft.toml`, the color statistics to `palette_colors.json`
+/// next to it.
+pub async fn discover_palette(
+    dataset_path: &str,
+    save_path: Option<&str>,
+    rare_fraction: f64,
+    max_distance: f64,
+    frequent_factor: f64,
+    walk: &WalkOptions,
+) -> Result<()> {
+    let dataset_path = PathBuf::from(dataset_path);
+    let entries = walk.collect_files(&dataset_path, IMAGE_EXTENSIONS, &[])?;
+    if entries.is_empty() {
+        bail!("No label image found in {}", dataset_path.display());
+    }
+
+    let sem = Arc::new(Semaphore::new(
+        (*THREAD_POOL
+            .read()
+            .map_err(|_| anyhow!("THREAD_POOL lock poisoned"))?)
+        .into(),
+    ));
+    let mut threads = JoinSet::new();
+
+    let header_span = info_span!("discover_palette_threads");
+    header_span.pb_set_style(&ProgressStyle::with_template(
+        "{spinner} Processing {msg}\n{wide_bar} {pos}/{len}",
+    )?);
+    header_span.pb_set_length(entries.len() as u64);
+
+    let header_span_enter = header_span.enter();
+
+    for entry in entries {
+        let permit = sem.clone().acquire_owned().await?;
+        let header_span = header_span.clone();
+        threads.spawn_blocking(move || -> Result<HashMap<[u8; 3], u64>> {
+            let _permit = permit;
+            let counts =
+                count_colors(&entry).with_context(|| format!("Processing {}", entry.display()))?;
+            header_span.pb_set_message(&entry.to_string_lossy());
+            header_span.pb_inc(1);
+            Ok(counts)
+        });
+    }
+
+    let mut totals = HashMap::<[u8; 3], (u64, u64)>::new();
+    while let Some(result) = threads.join_next().await {
+        for (rgb, pixels) in result?? {
+            let total = totals.entry(rgb).or_default();
+            total.0 += pixels;
+            total.1 += 1;
+        }
+    }
+    drop(header_span_enter);
+    drop(header_span);
+
+    let total_pixels = totals.values().map(|(pixels, _)| pixels).sum::<u64>();
+    let mut colors = totals
+        .into_iter()
+        .map(|(rgb, (pixels, images))| ColorStats {
+            rgb,
+            pixels,
+            images,
+            fraction: pixels as f64 / total_pixels.max(1) as f64,
+            noise_of: None,
+        })
+        .collect::<Vec<_>>();
+    colors.sort_by(|a, b| b.pixels.cmp(&a.pixels).then(a.rgb.cmp(&b.rgb)));
+
+    // Colors are sorted by pixels, the frequent ones come first. There are at most
+    // 1 / rare_fraction of them, so noisy labels with many rare colors stay linear
+    let frequent = colors
+        .iter()
+        .take_while(|c| c.fraction >= rare_fraction)
+        .map(|c| (c.rgb, c.pixels))
+        .collect::<Vec<_>>();
+    for color in colors.iter_mut().skip(frequent.len()) {
+        color.noise_of = frequent
+            .iter()
+            .filter(|(_, pixels)| *pixels as f64 >= color.pixels as f64 * frequent_factor)
+            .map(|(rgb, _)| (distance(rgb, &color.rgb), *rgb))
+            .filter(|(d, _)| *d <= max_distance)
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, rgb)| rgb);
+    }
+
+    let kept = colors
+        .iter()
+        .filter(|c| c.noise_of.is_none())
+        .collect::<Vec<_>>();
+    if kept.len() > 256 {
+        bail!(
+            "{} distinct colors remain, at most 256 classes are supported, try a larger rare fraction or distance",
+            kept.len()
+        );
+    }
+    let palette = Palette {
+        classes: kept
+            .iter()
+            .enumerate()
+            .map(|(id, c)| PaletteClass {
+                id: id as u8,
+                name: format!("class_{}", id),
+                rgb: c.rgb,
+                ignore: false,
+            })
+            .collect(),
+    };
+
+    for color in &colors {
+        match color.noise_of {
+            None => tracing::info!(
+                "RGB({},{},{}): {} pixels in {} images ({:.4}%)",
+                color.rgb[0],
+                color.rgb[1],
+                color.rgb[2],
+                color.pixels,
+                color.images,
+                color.fraction * 100.0
+            ),
+            Some(of) => tracing::debug!(
+                "RGB({},{},{}): {} pixels, noise of RGB({},{},{})",
+                color.rgb[0],
+                color.rgb[1],
+                color.rgb[2],
+                color.pixels,
+                of[0],
+                of[1],
+                of[2]
+            ),
+        }
+    }
+    let noise = colors.iter().filter(|c| c.noise_of.is_some()).count();
+    if noise > 0 {
+        tracing::warn!(
+            "{} colors ({} pixels) look like noise and are left out of the palette",
+            noise,
+            colors
+                .iter()
+                .filter(|c| c.noise_of.is_some())
+                .map(|c| c.pixels)
+                .sum::<u64>()
+        );
+    }
+
+    let save_path = match save_path {
+        Some(path) => PathBuf::from(path),
+        None if dataset_path.is_file() => dataset_path
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join("palette_draft.toml"),
+        None => dataset_path.join("palette_draft.toml"),
+    };
+    palette.save(&save_path)?;
+    let colors_path = save_path.with_file_name("palette_colors.json");
+    fs::write(&colors_path, serde_json::to_string_pretty(&colors)?)
+        .with_context(|| format!("Writing {}", colors_path.display()))?;
+
+    tracing::info!(
+        "Draft palette of {} classes saved to {}",
+        palette.classes.len(),
+        save_path.display()
+    );
+    tracing::info!("Color statistics saved to {}", colors_path.display());
+    Ok(())
+}
+
+fn count_colors(path: &Path) -> Result<HashMap<[u8; 3], u64>> {
+    let label = imgcodecs::imread(
+        path.to_str().ok_or(anyhow!("Invalid path"))?,
+        imgcodecs::IMREAD_COLOR,
+    )?;
+    if label.empty() {
+        bail!("Label is not readable");
+    }
+    let label = if label.is_continuous() {
+        label
+    } else {
+        label.try_clone()?
+    };
+
+    let flat = label.reshape(1, 0)?;
+    let mut counts = HashMap::new();
+    // BGR in OpenCV
+    for pixel in flat.data_typed::<u8>()?.chunks_exact(3) {
+        *counts.entry([pixel[2], pixel[1], pixel[0]]).or_insert(0) += 1;
+    }
+    Ok(counts)
+}
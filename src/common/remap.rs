@@ -13,17 +13,20 @@ use rayon::{
     slice::{ParallelSlice, ParallelSliceMut},
 };
 
-use std::{collections::HashMap, fs, path::PathBuf, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 use tokio::{sync::Semaphore, task::JoinSet};
 use tracing::{info_span, Span};
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 
+use super::palette::Palette;
+use super::walk::{output_path, output_root, WalkOptions, IMAGE_EXTENSIONS};
 use crate::THREAD_POOL;
 
 pub async fn remap_color(
     original_color: &str,
     new_color: &str,
     dataset_path: &String,
+    walk: &WalkOptions,
 ) -> Result<()> {
     let mut original_color_vec: Vec<u8> = vec![];
     for splited in original_color.split(',') {
@@ -43,23 +46,9 @@ pub async fn remap_color(
         bail!("Malformed color RGB, please use R,G,B format");
     }
 
-    let mut entries: Vec<PathBuf> = Vec::new();
     let dataset_path = PathBuf::from(dataset_path);
-    if dataset_path.is_file() {
-        entries.push(dataset_path.clone());
-        fs::create_dir_all(
-            dataset_path
-                .parent()
-                .ok_or(anyhow!("Failed to get dataset path parent dir"))?
-                .join("output"),
-        )?;
-    } else {
-        entries = fs::read_dir(&dataset_path)
-            .context(format!("Failed to read dir {:?}", dataset_path))?
-            .map(|x| x.map(|e| e.path()))
-            .collect::<Result<Vec<_>, _>>()?;
-        fs::create_dir_all(dataset_path.join("output"))?;
-    }
+    let output_root = output_root(&dataset_path, "output")?;
+    let entries = walk.collect_files(&dataset_path, IMAGE_EXTENSIONS, &[&output_root])?;
 
     let mut threads = JoinSet::new();
     let semaphore = Arc::new(Semaphore::new(
@@ -80,7 +69,8 @@ pub async fn remap_color(
     let header_span_enter = header_span.enter();
 
     for entry in entries {
-        let entry = entry.clone();
+        let dataset_path = dataset_path.clone();
+        let output_root = output_root.clone();
         let sem = semaphore.clone();
         let original_color = original_color_vec.clone();
         let new_color = new_color_vec.clone();
@@ -142,11 +132,7 @@ pub async fn remap_color(
             }
 
             imwrite(
-                entry
-                    .parent()
-                    .ok_or(anyhow!("Failed to get {:?} parent dir", entry))?
-                    .join("output")
-                    .join(&file_name)
+                output_path(&dataset_path, &entry, &output_root)?
                     .to_str()
                     .ok_or(anyhow!("Failed to convert path to string"))?,
                 &img,
@@ -173,6 +159,7 @@ pub async fn remap_background_color(
     valid_colors: &str,
     new_color: &str,
     dataset_path: &String,
+    walk: &WalkOptions,
 ) -> Result<()> {
     let mut valid_color_vec: Vec<Vec<u8>> = vec![];
     for valid_color in valid_colors.split(";") {
@@ -199,22 +186,9 @@ pub async fn remap_background_color(
         bail!("Malformed color RGB, please use R,G,B format");
     }
 
-    let mut entries: Vec<PathBuf> = Vec::new();
     let dataset_path = PathBuf::from(dataset_path);
-    if dataset_path.is_file() {
-        entries.push(dataset_path.clone());
-        fs::create_dir_all(
-            dataset_path
-                .parent()
-                .ok_or(anyhow!("Failed to get dataset path parent dir"))?
-                .join("output"),
-        )?;
-    } else {
-        entries = fs::read_dir(dataset_path.clone())?
-            .map(|x| x.map(|e| e.path()))
-            .collect::<Result<Vec<_>, _>>()?;
-        fs::create_dir_all(dataset_path.join("output"))?;
-    }
+    let output_root = output_root(&dataset_path, "output")?;
+    let entries = walk.collect_files(&dataset_path, IMAGE_EXTENSIONS, &[&output_root])?;
 
     let mut threads = JoinSet::new();
     let semaphore = Arc::new(Semaphore::new(
@@ -235,7 +209,8 @@ pub async fn remap_background_color(
     let header_span_enter = header_span.enter();
 
     for entry in entries {
-        let entry = entry.clone();
+        let dataset_path = dataset_path.clone();
+        let output_root = output_root.clone();
         let valid_colors = valid_color_vec.clone();
         let new_color = new_color_vec.clone();
 
@@ -272,11 +247,7 @@ pub async fn remap_background_color(
                 }
             }
             imwrite(
-                entry
-                    .parent()
-                    .ok_or(anyhow!("Failed to get {:?} parent dir", entry))?
-                    .join("output")
-                    .join(&file_name)
+                output_path(&dataset_path, &entry, &output_root)?
                     .to_str()
                     .ok_or(anyhow!("Failed to convert path to string"))?,
                 &img,
@@ -298,42 +269,16 @@ pub async fn remap_background_color(
     Ok(())
 }
 
-pub async fn class2rgb(dataset_path: &str, rgb_list: &str) -> Result<()> {
-    let mut entries: Vec<PathBuf> = Vec::new();
+pub async fn class2rgb(dataset_path: &str, palette: &Palette, walk: &WalkOptions) -> Result<()> {
     let dataset_path = PathBuf::from(dataset_path);
-    if dataset_path.is_file() {
-        entries.push(dataset_path.clone());
-        fs::create_dir_all(
-            dataset_path
-                .parent()
-                .ok_or(anyhow!("Failed to get dataset path parent dir"))?
-                .join("output"),
-        )?;
-    } else {
-        entries = fs::read_dir(dataset_path.clone())?
-            .map(|x| x.map(|e| e.path()))
-            .collect::<Result<Vec<_>, _>>()?;
-        fs::create_dir_all(dataset_path.join("output"))?;
-    }
-
-    let mut transform_map = HashMap::<u8, Vec3b>::new();
-    {
-        // Split RGB list
-        for (class_id, rgb) in rgb_list.split(";").enumerate() {
-            let mut rgb_vec: Vec<u8> = vec![];
-            for splited in rgb.split(',') {
-                let splited = splited
-                    .parse::<u8>()
-                    .context("Malformed original color RGB, please use R,G,B format")?;
-                rgb_vec.push(splited);
-            }
+    let output_root = output_root(&dataset_path, "output")?;
+    let entries = walk.collect_files(&dataset_path, IMAGE_EXTENSIONS, &[&output_root])?;
 
-            transform_map.insert(
-                class_id as u8,
-                Vec3b::from_array([rgb_vec[0], rgb_vec[1], rgb_vec[2]]),
-            );
-        }
-    }
+    let transform_map = palette
+        .classes
+        .iter()
+        .map(|class| (class.id, Vec3b::from_array(class.rgb)))
+        .collect::<HashMap<u8, Vec3b>>();
 
     let mut transform_platte = vec![
         Vec3b::default();
@@ -369,9 +314,8 @@ pub async fn class2rgb(dataset_path: &str, rgb_list: &str) -> Result<()> {
     tracing::info!("Process {} files", entries.len());
 
     for entry in entries {
-        if !entry.is_file() {
-            continue;
-        }
+        let dataset_path = dataset_path.clone();
+        let output_root = output_root.clone();
         let header_span = header_span.clone();
         let transform_platte = Arc::clone(&transform_platte);
 
@@ -437,11 +381,7 @@ pub async fn class2rgb(dataset_path: &str, rgb_list: &str) -> Result<()> {
             }
 
             imwrite(
-                entry
-                    .parent()
-                    .ok_or(anyhow!("Failed to get {:?} parent dir", entry))?
-                    .join("output")
-                    .join(&file_name)
+                output_path(&dataset_path, &entry, &output_root)?
                     .to_str()
                     .ok_or(anyhow!("Failed to convert path to string"))?,
                 &output,
@@ -458,49 +398,16 @@ pub async fn class2rgb(dataset_path: &str, rgb_list: &str) -> Result<()> {
     }
     std::mem::drop(header_span_enter);
     tracing::info!("All done");
-    tracing::info!(
-        "Saved to {}",
-        dataset_path
-            .to_str()
-            .ok_or(anyhow!("Failed to convert dataset_path to string"))?
-    );
+    tracing::info!("Saved to {}", output_root.display());
     Ok(())
 }
 
-pub async fn rgb2class(dataset_path: &str, rgb_list: &str) -> Result<()> {
-    let mut entries: Vec<PathBuf> = Vec::new();
+pub async fn rgb2class(dataset_path: &str, palette: &Palette, walk: &WalkOptions) -> Result<()> {
     let dataset_path = PathBuf::from(dataset_path);
-    if dataset_path.is_file() {
-        entries.push(dataset_path.clone());
-        fs::create_dir_all(
-            dataset_path
-                .parent()
-                .ok_or(anyhow!("Failed to get dataset path parent dir"))?
-                .join("output"),
-        )?;
-    } else {
-        entries = fs::read_dir(dataset_path.clone())?
-            .map(|x| x.map(|e| e.path()))
-            .collect::<Result<Vec<_>, _>>()?;
-        fs::create_dir_all(dataset_path.join("output"))?;
-    }
-
-    let mut transform_map = HashMap::<[u8; 3], u8>::new();
-
-    // Split RGB list
-    for (class_id, rgb) in rgb_list.split(";").enumerate() {
-        let mut rgb_vec: Vec<u8> = vec![];
-        for splited in rgb.split(',') {
-            let splited = splited
-                .parse::<u8>()
-                .context("Malformed color RGB, please use R,G,B format")?;
-            rgb_vec.push(splited);
-        }
-
-        transform_map.insert([rgb_vec[0], rgb_vec[1], rgb_vec[2]], class_id as u8);
-    }
+    let output_root = output_root(&dataset_path, "output")?;
+    let entries = walk.collect_files(&dataset_path, IMAGE_EXTENSIONS, &[&output_root])?;
 
-    let transform_map = Arc::new(transform_map);
+    let transform_map = Arc::new(palette.rgb_to_id());
 
     let mut threads = JoinSet::new();
     let sem = Arc::new(Semaphore::new(
@@ -521,9 +428,8 @@ pub async fn rgb2class(dataset_path: &str, rgb_list: &str) -> Result<()> {
     let header_span_enter = header_span.enter();
 
     for entry in entries {
-        if !entry.is_file() {
-            continue;
-        }
+        let dataset_path = dataset_path.clone();
+        let output_root = output_root.clone();
         let permit = sem.clone().acquire_owned().await?;
         let transform_map = Arc::clone(&transform_map);
         let header_span = header_span.clone();
@@ -562,7 +468,7 @@ pub async fn rgb2class(dataset_path: &str, rgb_list: &str) -> Result<()> {
                     data[2] = *new_color;
                 } else {
                     bail!(
-                        "Image {} Color {}, {}, {} not found in RGB list",
+                        "Image {} Color {}, {}, {} not found in the palette",
                         file_name,
                         data[0],
                         data[1],
@@ -579,11 +485,7 @@ pub async fn rgb2class(dataset_path: &str, rgb_list: &str) -> Result<()> {
             }
 
             imwrite(
-                entry
-                    .parent()
-                    .ok_or(anyhow!("Failed to get {:?} parent dir", entry))?
-                    .join("output")
-                    .join(&file_name)
+                output_path(&dataset_path, &entry, &output_root)?
                     .to_str()
                     .ok_or(anyhow!("Failed to convert path to string"))?,
                 &img,
@@ -601,11 +503,6 @@ pub async fn rgb2class(dataset_path: &str, rgb_list: &str) -> Result<()> {
     std::mem::drop(header_span);
 
     tracing::info!("All done");
-    tracing::info!(
-        "Saved to {}/output/",
-        dataset_path
-            .to_str()
-            .ok_or(anyhow!("Failed to convert path to string"))?
-    );
+    tracing::info!("Saved to {}", output_root.display());
     Ok(())
 }
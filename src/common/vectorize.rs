@@ -0,0 +1,218 @@
+use anyhow::{bail, Result};
+use clap::{Args, ValueEnum};
+use opencv::{
+    core::{self, Mat, Point, Scalar, Vector},
+    imgproc,
+};
+
+/// How the holes of a mask region end up in its polygons
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HoleStrategy {
+    /// Holes are joined to the outer contour by a zero width cut, one polygon per region
+    Bridge,
+    /// Holes are filled, only the outer contour is kept
+    Drop,
+    /// Holes are written as polygons of their own next to the outer contour
+    Separate,
+}
+
+/// Turning binary masks into polygons, shared by the YOLO and COCO exporters
+#[derive(Args, Debug, Clone, Copy)]
+pub struct VectorizeOptions {
+    #[arg(
+        long,
+        value_enum,
+        default_value = "bridge",
+        help = "How holes inside a region are written"
+    )]
+    pub holes: HoleStrategy,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Douglas-Peucker tolerance in pixels, 0 keeps the contour points"
+    )]
+    pub simplify: f64,
+
+    #[arg(
+        long,
+        help = "Simplify further until every polygon has at most this many points, at least --min-points"
+    )]
+    pub max_points: Option<usize>,
+
+    #[arg(
+        long,
+        default_value = "11",
+        help = "Regions whose outer contour has fewer points after --simplify are dropped, the default keeps the old rgb2yolo rule of more than 10 points (at least 3)"
+    )]
+    pub min_points: usize,
+}
+
+impl VectorizeOptions {
+    /// A cap below `min_points` would leave no region, so it is refused up front
+    pub fn validate(&self) -> Result<()> {
+        if let Some(max_points) = self.max_points {
+            if max_points < self.min_points.max(3) {
+                bail!(
+                    "--max-points {} is below --min-points {}, lower --min-points as well",
+                    max_points,
+                    self.min_points.max(3)
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One connected region of a mask
+#[derive(Debug, Clone)]
+pub struct MaskRegion {
+    /// Pixel coordinates, the outer contour first
+    pub polygons: Vec<Vec<(i32, i32)>>,
+    /// Area in pixels, holes excluded unless they are dropped
+    pub area: f64,
+}
+
+/// Binary mask of the pixels of color `rgb` in a BGR `label`
+pub fn color_mask(label: &Mat, rgb: [u8; 3]) -> Result<Mat> {
+    let [r, g, b] = rgb;
+    // BGR in OpenCV
+    let scalar = Scalar::new(b as f64, g as f64, r as f64, 0.0);
+    let mut mask = Mat::default();
+    core::in_range(label, &scalar, &scalar, &mut mask)?;
+    Ok(mask)
+}
+
+/// Vectorize the non zero pixels of an 8 bit `mask` into one [`MaskRegion`] per outer contour
+pub fn vectorize_mask(mask: &Mat, options: &VectorizeOptions) -> Result<Vec<MaskRegion>> {
+    let method = if options.simplify > 0.0 || options.max_points.is_some() {
+        imgproc::CHAIN_APPROX_NONE
+    } else {
+        imgproc::CHAIN_APPROX_TC89_KCOS
+    };
+    let mut contours = Vector::<Vector<Point>>::new();
+    // Same level next
+    // Same level previous
+    // Child
+    // Parent
+    let mut hierarchy = Vector::<core::Vec4i>::new();
+    imgproc::find_contours_with_hierarchy_def(
+        mask,
+        &mut contours,
+        &mut hierarchy,
+        imgproc::RETR_CCOMP,
+        method,
+    )?;
+
+    let mut regions = Vec::new();
+    for (index, node) in hierarchy.iter().enumerate() {
+        // RETR_CCOMP gives two levels, outer contours have no parent
+        if node[3] != -1 {
+            continue;
+        }
+        let outer = contours.get(index)?;
+        let mut holes = Vec::new();
+        let mut child = node[2];
+        while child != -1 {
+            holes.push(contours.get(child as usize)?);
+            child = hierarchy.get(child as usize)?[0];
+        }
+
+        let mut area = imgproc::contour_area_def(&outer)?;
+        if options.holes != HoleStrategy::Drop {
+            for hole in &holes {
+                area -= imgproc::contour_area_def(hole)?;
+            }
+        }
+        if let Some(polygons) = region_polygons(&outer, &holes, options)? {
+            regions.push(MaskRegion { polygons, area });
+        }
+    }
+    Ok(regions)
+}
+
+/// Polygons of one region, `None` when the outer contour is too small.
+///
+/// `min_points` applies to the contour simplified by `simplify` only, the `max_points`
+/// cap then simplifies further as long as the outer contour stays a polygon
+fn region_polygons(
+    outer: &Vector<Point>,
+    holes: &[Vector<Point>],
+    options: &VectorizeOptions,
+) -> Result<Option<Vec<Vec<(i32, i32)>>>> {
+    if simplify(outer, options.simplify)?.len() < options.min_points.max(3) {
+        return Ok(None);
+    }
+    // Beyond the perimeter every contour is down to a line, simplifying more changes nothing
+    let perimeter = imgproc::arc_length(outer, true)?;
+    let mut epsilon = options.simplify;
+    let mut previous = None;
+    loop {
+        let outer_points = simplify(outer, epsilon)?;
+        if outer_points.len() < 3 {
+            // Simplified past a polygon, the last polygons are as close to the cap as it gets
+            return Ok(previous);
+        }
+        let mut hole_points = Vec::new();
+        for hole in holes {
+            let points = simplify(hole, epsilon)?;
+            // Holes only need to be valid polygons, the size rule is for regions
+            if points.len() >= 3 {
+                hole_points.push(points);
+            }
+        }
+
+        let polygons = match options.holes {
+            HoleStrategy::Bridge => vec![hole_points
+                .iter()
+                .fold(outer_points, |outer, hole| bridge(&outer, hole))],
+            HoleStrategy::Drop => vec![outer_points],
+            HoleStrategy::Separate => std::iter::once(outer_points).chain(hole_points).collect(),
+        };
+        let within_cap = match options.max_points {
+            Some(max_points) => polygons.iter().all(|p| p.len() <= max_points),
+            None => true,
+        };
+        if within_cap || epsilon > perimeter {
+            return Ok(Some(polygons));
+        }
+        previous = Some(polygons);
+        epsilon = if epsilon > 0.0 { epsilon * 2.0 } else { 0.5 };
+    }
+}
+
+fn simplify(contour: &Vector<Point>, epsilon: f64) -> Result<Vec<(i32, i32)>> {
+    let contour = if epsilon > 0.0 {
+        let mut approx = Vector::<Point>::new();
+        imgproc::approx_poly_dp(contour, &mut approx, epsilon, true)?;
+        approx
+    } else {
+        contour.clone()
+    };
+    Ok(contour.iter().map(|point| (point.x, point.y)).collect())
+}
+
+/// Join `hole` into `outer` at their nearest points, walking into the hole and back
+fn bridge(outer: &[(i32, i32)], hole: &[(i32, i32)]) -> Vec<(i32, i32)> {
+    let mut min_distance = i32::MAX;
+    let mut parent_index = 0;
+    let mut child_index = 0;
+    for (i, parent_point) in outer.iter().enumerate() {
+        for (j, child_point) in hole.iter().enumerate() {
+            let distance =
+                (parent_point.0 - child_point.0).pow(2) + (parent_point.1 - child_point.1).pow(2);
+            if distance < min_distance {
+                min_distance = distance;
+                parent_index = i;
+                child_index = j;
+            }
+        }
+    }
+
+    let mut points = Vec::with_capacity(outer.len() + hole.len() + 2);
+    points.extend(outer.iter().take(parent_index + 1));
+    points.extend(hole.iter().skip(child_index));
+    points.extend(hole.iter().take(child_index + 1));
+    points.extend(outer.iter().skip(parent_index));
+    points
+}
@@ -0,0 +1,160 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{ArgAction, Args};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use walkdir::WalkDir;
+
+/// Extensions processed by the image batch commands
+pub const IMAGE_EXTENSIONS: &[&str] = &["tif", "tiff", "png", "jpg", "jpeg", "bmp"];
+
+/// How batch commands traverse their input folder
+#[derive(Args, Debug, Clone, Default)]
+pub struct WalkOptions {
+    #[arg(
+        long,
+        help = "Also process files in subdirectories, the output keeps the same structure",
+        action = ArgAction::SetTrue
+    )]
+    pub recursive: bool,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Only process files whose relative path matches one of these globs, comma separated"
+    )]
+    pub include: Vec<String>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Skip files whose relative path matches one of these globs, comma separated"
+    )]
+    pub exclude: Vec<String>,
+}
+
+impl WalkOptions {
+    /// Collect the files to process under `input`, sorted by path.
+    ///
+    /// A single file is returned as is. Inside a folder only files with one of
+    /// `extensions` (case insensitive, empty accepts all) are kept, and folders listed
+    /// in `skip_dirs` (usually the output folder) are never entered.
+    pub fn collect_files(
+        &self,
+        input: &Path,
+        extensions: &[&str],
+        skip_dirs: &[&Path],
+    ) -> Result<Vec<PathBuf>> {
+        if input.is_file() {
+            return Ok(vec![input.to_path_buf()]);
+        }
+        if !input.is_dir() {
+            bail!("Input path {} does not exist", input.display());
+        }
+
+        let include = build_globset(&self.include)?;
+        let exclude = build_globset(&self.exclude)?;
+
+        let walker = if self.recursive {
+            WalkDir::new(input)
+        } else {
+            WalkDir::new(input).max_depth(1)
+        };
+
+        let mut files = Vec::new();
+        for entry in walker
+            .follow_links(true)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|e| !(e.file_type().is_dir() && skip_dirs.contains(&e.path())))
+        {
+            let entry = entry.with_context(|| format!("Failed to walk {}", input.display()))?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let path = entry.path();
+            if !extensions.is_empty()
+                && !path
+                    .extension()
+                    .and_then(|s| s.to_str())
+                    .is_some_and(|ext| extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)))
+            {
+                continue;
+            }
+
+            let rel = glob_path(path.strip_prefix(input)?);
+            if include.as_ref().is_some_and(|set| !set.is_match(&rel)) {
+                continue;
+            }
+            if exclude.as_ref().is_some_and(|set| set.is_match(&rel)) {
+                continue;
+            }
+            files.push(path.to_path_buf());
+        }
+
+        tracing::debug!("Collected {} files under {}", files.len(), input.display());
+        Ok(files)
+    }
+}
+
+/// Default output folder of a batch command:
+/// `<input>/<name>` for a folder, `<input parent>/<name>` for a single file
+pub fn output_root(input: &Path, name: &str) -> Result<PathBuf> {
+    if input.is_file() {
+        Ok(input
+            .parent()
+            .ok_or(anyhow!("Failed to get parent dir of {}", input.display()))?
+            .join(name))
+    } else {
+        Ok(input.join(name))
+    }
+}
+
+/// Folder under `output_root` mirroring the folder of `file` relative to `input`,
+/// created if missing
+pub fn output_dir(input: &Path, file: &Path, output_root: &Path) -> Result<PathBuf> {
+    let dir = output_root.join(relative_parent(input, file)?);
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    Ok(dir)
+}
+
+/// Path under `output_root` mirroring `file` relative to `input`, its folder is created if missing
+pub fn output_path(input: &Path, file: &Path, output_root: &Path) -> Result<PathBuf> {
+    let file_name = file
+        .file_name()
+        .ok_or(anyhow!("Failed to get file name of {}", file.display()))?;
+    Ok(output_dir(input, file, output_root)?.join(file_name))
+}
+
+/// Folder of `file` relative to `input`, empty when `input` is the file itself
+pub fn relative_parent(input: &Path, file: &Path) -> Result<PathBuf> {
+    if input == file {
+        return Ok(PathBuf::new());
+    }
+    let rel = file
+        .strip_prefix(input)
+        .with_context(|| format!("{} is not under {}", file.display(), input.display()))?;
+    Ok(rel.parent().map(Path::to_path_buf).unwrap_or_default())
+}
+
+fn build_globset(patterns: &[String]) -> Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).with_context(|| format!("Invalid glob {}", pattern))?);
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// Relative path with `/` separators so globs behave the same on every platform
+fn glob_path(rel: &Path) -> String {
+    rel.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
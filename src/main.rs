@@ -1,7 +1,12 @@
 use std::sync::{LazyLock, RwLock};
 
-use clap::{ArgAction, Parser, Subcommand};
-use common::operation::EdgePosition;
+use clap::{ArgAction, Args, Parser, Subcommand};
+use common::{
+    dataset::{paths::PathOptions, splits::SplitOptions},
+    operation::EdgePosition,
+    palette::PaletteArgs,
+    walk::WalkOptions,
+};
 use tracing::level_filters::LevelFilter;
 use tracing_indicatif::IndicatifLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
@@ -66,6 +71,9 @@ enum CommonCommands {
         max: f64,
         #[arg(long, help = "The min value for normalization")]
         min: f64,
+
+        #[command(flatten)]
+        walk: WalkOptions,
     },
 
     // TODO: Add arg for image extension selection
@@ -83,6 +91,9 @@ enum CommonCommands {
             help = "The path for the original image / Directory containing images"
         )]
         dataset_path: String,
+
+        #[command(flatten)]
+        walk: WalkOptions,
     },
 
     /// Map all the non-valid color in the image to a given color
@@ -95,6 +106,9 @@ enum CommonCommands {
 
         #[arg(short, long, help = "The path for the original image")]
         dataset_path: String,
+
+        #[command(flatten)]
+        walk: WalkOptions,
     },
 
     /// Split large images to small pieces for augmentation purposes
@@ -107,6 +121,9 @@ enum CommonCommands {
 
         #[arg(long = "width", help = "Width for each split")]
         target_width: u32,
+
+        #[command(flatten)]
+        walk: WalkOptions,
     },
 
     /// Split large images to small pieces for augmentation purposes with bias
@@ -123,6 +140,9 @@ enum CommonCommands {
 
         #[arg(long = "width", help = "Width for each split")]
         target_width: u32,
+
+        #[command(flatten)]
+        walk: WalkOptions,
     },
 
     /// Split label images to small pieces with a filter for enough valid pixels
@@ -131,8 +151,8 @@ enum CommonCommands {
         #[arg(short, long, help = "The path for the folder containing images")]
         images_path: String,
 
-        #[arg(short, long, help = "RGB list, in R0,G0,B0;R1,G1,B1 format")]
-        rgb_list: String,
+        #[command(flatten)]
+        palette: PaletteArgs,
 
         #[arg(long = "height", help = "Height for each split")]
         target_height: u32,
@@ -143,6 +163,9 @@ enum CommonCommands {
         /// If set to true, the RGB value in RGB list is considered valid
         #[arg(short, help = "Use valid RGB filter mode", default_value = "false", action = ArgAction::SetTrue)]
         valid_rgb_mode: bool,
+
+        #[command(flatten)]
+        walk: WalkOptions,
     },
 
     /// Split images to small pieces with a filter for label name match
@@ -163,6 +186,9 @@ enum CommonCommands {
 
         #[arg(long = "width", help = "Width for each split")]
         target_width: u32,
+
+        #[command(flatten)]
+        walk: WalkOptions,
     },
 
     /// Process dataset with RGB list
@@ -175,11 +201,14 @@ enum CommonCommands {
         )]
         dataset_path: String,
 
-        #[arg(short, long, help = "RGB list, in R0,G0,B0;R1,G1,B1 format")]
-        rgb_list: String,
+        #[command(flatten)]
+        palette: PaletteArgs,
 
         #[arg(short, help = "Use valid RGB filter mode", default_value = "false", action = ArgAction::SetTrue)]
         valid_rgb_mode: bool,
+
+        #[command(flatten)]
+        walk: WalkOptions,
     },
 
     /// Map 8 bit grayscale PNG class image to RGB image
@@ -192,8 +221,11 @@ enum CommonCommands {
         )]
         dataset_path: String,
 
-        #[arg(short, long, help = "List of RGB colors, in R0,G0,B0;R1,G1,B1 format")]
-        rgb_list: String,
+        #[command(flatten)]
+        palette: PaletteArgs,
+
+        #[command(flatten)]
+        walk: WalkOptions,
     },
 
     /// Map RGB image to 8 bit grayscale PNG class image
@@ -206,8 +238,11 @@ enum CommonCommands {
         )]
         dataset_path: String,
 
-        #[arg(short, long, help = "List of RGB colors, in R0,G0,B0;R1,G1,B1 format")]
-        rgb_list: String,
+        #[command(flatten)]
+        palette: PaletteArgs,
+
+        #[command(flatten)]
+        walk: WalkOptions,
     },
 
     /// Resize all images in a given folder to a given size with a given filter
@@ -229,7 +264,7 @@ enum CommonCommands {
         filter: String,
     },
 
-    /// Convert RGB semantic segmentation PNG labels to RLE format
+    /// Convert RGB semantic segmentation PNG labels to COCO RLE / instance format
     #[command(name = "rgb2rle")]
     Rgb2Rle {
         #[arg(
@@ -239,52 +274,151 @@ enum CommonCommands {
         )]
         dataset_path: String,
 
+        #[command(flatten)]
+        palette: PaletteArgs,
+
+        #[command(flatten)]
+        coco: common::convert::CocoExportOptions,
+
+        #[command(flatten)]
+        walk: WalkOptions,
+    },
+
+    /// Rasterize COCO annotations into RGB masks with the palette colors
+    #[command(name = "coco2rgb")]
+    Coco2Rgb {
+        #[arg(short, long, help = "The path for the COCO JSON file")]
+        dataset_path: String,
+
+        #[command(flatten)]
+        palette: PaletteArgs,
+
+        #[command(flatten)]
+        raster: common::convert::coco::CocoRasterOptions,
+    },
+
+    /// Rasterize COCO annotations into 8 bit class masks
+    #[command(name = "coco2class")]
+    Coco2Class {
+        #[arg(short, long, help = "The path for the COCO JSON file")]
+        dataset_path: String,
+
         #[arg(
-            short,
             long,
-            help = "Required RGB list, in R0,G0,B0,class_name;R1,G1,B1,class_name format"
+            help = "Palette file (JSON / TOML) to match the categories against, class ids are the category ids without it"
         )]
-        rgb_list: String,
+        palette: Option<String>,
+
+        #[command(flatten)]
+        raster: common::convert::coco::CocoRasterOptions,
     },
 
-    /// Generate CSV format dataset list compatible with huggingface dataset library
-    GenerateDatasetCSV {
+    /// Convert Pascal VOC XML annotations, and optionally object masks, to a COCO JSON
+    #[command(name = "voc2coco")]
+    Voc2Coco {
+        #[arg(short, long, help = "The path for the folder containing VOC XML files")]
+        dataset_path: String,
+
         #[arg(
             short,
             long,
-            help = "The path for the dataset root folder, should contain images and labels folders"
+            help = "The SegmentationObject folder, object masks become RLE segmentations"
+        )]
+        masks_path: Option<String>,
+
+        #[arg(
+            short,
+            long,
+            help = "Class names, a TXT file with one name per line or a palette file, fixes the category ids"
+        )]
+        names: Option<String>,
+
+        #[arg(
+            short,
+            long,
+            help = "The path to save the COCO JSON, defaults to annotations.json next to the annotations folder"
         )]
+        save_path: Option<String>,
+
+        #[command(flatten)]
+        walk: WalkOptions,
+    },
+
+    /// Convert a COCO JSON to Pascal VOC XML annotations, and optionally object / class masks
+    #[command(name = "coco2voc")]
+    Coco2Voc {
+        #[arg(short, long, help = "The path for the COCO JSON file")]
         dataset_path: String,
 
         #[arg(
             short,
             long,
-            help = "The ratio of train set, should be between 0 and 1"
+            help = "The folder to save Annotations (and masks) to, defaults to the folder of the JSON"
+        )]
+        output_path: Option<String>,
+
+        #[arg(
+            long,
+            help = "Also write the SegmentationObject and SegmentationClass PNGs"
         )]
-        train_ratio: f32,
+        masks: bool,
     },
 
-    /// Generate JSON format dataset list compatible with huggingface dataset library
-    #[command(name = "generate-dataset-json")]
-    GenerateDatasetJSON {
+    /// Rasterize LabelMe JSON polygons, rectangles, circles and masks into RGB / class masks
+    #[command(name = "labelme2mask")]
+    Labelme2Mask {
         #[arg(
             short,
             long,
-            help = "The path for the dataset root folder, should contain images and labels folders"
+            help = "The path for the folder containing LabelMe JSON files"
         )]
         dataset_path: String,
 
+        #[command(flatten)]
+        palette: PaletteArgs,
+
+        #[command(flatten)]
+        raster: common::convert::coco::LabelRasterOptions,
+
+        #[command(flatten)]
+        walk: WalkOptions,
+    },
+
+    /// Rasterize a CVAT for images 1.1 XML (boxes, polygons, ellipses, masks) into RGB / class masks
+    #[command(name = "cvat2mask")]
+    Cvat2Mask {
+        #[arg(short, long, help = "The path for the CVAT annotations XML file")]
+        dataset_path: String,
+
+        #[command(flatten)]
+        palette: PaletteArgs,
+
+        #[command(flatten)]
+        raster: common::convert::coco::LabelRasterOptions,
+    },
+
+    /// Generate CSV format dataset list compatible with huggingface dataset library
+    GenerateDatasetCSV {
         #[arg(
             short,
             long,
-            help = "The ratio of train set, should be between 0 and 1"
+            help = "The path for the dataset root folder, should contain images and labels folders"
         )]
-        train_ratio: f32,
+        dataset_path: String,
+
+        #[command(flatten)]
+        split: SplitOptions,
+
+        #[command(flatten)]
+        pairing: PairingArgs,
+
+        #[command(flatten)]
+        path: PathOptions,
     },
 
-    /// Split dataset into train and test sets and save file names to txt file, for yolo dataset
-    #[command(name = "generate-dataset-txt")]
-    GenerateDatasetTXT {
+    /// Generate JSON format dataset list compatible with huggingface dataset library
+    #[command(name = "generate-dataset-json")]
+    GenerateDatasetJSON {
         #[arg(
             short,
             long,
@@ -292,18 +426,50 @@ enum CommonCommands {
         )]
         dataset_path: String,
 
+        #[command(flatten)]
+        split: SplitOptions,
+
+        #[command(flatten)]
+        pairing: PairingArgs,
+
+        #[command(flatten)]
+        path: PathOptions,
+    },
+
+    /// Split dataset into train and test sets and save file names to txt file, for yolo dataset
+    #[command(name = "generate-dataset-txt")]
+    GenerateDatasetTXT {
         #[arg(
             short,
             long,
-            help = "The ratio of train set, should be between 0 and 1"
+            help = "The path for the dataset root folder, should contain images and labels folders"
         )]
-        train_ratio: f32,
+        dataset_path: String,
+
+        #[command(flatten)]
+        split: SplitOptions,
+
+        #[command(flatten)]
+        pairing: PairingArgs,
     },
 
     #[command(name = "txt2json")]
     TXT2JSON {
         #[arg(short, long, help = "TXT file path")]
         txt_path: String,
+
+        #[arg(
+            short,
+            long,
+            help = "The dataset root folder containing images and labels folders, defaults to the folder of the TXT file"
+        )]
+        dataset_path: Option<String>,
+
+        #[command(flatten)]
+        pairing: PairingArgs,
+
+        #[command(flatten)]
+        path: PathOptions,
     },
 
     /// Combine multiple JSON format dataset list compatible with huggingface dataset library
@@ -320,6 +486,71 @@ enum CommonCommands {
         save_path: String,
     },
 
+    /// Rewrite the paths of JSON / CSV / TXT dataset lists, to relative paths or another prefix
+    Rebase {
+        #[arg(
+            short,
+            long,
+            help = "The list files to rebase, can be given multiple times"
+        )]
+        list_path: Vec<String>,
+
+        #[arg(
+            short,
+            long,
+            help = "The folder relative entries are relative to, defaults to the folder of each list"
+        )]
+        base: Option<String>,
+
+        #[arg(
+            short,
+            long,
+            help = "Save path for a single list, defaults to <stem>_rebased.<ext> next to each list"
+        )]
+        save_path: Option<String>,
+
+        #[command(flatten)]
+        path: PathOptions,
+    },
+
+    /// Merge several images / labels datasets into a new root with a unified palette
+    MergeDatasets {
+        #[arg(
+            short,
+            long,
+            help = "Multiple paths for the dataset root folders, should contain images and labels folders"
+        )]
+        dataset_path: Vec<String>,
+
+        #[arg(short, long, help = "The path for the merged dataset root folder")]
+        save_path: String,
+
+        #[arg(
+            long,
+            value_enum,
+            default_value = "rgb",
+            help = "Whether the labels are RGB colors or 8 bit class ids"
+        )]
+        label_kind: common::dataset::merge::LabelKind,
+
+        #[arg(
+            short,
+            long,
+            help = "JSON file mapping each source (folder name or path) to a {\"original\": \"unified\"} value map"
+        )]
+        remap: Option<String>,
+
+        #[arg(
+            long,
+            help = "Fail when a label value is not shared by every source",
+            action = ArgAction::SetTrue
+        )]
+        strict: bool,
+
+        #[command(flatten)]
+        pairing: PairingArgs,
+    },
+
     /// Split dataset into train and test sets inplace
     SplitDataset {
         #[arg(
@@ -329,26 +560,182 @@ enum CommonCommands {
         )]
         dataset_path: String,
 
+        #[command(flatten)]
+        split: SplitOptions,
+
+        #[command(flatten)]
+        pairing: PairingArgs,
+
         #[arg(
-            short,
             long,
-            help = "The ratio of train set, should be between 0 and 1"
+            help = "Only print the planned moves, nothing is changed",
+            action = ArgAction::SetTrue
         )]
-        train_ratio: f32,
+        dry_run: bool,
     },
 
-    /// Count class for 8 bit PNG image & Calc class balance weight
+    /// Count class for 8 bit PNG image & Calc class balance weights
     CountClasses {
         #[arg(short, long, help = "The path for the folder containing images")]
         dataset_path: String,
+
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = "255",
+            help = "Class ids left out of the weights, comma separated, the weights cover ids up to the largest other one"
+        )]
+        ignore_index: Vec<u8>,
+
+        #[command(flatten)]
+        walk: WalkOptions,
+
+        #[command(flatten)]
+        weights: common::dataset::weights::WeightOptions,
+    },
+
+    /// Export a CSV with the pixel count and fraction of every class for each label image
+    ClassHistogram {
+        #[arg(short, long, help = "The path for the folder containing labels")]
+        dataset_path: String,
+
+        #[arg(
+            long,
+            value_enum,
+            default_value = "rgb",
+            help = "Whether the labels are RGB colors or 8 bit class ids"
+        )]
+        label_kind: common::dataset::merge::LabelKind,
+
+        #[arg(
+            short,
+            long,
+            help = "Class columns in order, class id or R,G,B, in V0;V1 format, other values are counted as other"
+        )]
+        class_list: Option<String>,
+
+        #[arg(
+            short,
+            long,
+            help = "The path for the CSV, defaults to <dataset_path>/class_histogram.csv"
+        )]
+        save_path: Option<String>,
+
+        #[command(flatten)]
+        walk: WalkOptions,
+    },
+
+    /// Generate WeightedRandomSampler weights and an oversampled list from class histograms
+    SampleWeights {
+        #[arg(
+            short,
+            long,
+            help = "The path for the dataset root folder, should contain pregenerated json files"
+        )]
+        dataset_path: String,
+
+        #[arg(
+            long,
+            default_value = "train",
+            help = "The list to weight, <split>.json"
+        )]
+        split: String,
+
+        #[arg(
+            long,
+            help = "The path for the CSV written by class-histogram for the labels"
+        )]
+        histogram_path: String,
+
+        #[arg(
+            long,
+            value_enum,
+            default_value = "median-frequency",
+            help = "Class weighting scheme"
+        )]
+        scheme: common::dataset::sampling::WeightScheme,
+
+        #[arg(
+            long,
+            value_enum,
+            default_value = "max",
+            help = "How the class weights of a sample are combined"
+        )]
+        reduce: common::dataset::sampling::WeightReduce,
+
+        #[arg(
+            long,
+            help = "Classes left out of the weights, e.g. background, class id or R,G,B, in V0;V1 format"
+        )]
+        ignore_classes: Option<String>,
+
+        #[arg(
+            long,
+            default_value = "0",
+            help = "Min pixel fraction for a class to count as present in a sample"
+        )]
+        min_fraction: f64,
+
+        #[arg(
+            long,
+            default_value = "10",
+            help = "Max times a sample is repeated in the oversampled list"
+        )]
+        max_repeat: u32,
+
+        #[command(flatten)]
+        weights: common::dataset::weights::WeightOptions,
     },
 
-    /// Count class for 8 bit PNG image & Calc class balance weight
+    /// Count colors of RGB labels & Calc class balance weights, in class id order
     CountRGB {
         #[arg(short, long, help = "The path for the folder containing images")]
         dataset_path: String,
-        #[arg(short, long, help = "RGB list, in R0,G0,B0;R1,G1,B1 format")]
-        rgb_list: String,
+        #[command(flatten)]
+        palette: PaletteArgs,
+
+        #[command(flatten)]
+        walk: WalkOptions,
+
+        #[command(flatten)]
+        weights: common::dataset::weights::WeightOptions,
+    },
+
+    /// Find the distinct colors of RGB labels and write a draft palette, flagging noise colors
+    DiscoverPalette {
+        #[arg(short, long, help = "The path for the folder containing RGB labels")]
+        dataset_path: String,
+
+        #[arg(
+            short,
+            long,
+            help = "The path for the draft palette (JSON / TOML), defaults to <dataset_path>/palette_draft.toml"
+        )]
+        save_path: Option<String>,
+
+        #[arg(
+            long,
+            default_value = "0.001",
+            help = "Colors below this fraction of all pixels may be noise"
+        )]
+        rare_fraction: f64,
+
+        #[arg(
+            long,
+            default_value = "48",
+            help = "Max RGB distance between a noise color and the color it comes from"
+        )]
+        max_distance: f64,
+
+        #[arg(
+            long,
+            default_value = "10",
+            help = "How many times more pixels the color a noise color comes from should have"
+        )]
+        frequent_factor: f64,
+
+        #[command(flatten)]
+        walk: WalkOptions,
     },
 
     /// Strip image edges
@@ -384,12 +771,21 @@ enum CommonCommands {
 
         #[arg(long, help = "The stiched image width")]
         target_width: i32,
+
+        #[command(flatten)]
+        walk: WalkOptions,
     },
 
     /// Calc the mean and std of a dataset for normalization
     CalcMeanStd {
         #[arg(short, long, help = "The path for the folder containing images")]
         dataset_path: String,
+
+        #[command(flatten)]
+        walk: WalkOptions,
+
+        #[command(flatten)]
+        options: common::dataset::stats::MeanStdOptions,
     },
 
     /// Calc the IoU of two images
@@ -400,6 +796,12 @@ enum CommonCommands {
 
         #[arg(short, long, help = "The path for the ground truth image")]
         gt_image: String,
+
+        #[arg(
+            long,
+            help = "Palette file (JSON / TOML) to name the classes and leave out ignored ones"
+        )]
+        palette: Option<String>,
     },
 
     /// Mask file names while maintaining dataset correspondense
@@ -408,64 +810,460 @@ enum CommonCommands {
         image_dir: String,
         #[arg(short, long, help = "The path for the label dir")]
         label_dir: String,
-    },
-}
 
-#[derive(Subcommand)]
-enum YoloCommands {
-    /// Split dataset into train and test sets
-    /// Will store result in TXT file
-    SplitDataset {
-        #[arg(short, long, help = "The path for the folder containing TXT labels")]
-        dataset_path: String,
+        #[command(flatten)]
+        pairing: PairingArgs,
 
         #[arg(
-            short,
             long,
-            help = "The ratio of train set, should be between 0 and 1"
+            help = "Only print the planned moves, nothing is changed",
+            action = ArgAction::SetTrue
         )]
-        train_ratio: f32,
+        dry_run: bool,
     },
 
-    /// Count the object number of each type in the dataset
-    CountTypes {
-        #[arg(short, long, help = "The path for the folder containing TXT labels")]
-        dataset_path: String,
-    },
-
-    /// Convert RGB labels to YOLO TXT format
-    #[command(name = "rgb2yolo")]
-    Rgb2Yolo {
+    /// Restore file names masked by mask-dataset using its remap.json
+    UnmaskDataset {
         #[arg(
             short,
             long,
-            help = "The path for the folder containing RGB semantic segmentation PNG labels"
+            help = "The path for the remap.json written by mask-dataset"
         )]
-        dataset_path: String,
-
+        remap_path: String,
         #[arg(
             short,
             long,
-            help = "Required RGB list, in R0,G0,B0,class_name;R1,G1,B1,class_name format"
+            help = "List or prediction files using masked names to map back, can be given multiple times"
         )]
-        rgb_list: String,
-    },
-}
+        list_path: Vec<String>,
 
-#[derive(Subcommand)]
-enum RemoteSensingCommands {
-    ResizeImages {
-        #[arg(short, long, help = "Path of the src image")]
-        src_path: String,
         #[arg(
-            short,
             long,
-            help = "Resize ratio, the final H / W will become H*ratio / W*ratio"
+            help = "Also map bare masked stems like 0001 in list files, not only paths and file names with their extension",
+            action = ArgAction::SetTrue
         )]
-        ratio: f32,
-        #[arg(short, long, help = "Gaussian blur sigma arg")]
-        blur_sigma: f32,
-    },
+        match_stems: bool,
+
+        #[arg(
+            long,
+            help = "Only print the planned moves, nothing is changed",
+            action = ArgAction::SetTrue
+        )]
+        dry_run: bool,
+    },
+
+    /// Resume an interrupted split-dataset / mask-dataset / unmask-dataset from its journal
+    ResumeJournal {
+        #[arg(short, long, help = "The path for the *.journal.jsonl file")]
+        journal_path: String,
+    },
+
+    /// Roll back split-dataset / mask-dataset / unmask-dataset using its journal
+    RollbackJournal {
+        #[arg(short, long, help = "The path for the *.journal.jsonl file")]
+        journal_path: String,
+    },
+
+    /// Find near-duplicate images and train/val leakage with perceptual hashing
+    FindDuplicates {
+        #[arg(
+            short,
+            long,
+            help = "The path for the dataset root folder, should contain images folder and pregenerated json files"
+        )]
+        dataset_path: String,
+
+        #[arg(
+            long = "hash",
+            value_enum,
+            default_value = "dhash",
+            help = "Perceptual hash type"
+        )]
+        hash_kind: common::dataset::dedup::HashKind,
+
+        #[arg(
+            short,
+            long,
+            default_value = "4",
+            help = "Max Hamming distance between two hashes to be considered duplicated"
+        )]
+        max_distance: u32,
+
+        #[arg(
+            short,
+            long,
+            value_enum,
+            default_value = "report",
+            help = "What to do with the duplicates in the json files"
+        )]
+        action: common::dataset::dedup::DuplicateAction,
+
+        #[arg(
+            long,
+            help = "Target split for the merge action, defaults to the split of the first image in each cluster"
+        )]
+        merge_split: Option<String>,
+    },
+
+    /// Write a manifest with size, SHA-256, image info and class histogram of every file
+    GenerateManifest {
+        #[arg(short, long, help = "The path for the dataset root folder")]
+        dataset_path: String,
+
+        #[arg(
+            short,
+            long,
+            help = "The path for the manifest, defaults to <dataset_path>/manifest.json"
+        )]
+        save_path: Option<String>,
+
+        // The manifest always walks the whole tree, only the globs of `WalkOptions` apply
+        #[arg(
+            long,
+            value_delimiter = ',',
+            help = "Only describe files whose relative path matches one of these globs, comma separated"
+        )]
+        include: Vec<String>,
+
+        #[arg(
+            long,
+            value_delimiter = ',',
+            help = "Skip files whose relative path matches one of these globs, comma separated"
+        )]
+        exclude: Vec<String>,
+    },
+
+    /// Write an HTML / Markdown report summarising an images / labels dataset root
+    DatasetReport {
+        #[arg(
+            short,
+            long,
+            help = "The path for the dataset root folder, should contain images and optionally labels folders"
+        )]
+        dataset_path: String,
+
+        #[arg(
+            short,
+            long,
+            value_enum,
+            default_value = "html",
+            help = "Report format"
+        )]
+        format: common::dataset::report::ReportFormat,
+
+        #[arg(
+            long,
+            value_enum,
+            default_value = "rgb",
+            help = "Whether the labels are RGB colors or 8 bit class ids"
+        )]
+        label_kind: common::dataset::merge::LabelKind,
+
+        #[arg(
+            short,
+            long,
+            help = "The path for the report, defaults to <dataset_path>/dataset_report.<html|md>"
+        )]
+        save_path: Option<String>,
+
+        #[command(flatten)]
+        pairing: PairingArgs,
+    },
+
+    /// Compare two manifests, list added / removed / modified pairs and class distribution changes
+    DiffManifest {
+        #[arg(short, long, help = "The path for the old manifest")]
+        old_manifest: String,
+
+        #[arg(short, long, help = "The path for the new manifest")]
+        new_manifest: String,
+
+        #[arg(
+            short,
+            long,
+            help = "The path for the diff report, defaults to manifest_diff.json next to the new manifest"
+        )]
+        save_path: Option<String>,
+    },
+}
+
+/// Extensions used to pair images with labels by relative folder + stem
+#[derive(Args)]
+struct PairingArgs {
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = common::dataset::pairing::DEFAULT_IMAGE_EXTENSIONS,
+        help = "Image extensions, comma separated"
+    )]
+    image_extensions: Vec<String>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = common::dataset::pairing::DEFAULT_LABEL_EXTENSIONS,
+        help = "Label extensions, comma separated"
+    )]
+    label_extensions: Vec<String>,
+}
+
+#[derive(Subcommand)]
+enum YoloCommands {
+    /// Split dataset into train and test sets
+    /// Will store result in TXT file
+    SplitDataset {
+        #[arg(short, long, help = "The path for the folder containing TXT labels")]
+        dataset_path: String,
+
+        #[command(flatten)]
+        split: SplitOptions,
+
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = common::dataset::pairing::DEFAULT_IMAGE_EXTENSIONS,
+            help = "Image extensions to pair with the TXT labels, comma separated"
+        )]
+        image_extensions: Vec<String>,
+    },
+
+    /// Count the object number of each type in the dataset
+    CountTypes {
+        #[arg(short, long, help = "The path for the folder containing TXT labels")]
+        dataset_path: String,
+
+        #[command(flatten)]
+        walk: WalkOptions,
+    },
+
+    /// Convert RGB labels to YOLO TXT format, segmentation polygons, boxes or oriented boxes
+    #[command(name = "rgb2yolo")]
+    Rgb2Yolo {
+        #[arg(
+            short,
+            long,
+            help = "The path for the folder containing RGB semantic segmentation PNG labels"
+        )]
+        dataset_path: String,
+
+        #[command(flatten)]
+        palette: PaletteArgs,
+
+        #[command(flatten)]
+        export: yolo::convert::YoloExportOptions,
+
+        #[arg(
+            short,
+            long,
+            help = "The folder to save the TXT labels to, defaults to an output folder inside the dataset path"
+        )]
+        output_path: Option<String>,
+
+        #[command(flatten)]
+        walk: WalkOptions,
+    },
+
+    /// Rasterize YOLO polygons / boxes into RGB masks with the palette colors
+    #[command(name = "yolo2rgb")]
+    Yolo2Rgb {
+        #[arg(short, long, help = "The path for the folder containing TXT labels")]
+        dataset_path: String,
+
+        #[command(flatten)]
+        palette: PaletteArgs,
+
+        #[command(flatten)]
+        raster: yolo::raster::RasterOptions,
+
+        #[command(flatten)]
+        walk: WalkOptions,
+    },
+
+    /// Rasterize YOLO polygons / boxes into 8 bit class masks
+    #[command(name = "yolo2class")]
+    Yolo2Class {
+        #[arg(short, long, help = "The path for the folder containing TXT labels")]
+        dataset_path: String,
+
+        #[command(flatten)]
+        raster: yolo::raster::RasterOptions,
+
+        #[command(flatten)]
+        walk: WalkOptions,
+    },
+
+    /// Convert a YOLO dataset (images, TXT labels and class names) to a COCO JSON
+    #[command(name = "yolo2coco")]
+    Yolo2Coco {
+        #[arg(short, long, help = "The path for the folder containing TXT labels")]
+        dataset_path: String,
+
+        #[arg(
+            short,
+            long,
+            help = "The folder containing the images, defaults to the sibling images folder"
+        )]
+        images_path: Option<String>,
+
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = common::dataset::pairing::DEFAULT_IMAGE_EXTENSIONS,
+            help = "Image extensions to pair with the TXT labels, comma separated"
+        )]
+        image_extensions: Vec<String>,
+
+        #[arg(
+            short,
+            long,
+            help = "Class names, a TXT file with one name per line or a palette file, defaults to classes.txt in the labels folder"
+        )]
+        names: Option<String>,
+
+        #[arg(
+            short,
+            long,
+            help = "The path to save the COCO JSON, defaults to annotations.json next to the labels folder"
+        )]
+        save_path: Option<String>,
+
+        #[command(flatten)]
+        walk: WalkOptions,
+    },
+
+    /// Convert a COCO JSON to YOLO TXT labels, boxes, polygons or oriented boxes
+    #[command(name = "coco2yolo")]
+    Coco2Yolo {
+        #[arg(short, long, help = "The path for the COCO JSON file")]
+        dataset_path: String,
+
+        #[arg(
+            short,
+            long,
+            help = "The folder to save the TXT labels to, defaults to the labels folder next to the JSON"
+        )]
+        output_path: Option<String>,
+
+        #[arg(
+            long,
+            value_enum,
+            default_value = "segment",
+            help = "The YOLO label format"
+        )]
+        format: yolo::convert::YoloFormat,
+
+        #[arg(long, help = "Keep crowd annotations as ordinary objects")]
+        include_crowd: bool,
+
+        #[command(flatten)]
+        vectorize: common::vectorize::VectorizeOptions,
+    },
+
+    /// Convert Pascal VOC XML annotations, and optionally object masks, to YOLO TXT labels
+    #[command(name = "voc2yolo")]
+    Voc2Yolo {
+        #[arg(short, long, help = "The path for the folder containing VOC XML files")]
+        dataset_path: String,
+
+        #[arg(
+            short,
+            long,
+            help = "The SegmentationObject folder, needed for polygon labels"
+        )]
+        masks_path: Option<String>,
+
+        #[arg(
+            short,
+            long,
+            help = "Class names, a TXT file with one name per line or a palette file, fixes the class ids"
+        )]
+        names: Option<String>,
+
+        #[arg(
+            short,
+            long,
+            help = "The folder to save the TXT labels to, defaults to the labels folder next to the annotations folder"
+        )]
+        output_path: Option<String>,
+
+        #[arg(
+            long,
+            value_enum,
+            default_value = "bbox",
+            help = "The YOLO label format"
+        )]
+        format: yolo::convert::YoloFormat,
+
+        #[command(flatten)]
+        vectorize: common::vectorize::VectorizeOptions,
+
+        #[command(flatten)]
+        walk: WalkOptions,
+    },
+
+    /// Convert YOLO TXT labels to Pascal VOC XML annotations, and optionally object / class masks
+    #[command(name = "yolo2voc")]
+    Yolo2Voc {
+        #[arg(short, long, help = "The path for the folder containing TXT labels")]
+        dataset_path: String,
+
+        #[arg(
+            short,
+            long,
+            help = "The folder containing the images, defaults to the sibling images folder"
+        )]
+        images_path: Option<String>,
+
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = common::dataset::pairing::DEFAULT_IMAGE_EXTENSIONS,
+            help = "Image extensions to pair with the TXT labels, comma separated"
+        )]
+        image_extensions: Vec<String>,
+
+        #[arg(
+            short,
+            long,
+            help = "Class names, a TXT file with one name per line or a palette file, defaults to classes.txt in the labels folder"
+        )]
+        names: Option<String>,
+
+        #[arg(
+            short,
+            long,
+            help = "The folder to save Annotations (and masks) to, defaults to the parent of the labels folder"
+        )]
+        output_path: Option<String>,
+
+        #[arg(
+            long,
+            help = "Also write the SegmentationObject and SegmentationClass PNGs"
+        )]
+        masks: bool,
+
+        #[command(flatten)]
+        walk: WalkOptions,
+    },
+}
+
+#[derive(Subcommand)]
+enum RemoteSensingCommands {
+    ResizeImages {
+        #[arg(short, long, help = "Path of the src image")]
+        src_path: String,
+        #[arg(
+            short,
+            long,
+            help = "Resize ratio, the final H / W will become H*ratio / W*ratio"
+        )]
+        ratio: f32,
+        #[arg(short, long, help = "Gaussian blur sigma arg")]
+        blur_sigma: f32,
+
+        #[command(flatten)]
+        walk: WalkOptions,
+    },
     ResizeLabels {
         #[arg(short, long, help = "Path of the src label")]
         src_path: String,
@@ -475,6 +1273,9 @@ enum RemoteSensingCommands {
             help = "Resize ratio, the final H / W will become H*ratio / W*ratio"
         )]
         ratio: f32,
+
+        #[command(flatten)]
+        walk: WalkOptions,
     },
 }
 
@@ -524,15 +1325,17 @@ async fn main() {
                 dataset_path,
                 max,
                 min,
+                walk,
             } => {
-                common::operation::normalize(dataset_path, max, min);
+                common::operation::normalize(dataset_path, max, min, walk);
             }
             CommonCommands::MapColor {
                 original_color,
                 new_color,
                 dataset_path,
+                walk,
             } => {
-                common::remap::remap_color(original_color, new_color, dataset_path)
+                common::remap::remap_color(original_color, new_color, dataset_path, walk)
                     .await
                     .unwrap_or_log();
             }
@@ -540,8 +1343,9 @@ async fn main() {
                 valid_colors,
                 new_color,
                 dataset_path,
+                walk,
             } => {
-                common::remap::remap_background_color(valid_colors, new_color, dataset_path)
+                common::remap::remap_background_color(valid_colors, new_color, dataset_path, walk)
                     .await
                     .unwrap_or_log();
             }
@@ -549,20 +1353,24 @@ async fn main() {
                 dataset_path,
                 target_height,
                 target_width,
+                walk,
             } => {
-                common::augment::split_images(dataset_path, target_height, target_width).await;
+                common::augment::split_images(dataset_path, target_height, target_width, walk)
+                    .await;
             }
             CommonCommands::SplitImagesWithBias {
                 dataset_path,
                 bias_step,
                 target_height,
                 target_width,
+                walk,
             } => {
                 common::augment::split_images_with_bias(
                     dataset_path,
                     bias_step,
                     target_height,
                     target_width,
+                    walk,
                 )
                 .await;
             }
@@ -570,15 +1378,17 @@ async fn main() {
                 images_path,
                 target_height,
                 target_width,
-                rgb_list,
+                palette,
                 valid_rgb_mode,
+                walk,
             } => {
                 common::augment::split_images_with_rgb_filter(
                     images_path,
                     target_height,
                     target_width,
-                    rgb_list,
+                    &palette.resolve().unwrap_or_log(),
                     *valid_rgb_mode,
+                    walk,
                 )
                 .await;
             }
@@ -587,25 +1397,29 @@ async fn main() {
                 labels_path,
                 target_height,
                 target_width,
+                walk,
             } => {
                 common::augment::split_images_with_label_filter(
                     images_path,
                     labels_path,
                     target_height,
                     target_width,
+                    walk,
                 )
                 .await
                 .unwrap_or_log();
             }
             CommonCommands::ProcessDatasetWithRGBList {
                 dataset_path,
-                rgb_list,
+                palette,
                 valid_rgb_mode,
+                walk,
             } => {
                 common::augment::process_dataset_with_rgblist(
                     dataset_path,
-                    rgb_list,
+                    &palette.resolve().unwrap_or_log(),
                     *valid_rgb_mode,
+                    walk,
                 )
                 .await;
             }
@@ -613,14 +1427,17 @@ async fn main() {
                 image_output_path,
                 target_height,
                 target_width,
+                walk,
             } => {
-                common::augment::stich_images(image_output_path, target_height, target_width).await;
+                common::augment::stich_images(image_output_path, target_height, target_width, walk)
+                    .await;
             }
             CommonCommands::Class2RGB {
                 dataset_path,
-                rgb_list,
+                palette,
+                walk,
             } => {
-                common::remap::class2rgb(dataset_path, rgb_list)
+                common::remap::class2rgb(dataset_path, &palette.resolve().unwrap_or_log(), walk)
                     .await
                     .unwrap_or_log();
             }
@@ -636,32 +1453,157 @@ async fn main() {
             }
             CommonCommands::Rgb2Rle {
                 dataset_path,
-                rgb_list,
+                palette,
+                coco,
+                walk,
+            } => {
+                common::convert::rgb2rle(
+                    dataset_path,
+                    &palette.resolve().unwrap_or_log(),
+                    coco,
+                    walk,
+                )
+                .await
+                .unwrap_or_log();
+            }
+            CommonCommands::Coco2Rgb {
+                dataset_path,
+                palette,
+                raster,
+            } => {
+                common::convert::coco::coco2mask(
+                    dataset_path,
+                    common::dataset::merge::LabelKind::Rgb,
+                    Some(&palette.resolve().unwrap_or_log()),
+                    raster,
+                )
+                .await
+                .unwrap_or_log();
+            }
+            CommonCommands::Coco2Class {
+                dataset_path,
+                palette,
+                raster,
+            } => {
+                let palette = palette
+                    .as_ref()
+                    .map(|path| common::palette::Palette::load(std::path::Path::new(path)))
+                    .transpose()
+                    .unwrap_or_log();
+                common::convert::coco::coco2mask(
+                    dataset_path,
+                    common::dataset::merge::LabelKind::Class,
+                    palette.as_ref(),
+                    raster,
+                )
+                .await
+                .unwrap_or_log();
+            }
+            CommonCommands::Voc2Coco {
+                dataset_path,
+                masks_path,
+                names,
+                save_path,
+                walk,
+            } => {
+                common::convert::voc::voc2coco(
+                    dataset_path,
+                    masks_path.as_deref(),
+                    names.as_deref(),
+                    save_path.as_deref(),
+                    walk,
+                )
+                .unwrap_or_log();
+            }
+            CommonCommands::Coco2Voc {
+                dataset_path,
+                output_path,
+                masks,
+            } => {
+                common::convert::voc::coco2voc(dataset_path, output_path.as_deref(), *masks)
+                    .unwrap_or_log();
+            }
+            CommonCommands::Labelme2Mask {
+                dataset_path,
+                palette,
+                raster,
+                walk,
+            } => {
+                common::convert::labelme::labelme2mask(
+                    dataset_path,
+                    &palette.resolve().unwrap_or_log(),
+                    raster,
+                    walk,
+                )
+                .await
+                .unwrap_or_log();
+            }
+            CommonCommands::Cvat2Mask {
+                dataset_path,
+                palette,
+                raster,
             } => {
-                common::convert::rgb2rle(dataset_path, rgb_list).await;
+                common::convert::cvat::cvat2mask(
+                    dataset_path,
+                    &palette.resolve().unwrap_or_log(),
+                    raster,
+                )
+                .await
+                .unwrap_or_log();
             }
             CommonCommands::RGB2Class {
                 dataset_path,
-                rgb_list,
+                palette,
+                walk,
             } => {
-                common::remap::rgb2class(dataset_path, rgb_list)
+                common::remap::rgb2class(dataset_path, &palette.resolve().unwrap_or_log(), walk)
                     .await
                     .unwrap_or_log();
             }
             CommonCommands::GenerateDatasetCSV {
                 dataset_path,
-                train_ratio,
+                split,
+                pairing,
+                path,
             } => {
-                common::dataset::generate_dataset_csv(dataset_path, train_ratio);
+                common::dataset::generate_dataset_csv(
+                    dataset_path,
+                    &split.resolve().unwrap_or_log(),
+                    &pairing.image_extensions,
+                    &pairing.label_extensions,
+                    path,
+                )
+                .unwrap_or_log();
             }
             CommonCommands::GenerateDatasetJSON {
                 dataset_path,
-                train_ratio,
+                split,
+                pairing,
+                path,
             } => {
-                common::dataset::generate_dataset_json(dataset_path, train_ratio);
+                common::dataset::generate_dataset_json(
+                    dataset_path,
+                    &split.resolve().unwrap_or_log(),
+                    &pairing.image_extensions,
+                    &pairing.label_extensions,
+                    path,
+                )
+                .unwrap_or_log();
             }
-            CommonCommands::TXT2JSON { txt_path } => {
-                common::dataset::txt2json(txt_path);
+            CommonCommands::TXT2JSON {
+                txt_path,
+                dataset_path,
+                pairing,
+                path,
+            } => {
+                common::dataset::txt2json(
+                    txt_path,
+                    dataset_path.as_ref(),
+                    &pairing.image_extensions,
+                    &pairing.label_extensions,
+                    path,
+                )
+                .unwrap_or_log();
             }
             CommonCommands::CombineDatasetJSON {
                 dataset_path,
@@ -669,26 +1611,151 @@ async fn main() {
             } => {
                 common::dataset::combine_dataset_json(dataset_path, save_path);
             }
+            CommonCommands::Rebase {
+                list_path,
+                base,
+                save_path,
+                path,
+            } => {
+                common::dataset::paths::rebase_lists(
+                    list_path,
+                    base.as_deref(),
+                    save_path.as_deref(),
+                    path,
+                )
+                .unwrap_or_log();
+            }
+            CommonCommands::MergeDatasets {
+                dataset_path,
+                save_path,
+                label_kind,
+                remap,
+                strict,
+                pairing,
+            } => {
+                common::dataset::merge::merge_datasets(
+                    dataset_path,
+                    save_path,
+                    *label_kind,
+                    remap.as_deref(),
+                    &pairing.image_extensions,
+                    &pairing.label_extensions,
+                    *strict,
+                )
+                .await
+                .unwrap_or_log();
+            }
             CommonCommands::GenerateDatasetTXT {
                 dataset_path,
-                train_ratio,
+                split,
+                pairing,
             } => {
-                common::dataset::generate_dataset_txt(dataset_path, train_ratio).await;
+                common::dataset::generate_dataset_txt(
+                    dataset_path,
+                    &split.resolve().unwrap_or_log(),
+                    &pairing.image_extensions,
+                    &pairing.label_extensions,
+                )
+                .await
+                .unwrap_or_log();
             }
             CommonCommands::SplitDataset {
                 dataset_path,
-                train_ratio,
+                split,
+                pairing,
+                dry_run,
+            } => {
+                common::dataset::split_dataset(
+                    dataset_path,
+                    &split.resolve().unwrap_or_log(),
+                    &pairing.image_extensions,
+                    &pairing.label_extensions,
+                    *dry_run,
+                )
+                .await
+                .unwrap_or_log();
+            }
+            CommonCommands::CountClasses {
+                dataset_path,
+                ignore_index,
+                walk,
+                weights,
             } => {
-                common::dataset::split_dataset(dataset_path, train_ratio).await;
+                common::dataset::count_classes(dataset_path, ignore_index, walk, weights).await;
             }
-            CommonCommands::CountClasses { dataset_path } => {
-                common::dataset::count_classes(dataset_path).await;
+            CommonCommands::ClassHistogram {
+                dataset_path,
+                label_kind,
+                class_list,
+                save_path,
+                walk,
+            } => {
+                common::dataset::histogram::class_histogram(
+                    dataset_path,
+                    *label_kind,
+                    class_list.as_deref(),
+                    save_path.as_deref(),
+                    walk,
+                )
+                .await
+                .unwrap_or_log();
+            }
+            CommonCommands::SampleWeights {
+                dataset_path,
+                split,
+                histogram_path,
+                scheme,
+                reduce,
+                ignore_classes,
+                min_fraction,
+                max_repeat,
+                weights,
+            } => {
+                common::dataset::sampling::sample_weights(
+                    dataset_path,
+                    split,
+                    histogram_path,
+                    *scheme,
+                    *reduce,
+                    ignore_classes.as_deref(),
+                    *min_fraction,
+                    *max_repeat,
+                    weights,
+                )
+                .unwrap_or_log();
             }
             CommonCommands::CountRGB {
                 dataset_path,
-                rgb_list,
+                palette,
+                walk,
+                weights,
+            } => {
+                common::dataset::count_rgb(
+                    dataset_path,
+                    &palette.resolve().unwrap_or_log(),
+                    walk,
+                    weights,
+                )
+                .await;
+            }
+            CommonCommands::DiscoverPalette {
+                dataset_path,
+                save_path,
+                rare_fraction,
+                max_distance,
+                frequent_factor,
+                walk,
             } => {
-                common::dataset::count_rgb(dataset_path, rgb_list).await;
+                common::palette::discover::discover_palette(
+                    dataset_path,
+                    save_path.as_deref(),
+                    *rare_fraction,
+                    *max_distance,
+                    *frequent_factor,
+                    walk,
+                )
+                .await
+                .unwrap_or_log();
             }
             CommonCommands::StripImageEdge {
                 source_path,
@@ -709,39 +1776,267 @@ async fn main() {
                 common::operation::strip_image_edge(source_path, save_path, &direction, length)
                     .await;
             }
-            CommonCommands::CalcMeanStd { dataset_path } => {
-                common::dataset::calc_mean_std(dataset_path).await;
+            CommonCommands::CalcMeanStd {
+                dataset_path,
+                walk,
+                options,
+            } => {
+                common::dataset::stats::calc_mean_std(dataset_path, walk, options)
+                    .await
+                    .unwrap_or_log();
             }
             CommonCommands::CalcIoU {
                 target_image,
                 gt_image,
+                palette,
             } => {
-                common::metric::calc_iou(target_image, gt_image);
+                let palette = palette
+                    .as_ref()
+                    .map(|path| common::palette::Palette::load(std::path::Path::new(path)))
+                    .transpose()
+                    .unwrap_or_log();
+                common::metric::calc_iou(target_image, gt_image, palette.as_ref());
             }
             CommonCommands::MaskDataset {
                 image_dir,
                 label_dir,
+                pairing,
+                dry_run,
             } => {
-                common::dataset::mask::mask_dataset(image_dir, label_dir)
-                    .await
-                    .unwrap_or_log();
+                common::dataset::mask::mask_dataset(
+                    image_dir,
+                    label_dir,
+                    &pairing.image_extensions,
+                    &pairing.label_extensions,
+                    *dry_run,
+                )
+                .await
+                .unwrap_or_log();
+            }
+            CommonCommands::UnmaskDataset {
+                remap_path,
+                list_path,
+                match_stems,
+                dry_run,
+            } => {
+                common::dataset::mask::unmask_dataset(
+                    remap_path,
+                    list_path,
+                    *match_stems,
+                    *dry_run,
+                )
+                .await
+                .unwrap_or_log();
+            }
+            CommonCommands::ResumeJournal { journal_path } => {
+                common::dataset::journal::resume_journal(journal_path).unwrap_or_log();
+            }
+            CommonCommands::RollbackJournal { journal_path } => {
+                common::dataset::journal::rollback_journal(journal_path).unwrap_or_log();
+            }
+            CommonCommands::FindDuplicates {
+                dataset_path,
+                hash_kind,
+                max_distance,
+                action,
+                merge_split,
+            } => {
+                common::dataset::dedup::find_duplicates(
+                    dataset_path,
+                    *hash_kind,
+                    *max_distance,
+                    *action,
+                    merge_split.as_deref(),
+                )
+                .await
+                .unwrap_or_log();
+            }
+            CommonCommands::GenerateManifest {
+                dataset_path,
+                save_path,
+                include,
+                exclude,
+            } => {
+                common::dataset::manifest::generate_manifest(
+                    dataset_path,
+                    save_path.as_deref(),
+                    include,
+                    exclude,
+                )
+                .await
+                .unwrap_or_log();
+            }
+            CommonCommands::DatasetReport {
+                dataset_path,
+                format,
+                label_kind,
+                save_path,
+                pairing,
+            } => {
+                common::dataset::report::dataset_report(
+                    dataset_path,
+                    *format,
+                    *label_kind,
+                    save_path.as_deref(),
+                    &pairing.image_extensions,
+                    &pairing.label_extensions,
+                )
+                .await
+                .unwrap_or_log();
+            }
+            CommonCommands::DiffManifest {
+                old_manifest,
+                new_manifest,
+                save_path,
+            } => {
+                common::dataset::manifest::diff_manifests(
+                    old_manifest,
+                    new_manifest,
+                    save_path.as_deref(),
+                )
+                .unwrap_or_log();
             }
         },
         Some(Commands::Yolo { command }) => match command {
             YoloCommands::SplitDataset {
                 dataset_path,
-                train_ratio,
+                split,
+                image_extensions,
             } => {
-                yolo::dataset::split_dataset(dataset_path, train_ratio).await;
+                yolo::dataset::split_dataset(
+                    dataset_path,
+                    &split.resolve().unwrap_or_log(),
+                    image_extensions,
+                )
+                .await
+                .unwrap_or_log();
             }
-            YoloCommands::CountTypes { dataset_path } => {
-                yolo::dataset::count_types(dataset_path).await;
+            YoloCommands::CountTypes { dataset_path, walk } => {
+                yolo::dataset::count_types(dataset_path, walk)
+                    .await
+                    .unwrap_or_log();
             }
             YoloCommands::Rgb2Yolo {
                 dataset_path,
-                rgb_list,
+                palette,
+                export,
+                output_path,
+                walk,
+            } => {
+                yolo::convert::rgb2yolo(
+                    dataset_path,
+                    &palette.resolve().unwrap_or_log(),
+                    export,
+                    output_path.as_deref(),
+                    walk,
+                )
+                .await
+                .unwrap_or_log();
+            }
+            YoloCommands::Yolo2Rgb {
+                dataset_path,
+                palette,
+                raster,
+                walk,
+            } => {
+                yolo::raster::yolo2mask(
+                    dataset_path,
+                    common::dataset::merge::LabelKind::Rgb,
+                    Some(&palette.resolve().unwrap_or_log()),
+                    raster,
+                    walk,
+                )
+                .await
+                .unwrap_or_log();
+            }
+            YoloCommands::Yolo2Class {
+                dataset_path,
+                raster,
+                walk,
+            } => {
+                yolo::raster::yolo2mask(
+                    dataset_path,
+                    common::dataset::merge::LabelKind::Class,
+                    None,
+                    raster,
+                    walk,
+                )
+                .await
+                .unwrap_or_log();
+            }
+            YoloCommands::Yolo2Coco {
+                dataset_path,
+                images_path,
+                image_extensions,
+                names,
+                save_path,
+                walk,
+            } => {
+                yolo::coco::yolo2coco(
+                    dataset_path,
+                    images_path.as_deref(),
+                    image_extensions,
+                    names.as_deref(),
+                    save_path.as_deref(),
+                    walk,
+                )
+                .unwrap_or_log();
+            }
+            YoloCommands::Coco2Yolo {
+                dataset_path,
+                output_path,
+                format,
+                include_crowd,
+                vectorize,
+            } => {
+                yolo::coco::coco2yolo(
+                    dataset_path,
+                    output_path.as_deref(),
+                    *format,
+                    *include_crowd,
+                    vectorize,
+                )
+                .unwrap_or_log();
+            }
+            YoloCommands::Voc2Yolo {
+                dataset_path,
+                masks_path,
+                names,
+                output_path,
+                format,
+                vectorize,
+                walk,
+            } => {
+                common::convert::voc::voc2yolo(
+                    dataset_path,
+                    masks_path.as_deref(),
+                    names.as_deref(),
+                    output_path.as_deref(),
+                    *format,
+                    vectorize,
+                    walk,
+                )
+                .unwrap_or_log();
+            }
+            YoloCommands::Yolo2Voc {
+                dataset_path,
+                images_path,
+                image_extensions,
+                names,
+                output_path,
+                masks,
+                walk,
             } => {
-                yolo::convert::rgb2yolo(dataset_path, rgb_list).await;
+                common::convert::voc::yolo2voc(
+                    dataset_path,
+                    images_path.as_deref(),
+                    image_extensions,
+                    names.as_deref(),
+                    output_path.as_deref(),
+                    *masks,
+                    walk,
+                )
+                .unwrap_or_log();
             }
         },
         Some(Commands::RemoteSensing { command }) => match command {
@@ -749,14 +2044,17 @@ async fn main() {
                 src_path,
                 ratio,
                 blur_sigma,
-            } => remote_sensing::resize::resize_images(src_path, *ratio, *blur_sigma)
+                walk,
+            } => remote_sensing::resize::resize_images(src_path, *ratio, *blur_sigma, walk)
+                .await
+                .unwrap_or_log(),
+            RemoteSensingCommands::ResizeLabels {
+                src_path,
+                ratio,
+                walk,
+            } => remote_sensing::resize::resize_labels(src_path, *ratio, walk)
                 .await
                 .unwrap_or_log(),
-            RemoteSensingCommands::ResizeLabels { src_path, ratio } => {
-                remote_sensing::resize::resize_labels(src_path, *ratio)
-                    .await
-                    .unwrap_or_log()
-            }
         },
         None => {
             tracing::error!("No command specified, use --help for more information");
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, fs, path::PathBuf, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
 use anyhow::{anyhow, bail, Result};
 use indicatif::ProgressStyle;
@@ -8,30 +8,23 @@ use tokio::{sync::Semaphore, task::JoinSet};
 use tracing::info_span;
 use tracing_indicatif::span_ext::IndicatifSpanExt;
 
+use crate::common::walk::{output_path, output_root, WalkOptions, IMAGE_EXTENSIONS};
 use crate::THREAD_POOL;
 
-pub async fn resize_images(src_path: &str, ratio: f32, blur_sigma: f32) -> Result<()> {
+pub async fn resize_images(
+    src_path: &str,
+    ratio: f32,
+    blur_sigma: f32,
+    walk: &WalkOptions,
+) -> Result<()> {
     if ratio >= 1.0 {
         bail!("Can only shrink image, currently super resolution is not supported")
     }
 
     let src_path = PathBuf::from(src_path);
 
-    let mut src_entries = Vec::<PathBuf>::new();
-    let output_dir = if src_path.is_file() {
-        src_entries.push(src_path.clone());
-        src_path
-            .parent()
-            .ok_or(anyhow!("Failed to read parent dir for src path"))?
-            .join("rs_resize_output")
-    } else {
-        // Find all files inside
-        for entry in fs::read_dir(&src_path)? {
-            src_entries.push(entry?.path());
-        }
-        src_path.join("rs_resize_output")
-    };
-    fs::create_dir_all(&output_dir)?;
+    let output_root = output_root(&src_path, "rs_resize_output")?;
+    let src_entries = walk.collect_files(&src_path, IMAGE_EXTENSIONS, &[&output_root])?;
 
     let mut threads = JoinSet::new();
 
@@ -52,7 +45,7 @@ pub async fn resize_images(src_path: &str, ratio: f32, blur_sigma: f32) -> Resul
 
     for entry in src_entries {
         let permit = sem.clone().acquire_owned().await?;
-        let output_dir = output_dir.clone();
+        let output_path = output_path(&src_path, &entry, &output_root)?;
         let parent_span = parent_span.clone();
         threads.spawn_blocking(move || -> Result<()> {
             let _permit = permit;
@@ -108,7 +101,6 @@ pub async fn resize_images(src_path: &str, ratio: f32, blur_sigma: f32) -> Resul
 
             tracing::info!("Image {} resize complete.", file_name);
 
-            let output_path = output_dir.join(file_name);
             let output_path = output_path
                 .to_str()
                 .ok_or(anyhow!("Failed to generate output path string"))?;
@@ -136,28 +128,15 @@ pub async fn resize_images(src_path: &str, ratio: f32, blur_sigma: f32) -> Resul
     Ok(())
 }
 
-pub async fn resize_labels(src_path: &str, ratio: f32) -> Result<()> {
+pub async fn resize_labels(src_path: &str, ratio: f32, walk: &WalkOptions) -> Result<()> {
     if ratio >= 1.0 {
         bail!("Can only shrink image, currently super resolution is not supported")
     }
 
     let src_path = PathBuf::from(src_path);
 
-    let mut src_entries = Vec::<PathBuf>::new();
-    let output_dir = if src_path.is_file() {
-        src_entries.push(src_path.clone());
-        src_path
-            .parent()
-            .ok_or(anyhow!("Failed to read parent dir for src path"))?
-            .join("rs_resize_output")
-    } else {
-        // Find all files inside
-        for entry in fs::read_dir(&src_path)? {
-            src_entries.push(entry?.path());
-        }
-        src_path.join("rs_resize_output")
-    };
-    fs::create_dir_all(&output_dir)?;
+    let output_root = output_root(&src_path, "rs_resize_output")?;
+    let src_entries = walk.collect_files(&src_path, IMAGE_EXTENSIONS, &[&output_root])?;
 
     let mut threads = JoinSet::new();
 
@@ -178,7 +157,7 @@ pub async fn resize_labels(src_path: &str, ratio: f32) -> Result<()> {
 
     for entry in src_entries {
         let permit = sem.clone().acquire_owned().await?;
-        let output_dir = output_dir.clone();
+        let output_path = output_path(&src_path, &entry, &output_root)?;
         let parent_span = parent_span.clone();
         threads.spawn_blocking(move || -> Result<()> {
             let _permit = permit;
@@ -289,7 +268,6 @@ pub async fn resize_labels(src_path: &str, ratio: f32) -> Result<()> {
                 }
                 task_span.pb_inc(1);
             }
-            let output_path = output_dir.join(file_name);
             let output_path = output_path
                 .to_str()
                 .ok_or(anyhow!("Failed to generate output path string"))?;
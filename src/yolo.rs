@@ -1,2 +1,4 @@
 pub mod dataset;
-pub mod convert;
\ No newline at end of file
+pub mod convert;
+pub mod raster;
+pub mod coco;
\ No newline at end of file
@@ -0,0 +1,429 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use cocotools::coco::object_detection::{Annotation, Bbox, Category, Dataset, Image, Segmentation};
+use cocotools::mask::Mask;
+use opencv::{
+    core::{Mat, MatTraitConst, Point2f, Vector},
+    imgproc,
+};
+
+use super::convert::YoloFormat;
+use super::raster::{default_images_path, read_yolo_labels, YoloShape};
+use crate::common::convert::coco::load_dataset;
+use crate::common::dataset::pairing::index_by_relparent_and_stem;
+use crate::common::dataset::paths::contained_path;
+use crate::common::palette::Palette;
+use crate::common::vectorize::{vectorize_mask, VectorizeOptions};
+use crate::common::walk::WalkOptions;
+
+/// Class names of a YOLO dataset, one per line in id order, kept next to the TXT labels
+pub const CLASSES_FILE: &str = "classes.txt";
+
+/// Whether `path` is the class names file, which every walker over TXT labels skips
+pub fn is_classes_file(path: &Path) -> bool {
+    path.file_name().and_then(|s| s.to_str()) == Some(CLASSES_FILE)
+}
+
+/// Class names by YOLO id, from a TXT file with one name per line or a palette file
+pub fn load_class_names(path: &Path) -> Result<BTreeMap<u32, String>> {
+    match path.extension().and_then(|s| s.to_str()) {
+        Some("json") | Some("toml") => Ok(Palette::load(path)?
+            .classes
+            .into_iter()
+            .map(|class| (class.id as u32, class.name))
+            .collect()),
+        _ => Ok(fs::read_to_string(path)
+            .with_context(|| format!("Reading {}", path.display()))?
+            .lines()
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .enumerate()
+            .map(|(id, name)| (id as u32, name.to_string()))
+            .collect()),
+    }
+}
+
+/// Read a YOLO dataset into a COCO dataset.
+///
+/// Every image of `images_path` (the sibling `images` folder by default) becomes a COCO image,
+/// its size read from disk to denormalize the boxes and polygons. Category ids are the
+/// YOLO ids plus one, named after `names_path`, or the `classes.txt` in the labels folder.
+pub fn read_yolo_dataset(
+    label_root: &Path,
+    images_path: Option<&str>,
+    image_extensions: &[String],
+    names_path: Option<&str>,
+    walk: &WalkOptions,
+) -> Result<Dataset> {
+    if !label_root.is_dir() {
+        bail!("{} is not a folder of TXT labels", label_root.display());
+    }
+    let image_root = match images_path {
+        Some(path) => PathBuf::from(path),
+        None => default_images_path(label_root)?,
+    };
+    let images = index_by_relparent_and_stem(&image_root, image_extensions, walk.recursive)?;
+    if images.is_empty() {
+        bail!("No image found in {}", image_root.display());
+    }
+    let mut labels = index_by_relparent_and_stem(label_root, &["txt".to_string()], walk.recursive)?;
+    labels.retain(|_, path| !is_classes_file(path));
+
+    let names_path = match names_path {
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(label_root.join(CLASSES_FILE)).filter(|path| path.is_file()),
+    };
+    let names = match &names_path {
+        Some(path) => load_class_names(path)?,
+        None => BTreeMap::new(),
+    };
+
+    let mut dataset = Dataset::default();
+    let mut class_ids = names.keys().copied().collect::<BTreeSet<_>>();
+    for (key, image_path) in &images {
+        let (width, height) = image::image_dimensions(image_path)
+            .with_context(|| format!("Reading size of {}", image_path.display()))?;
+        let image = Image {
+            id: dataset.images.len() as u64 + 1,
+            width,
+            height,
+            file_name: image_path
+                .strip_prefix(&image_root)?
+                .to_string_lossy()
+                .into_owned(),
+            ..Default::default()
+        };
+
+        // Images without a label file are negatives, kept without annotations
+        if let Some(label_path) = labels.remove(key) {
+            for shape in read_yolo_labels(&label_path)? {
+                class_ids.insert(shape.class_id());
+                let mut annotation = shape_annotation(&shape, width as f64, height as f64);
+                annotation.id = dataset.annotations.len() as u64 + 1;
+                annotation.image_id = image.id;
+                dataset.annotations.push(annotation);
+            }
+        }
+        dataset.images.push(image);
+    }
+    for label_path in labels.values() {
+        tracing::warn!("{} has no image, skipped", label_path.display());
+    }
+
+    dataset.categories = class_ids
+        .into_iter()
+        .map(|class_id| {
+            let name = names
+                .get(&class_id)
+                .cloned()
+                .unwrap_or(format!("class_{}", class_id));
+            Category {
+                id: class_id + 1,
+                name: name.clone(),
+                supercategory: name,
+            }
+        })
+        .collect();
+    Ok(dataset)
+}
+
+/// Convert the YOLO TXT labels in `dataset_path` to a COCO JSON, see [`read_yolo_dataset`].
+/// Saved to `save_path`, defaulting to `annotations.json` next to the labels folder.
+pub fn yolo2coco(
+    dataset_path: &str,
+    images_path: Option<&str>,
+    image_extensions: &[String],
+    names_path: Option<&str>,
+    save_path: Option<&str>,
+    walk: &WalkOptions,
+) -> Result<()> {
+    let label_root = PathBuf::from(dataset_path);
+    let dataset = read_yolo_dataset(&label_root, images_path, image_extensions, names_path, walk)?;
+
+    let save_path = match save_path {
+        Some(path) => PathBuf::from(path),
+        None => label_root
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join("annotations.json"),
+    };
+    fs::write(&save_path, serde_json::to_string(&dataset)?)
+        .with_context(|| format!("Writing {}", save_path.display()))?;
+    tracing::info!(
+        "{} images, {} annotations, {} categories saved to {}",
+        dataset.images.len(),
+        dataset.annotations.len(),
+        dataset.categories.len(),
+        save_path.display()
+    );
+    Ok(())
+}
+
+/// COCO annotation of a YOLO shape in pixels, ids are assigned by the caller
+fn shape_annotation(shape: &YoloShape, width: f64, height: f64) -> Annotation {
+    let (segmentation, bbox, area) = match shape {
+        YoloShape::Bbox { cx, cy, w, h, .. } => {
+            let bbox = Bbox {
+                left: (cx - w / 2.0) * width,
+                top: (cy - h / 2.0) * height,
+                width: w * width,
+                height: h * height,
+            };
+            let area = bbox.width * bbox.height;
+            (Segmentation::Polygons(vec![]), bbox, area)
+        }
+        YoloShape::Polygon { points, .. } => {
+            let points = points
+                .iter()
+                .map(|(x, y)| (x * width, y * height))
+                .collect::<Vec<_>>();
+            let left = points.iter().map(|p| p.0).fold(f64::MAX, f64::min);
+            let top = points.iter().map(|p| p.1).fold(f64::MAX, f64::min);
+            let right = points.iter().map(|p| p.0).fold(f64::MIN, f64::max);
+            let bottom = points.iter().map(|p| p.1).fold(f64::MIN, f64::max);
+            (
+                Segmentation::Polygons(vec![points.iter().flat_map(|(x, y)| [*x, *y]).collect()]),
+                Bbox {
+                    left,
+                    top,
+                    width: right - left,
+                    height: bottom - top,
+                },
+                shape.area() * width * height,
+            )
+        }
+    };
+    Annotation {
+        id: 0,
+        image_id: 0,
+        category_id: shape.class_id() + 1,
+        segmentation,
+        area,
+        bbox,
+        iscrowd: 0,
+    }
+}
+
+/// Write the YOLO TXT labels of a COCO dataset to `output_root`, one per image named after
+/// its `file_name`.
+///
+/// Categories sorted by id become the YOLO ids 0, 1, ..., their names are written to
+/// `classes.txt` in the output folder. Boxes come from the COCO `bbox`, polygons from the
+/// segmentation, RLE masks are vectorized with `vectorize`. Crowd annotations are skipped
+/// unless `include_crowd` is set.
+pub fn write_yolo_dataset(
+    dataset: &Dataset,
+    output_root: &Path,
+    format: YoloFormat,
+    include_crowd: bool,
+    vectorize: &VectorizeOptions,
+) -> Result<()> {
+    fs::create_dir_all(output_root)?;
+
+    // COCO ids often start at 1 and have gaps, YOLO ids are contiguous from 0
+    let mut categories = dataset.categories.iter().collect::<Vec<_>>();
+    categories.sort_by_key(|category| category.id);
+    let class_ids = categories
+        .iter()
+        .enumerate()
+        .map(|(class_id, category)| (category.id, class_id as u32))
+        .collect::<HashMap<_, _>>();
+    let names = categories
+        .iter()
+        .map(|category| format!("{}\n", category.name))
+        .collect::<String>();
+    fs::write(output_root.join(CLASSES_FILE), names)?;
+
+    let mut image_annotations = HashMap::<u64, Vec<&Annotation>>::new();
+    for annotation in &dataset.annotations {
+        image_annotations
+            .entry(annotation.image_id)
+            .or_default()
+            .push(annotation);
+    }
+
+    let mut written = 0;
+    let mut crowds = 0;
+    for image in &dataset.images {
+        if image.width == 0 || image.height == 0 {
+            bail!("Image {} has no size", image.file_name);
+        }
+        let (width, height) = (image.width as f64, image.height as f64);
+        let mut lines = Vec::new();
+        for annotation in image_annotations.remove(&image.id).unwrap_or_default() {
+            if annotation.iscrowd == 1 && !include_crowd {
+                crowds += 1;
+                continue;
+            }
+            let class_id = class_ids.get(&annotation.category_id).ok_or(anyhow!(
+                "Annotation {} has the unknown category {}",
+                annotation.id,
+                annotation.category_id
+            ))?;
+            let annotation_lines =
+                annotation_lines(annotation, *class_id, width, height, format, vectorize)
+                    .with_context(|| format!("Converting annotation {}", annotation.id))?;
+            written += annotation_lines.len();
+            lines.extend(annotation_lines);
+        }
+
+        let save_path = output_root
+            .join(contained_path(&image.file_name))
+            .with_extension("txt");
+        if let Some(parent) = save_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&save_path, lines.concat())
+            .with_context(|| format!("Writing {}", save_path.display()))?;
+    }
+
+    if crowds > 0 {
+        tracing::warn!("{} crowd annotations skipped", crowds);
+    }
+    tracing::info!(
+        "{} labels with {} objects saved to {}",
+        dataset.images.len(),
+        written,
+        output_root.display()
+    );
+    Ok(())
+}
+
+/// Convert the COCO JSON at `dataset_path` to YOLO TXT labels, see [`write_yolo_dataset`].
+/// Saved to `output_path`, defaulting to the `labels` folder next to the JSON.
+pub fn coco2yolo(
+    dataset_path: &str,
+    output_path: Option<&str>,
+    format: YoloFormat,
+    include_crowd: bool,
+    vectorize: &VectorizeOptions,
+) -> Result<()> {
+    vectorize.validate()?;
+    let dataset_path = PathBuf::from(dataset_path);
+    let dataset = load_dataset(&dataset_path)?;
+    let output_root = match output_path {
+        Some(path) => PathBuf::from(path),
+        None => dataset_path
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join("labels"),
+    };
+    write_yolo_dataset(&dataset, &output_root, format, include_crowd, vectorize)
+}
+
+/// YOLO lines of one COCO annotation, a segmentation of several polygons gives one line each
+fn annotation_lines(
+    annotation: &Annotation,
+    class_id: u32,
+    width: f64,
+    height: f64,
+    format: YoloFormat,
+    vectorize: &VectorizeOptions,
+) -> Result<Vec<String>> {
+    let bbox = &annotation.bbox;
+    let polygons = match format {
+        YoloFormat::Bbox => {
+            return Ok(vec![format!(
+                "{} {} {} {} {}\n",
+                class_id,
+                (bbox.left + bbox.width / 2.0) / width,
+                (bbox.top + bbox.height / 2.0) / height,
+                bbox.width / width,
+                bbox.height / height
+            )]);
+        }
+        YoloFormat::Segment | YoloFormat::Obb => {
+            let polygons = segmentation_polygons(&annotation.segmentation, vectorize)?;
+            if polygons.is_empty() {
+                // Box only annotations
+                vec![vec![
+                    (bbox.left, bbox.top),
+                    (bbox.left + bbox.width, bbox.top),
+                    (bbox.left + bbox.width, bbox.top + bbox.height),
+                    (bbox.left, bbox.top + bbox.height),
+                ]]
+            } else {
+                polygons
+            }
+        }
+    };
+
+    let polygons = if format == YoloFormat::Obb {
+        // One rotated box around all parts of the object
+        let points = polygons
+            .iter()
+            .flatten()
+            .map(|(x, y)| Point2f::new(*x as f32, *y as f32))
+            .collect::<Vector<Point2f>>();
+        let rotated = imgproc::min_area_rect(&points)?;
+        let mut corners = Mat::default();
+        imgproc::box_points(rotated, &mut corners)?;
+        let mut corner_points = Vec::new();
+        for corner in 0..4 {
+            corner_points.push((
+                *corners.at_2d::<f32>(corner, 0)? as f64,
+                *corners.at_2d::<f32>(corner, 1)? as f64,
+            ));
+        }
+        vec![corner_points]
+    } else {
+        polygons
+    };
+
+    Ok(polygons
+        .iter()
+        .map(|polygon| {
+            let mut line = class_id.to_string();
+            for (x, y) in polygon {
+                line.push_str(&format!(
+                    " {} {}",
+                    (x / width).clamp(0.0, 1.0),
+                    (y / height).clamp(0.0, 1.0)
+                ));
+            }
+            line.push('\n');
+            line
+        })
+        .collect())
+}
+
+/// Pixel polygons of a segmentation, RLE masks are vectorized
+fn segmentation_polygons(
+    segmentation: &Segmentation,
+    vectorize: &VectorizeOptions,
+) -> Result<Vec<Vec<(f64, f64)>>> {
+    match segmentation {
+        Segmentation::Polygons(polygons) => Ok(point_pairs(polygons)),
+        Segmentation::PolygonsRS(polygons) => Ok(point_pairs(&polygons.counts)),
+        Segmentation::Rle(_) | Segmentation::CocoRle(_) => {
+            let mask = Mask::try_from(segmentation)?;
+            // ndarray iterates in logical (row major) order whatever the memory layout
+            let data = mask.iter().copied().collect::<Vec<u8>>();
+            let mask = Mat::from_slice(&data)?
+                .reshape(1, mask.nrows() as i32)?
+                .try_clone()?;
+            Ok(vectorize_mask(&mask, vectorize)?
+                .into_iter()
+                .flat_map(|region| region.polygons)
+                .map(|polygon| {
+                    polygon
+                        .into_iter()
+                        .map(|(x, y)| (x as f64, y as f64))
+                        .collect()
+                })
+                .collect())
+        }
+    }
+}
+
+/// `[x1, y1, x2, y2, ...]` polygons as points, degenerate ones dropped
+fn point_pairs(polygons: &[Vec<f64>]) -> Vec<Vec<(f64, f64)>> {
+    polygons
+        .iter()
+        .filter(|polygon| polygon.len() >= 6)
+        .map(|polygon| polygon.chunks_exact(2).map(|p| (p[0], p[1])).collect())
+        .collect()
+}
@@ -1,264 +1,284 @@
-use std::{collections::HashMap, fs, sync::Arc};
+use std::{fs, path::PathBuf, sync::Arc};
 
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Args, ValueEnum};
 use image::Rgb;
-use opencv::{core::MatTrait, imgproc};
-use tokio::{fs::File, io::AsyncWriteExt, sync::Semaphore, task::JoinSet};
-use tracing_unwrap::ResultExt;
+use indicatif::ProgressStyle;
+use opencv::{
+    core::{self, Mat, MatTraitConst, Point, Scalar, Size},
+    imgcodecs, imgproc,
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::info_span;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
 
+use crate::common::palette::Palette;
+use crate::common::vectorize::{color_mask, vectorize_mask, VectorizeOptions};
+use crate::common::walk::{output_dir, output_root, WalkOptions};
 use crate::THREAD_POOL;
 
-pub async fn rgb2yolo(dataset_path: &String, rgb_list: &str) {
-    let mut color_class_map = HashMap::<Rgb<u8>, u32>::new();
-    // 卫星数据
-    // color_class_map.insert(Rgb([0, 0, 0]), 0);
-    // color_class_map.insert(Rgb([0, 255, 0]), 1);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum YoloFormat {
+    /// Segmentation polygons, `class x1 y1 x2 y2 ...`
+    Segment,
+    /// Axis aligned boxes, `class cx cy w h`
+    Bbox,
+    /// Oriented boxes (YOLO-OBB) from minimum area rectangles, `class x1 y1 x2 y2 x3 y3 x4 y4`
+    Obb,
+}
 
-    // color_class_map.insert(Rgb([255, 255, 0]), 2);
-    // color_class_map.insert(Rgb([255, 0, 255]), 3);
-    // color_class_map.insert(Rgb([0, 255, 255]), 4);
-    // color_class_map.insert(Rgb([0, 0, 255]), 5);
-    // color_class_map.insert(Rgb([255, 255, 255]), 6);
+#[derive(Args, Debug, Clone, Copy)]
+pub struct YoloExportOptions {
+    #[arg(
+        long,
+        value_enum,
+        default_value = "segment",
+        help = "The YOLO label format"
+    )]
+    pub format: YoloFormat,
 
-    // YRCC_MS without land
-    // color_class_map.insert(Rgb([0, 255, 0]), 0);
-    // color_class_map.insert(Rgb([255, 255, 0]), 1);
-    // color_class_map.insert(Rgb([255, 0, 255]), 2);
-    // color_class_map.insert(Rgb([0, 255, 255]), 3);
-    // color_class_map.insert(Rgb([0, 0, 255]), 4);
-    // color_class_map.insert(Rgb([255, 255, 255]), 5);
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Objects with fewer pixels are dropped, the polygon area for segment, the component size for bbox / obb"
+    )]
+    pub min_area: u32,
 
-    // YRCC_ORI
-    // color_class_map.insert(Rgb([0, 255, 255]), 0); // ice
-    // color_class_map.insert(Rgb([0, 255, 0]), 1); // water
+    #[command(flatten)]
+    pub vectorize: VectorizeOptions,
 
-    // 航拍数据
-    // color_class_map.insert(Rgb([0, 0, 0]), 0); // land
-    // color_class_map.insert(Rgb([0, 255, 0]), 1);
-    // color_class_map.insert(Rgb([0, 255, 255]), 1); // shore_ice
-    // color_class_map.insert(Rgb([255, 0, 255]), 2); // stream_ice
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Components of a class at most this many pixels apart are merged into one box, bbox / obb only"
+    )]
+    pub merge_distance: u32,
+}
 
-    // Albert
-    // color_class_map.insert(Rgb([0, 0, 0]), 1);
-    // color_class_map.insert(Rgb([128, 128, 128]), 0);
-    // color_class_map.insert(Rgb([255, 255, 255]), 2);
+/// Convert the RGB labels in `dataset_path` to YOLO TXT labels, one `<stem>.txt` per label,
+/// saved to `output_path` (defaults to `<dataset_path>/output`).
+///
+/// Coordinates are normalized by the width and height of every label, any size works.
+pub async fn rgb2yolo(
+    dataset_path: &String,
+    palette: &Palette,
+    options: &YoloExportOptions,
+    output_path: Option<&str>,
+    walk: &WalkOptions,
+) -> Result<()> {
+    options.vectorize.validate()?;
+    // Ignored classes get no objects
+    let mut classes = palette
+        .active()
+        .map(|class| (Rgb(class.rgb), class.id as u32))
+        .collect::<Vec<_>>();
+    classes.sort_by_key(|(_, class_id)| *class_id);
+    let classes = Arc::new(classes);
 
-    for (class_id, rgb) in rgb_list.split(";").enumerate() {
-        let mut rgb_vec: Vec<u8> = vec![];
-        for splited in rgb.split(',') {
-            let splited = splited.parse::<u8>().unwrap();
-            rgb_vec.push(splited);
+    let root = PathBuf::from(dataset_path);
+    let output_root = match output_path {
+        Some(path) => {
+            let path = PathBuf::from(path);
+            fs::create_dir_all(&path)?;
+            path
         }
-        color_class_map.insert(Rgb([rgb_vec[0], rgb_vec[1], rgb_vec[2]]), class_id as u32);
-    }
+        None => output_root(&root, "output")?,
+    };
+    let entries = walk.collect_files(&root, &["png"], &[&output_root])?;
 
-    fs::create_dir_all(format!("{}/../outputs/", dataset_path))
-        .expect_or_log("Create output dir error");
-
-    let mut threads = JoinSet::new();
     let sem = Arc::new(Semaphore::new(
-        (*THREAD_POOL.read().expect_or_log("Get pool error")).into(),
+        (*THREAD_POOL
+            .read()
+            .map_err(|_| anyhow!("THREAD_POOL lock poisoned"))?)
+        .into(),
     ));
+    let mut threads = JoinSet::new();
 
-    // Walk through all images in BASE_PATH
-    let entries = fs::read_dir(dataset_path).unwrap();
-    for entry in entries {
-        let entry = entry.unwrap();
-        if entry
-            .path()
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .ends_with(".png")
-        {
-            let permit = Arc::clone(&sem);
-            let color_class_map = color_class_map.clone();
-            let dataset_path = dataset_path.clone();
-            threads.spawn(async move {
-                // Limit tasks to 10
-                let _permit = permit.acquire().await.unwrap();
-
-                let img: image::ImageBuffer<Rgb<u8>, Vec<u8>> =
-                    image::open(entry.path()).unwrap().into_rgb8();
-
-                let mut labels = Vec::<String>::new();
-                for (color, class_id) in color_class_map.clone().iter() {
-
-                    let mut mat = opencv::core::Mat::new_rows_cols_with_default(
-                        768,
-                        768,
-                        opencv::core::CV_8U,
-                        opencv::core::Scalar::all(0.),
-                    )
-                    .unwrap();
-                    // println!("{:?}", mat);
+    let header_span = info_span!("rgb2yolo_threads");
+    header_span.pb_set_style(&ProgressStyle::with_template(
+        "{spinner} Processing {msg}\n{wide_bar} {pos}/{len}",
+    )?);
+    header_span.pb_set_length(entries.len() as u64);
 
-                    // Turn rgb label to gray image mask
-                    for (x, y, pixel) in img.enumerate_pixels() {
-                        let Rgb([r, g, b]) = pixel;
-                        let Rgb([tr, tg, tb]) = color;
-                        if r == tr && g == tg && b == tb {
-                            // Set mat at x,y to 255
-                            *mat.at_2d_mut::<u8>(x as i32, y as i32).unwrap() = 255;
-                        } else {
-                            *mat.at_2d_mut::<u8>(x as i32, y as i32).unwrap() = 0;
-                        }
-                    }
+    let header_span_enter = header_span.enter();
 
-                    let mut contours =
-                        opencv::core::Vector::<opencv::core::Vector<opencv::core::Point>>::new();
+    for entry in entries {
+        let save_path = output_dir(&root, &entry, &output_root)?
+            .join(entry.with_extension("txt").file_name().unwrap_or_default());
 
-                    // Same level next
-                    // Same level previous
-                    // Child
-                    // Parent
-                    let mut hierarchy = opencv::core::Vector::<opencv::core::Vec4i>::new();
-                    imgproc::find_contours_with_hierarchy_def(
-                        &mat,
-                        &mut contours,
-                        &mut hierarchy,
-                        imgproc::RETR_CCOMP,
-                        imgproc::CHAIN_APPROX_TC89_KCOS,
-                    )
-                    .unwrap();
+        let permit = sem.clone().acquire_owned().await?;
+        let header_span = header_span.clone();
+        let classes = classes.clone();
+        let options = *options;
+        threads.spawn_blocking(move || -> Result<()> {
+            let _permit = permit;
+            let label = imgcodecs::imread(
+                entry.to_str().ok_or(anyhow!("Invalid path"))?,
+                imgcodecs::IMREAD_COLOR,
+            )?;
+            if label.empty() {
+                bail!("Label {} is not readable", entry.display());
+            }
 
-                    // println!("{:?}", contours);
-                    // println!("{:?}", hierarchy);
+            let labels = match options.format {
+                YoloFormat::Segment => segment_labels(&label, &classes, &options),
+                YoloFormat::Bbox | YoloFormat::Obb => box_labels(&label, &classes, &options),
+            }
+            .with_context(|| format!("Converting {}", entry.display()))?;
+            fs::write(&save_path, labels.concat())
+                .with_context(|| format!("Writing {}", save_path.display()))?;
+            header_span.pb_set_message(&entry.to_string_lossy());
+            header_span.pb_inc(1);
+            Ok(())
+        });
+    }
 
-                    let mut combined_contours: Vec<Vec<(i32, i32)>> = Vec::new();
+    while let Some(result) = threads.join_next().await {
+        result??;
+    }
+    drop(header_span_enter);
+    drop(header_span);
+    tracing::info!("Saved to {}", output_root.display());
+    Ok(())
+}
 
-                    // Now go through all the hierarchy and combine contours
-                    let mut current_index: i32 = 0;
-                    while current_index != -1 && !contours.is_empty() {
-                        let current_contour = contours.get(current_index as usize).unwrap();
-                        let current_hierarchy = hierarchy.get(current_index as usize).unwrap();
+/// Segmentation polygons, `class x1 y1 x2 y2 ...`, one line per polygon of every region.
+///
+/// Regions with an area (holes excluded) below `min_area` pixels are dropped.
+fn segment_labels(
+    label: &Mat,
+    classes: &[(Rgb<u8>, u32)],
+    options: &YoloExportOptions,
+) -> Result<Vec<String>> {
+    let (width, height) = (label.cols() as f64, label.rows() as f64);
+    let mut labels = Vec::<String>::new();
+    for (color, class_id) in classes {
+        let mask = color_mask(label, color.0)?;
+        for region in vectorize_mask(&mask, &options.vectorize)? {
+            if region.area < options.min_area as f64 {
+                continue;
+            }
+            for polygon in &region.polygons {
+                let mut line = class_id.to_string();
+                for (x, y) in polygon {
+                    line.push_str(&format!(
+                        " {} {}",
+                        f64::from(*x) / width,
+                        f64::from(*y) / height
+                    ));
+                }
+                line.push('\n');
+                labels.push(line);
+            }
+        }
+    }
+    Ok(labels)
+}
 
-                        let mut parent_points = Vec::<(i32, i32)>::new();
-                        current_contour.iter().for_each(|point| {
-                            parent_points.push((point.x, point.y));
-                        });
-                        if current_hierarchy.get(2).unwrap() != &-1 {
-                            // Contain child, go through holes
-                            let mut child_contour_index = *current_hierarchy.get(2).unwrap();
-                            loop {
-                                let child_contour =
-                                    contours.get(child_contour_index as usize).unwrap();
-                                let child_hierarchy =
-                                    hierarchy.get(child_contour_index as usize).unwrap();
+/// Boxes of the connected components of every class, as `class cx cy w h` or,
+/// for OBB, the 4 corners of the minimum area rectangle, normalized by the image size.
+///
+/// Components at most `merge_distance` pixels apart are merged by labelling a dilated mask,
+/// the boxes are still fitted to the pixels of the class only.
+fn box_labels(
+    label: &Mat,
+    classes: &[(Rgb<u8>, u32)],
+    options: &YoloExportOptions,
+) -> Result<Vec<String>> {
+    let (width, height) = (label.cols() as f32, label.rows() as f32);
 
-                                let mut child_points = Vec::<(i32, i32)>::new();
-                                child_contour.iter().for_each(|point| {
-                                    child_points.push((point.x, point.y));
-                                });
-                                if child_points.len() > 3 {
-                                    // Find the nearest point between child_points and contour_points
-                                    let mut min_distance = f64::MAX;
-                                    let mut child_index = 0;
-                                    let mut parent_index = 0;
-                                    for (i, parent_point) in parent_points.iter().enumerate() {
-                                        for (j, child_point) in child_points.iter().enumerate() {
-                                            let distance = f64::from(
-                                                (parent_point.0 - child_point.0).pow(2)
-                                                    + (parent_point.1 - child_point.1).pow(2),
-                                            )
-                                            .sqrt();
-                                            if distance < min_distance {
-                                                min_distance = distance;
-                                                child_index = j;
-                                                parent_index = i;
-                                            }
-                                        }
-                                    }
+    let mut labels = Vec::new();
+    for (color, class_id) in classes {
+        let mask = color_mask(label, color.0)?;
 
-                                    // Combine two contours
-                                    let mut new_points = Vec::<(i32, i32)>::new();
-                                    new_points.extend(parent_points.iter().take(parent_index + 1));
-                                    new_points.extend(child_points.iter().skip(child_index));
-                                    new_points.extend(child_points.iter().take(child_index + 1));
-                                    new_points.extend(parent_points.iter().skip(parent_index));
-                                    parent_points = new_points;
-                                }
-                                child_contour_index = *child_hierarchy.first().unwrap();
-                                if child_contour_index == -1 {
-                                    break;
-                                }
-                            }
-                        }
-                        // No more child
-                        if parent_points.len() > 10 {
-                            // Can't form valid polygon
-                            combined_contours.push(parent_points);
-                        }
+        let grouped = if options.merge_distance > 0 {
+            // Both sides grow, so a gap of merge_distance pixels is closed by a kernel one larger
+            let size = options.merge_distance as i32 + 1;
+            let kernel = imgproc::get_structuring_element(
+                imgproc::MORPH_RECT,
+                Size::new(size, size),
+                Point::new(-1, -1),
+            )?;
+            let mut dilated = Mat::default();
+            imgproc::dilate_def(&mask, &mut dilated, &kernel)?;
+            dilated
+        } else {
+            mask.clone()
+        };
 
-                        current_index = *current_hierarchy.first().unwrap();
-                    }
+        let mut components = Mat::default();
+        let mut component_stats = Mat::default();
+        let mut centroids = Mat::default();
+        let count = imgproc::connected_components_with_stats(
+            &grouped,
+            &mut components,
+            &mut component_stats,
+            &mut centroids,
+            8,
+            core::CV_32S,
+        )?;
 
-                    for contour in combined_contours.iter() {
-                        let mut result = String::new();
-                        result.push_str(class_id.to_string().as_str());
-                        result.push(' ');
-                        contour.iter().for_each(|point| {
-                            result.push_str(&format!(
-                                "{} ",
-                                (f64::from(point.1) / f64::from(img.width()))
-                            ));
-                            result.push_str(&format!(
-                                "{} ",
-                                f64::from(point.0) / f64::from(img.height())
-                            ));
-                        });
-                        result.push('\n');
-                        labels.push(result);
+        // Component 0 is the background of the mask
+        for index in 1..count {
+            let rect = core::Rect::new(
+                *component_stats.at_2d::<i32>(index, imgproc::CC_STAT_LEFT)?,
+                *component_stats.at_2d::<i32>(index, imgproc::CC_STAT_TOP)?,
+                *component_stats.at_2d::<i32>(index, imgproc::CC_STAT_WIDTH)?,
+                *component_stats.at_2d::<i32>(index, imgproc::CC_STAT_HEIGHT)?,
+            );
+            let mut component = Mat::default();
+            core::compare(
+                &Mat::roi(&components, rect)?,
+                &Scalar::all(index as f64),
+                &mut component,
+                core::CMP_EQ,
+            )?;
+            let mut pixels = Mat::default();
+            core::bitwise_and_def(&component, &Mat::roi(&mask, rect)?, &mut pixels)?;
+            if core::count_non_zero(&pixels)? < (options.min_area as i32).max(1) {
+                continue;
+            }
+            let mut points = core::Vector::<Point>::new();
+            core::find_non_zero(&pixels, &mut points)?;
 
-                        /*
-                        imageproc::drawing::draw_antialiased_polygon_mut(
-                            &mut output_img,
-                            contour
-                                .iter()
-                                .map(|point| imageproc::point::Point {
-                                    x: point.1 as i32,
-                                    y: point.0 as i32,
-                                })
-                                .collect::<Vec<imageproc::point::Point<i32>>>()
-                                .as_slice(),
-                            Rgb([255, 128, 0]),
-                            interpolate,
-                        );
-                        */
+            let mut line = class_id.to_string();
+            match options.format {
+                YoloFormat::Obb => {
+                    let rotated = imgproc::min_area_rect(&points)?;
+                    // Fitted on pixel centers, one pixel larger to cover them
+                    let rotated = core::RotatedRect::new(
+                        rotated.center,
+                        core::Size2f::new(rotated.size.width + 1.0, rotated.size.height + 1.0),
+                        rotated.angle,
+                    )?;
+                    let mut corners = Mat::default();
+                    imgproc::box_points(rotated, &mut corners)?;
+                    for corner in 0..4 {
+                        let x = *corners.at_2d::<f32>(corner, 0)? + rect.x as f32;
+                        let y = *corners.at_2d::<f32>(corner, 1)? + rect.y as f32;
+                        line.push_str(&format!(
+                            " {} {}",
+                            ((x + 0.5) / width).clamp(0.0, 1.0),
+                            ((y + 0.5) / height).clamp(0.0, 1.0)
+                        ));
                     }
-                    /*
-                    output_img
-                        .save(format!(
-                            "./outputs/images/{}/{}",
-                            class_id,
-                            entry.file_name().into_string().unwrap()
-                        ))
-                        .unwrap();
-                    */
                 }
-                File::create(format!(
-                    "{}/../outputs/{}",
-                    dataset_path,
-                    entry
-                        .file_name()
-                        .into_string()
-                        .unwrap()
-                        .to_string()
-                        .replace(".png", ".txt")
-                        .replace("v2_", "")
-                ))
-                .await
-                .unwrap()
-                .write_all(labels.concat().as_bytes())
-                .await
-                .unwrap();
-                println!(
-                    "{} finished process",
-                    entry.file_name().into_string().unwrap()
-                );
-            });
+                _ => {
+                    let bounds = imgproc::bounding_rect(&points)?;
+                    let x = (bounds.x + rect.x) as f32;
+                    let y = (bounds.y + rect.y) as f32;
+                    line.push_str(&format!(
+                        " {} {} {} {}",
+                        (x + bounds.width as f32 / 2.0) / width,
+                        (y + bounds.height as f32 / 2.0) / height,
+                        bounds.width as f32 / width,
+                        bounds.height as f32 / height
+                    ));
+                }
+            }
+            line.push('\n');
+            labels.push(line);
         }
     }
-
-    while threads.join_next().await.is_some() {}
+    Ok(labels)
 }
@@ -1,86 +1,74 @@
 use std::{
     collections::HashMap,
     fs,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
 
+use anyhow::{anyhow, Context, Result};
 use itertools::Itertools;
-use tokio::{sync::Semaphore, task::JoinSet};
-use tracing_unwrap::ResultExt;
+use tokio::task::JoinSet;
 
-use crate::THREAD_POOL;
+use crate::common::{
+    dataset::{
+        pairing::index_by_relparent_and_stem,
+        splits::{assign_splits, Split},
+    },
+    walk::WalkOptions,
+};
 
-pub async fn split_dataset(dataset_path: &String, train_ratio: &f32) {
-    let entries = fs::read_dir(dataset_path).unwrap();
-    let mut threads = JoinSet::new();
-    let sem = Arc::new(Semaphore::new(
-        (*THREAD_POOL.read().expect_or_log("Get pool error")).into(),
-    ));
-    let result = Arc::new(Mutex::new(Vec::<String>::new()));
-    for entry in entries {
-        let entry = entry.unwrap();
-        if entry
-            .path()
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .ends_with(".txt")
-        {
-            let permit = Arc::clone(&sem);
-            let result = Arc::clone(&result);
-            threads.spawn(async move {
-                let _permit = permit.acquire().await.unwrap();
-                result.lock().unwrap().push(format!(
-                    "./images/{}\n",
-                    entry
-                        .path()
-                        .file_name()
-                        .unwrap()
-                        .to_str()
-                        .unwrap()
-                        .to_string()
-                        .replace(".txt", ".png")
-                ));
-            });
+use super::coco::is_classes_file;
+
+/// Pair the TXT labels in `dataset_path` with the images in the sibling `images` folder
+/// by stem, labels without an image are skipped with a warning
+pub async fn split_dataset(
+    dataset_path: &String,
+    splits: &[Split],
+    image_extensions: &[String],
+) -> Result<()> {
+    let label_root = PathBuf::from(dataset_path);
+    let image_root = label_root
+        .parent()
+        .ok_or(anyhow!("Failed to get parent of {}", label_root.display()))?
+        .join("images");
+
+    let mut label_index = index_by_relparent_and_stem(&label_root, &["txt".to_string()], false)?;
+    label_index.retain(|_, path| !is_classes_file(path));
+    let image_index = index_by_relparent_and_stem(&image_root, image_extensions, false)
+        .with_context(|| format!("Indexing image dir {}", image_root.display()))?;
+
+    let mut data = Vec::<String>::new();
+    for key in label_index.keys() {
+        match image_index.get(key).and_then(|x| x.file_name()) {
+            Some(file_name) => data.push(format!("./images/{}\n", file_name.to_string_lossy())),
+            None => tracing::warn!("No image found for label {}, skipped", key),
         }
     }
 
-    while threads.join_next().await.is_some() {}
-
-    let mut data = result.lock().unwrap();
     use rand::seq::SliceRandom;
     data.shuffle(&mut rand::thread_rng());
-    let train_count = (data.len() as f32 * train_ratio) as i32;
-    let train_data = data[0..train_count as usize].to_vec();
-    let valid_data = data[train_count as usize..].to_vec();
 
-    fs::write(
-        format!("{}/../val.txt", dataset_path),
-        valid_data.concat(),
-    )
-    .unwrap();
-    fs::write(
-        format!("{}/../train.txt", dataset_path),
-        train_data.concat(),
-    )
-    .unwrap();
-    tracing::info!("Train dataset length: {}", train_data.len());
-    tracing::info!("Saved to {}/../train.txt", dataset_path);
-    tracing::info!("Valid dataset length: {}", valid_data.len());
-    tracing::info!("Saved to {}/../val.txt", dataset_path);
+    for (split, split_data) in assign_splits(data, splits) {
+        fs::write(
+            format!("{}/../{}.txt", dataset_path, split),
+            split_data.concat(),
+        )?;
+        tracing::info!("{} dataset length: {}", split, split_data.len());
+        tracing::info!("Saved to {}/../{}.txt", dataset_path, split);
+    }
     tracing::info!("Dataset split done");
+    Ok(())
 }
 
-pub async fn count_types(dataset_path: &String) {
-    let entries = fs::read_dir(dataset_path).unwrap();
+pub async fn count_types(dataset_path: &String, walk: &WalkOptions) -> Result<()> {
+    let mut entries = walk.collect_files(Path::new(dataset_path), &["txt"], &[])?;
+    entries.retain(|path| !is_classes_file(path));
     let type_map = Arc::new(Mutex::new(HashMap::<u8, u32>::new()));
     let mut threads = JoinSet::new();
     for entry in entries {
-        let entry = entry.unwrap();
         let type_map = Arc::clone(&type_map);
         threads.spawn(async move {
-            let content = fs::read_to_string(entry.path()).unwrap();
+            let content = fs::read_to_string(&entry).unwrap();
             let mut current_type_map = HashMap::<u8, u32>::new();
             content.lines().for_each(|line| {
                 let class_id = line
@@ -106,4 +94,5 @@ pub async fn count_types(dataset_path: &String) {
         let count = type_map.get(class_id).unwrap();
         tracing::info!("Class {}: {}", class_id, count);
     }
+    Ok(())
 }
@@ -0,0 +1,438 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Args, ValueEnum};
+use indicatif::ProgressStyle;
+use opencv::{
+    core::{self, Mat, Point, Rect, Scalar, Vector},
+    imgcodecs, imgproc,
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::info_span;
+use tracing_indicatif::span_ext::IndicatifSpanExt;
+
+use super::coco::is_classes_file;
+use crate::common::dataset::merge::LabelKind;
+use crate::common::dataset::pairing::{index_by_relparent_and_stem, relparent_and_stem};
+use crate::common::palette::Palette;
+use crate::common::walk::{output_dir, output_root, WalkOptions};
+use crate::THREAD_POOL;
+
+/// Which shape ends up on top where shapes overlap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OverlapOrder {
+    /// Later lines of the label file are drawn over earlier ones
+    File,
+    /// Higher class ids are drawn over lower ones
+    ClassAsc,
+    /// Lower class ids are drawn over higher ones
+    ClassDesc,
+    /// Smaller shapes are drawn over larger ones
+    AreaDesc,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct RasterOptions {
+    #[arg(
+        short,
+        long,
+        help = "The folder containing the images, used for the mask sizes, defaults to the sibling images folder"
+    )]
+    pub images_path: Option<String>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = crate::common::dataset::pairing::DEFAULT_IMAGE_EXTENSIONS,
+        help = "Image extensions to pair with the TXT labels, comma separated"
+    )]
+    pub image_extensions: Vec<String>,
+
+    #[arg(
+        long,
+        requires = "height",
+        help = "Mask width for labels without an image"
+    )]
+    pub width: Option<u32>,
+
+    #[arg(
+        long,
+        requires = "width",
+        help = "Mask height for labels without an image"
+    )]
+    pub height: Option<u32>,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Class id of the pixels not covered by any shape"
+    )]
+    pub background: u8,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "file",
+        help = "Which shape is kept where shapes overlap"
+    )]
+    pub overlap: OverlapOrder,
+}
+
+/// One object of a YOLO label file, coordinates normalized to 0-1
+#[derive(Debug, Clone, PartialEq)]
+pub enum YoloShape {
+    /// `class cx cy w h`
+    Bbox {
+        class_id: u32,
+        cx: f64,
+        cy: f64,
+        w: f64,
+        h: f64,
+    },
+    /// `class x1 y1 x2 y2 ...`, segmentation polygons and OBB corners
+    Polygon {
+        class_id: u32,
+        points: Vec<(f64, f64)>,
+    },
+}
+
+impl YoloShape {
+    pub fn class_id(&self) -> u32 {
+        match self {
+            YoloShape::Bbox { class_id, .. } | YoloShape::Polygon { class_id, .. } => *class_id,
+        }
+    }
+
+    /// Area in normalized units
+    pub fn area(&self) -> f64 {
+        match self {
+            YoloShape::Bbox { w, h, .. } => w * h,
+            YoloShape::Polygon { points, .. } => {
+                let doubled = points
+                    .iter()
+                    .zip(points.iter().cycle().skip(1))
+                    .map(|(a, b)| a.0 * b.1 - b.0 * a.1)
+                    .sum::<f64>();
+                doubled.abs() / 2.0
+            }
+        }
+    }
+}
+
+/// Parse one line of a YOLO label or prediction file.
+///
+/// 5 values are a box, more are a polygon. A trailing confidence, as written by
+/// predictions, is dropped: a 6th value after a box, an odd number of coordinates otherwise.
+pub fn parse_yolo_line(line: &str) -> Result<Option<YoloShape>> {
+    let values = line.split_whitespace().collect::<Vec<_>>();
+    if values.is_empty() {
+        return Ok(None);
+    }
+    let class_id = values[0]
+        .parse::<u32>()
+        .with_context(|| format!("Parsing class id of '{}'", line))?;
+    let coordinates = values[1..]
+        .iter()
+        .map(|v| v.parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Parsing coordinates of '{}'", line))?;
+    match coordinates.len() {
+        4 | 5 => Ok(Some(YoloShape::Bbox {
+            class_id,
+            cx: coordinates[0],
+            cy: coordinates[1],
+            w: coordinates[2],
+            h: coordinates[3],
+        })),
+        n if n >= 6 => Ok(Some(YoloShape::Polygon {
+            class_id,
+            points: coordinates[..n - n % 2]
+                .chunks_exact(2)
+                .map(|p| (p[0], p[1]))
+                .collect(),
+        })),
+        _ => bail!("'{}' is neither a box nor a polygon", line),
+    }
+}
+
+pub fn read_yolo_labels(path: &Path) -> Result<Vec<YoloShape>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Reading {}", path.display()))?;
+    let mut shapes = Vec::new();
+    for line in content.lines() {
+        if let Some(shape) =
+            parse_yolo_line(line).with_context(|| format!("Parsing {}", path.display()))?
+        {
+            shapes.push(shape);
+        }
+    }
+    Ok(shapes)
+}
+
+/// The `images` folder next to the labels folder of a YOLO dataset
+pub fn default_images_path(label_path: &Path) -> Result<PathBuf> {
+    let label_dir = if label_path.is_file() {
+        label_path.parent().unwrap_or(Path::new("."))
+    } else {
+        label_path
+    };
+    Ok(label_dir
+        .parent()
+        .ok_or(anyhow!("Failed to get parent of {}", label_dir.display()))?
+        .join("images"))
+}
+
+/// Rasterize the YOLO TXT labels in `dataset_path` into RGB masks (palette colors) or
+/// 8 bit class masks, saved as PNG to `<dataset_path>/output`.
+///
+/// The mask size comes from the image of the same relative folder + stem in
+/// `options.images_path` (the sibling `images` folder by default), or from
+/// `options.width` / `options.height` when there is none.
+pub async fn yolo2mask(
+    dataset_path: &str,
+    label_kind: LabelKind,
+    palette: Option<&Palette>,
+    options: &RasterOptions,
+    walk: &WalkOptions,
+) -> Result<()> {
+    let label_root = PathBuf::from(dataset_path);
+    let output_root = output_root(&label_root, "output")?;
+    let mut entries = walk.collect_files(&label_root, &["txt"], &[&output_root])?;
+    entries.retain(|path| !is_classes_file(path));
+
+    let image_root = match &options.images_path {
+        Some(path) => PathBuf::from(path),
+        None => default_images_path(&label_root)?,
+    };
+    let image_index = if image_root.is_dir() {
+        index_by_relparent_and_stem(&image_root, &options.image_extensions, walk.recursive)?
+    } else {
+        Default::default()
+    };
+    let default_size = options.width.zip(options.height);
+    if image_index.is_empty() && default_size.is_none() {
+        bail!(
+            "No image found in {}, please give the mask size with --width and --height",
+            image_root.display()
+        );
+    }
+
+    // Class id -> BGR color
+    let colors = match (label_kind, palette) {
+        (LabelKind::Rgb, Some(palette)) => palette
+            .classes
+            .iter()
+            .map(|class| {
+                (
+                    class.id as u32,
+                    Scalar::new(
+                        class.rgb[2] as f64,
+                        class.rgb[1] as f64,
+                        class.rgb[0] as f64,
+                        0.0,
+                    ),
+                )
+            })
+            .collect::<HashMap<_, _>>(),
+        (LabelKind::Rgb, None) => bail!("RGB masks need a palette"),
+        (LabelKind::Class, _) => (0..=u8::MAX as u32)
+            .map(|id| (id, Scalar::all(id as f64)))
+            .collect(),
+    };
+    let background = *colors
+        .get(&(options.background as u32))
+        .unwrap_or(&Scalar::all(0.0));
+    let colors = Arc::new(colors);
+
+    let sem = Arc::new(Semaphore::new(
+        (*THREAD_POOL
+            .read()
+            .map_err(|_| anyhow!("THREAD_POOL lock poisoned"))?)
+        .into(),
+    ));
+    let mut threads = JoinSet::new();
+
+    let header_span = info_span!("yolo2mask_threads");
+    header_span.pb_set_style(&ProgressStyle::with_template(
+        "{spinner} Processing {msg}\n{wide_bar} {pos}/{len}",
+    )?);
+    header_span.pb_set_length(entries.len() as u64);
+
+    let header_span_enter = header_span.enter();
+
+    for entry in entries {
+        let key = if label_root.is_file() {
+            relparent_and_stem(label_root.parent().unwrap_or(Path::new("")), &entry)?
+        } else {
+            relparent_and_stem(&label_root, &entry)?
+        };
+        let size = match image_index.get(&key) {
+            Some(image) => image::image_dimensions(image)
+                .with_context(|| format!("Reading size of {}", image.display()))?,
+            None => default_size.ok_or(anyhow!(
+                "No image found for {}, please give the mask size with --width and --height",
+                entry.display()
+            ))?,
+        };
+        let save_path = output_dir(&label_root, &entry, &output_root)?
+            .join(entry.with_extension("png").file_name().unwrap_or_default());
+
+        let permit = sem.clone().acquire_owned().await?;
+        let header_span = header_span.clone();
+        let colors = colors.clone();
+        let overlap = options.overlap;
+        threads.spawn_blocking(move || -> Result<()> {
+            let _permit = permit;
+            let mut shapes = read_yolo_labels(&entry)?;
+            match overlap {
+                OverlapOrder::File => {}
+                OverlapOrder::ClassAsc => shapes.sort_by_key(|s| s.class_id()),
+                OverlapOrder::ClassDesc => shapes.sort_by_key(|s| std::cmp::Reverse(s.class_id())),
+                OverlapOrder::AreaDesc => shapes.sort_by(|a, b| b.area().total_cmp(&a.area())),
+            }
+
+            let mask = rasterize(&shapes, size, label_kind, &colors, background)
+                .with_context(|| format!("Rasterizing {}", entry.display()))?;
+            imgcodecs::imwrite(
+                save_path.to_str().ok_or(anyhow!("Invalid path"))?,
+                &mask,
+                &Vector::new(),
+            )?;
+            header_span.pb_set_message(&entry.to_string_lossy());
+            header_span.pb_inc(1);
+            Ok(())
+        });
+    }
+
+    while let Some(result) = threads.join_next().await {
+        result??;
+    }
+    drop(header_span_enter);
+    drop(header_span);
+    tracing::info!("Saved to {}", output_root.display());
+    Ok(())
+}
+
+/// Draw `shapes` in order, later ones on top
+fn rasterize(
+    shapes: &[YoloShape],
+    (width, height): (u32, u32),
+    label_kind: LabelKind,
+    colors: &HashMap<u32, Scalar>,
+    background: Scalar,
+) -> Result<Mat> {
+    let mat_type = match label_kind {
+        LabelKind::Rgb => core::CV_8UC3,
+        LabelKind::Class => core::CV_8UC1,
+    };
+    let mut mask =
+        Mat::new_rows_cols_with_default(height as i32, width as i32, mat_type, background)?;
+    let (w, h) = (width as f64, height as f64);
+
+    for shape in shapes {
+        let color = *colors
+            .get(&shape.class_id())
+            .ok_or(anyhow!("Class {} has no color", shape.class_id()))?;
+        match shape {
+            YoloShape::Bbox {
+                cx,
+                cy,
+                w: bw,
+                h: bh,
+                ..
+            } => {
+                let x0 = ((cx - bw / 2.0) * w).round().clamp(0.0, w) as i32;
+                let y0 = ((cy - bh / 2.0) * h).round().clamp(0.0, h) as i32;
+                let x1 = ((cx + bw / 2.0) * w).round().clamp(0.0, w) as i32;
+                let y1 = ((cy + bh / 2.0) * h).round().clamp(0.0, h) as i32;
+                if x1 > x0 && y1 > y0 {
+                    imgproc::rectangle(
+                        &mut mask,
+                        Rect::new(x0, y0, x1 - x0, y1 - y0),
+                        color,
+                        imgproc::FILLED,
+                        imgproc::LINE_8,
+                        0,
+                    )?;
+                }
+            }
+            YoloShape::Polygon { points, .. } => {
+                if points.len() < 3 {
+                    continue;
+                }
+                // Polygon points are pixel positions, as written by rgb2yolo
+                let polygon = points
+                    .iter()
+                    .map(|(x, y)| {
+                        Point::new(
+                            (x * w).round().clamp(0.0, w - 1.0) as i32,
+                            (y * h).round().clamp(0.0, h - 1.0) as i32,
+                        )
+                    })
+                    .collect::<Vector<Point>>();
+                let polygons = Vector::<Vector<Point>>::from_iter([polygon]);
+                imgproc::fill_poly_def(&mut mask, &polygons, color)?;
+            }
+        }
+    }
+    Ok(mask)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{parse_yolo_line, YoloShape};
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::bbox(
+        "3 0.5 0.25 0.2 0.1",
+        YoloShape::Bbox { class_id: 3, cx: 0.5, cy: 0.25, w: 0.2, h: 0.1 }
+    )]
+    #[case::bbox_with_confidence(
+        "3 0.5 0.25 0.2 0.1 0.87",
+        YoloShape::Bbox { class_id: 3, cx: 0.5, cy: 0.25, w: 0.2, h: 0.1 }
+    )]
+    #[case::polygon(
+        "1 0.1 0.1 0.9 0.1 0.5 0.8",
+        YoloShape::Polygon { class_id: 1, points: vec![(0.1, 0.1), (0.9, 0.1), (0.5, 0.8)] }
+    )]
+    #[case::polygon_with_confidence(
+        "1 0.1 0.1 0.9 0.1 0.5 0.8 0.42",
+        YoloShape::Polygon { class_id: 1, points: vec![(0.1, 0.1), (0.9, 0.1), (0.5, 0.8)] }
+    )]
+    #[case::obb(
+        "0 0.1 0.1 0.4 0.1 0.4 0.3 0.1 0.3",
+        YoloShape::Polygon {
+            class_id: 0,
+            points: vec![(0.1, 0.1), (0.4, 0.1), (0.4, 0.3), (0.1, 0.3)],
+        }
+    )]
+    #[case::extra_whitespace(
+        "  2\t0.5  0.5 1 1 ",
+        YoloShape::Bbox { class_id: 2, cx: 0.5, cy: 0.5, w: 1.0, h: 1.0 }
+    )]
+    fn parse_shape(#[case] line: &str, #[case] expected: YoloShape) {
+        assert_eq!(parse_yolo_line(line).unwrap(), Some(expected));
+    }
+
+    #[rstest]
+    #[case::empty("")]
+    #[case::whitespace(" \t ")]
+    fn parse_blank_line(#[case] line: &str) {
+        assert_eq!(parse_yolo_line(line).unwrap(), None);
+    }
+
+    #[rstest]
+    #[case::too_few_values("0 0.5 0.5 0.2")]
+    #[case::class_only("0")]
+    #[case::class_name("person 0.5 0.5 0.2 0.1")]
+    #[case::negative_class("-1 0.5 0.5 0.2 0.1")]
+    #[case::malformed_coordinate("0 0.5 0.5 0.2 abc")]
+    fn parse_malformed_line(#[case] line: &str) {
+        assert!(parse_yolo_line(line).is_err());
+    }
+}
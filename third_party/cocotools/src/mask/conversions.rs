@@ -378,8 +378,9 @@ impl TryFrom<&object_detection::PolygonsRS> for Mask {
             drawing::draw_polygon_mut(&mut mask, &points_poly, image::Luma([1u8]));
         }
 
+        // The image buffer is row major, (height, width)
         Self::from_shape_vec(
-            (poly_ann.size[1] as usize, poly_ann.size[0] as usize),
+            (poly_ann.size[0] as usize, poly_ann.size[1] as usize),
             mask.into_raw(),
         )
         .map_err(MaskError::ImageToNDArrayConversion)
@@ -499,7 +500,14 @@ mod tests {
                 [0, 0, 0, 0, 0, 0, 0],
                 [0, 0, 0, 0, 0, 0, 0]],
     )]
-    // FIXME: Non-square tests seem to fail.
+    #[case::non_square(
+        &PolygonsRS {size: vec![5, 7], counts: vec![vec![1.0, 1.0, 1.0, 3.0, 5.0, 3.0, 5.0, 1.0]]},
+        &array![[0, 0, 0, 0, 0, 0, 0],
+                [0, 1, 1, 1, 1, 1, 0],
+                [0, 1, 1, 1, 1, 1, 0],
+                [0, 1, 1, 1, 1, 1, 0],
+                [0, 0, 0, 0, 0, 0, 0]],
+    )]
     fn poly_rs_to_mask(#[case] poly: &PolygonsRS, #[case] expected_mask: &Mask) {
         let mask = Mask::try_from(poly).unwrap();
         assert_eq!(&mask, expected_mask);